@@ -0,0 +1,265 @@
+use std::{
+  alloc::{GlobalAlloc, Layout, System},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+  str::FromStr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  thread,
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dns::{
+  serve,
+  transport::{mock::MockTransport, NetworkTransport, Transport},
+  DnsServer, Message, Octets,
+};
+use domain::{
+  base::{
+    iana::{Class, Rcode, Rtype},
+    Dname, MessageBuilder, Question,
+  },
+  rdata::{Aaaa, Ns, A},
+};
+use rand::rngs::StdRng;
+
+// Name servers in the referral `resolve_large_referral` follows
+const REFERRAL_SERVERS: u8 = 13;
+
+/// Counts allocations, so benchmarks can report how many an operation makes as well as how long
+/// it takes.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn query(name: &Dname<Octets>, rtype: Rtype) -> Octets {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_rd(true);
+  let mut builder = builder.question();
+  builder.push((name, rtype)).unwrap();
+  builder.finish()
+}
+
+fn answer(name: &Dname<Octets>) -> Message {
+  let request = Message::from_octets(query(name, Rtype::A)).unwrap();
+  let mut builder = MessageBuilder::new_vec()
+    .start_answer(&request, Rcode::NoError)
+    .unwrap();
+  builder.header_mut().set_aa(true);
+  let addr = A::new(Ipv4Addr::new(192, 0, 2, 10));
+  builder.push((name, Class::In, 3600, addr)).unwrap();
+  Message::from_octets(builder.finish()).unwrap()
+}
+
+/// A referral from a server for the zone above `zone` to `host` at `addr`, with its glue.
+fn referral(
+  name: &Dname<Octets>,
+  zone: &Dname<Octets>,
+  host: &Dname<Octets>,
+  addr: Ipv4Addr,
+) -> Message {
+  let request = Message::from_octets(query(name, Rtype::A)).unwrap();
+  let mut builder = MessageBuilder::new_vec()
+    .start_answer(&request, Rcode::NoError)
+    .unwrap()
+    .authority();
+  builder
+    .push((zone, Class::In, 3600, Ns::new(host)))
+    .unwrap();
+  let mut builder = builder.additional();
+  builder.push((host, Class::In, 3600, A::new(addr))).unwrap();
+  Message::from_octets(builder.finish()).unwrap()
+}
+
+/// A server resolving with `transport` playing a root server, a com. server and an example.com.
+/// server, each referring to the next, so resolving www.example.com walks the whole tree.
+fn delegation_tree() -> DnsServer<StdRng, MockTransport> {
+  let name = Dname::<Octets>::from_str("www.example.com").unwrap();
+  let question = Question::new_in(name.clone(), Rtype::A);
+  let root = Ipv4Addr::new(192, 0, 2, 1);
+  let tld = Ipv4Addr::new(192, 0, 2, 2);
+  let auth = Ipv4Addr::new(192, 0, 2, 3);
+  let transport = MockTransport::default();
+  let com = Dname::from_str("com").unwrap();
+  let com_host = Dname::from_str("a.gtld-servers.com").unwrap();
+  transport.insert(
+    question.clone(),
+    SocketAddr::from((root, 53)),
+    referral(&name, &com, &com_host, tld),
+  );
+  let example = Dname::from_str("example.com").unwrap();
+  let example_host = Dname::from_str("ns.example.com").unwrap();
+  transport.insert(
+    question.clone(),
+    SocketAddr::from((tld, 53)),
+    referral(&name, &example, &example_host, auth),
+  );
+  transport.insert(question, SocketAddr::from((auth, 53)), answer(&name));
+
+  let mut server = DnsServer::with_cache_capacity(1000, transport).unwrap();
+  server.root_hints = vec![root.into()];
+  server
+}
+
+/// Calls `f` 100 times, returning how many allocations it makes on average.
+fn allocations(mut f: impl FnMut()) -> f64 {
+  let before = ALLOCATIONS.load(Ordering::Relaxed);
+  for _ in 0..100 {
+    f();
+  }
+  (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / 100.0
+}
+
+/// A client query answered from the cache, from the datagram arriving to the response being sent.
+fn answer_cached(c: &mut Criterion) {
+  let name = Dname::<Octets>::from_str("www.example.com").unwrap();
+  let upstream = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 53));
+  let transport = MockTransport::default();
+  transport.insert(
+    Question::new_in(name.clone(), Rtype::A),
+    upstream,
+    answer(&name),
+  );
+  let mut server = DnsServer::with_cache_capacity(1000, transport).unwrap();
+  server.forwarder = Some(upstream);
+
+  let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  client.connect(socket.local_addr().unwrap()).unwrap();
+  let request = query(&name, Rtype::A);
+  let mut buf = [0u8; 4096];
+  let mut response = [0u8; 4096];
+  c.bench_function("answer_cached", |b| {
+    b.iter(|| {
+      client.send(&request).unwrap();
+      serve::serve_udp(&socket, &mut buf, &mut server).unwrap();
+      client.recv(&mut response).unwrap()
+    })
+  });
+}
+
+/// A single query sent to another server over UDP, against one that echoes it straight back.
+fn udp_query(c: &mut Criterion) {
+  let upstream = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+  let addr = upstream.local_addr().unwrap();
+  thread::spawn(move || {
+    let mut buf = [0u8; 4096];
+    while let Ok((len, src)) = upstream.recv_from(&mut buf) {
+      let _ = upstream.send_to(&buf[..len], src);
+    }
+  });
+
+  let transport = NetworkTransport::default();
+  let request = query(&Dname::from_str("www.example.com").unwrap(), Rtype::A);
+  c.bench_function("udp_query", |b| {
+    b.iter(|| transport.query(&request, addr).unwrap())
+  });
+}
+
+/// Resolving a name from the root, whose referral names 13 servers and carries an A and an AAAA
+/// record of glue for each.
+fn resolve_large_referral(c: &mut Criterion) {
+  let name = Dname::<Octets>::from_str("www.example.com").unwrap();
+  let zone = Dname::<Octets>::from_str("example.com").unwrap();
+  let question = Question::new_in(name.clone(), Rtype::A);
+  let root = IpAddr::from(Ipv4Addr::new(192, 0, 2, 1));
+  let transport = MockTransport::default();
+
+  let request = Message::from_octets(query(&name, Rtype::A)).unwrap();
+  let mut builder = MessageBuilder::new_vec()
+    .start_answer(&request, Rcode::NoError)
+    .unwrap()
+    .authority();
+  let hosts: Vec<_> = (0..REFERRAL_SERVERS)
+    .map(|i| Dname::<Octets>::from_str(&format!("ns{i}.example.com")).unwrap())
+    .collect();
+  for host in &hosts {
+    builder
+      .push((&zone, Class::In, 3600, Ns::new(host)))
+      .unwrap();
+  }
+  let mut builder = builder.additional();
+  for (i, host) in (0..).zip(&hosts) {
+    let v4 = Ipv4Addr::new(198, 51, 100, i);
+    let v6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i.into());
+    builder.push((host, Class::In, 3600, A::new(v4))).unwrap();
+    builder
+      .push((host, Class::In, 3600, Aaaa::new(v6)))
+      .unwrap();
+    transport.insert(question.clone(), SocketAddr::from((v4, 53)), answer(&name));
+    transport.insert(question.clone(), SocketAddr::from((v6, 53)), answer(&name));
+  }
+  let referral = Message::from_octets(builder.finish()).unwrap();
+  transport.insert(question, SocketAddr::from((root, 53)), referral);
+
+  let mut server = DnsServer::with_cache_capacity(1000, transport).unwrap();
+  server.root_hints = vec![root];
+  let mut resolve = || {
+    server.control("flush");
+    server.resolve("www.example.com", Rtype::A).unwrap()
+  };
+  let allocations = allocations(|| {
+    resolve();
+  });
+  println!("resolve_large_referral: {allocations} allocations per resolution");
+  c.bench_function("resolve_large_referral", |b| b.iter(&mut resolve));
+}
+
+/// Resolving a name with nothing cached, following referrals from the root to the TLD to the
+/// name's own zone.
+fn resolve_cold(c: &mut Criterion) {
+  let mut server = delegation_tree();
+  let transport = Arc::clone(&server.transport);
+  let mut resolve = || {
+    server.control("flush");
+    server.resolve("www.example.com", Rtype::A).unwrap()
+  };
+  // every resolution has to walk the whole tree, or this would measure the cache
+  resolve();
+  assert_eq!(transport.sent().len(), 3);
+  let allocations = allocations(|| {
+    resolve();
+  });
+  println!("resolve_cold: {allocations} allocations per resolution");
+  c.bench_function("resolve_cold", |b| b.iter(&mut resolve));
+}
+
+/// Resolving the same name as `resolve_cold` once its answer is cached.
+fn resolve_warm(c: &mut Criterion) {
+  let mut server = delegation_tree();
+  let transport = Arc::clone(&server.transport);
+  server.resolve("www.example.com", Rtype::A).unwrap();
+  let mut resolve = || server.resolve("www.example.com", Rtype::A).unwrap();
+  let allocations = allocations(|| {
+    resolve();
+  });
+  // nothing past the first resolution may leave the cache
+  assert_eq!(transport.sent().len(), 3);
+  println!("resolve_warm: {allocations} allocations per resolution");
+  c.bench_function("resolve_warm", |b| b.iter(&mut resolve));
+}
+
+criterion_group!(
+  benches,
+  answer_cached,
+  udp_query,
+  resolve_large_referral,
+  resolve_cold,
+  resolve_warm
+);
+criterion_main!(benches);
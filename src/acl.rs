@@ -0,0 +1,194 @@
+use std::{
+  fmt,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use serde::Deserialize;
+
+/// A range of addresses written in CIDR notation, such as `10.0.0.0/8` or `fd00::/8`. A bare
+/// address stands for just that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+  addr: IpAddr,
+  prefix: u8,
+}
+
+impl Cidr {
+  /// The range of the addresses sharing the first `prefix` bits of `addr`, which is cut down to
+  /// the length of an address if longer. IPv4 addresses mapped into IPv6 give IPv4 ranges.
+  pub fn truncated(addr: IpAddr, prefix: u8) -> Cidr {
+    match addr.to_canonical() {
+      IpAddr::V4(v4) => {
+        let prefix = prefix.min(32);
+        let bits = u32::from(v4) & (u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0));
+        Cidr {
+          addr: Ipv4Addr::from(bits).into(),
+          prefix,
+        }
+      }
+      IpAddr::V6(v6) => {
+        let prefix = prefix.min(128);
+        let bits = u128::from(v6) & (u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0));
+        Cidr {
+          addr: Ipv6Addr::from(bits).into(),
+          prefix,
+        }
+      }
+    }
+  }
+
+  /// The first address of the range.
+  pub fn addr(&self) -> IpAddr {
+    self.addr
+  }
+
+  /// How many leading bits the addresses in the range share.
+  pub fn prefix(&self) -> u8 {
+    self.prefix
+  }
+
+  /// Whether `addr` is in the range. IPv4 addresses mapped into IPv6 match IPv4 ranges.
+  pub fn contains(&self, addr: IpAddr) -> bool {
+    let addr = match addr {
+      IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+      IpAddr::V4(_) => addr,
+    };
+    match (self.addr, addr) {
+      (IpAddr::V4(range), IpAddr::V4(addr)) => prefix_matches(
+        u32::from(range).into(),
+        u32::from(addr).into(),
+        32,
+        self.prefix,
+      ),
+      (IpAddr::V6(range), IpAddr::V6(addr)) => {
+        prefix_matches(range.into(), addr.into(), 128, self.prefix)
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Whether the top `prefix` of `bits` bits agree between `a` and `b`.
+fn prefix_matches(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+  if prefix == 0 {
+    return true;
+  }
+  let shift = bits - prefix;
+  a >> shift == b >> shift
+}
+
+impl fmt::Display for Cidr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+impl FromStr for Cidr {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Cidr> {
+    let (addr, prefix) = match s.split_once('/') {
+      Some((addr, prefix)) => (addr, Some(prefix)),
+      None => (s, None),
+    };
+    let addr: IpAddr = addr
+      .parse()
+      .with_context(|| format!("invalid address in {s}"))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix
+        .parse()
+        .map_err(|_| anyhow!("invalid prefix length in {s}"))?,
+      None => max,
+    };
+    if prefix > max {
+      bail!("prefix length in {s} is longer than {max} bits");
+    }
+    Ok(Cidr { addr, prefix })
+  }
+}
+
+impl TryFrom<String> for Cidr {
+  type Error = Error;
+
+  fn try_from(s: String) -> Result<Cidr> {
+    s.parse()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use domain::base::iana::{Rcode, Rtype};
+
+  use super::*;
+  use crate::{
+    tests::{ask_from, ip, query, resolving_www_mock, server},
+    Protocol,
+  };
+
+  fn cidr(s: &str) -> Cidr {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn ranges_match_the_addresses_sharing_their_prefix() {
+    let v4 = cidr("192.168.0.0/16");
+    assert!(v4.contains(ip("192.168.1.10")));
+    assert!(v4.contains(ip("::ffff:192.168.1.10")));
+    assert!(!v4.contains(ip("192.169.0.1")));
+    let v6 = cidr("fd00::/8");
+    assert!(v6.contains(ip("fd12:3456::1")));
+    assert!(!v6.contains(ip("fe80::1")));
+    let single = cidr("10.0.0.5");
+    assert!(single.contains(ip("10.0.0.5")));
+    assert!(!single.contains(ip("10.0.0.6")));
+    assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.1")));
+    assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+  }
+
+  #[test]
+  fn truncated_ranges_keep_only_the_prefix() {
+    assert_eq!(Cidr::truncated(ip("10.1.2.3"), 24), cidr("10.1.2.0/24"));
+    assert_eq!(
+      Cidr::truncated(ip("::ffff:10.1.2.3"), 16),
+      cidr("10.1.0.0/16")
+    );
+    assert_eq!(
+      Cidr::truncated(ip("2001:db8:aa:bbcc::1"), 56),
+      cidr("2001:db8:aa:bb00::/56")
+    );
+    assert_eq!(Cidr::truncated(ip("10.1.2.3"), 40), cidr("10.1.2.3/32"));
+    assert_eq!(Cidr::truncated(ip("10.1.2.3"), 0), cidr("0.0.0.0/0"));
+  }
+
+  #[test]
+  fn clients_outside_the_allowed_ranges_are_refused() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.allowed_clients = Some(Arc::new(vec![cidr("10.0.0.5"), cidr("2001:db8:1::/48")]));
+    let request = query("www.example.com", Rtype::A);
+
+    for (client, rcode) in [
+      ("10.0.0.5:5353", Rcode::NoError),
+      ("[2001:db8:1:2::7]:5353", Rcode::NoError),
+      ("10.0.0.6:5353", Rcode::Refused),
+      ("[2001:db8:2::7]:5353", Rcode::Refused),
+    ] {
+      let response = ask_from(
+        &mut server,
+        &request,
+        client.parse().unwrap(),
+        Protocol::Udp,
+      );
+      assert_eq!(response.header().rcode(), rcode, "{client}");
+    }
+  }
+}
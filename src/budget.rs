@@ -0,0 +1,93 @@
+use std::{
+  cell::Cell,
+  time::{Duration, Instant},
+};
+
+use crate::ResolveError;
+
+/// Limits on the work resolving a single client query may do: a deadline, and how many queries
+/// it may send to other servers. One budget is shared by every lookup the query leads to,
+/// including the nested ones that find name server addresses and DNSSEC keys.
+pub struct Budget {
+  deadline: Instant,
+  timeout: Duration,
+  max_queries: usize,
+  queries: Cell<usize>,
+}
+
+impl Budget {
+  pub fn new(timeout: Duration, max_queries: usize) -> Budget {
+    Budget {
+      deadline: Instant::now() + timeout,
+      timeout,
+      max_queries,
+      queries: Cell::new(0),
+    }
+  }
+
+  /// Counts a query about to be sent to another server, failing if the budget doesn't allow it.
+  pub fn spend_query(&self) -> Result<(), ResolveError> {
+    self.remaining()?;
+    if self.queries.get() >= self.max_queries {
+      return Err(ResolveError::BudgetExceeded(format!(
+        "needed more than {} upstream queries",
+        self.max_queries
+      )));
+    }
+    self.queries.set(self.queries.get() + 1);
+    Ok(())
+  }
+
+  /// How many queries to other servers have been counted so far.
+  pub fn queries(&self) -> usize {
+    self.queries.get()
+  }
+
+  /// Time left until the deadline, failing if it has passed.
+  pub fn remaining(&self) -> Result<Duration, ResolveError> {
+    match self.deadline.checked_duration_since(Instant::now()) {
+      Some(left) if !left.is_zero() => Ok(left),
+      _ => Err(self.out_of_time()),
+    }
+  }
+
+  /// When resolving has to be done by.
+  pub fn deadline(&self) -> Instant {
+    self.deadline
+  }
+
+  /// The error for going past the deadline.
+  pub fn out_of_time(&self) -> ResolveError {
+    ResolveError::BudgetExceeded(format!("took longer than {:?}", self.timeout))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn queries_beyond_the_limit_are_refused() {
+    let budget = Budget::new(Duration::from_secs(60), 2);
+    assert!(budget.spend_query().is_ok());
+    assert!(budget.spend_query().is_ok());
+    let error = budget.spend_query().unwrap_err();
+    assert!(
+      matches!(error, ResolveError::BudgetExceeded(_)),
+      "{error:?}"
+    );
+    assert_eq!(budget.queries(), 2);
+  }
+
+  #[test]
+  fn nothing_is_left_after_the_deadline() {
+    let budget = Budget::new(Duration::from_millis(10), 50);
+    assert!(budget.remaining().unwrap() <= Duration::from_millis(10));
+    thread::sleep(Duration::from_millis(20));
+    assert!(budget.remaining().is_err());
+    assert!(budget.spend_query().is_err());
+    assert_eq!(budget.queries(), 0);
+  }
+}
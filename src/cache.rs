@@ -0,0 +1,295 @@
+use std::{
+  borrow::Borrow,
+  collections::{BTreeMap, HashMap},
+  hash::Hash,
+  sync::Arc,
+};
+
+use crate::{CacheEntry, QuestionKey};
+
+/// Where a server keeps the responses it has resolved, by the question each answers. A cache is
+/// shared by every fork of a server behind a lock, so its methods take `&mut self` freely.
+/// Entries are handed out behind an `Arc` since one may still be in use, such as by a prefetch
+/// it started, after the cache has dropped it.
+pub trait Cache: Send {
+  /// Looks up the response cached for `question`, expired or not.
+  fn get(&mut self, question: &QuestionKey) -> Option<Arc<CacheEntry>>;
+
+  /// Caches `entry` as the response to `question`, replacing any cached before.
+  fn put(&mut self, question: QuestionKey, entry: Arc<CacheEntry>);
+
+  /// Removes the entries for which `keep` returns false. Returns how many were removed.
+  fn retain(&mut self, keep: &mut dyn FnMut(&QuestionKey, &CacheEntry) -> bool) -> usize;
+
+  /// Iterates over the entries, from the least to the most recently used if the cache tracks
+  /// that.
+  fn iter(&self) -> Box<dyn Iterator<Item = (&QuestionKey, &Arc<CacheEntry>)> + '_>;
+
+  /// How many entries the cache holds.
+  fn len(&self) -> usize;
+
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Most entries the cache holds at once.
+  fn capacity(&self) -> usize;
+}
+
+/// The default cache, kept in memory and evicting the least recently used response once full.
+pub struct MemoryCache {
+  entries: LruCache<QuestionKey, Arc<CacheEntry>>,
+}
+
+impl MemoryCache {
+  /// Creates a cache holding up to `capacity` responses.
+  pub fn new(capacity: usize) -> MemoryCache {
+    MemoryCache {
+      entries: LruCache::new(capacity),
+    }
+  }
+}
+
+impl Cache for MemoryCache {
+  fn get(&mut self, question: &QuestionKey) -> Option<Arc<CacheEntry>> {
+    self.entries.get(question).cloned()
+  }
+
+  fn put(&mut self, question: QuestionKey, entry: Arc<CacheEntry>) {
+    self.entries.insert(question, entry);
+  }
+
+  fn retain(&mut self, keep: &mut dyn FnMut(&QuestionKey, &CacheEntry) -> bool) -> usize {
+    self.entries.retain(|question, entry| keep(question, entry))
+  }
+
+  fn iter(&self) -> Box<dyn Iterator<Item = (&QuestionKey, &Arc<CacheEntry>)> + '_> {
+    Box::new(self.entries.iter())
+  }
+
+  fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  fn capacity(&self) -> usize {
+    self.entries.capacity()
+  }
+}
+
+/// A cache that holds nothing, so every query that isn't answered locally is resolved afresh.
+pub struct NoopCache;
+
+impl Cache for NoopCache {
+  fn get(&mut self, _: &QuestionKey) -> Option<Arc<CacheEntry>> {
+    None
+  }
+
+  fn put(&mut self, _: QuestionKey, _: Arc<CacheEntry>) {}
+
+  fn retain(&mut self, _: &mut dyn FnMut(&QuestionKey, &CacheEntry) -> bool) -> usize {
+    0
+  }
+
+  fn iter(&self) -> Box<dyn Iterator<Item = (&QuestionKey, &Arc<CacheEntry>)> + '_> {
+    Box::new(std::iter::empty())
+  }
+
+  fn len(&self) -> usize {
+    0
+  }
+
+  fn capacity(&self) -> usize {
+    0
+  }
+}
+
+/// A map holding at most `capacity` entries, evicting the least recently used entry when full.
+///
+/// Recency is tracked with a monotonically increasing tick: every `get` or `insert` stamps the
+/// entry with a fresh tick, and `order` maps ticks back to keys so the oldest is always first.
+pub struct LruCache<K, V> {
+  entries: HashMap<K, (V, u64)>,
+  order: BTreeMap<u64, K>,
+  tick: u64,
+  capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+  pub fn new(capacity: usize) -> LruCache<K, V> {
+    LruCache {
+      entries: HashMap::new(),
+      order: BTreeMap::new(),
+      tick: 0,
+      capacity,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Looks up `key`, marking it as the most recently used entry.
+  pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+  where
+    K: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+  {
+    self.get_mut(key).map(|value| &*value)
+  }
+
+  /// Looks up `key` for changing its value in place, marking it as the most recently used entry.
+  pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+  where
+    K: Borrow<Q>,
+    Q: Hash + Eq + ?Sized,
+  {
+    let tick = self.next_tick();
+    let (value, used) = self.entries.get_mut(key)?;
+    let old = std::mem::replace(used, tick);
+    if let Some(key) = self.order.remove(&old) {
+      self.order.insert(tick, key);
+    }
+    Some(value)
+  }
+
+  /// Inserts `value` under `key`, evicting the least recently used entries if over capacity.
+  pub fn insert(&mut self, key: K, value: V) {
+    let tick = self.next_tick();
+    if let Some((_, old)) = self.entries.insert(key.clone(), (value, tick)) {
+      self.order.remove(&old);
+    }
+    self.order.insert(tick, key);
+
+    while self.entries.len() > self.capacity {
+      match self.order.pop_first() {
+        Some((_, oldest)) => {
+          self.entries.remove(&oldest);
+        }
+        None => break,
+      }
+    }
+  }
+
+  /// Removes the entries for which `keep` returns false. Returns how many were removed.
+  pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) -> usize {
+    let before = self.entries.len();
+    let order = &mut self.order;
+    self.entries.retain(|key, (value, used)| {
+      let kept = keep(key, value);
+      if !kept {
+        order.remove(used);
+      }
+      kept
+    });
+    before - self.entries.len()
+  }
+
+  /// Iterates over the entries from the least to the most recently used, without marking any of
+  /// them as used.
+  pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    self
+      .order
+      .values()
+      .filter_map(|key| self.entries.get_key_value(key))
+      .map(|(key, (value, _))| (key, value))
+  }
+
+  fn next_tick(&mut self) -> u64 {
+    self.tick += 1;
+    self.tick
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use domain::base::iana::Rtype;
+
+  use super::*;
+  use crate::{
+    tests::{
+      answer, answer_addrs, ask, cached, ip, name, query, question, resolving_www_mock, server,
+      EXAMPLE, ROOT,
+    },
+    DnsServer,
+  };
+
+  #[test]
+  fn evicts_the_least_recently_used_entry() {
+    let mut cache = LruCache::new(2);
+    cache.insert("a", 1);
+    cache.insert("b", 2);
+    assert_eq!(cache.get("a"), Some(&1));
+    cache.insert("c", 3);
+
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get("b"), None);
+    let order: Vec<_> = cache.iter().map(|(key, _)| *key).collect();
+    assert_eq!(order, ["a", "c"]);
+  }
+
+  #[test]
+  fn replacing_an_entry_does_not_evict_another() {
+    let mut cache = LruCache::new(2);
+    cache.insert("a", 1);
+    cache.insert("b", 2);
+    cache.insert("a", 3);
+
+    assert_eq!(cache.len(), 2);
+    let order: Vec<_> = cache.iter().map(|(key, value)| (*key, *value)).collect();
+    assert_eq!(order, [("b", 2), ("a", 3)]);
+  }
+
+  #[test]
+  fn memory_caches_hold_entries_up_to_their_capacity() {
+    let mut cache = MemoryCache::new(2);
+    for qname in ["a.example.com", "b.example.com", "c.example.com"] {
+      cache.put(
+        question(qname, Rtype::A),
+        cached(answer(qname, "192.0.2.10")),
+      );
+    }
+
+    assert_eq!((cache.len(), cache.capacity()), (2, 2));
+    assert!(cache.get(&question("a.example.com", Rtype::A)).is_none());
+    assert!(cache.get(&question("c.example.com", Rtype::A)).is_some());
+    let removed = cache.retain(&mut |question, _| question.qname() != &name("b.example.com"));
+    assert_eq!(removed, 1);
+    let left: Vec<_> = cache.iter().map(|(question, _)| question.clone()).collect();
+    assert_eq!(left, [question("c.example.com", Rtype::A)]);
+  }
+
+  #[test]
+  fn noop_caches_send_every_query_upstream() {
+    let mock = resolving_www_mock();
+    // set up as for cache_size = 0, which leaves no room for answer records either
+    let mut server = DnsServer::with_cache_capacity(0, mock).unwrap();
+    server.root_hints = vec![ROOT.ip()];
+    server.set_cache(NoopCache);
+    // forks share the cache that was swapped in
+    let mut fork = server.fork();
+
+    for _ in 0..3 {
+      let response = ask(&mut fork, &query("www.example.com", Rtype::A));
+      assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    }
+    assert_eq!(server.transport.sent_to(EXAMPLE), 3);
+    assert!(server.cache().is_empty());
+  }
+
+  #[test]
+  fn memory_caches_answer_repeated_queries() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+
+    for _ in 0..3 {
+      let response = ask(&mut server, &query("www.example.com", Rtype::A));
+      assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    }
+    assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+    assert_eq!(server.cache().len(), 1);
+  }
+}
@@ -0,0 +1,333 @@
+use std::{
+  fs,
+  net::{IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+
+use crate::{acl::Cidr, selection::Selection};
+
+/// Settings the server is started with, read from a TOML file and the command line. Every
+/// setting is optional; unset ones fall back to the server's defaults.
+///
+/// ```toml
+/// listen_port = 5353
+/// forward = "9.9.9.9:53"
+/// cache_size = 50000
+/// timeout_ms = 1500
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// Address to listen for queries on, instead of both `::` and `0.0.0.0`.
+  pub listen_addr: Option<IpAddr>,
+  /// Port to listen for queries on, over both UDP and TCP.
+  pub listen_port: Option<u16>,
+  /// Port to send outbound queries from, instead of a random one per query.
+  pub outbound_port: Option<u16>,
+  /// Addresses to send outbound queries from, at most one IPv4 and one IPv6 address.
+  pub outbound_addrs: Option<Vec<IpAddr>>,
+  /// Upstream resolver to forward every query to.
+  pub forward: Option<SocketAddr>,
+  /// Name the forwarder's certificate is issued for, to forward over DNS over TLS instead of
+  /// plain UDP and TCP.
+  pub forward_tls_name: Option<String>,
+  /// URL of the forwarder's DNS over HTTPS endpoint, to forward over HTTPS instead of plain UDP
+  /// and TCP. Queries still go to the address in `forward`.
+  pub forward_https_url: Option<String>,
+  /// Whether to check the forwarder's TLS certificate against `forward_tls_name`, or the host of
+  /// `forward_https_url`.
+  pub forward_tls_verify: Option<bool>,
+  /// Hints file to load the root server addresses from.
+  pub root_hints: Option<PathBuf>,
+  /// Hosts file with names to answer without resolving them.
+  pub hosts: Option<PathBuf>,
+  /// File to append a line of JSON to for each client query answered, or `-` for stdout.
+  pub query_log: Option<PathBuf>,
+  /// Number of threads answering UDP queries on each address listened on.
+  pub workers: Option<usize>,
+  /// Port to serve Prometheus metrics on over HTTP.
+  pub metrics_port: Option<u16>,
+  /// Port on localhost to accept cache control commands on over TCP.
+  pub control_port: Option<u16>,
+  /// Whether to refresh popular cache entries in the background shortly before they expire.
+  pub prefetch: Option<bool>,
+  /// Fraction of a cached answer's lifetime below which a hit refreshes it.
+  pub prefetch_threshold: Option<f64>,
+  /// Whether to answer from expired cache entries when resolving a question again fails.
+  pub serve_stale: Option<bool>,
+  /// How long after expiring a cached answer may still be served, in seconds.
+  pub stale_window_secs: Option<u64>,
+  /// Whether to resolve queries that don't ask for recursion, rather than answering them from
+  /// the cache alone.
+  pub always_recurse: Option<bool>,
+  /// Whether to randomize the case of names in outbound queries and reject responses that
+  /// don't repeat it.
+  pub randomize_case: Option<bool>,
+  /// Whether to send each server only as much of the name as it needs to refer onwards.
+  pub qname_minimization: Option<bool>,
+  /// Whether to ignore glue for name servers outside the zone they serve, and look up their
+  /// addresses instead.
+  pub ignore_glue: Option<bool>,
+  /// How to pick the name servers asked first: `random`, `round-robin` or `fastest`.
+  pub server_selection: Option<Selection>,
+  /// Whether to answer ANY queries with a single HINFO record instead of resolving them.
+  pub minimal_any: Option<bool>,
+  /// Whether to leave the authority and additional sections out of positive answers.
+  pub minimal_responses: Option<bool>,
+  /// Whether to pass on the subnet of each client's address to the servers resolving its
+  /// queries, for answers suited to where the client is.
+  pub client_subnet: Option<bool>,
+  /// How many leading bits of IPv4 client addresses are passed on.
+  pub client_subnet_ipv4_prefix: Option<u8>,
+  /// How many leading bits of IPv6 client addresses are passed on.
+  pub client_subnet_ipv6_prefix: Option<u8>,
+  /// Whether to validate answers with DNSSEC, setting AD on secure ones and failing bogus ones.
+  pub dnssec: Option<bool>,
+  /// File of DS records to start validation from instead of the root zone's keys.
+  pub trust_anchor: Option<PathBuf>,
+  /// Address ranges of the clients allowed to send queries. When unset, anyone may.
+  pub allow: Option<Vec<Cidr>>,
+  /// Queries per second each client may send on average.
+  pub rate_limit: Option<f64>,
+  /// Queries each client may send at once before the rate limit applies.
+  pub rate_limit_burst: Option<u32>,
+  /// Most responses held in the cache at once. With 0, nothing is cached.
+  pub cache_size: Option<usize>,
+  /// File the cache is saved to on shutdown and loaded from on startup.
+  pub cache_file: Option<PathBuf>,
+  /// How long to wait for a name server to respond, in milliseconds.
+  pub timeout_ms: Option<u64>,
+  /// How long TCP connections to name servers are kept open between queries, in milliseconds. A
+  /// value of 0 closes each connection once its query is answered.
+  pub upstream_idle_timeout_ms: Option<u64>,
+  /// How many nested name server lookups a single client query may trigger.
+  pub max_depth: Option<usize>,
+  /// How long resolving a client query may take in all, in milliseconds.
+  pub resolution_timeout_ms: Option<u64>,
+  /// How many queries to other servers resolving a client query may send in all.
+  pub max_upstream_queries: Option<usize>,
+  /// How many queries to other servers may be outstanding at once, across every client query.
+  pub max_outstanding_queries: Option<usize>,
+  /// How many times to send a query again when a name server doesn't respond to it.
+  pub udp_retries: Option<usize>,
+  /// Shortest TTL answers are cached for and served with, in seconds. Unless it's set, answers
+  /// with a TTL of 0 aren't cached.
+  pub min_ttl: Option<u32>,
+  /// Longest TTL answers are cached for and served with, in seconds.
+  pub max_ttl: Option<u32>,
+  /// Shortest TTL delegations and glue learned from referrals are kept for, in seconds.
+  pub min_referral_ttl: Option<u32>,
+  /// Longest TTL delegations and glue learned from referrals are kept for, in seconds.
+  pub max_referral_ttl: Option<u32>,
+  /// Text to answer CHAOS class TXT queries for `version.bind` and `id.server` with.
+  pub identity: Option<String>,
+  /// Domains completing names given to `--query` without a trailing dot, tried in order.
+  pub search: Option<Vec<String>>,
+  /// Dots a name given to `--query` needs to be tried as it is before the search domains.
+  pub ndots: Option<usize>,
+  /// Static records served to groups of clients instead of resolving those names. The first view
+  /// matching a client is the one it gets.
+  pub views: Option<Vec<ViewConfig>>,
+  /// Zones served authoritatively from master files.
+  pub zones: Option<Vec<ZoneConfig>>,
+  /// Zones resolved by asking particular upstream resolvers instead of recursing or forwarding.
+  /// A name in several of them goes to the innermost one.
+  pub forward_zones: Option<Vec<ForwardZoneConfig>>,
+  /// Least severe messages to log, such as `warn` or `debug`.
+  pub log_level: Option<String>,
+  /// Whether to log how long each client query, and at the debug level each lookup it leads to,
+  /// took as it finishes.
+  pub log_spans: Option<bool>,
+}
+
+/// A view, which serves its own records to the clients in some address ranges.
+///
+/// ```toml
+/// [[views]]
+/// name = "internal"
+/// clients = ["10.0.0.0/8", "192.168.0.0/16"]
+/// records = ["10.0.0.5 intranet.example", "10.0.0.6 mail.example"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ViewConfig {
+  /// What the view is called in logs.
+  pub name: String,
+  /// Address ranges of the clients the view applies to.
+  pub clients: Vec<Cidr>,
+  /// The view's records, each a line of a hosts file: an address followed by its names.
+  #[serde(default)]
+  pub records: Vec<String>,
+}
+
+/// A zone served authoritatively from a master file.
+///
+/// ```toml
+/// [[zones]]
+/// origin = "home.example"
+/// file = "/etc/dns/home.example.zone"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneConfig {
+  /// The name at the zone's apex, which relative names in the file are relative to.
+  pub origin: String,
+  /// The master file holding the zone's records.
+  pub file: PathBuf,
+}
+
+/// A zone whose names are resolved by asking particular upstream resolvers.
+///
+/// ```toml
+/// [[forward_zones]]
+/// name = "corp.internal"
+/// servers = ["10.0.0.53:53", "10.0.1.53:53"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardZoneConfig {
+  /// The name at the zone's apex.
+  pub name: String,
+  /// The resolvers to ask, in order of preference.
+  pub servers: Vec<SocketAddr>,
+}
+
+impl Config {
+  /// Reads a TOML configuration file.
+  pub fn load(path: impl AsRef<Path>) -> Result<Config> {
+    let path = path.as_ref();
+    let contents =
+      fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    contents
+      .parse()
+      .with_context(|| format!("parsing {}", path.display()))
+  }
+
+  /// Combines two configurations, preferring the settings made in `overrides`.
+  pub fn merge(self, overrides: Config) -> Config {
+    Config {
+      listen_addr: overrides.listen_addr.or(self.listen_addr),
+      listen_port: overrides.listen_port.or(self.listen_port),
+      outbound_port: overrides.outbound_port.or(self.outbound_port),
+      outbound_addrs: overrides.outbound_addrs.or(self.outbound_addrs),
+      forward: overrides.forward.or(self.forward),
+      forward_tls_name: overrides.forward_tls_name.or(self.forward_tls_name),
+      forward_https_url: overrides.forward_https_url.or(self.forward_https_url),
+      forward_tls_verify: overrides.forward_tls_verify.or(self.forward_tls_verify),
+      root_hints: overrides.root_hints.or(self.root_hints),
+      hosts: overrides.hosts.or(self.hosts),
+      query_log: overrides.query_log.or(self.query_log),
+      workers: overrides.workers.or(self.workers),
+      metrics_port: overrides.metrics_port.or(self.metrics_port),
+      control_port: overrides.control_port.or(self.control_port),
+      prefetch: overrides.prefetch.or(self.prefetch),
+      prefetch_threshold: overrides.prefetch_threshold.or(self.prefetch_threshold),
+      serve_stale: overrides.serve_stale.or(self.serve_stale),
+      stale_window_secs: overrides.stale_window_secs.or(self.stale_window_secs),
+      always_recurse: overrides.always_recurse.or(self.always_recurse),
+      randomize_case: overrides.randomize_case.or(self.randomize_case),
+      qname_minimization: overrides.qname_minimization.or(self.qname_minimization),
+      ignore_glue: overrides.ignore_glue.or(self.ignore_glue),
+      server_selection: overrides.server_selection.or(self.server_selection),
+      minimal_any: overrides.minimal_any.or(self.minimal_any),
+      minimal_responses: overrides.minimal_responses.or(self.minimal_responses),
+      client_subnet: overrides.client_subnet.or(self.client_subnet),
+      client_subnet_ipv4_prefix: overrides
+        .client_subnet_ipv4_prefix
+        .or(self.client_subnet_ipv4_prefix),
+      client_subnet_ipv6_prefix: overrides
+        .client_subnet_ipv6_prefix
+        .or(self.client_subnet_ipv6_prefix),
+      dnssec: overrides.dnssec.or(self.dnssec),
+      trust_anchor: overrides.trust_anchor.or(self.trust_anchor),
+      allow: overrides.allow.or(self.allow),
+      rate_limit: overrides.rate_limit.or(self.rate_limit),
+      rate_limit_burst: overrides.rate_limit_burst.or(self.rate_limit_burst),
+      cache_size: overrides.cache_size.or(self.cache_size),
+      cache_file: overrides.cache_file.or(self.cache_file),
+      timeout_ms: overrides.timeout_ms.or(self.timeout_ms),
+      upstream_idle_timeout_ms: overrides
+        .upstream_idle_timeout_ms
+        .or(self.upstream_idle_timeout_ms),
+      max_depth: overrides.max_depth.or(self.max_depth),
+      resolution_timeout_ms: overrides
+        .resolution_timeout_ms
+        .or(self.resolution_timeout_ms),
+      max_upstream_queries: overrides.max_upstream_queries.or(self.max_upstream_queries),
+      max_outstanding_queries: overrides
+        .max_outstanding_queries
+        .or(self.max_outstanding_queries),
+      udp_retries: overrides.udp_retries.or(self.udp_retries),
+      min_ttl: overrides.min_ttl.or(self.min_ttl),
+      max_ttl: overrides.max_ttl.or(self.max_ttl),
+      min_referral_ttl: overrides.min_referral_ttl.or(self.min_referral_ttl),
+      max_referral_ttl: overrides.max_referral_ttl.or(self.max_referral_ttl),
+      identity: overrides.identity.or(self.identity),
+      search: overrides.search.or(self.search),
+      ndots: overrides.ndots.or(self.ndots),
+      views: overrides.views.or(self.views),
+      zones: overrides.zones.or(self.zones),
+      forward_zones: overrides.forward_zones.or(self.forward_zones),
+      log_level: overrides.log_level.or(self.log_level),
+      log_spans: overrides.log_spans.or(self.log_spans),
+    }
+  }
+}
+
+impl FromStr for Config {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Config> {
+    Ok(toml::from_str(s)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_a_toml_configuration() {
+    let config = Config::from_str(
+      r#"
+        cache_size = 50000
+        timeout_ms = 1500
+        root_hints = "/etc/dns/named.root"
+        forward = "9.9.9.9:53"
+        log_level = "debug"
+      "#,
+    )
+    .unwrap();
+    assert_eq!(config.cache_size, Some(50000));
+    assert_eq!(config.timeout_ms, Some(1500));
+    assert_eq!(
+      config.root_hints,
+      Some(PathBuf::from("/etc/dns/named.root"))
+    );
+    assert_eq!(config.forward, Some("9.9.9.9:53".parse().unwrap()));
+    assert_eq!(config.log_level.as_deref(), Some("debug"));
+    assert_eq!(config.listen_port, None);
+  }
+
+  #[test]
+  fn invalid_configurations_are_errors() {
+    assert!(Config::from_str("cache_size = ").is_err());
+    assert!(Config::from_str("cache_size = \"big\"").is_err());
+    assert!(Config::from_str("cache_sise = 10").is_err());
+  }
+
+  #[test]
+  fn overrides_take_precedence() {
+    let file = Config::from_str("cache_size = 10\nlisten_port = 5353").unwrap();
+    let merged = file.merge(Config {
+      listen_port: Some(5300),
+      ..Config::default()
+    });
+    assert_eq!(merged.listen_port, Some(5300));
+    assert_eq!(merged.cache_size, Some(10));
+  }
+}
@@ -0,0 +1,287 @@
+use std::{
+  fmt::Write as _,
+  io::{self, BufRead, BufReader, Write},
+  net::TcpStream,
+  str::FromStr,
+  sync::PoisonError,
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use domain::base::Dname;
+use rand::Rng;
+
+use crate::{transport::Transport, AnswerKind, DnsServer, Octets};
+
+// How long a control connection may sit idle between commands before it's closed
+const CONTROL_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// The commands there are, for error messages
+const COMMANDS: &str = "dump, flush, flush NAME or servers";
+
+impl<R: Rng + ?Sized, T: Transport> DnsServer<R, T> {
+  /// Carries out a single control command, returning the text to reply with. The commands are:
+  ///
+  /// - `dump`, which lists every cached question, least recently used first, with the kind of
+  ///   answer cached and the seconds left before it expires. Answers cached for a client subnet
+  ///   come after the rest, followed by the subnet.
+  /// - `flush`, which empties the cache, the record store and the DNSSEC key cache.
+  /// - `flush NAME`, which removes what the cache and the record store hold about `NAME`, for
+  ///   every type.
+  /// - `servers`, which lists every name server asked, least recently asked first, with how many
+  ///   lookups were sent to it, how many were answered, timed out, got SERVFAIL or REFUSED, and
+  ///   failed some other way, followed by its smoothed round trip time in milliseconds.
+  ///
+  /// Commands that can't be carried out get a reply starting with `error:`.
+  pub fn control(&self, command: &str) -> String {
+    match self.run_control(command) {
+      Ok(reply) => reply,
+      Err(e) => format!("error: {e:#}\n"),
+    }
+  }
+
+  fn run_control(&self, command: &str) -> Result<String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+      ["dump"] => Ok(self.dump_cache()),
+      ["servers"] => Ok(self.dump_servers()),
+      ["flush"] => Ok(self.flush_cache(None)),
+      ["flush", name] => {
+        let name =
+          Dname::<Octets>::from_str(name).with_context(|| format!("invalid name {name:?}"))?;
+        Ok(self.flush_cache(Some(&name)))
+      }
+      ["dump" | "flush" | "servers", ..] => bail!("too many arguments, expected {COMMANDS}"),
+      [] => bail!("no command given"),
+      [command, ..] => bail!("unknown command {command:?}, expected {COMMANDS}"),
+    }
+  }
+
+  fn dump_cache(&self) -> String {
+    let mut out = String::new();
+    let now = Instant::now();
+    for (question, entry) in self.cache().iter() {
+      let _ = write!(
+        out,
+        "{} {} {} {} ",
+        question.qname(),
+        question.qtype(),
+        question.qclass(),
+        kind_name(entry.kind)
+      );
+      let _ = match entry.expires.checked_duration_since(now) {
+        Some(remaining) => writeln!(out, "{}", remaining.as_secs()),
+        None => writeln!(out, "expired"),
+      };
+    }
+    for (question, entries) in self.scoped_cache().iter() {
+      for (scope, entry) in entries {
+        let _ = write!(
+          out,
+          "{} {} {} {} ",
+          question.qname(),
+          question.qtype(),
+          question.qclass(),
+          kind_name(entry.kind)
+        );
+        let _ = match entry.expires.checked_duration_since(now) {
+          Some(remaining) => writeln!(out, "{} {scope}", remaining.as_secs()),
+          None => writeln!(out, "expired {scope}"),
+        };
+      }
+    }
+    out
+  }
+
+  fn dump_servers(&self) -> String {
+    let mut out = String::new();
+    for (server, record) in self.server_stats.servers() {
+      let _ = writeln!(
+        out,
+        "{server} {} {} {} {} {} {:.1}",
+        record.queries,
+        record.answered,
+        record.timeouts,
+        record.servfails,
+        record.errors,
+        record.rtt.as_secs_f64() * 1000.0
+      );
+    }
+    out
+  }
+
+  fn flush_cache(&self, name: Option<&Dname<Octets>>) -> String {
+    let keep = |qname: &Dname<Octets>| name.is_some_and(|name| name != qname);
+    let mut answers = self
+      .cache()
+      .retain(&mut |question, _| keep(question.qname()));
+    self.scoped_cache().retain(|question, entries| {
+      let kept = keep(question.qname());
+      if !kept {
+        answers += entries.len();
+      }
+      kept
+    });
+    let records = self.records().remove(name);
+    if name.is_none() {
+      self
+        .zone_keys
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|_, _| false);
+    }
+    format!("flushed {answers} cached answers and {records} record sets\n")
+  }
+}
+
+/// What `dump` calls a kind of answer.
+fn kind_name(kind: AnswerKind) -> &'static str {
+  match kind {
+    AnswerKind::Positive => "answer",
+    AnswerKind::NxDomain => "nxdomain",
+    AnswerKind::NoData => "nodata",
+  }
+}
+
+/// Answers the control commands sent on `stream`, one per line, until the client closes it or
+/// leaves it idle. Each command is carried out by `DnsServer::control`.
+pub fn serve<R: Rng + ?Sized, T: Transport>(
+  mut stream: TcpStream,
+  server: &DnsServer<R, T>,
+) -> io::Result<()> {
+  stream.set_read_timeout(Some(CONTROL_IDLE_TIMEOUT))?;
+  let reader = BufReader::new(stream.try_clone()?);
+  for line in reader.lines() {
+    let line = match line {
+      Ok(line) => line,
+      // a client that's gone quiet is simply disconnected
+      Err(e)
+        if matches!(
+          e.kind(),
+          io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ) =>
+      {
+        break
+      }
+      Err(e) => return Err(e),
+    };
+    if line.trim().is_empty() {
+      continue;
+    }
+    stream.write_all(server.control(&line).as_bytes())?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use domain::base::iana::Rtype;
+
+  use crate::{
+    tests::{answer, cached, delegate, question, server, EXAMPLE},
+    transport::mock::MockTransport,
+  };
+
+  #[test]
+  fn dump_lists_cached_questions_with_their_time_left() {
+    let server = server(MockTransport::default());
+    server.cache().put(
+      question("www.example.com", Rtype::A),
+      cached(answer("www.example.com", "192.0.2.10")),
+    );
+
+    let dump = server.control("dump");
+    let fields: Vec<_> = dump.split_whitespace().collect();
+    assert_eq!(
+      fields[..5],
+      ["www.example.com", "A", "IN", "answer", fields[4]]
+    );
+    let remaining: u64 = fields[4].parse().unwrap();
+    assert!((3590..=3600).contains(&remaining), "{dump}");
+    assert_eq!(dump.lines().count(), 1);
+  }
+
+  #[test]
+  fn flush_removes_one_name_or_everything() {
+    let server = server(MockTransport::default());
+    for qname in ["www.example.com", "mail.example.com"] {
+      server.cache().put(
+        question(qname, Rtype::A),
+        cached(answer(qname, "192.0.2.10")),
+      );
+    }
+
+    assert_eq!(
+      server.control("flush WWW.example.com"),
+      "flushed 1 cached answers and 0 record sets\n"
+    );
+    assert!(server
+      .cache()
+      .get(&question("www.example.com", Rtype::A))
+      .is_none());
+    assert!(server
+      .control("dump")
+      .starts_with("mail.example.com A IN answer "));
+    assert_eq!(
+      server.control("flush"),
+      "flushed 1 cached answers and 0 record sets\n"
+    );
+    assert_eq!(server.control("dump"), "");
+  }
+
+  #[test]
+  fn servers_lists_lookup_counts_and_the_smoothed_rtt() {
+    let mock = MockTransport::default();
+    let names = [
+      "a.example.com",
+      "b.example.com",
+      "c.example.com",
+      "d.example.com",
+    ];
+    for qname in names {
+      delegate(&mock, qname, Rtype::A);
+      mock.insert(
+        question(qname, Rtype::A),
+        EXAMPLE,
+        answer(qname, "192.0.2.10"),
+      );
+    }
+    mock.set_server_delay(EXAMPLE, Duration::from_millis(40));
+    let mut server = server(mock);
+    for qname in names {
+      server.resolve(qname, Rtype::A).unwrap();
+    }
+
+    let servers = server.control("servers");
+    let lines: Vec<Vec<&str>> = servers
+      .lines()
+      .map(|line| line.split_whitespace().collect())
+      .collect();
+    // the delegation to example.com. is learned from the first resolution
+    assert_eq!(lines[0][..6], ["192.0.2.1", "1", "1", "0", "0", "0"]);
+    assert_eq!(lines[1][..6], ["192.0.2.2", "1", "1", "0", "0", "0"]);
+    assert_eq!(lines[2][..6], ["192.0.2.3", "4", "4", "0", "0", "0"]);
+    let rtt: f64 = lines[2][6].parse().unwrap();
+    assert!((40.0..200.0).contains(&rtt), "{servers}");
+    assert_eq!(lines.len(), 3);
+  }
+
+  #[test]
+  fn bad_commands_get_errors() {
+    let server = server(MockTransport::default());
+    assert!(server
+      .control("purge")
+      .starts_with("error: unknown command"));
+    assert!(server
+      .control("dump all")
+      .starts_with("error: too many arguments"));
+    assert!(server
+      .control("flush bad..name")
+      .starts_with("error: invalid name"));
+    assert!(server
+      .control("servers now")
+      .starts_with("error: too many arguments"));
+    assert!(server.control("").starts_with("error: no command given"));
+  }
+}
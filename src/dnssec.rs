@@ -0,0 +1,1232 @@
+use std::{
+  cmp::Ordering,
+  fmt::Display,
+  fs,
+  path::Path,
+  str::FromStr,
+  sync::{MutexGuard, PoisonError},
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use domain::{
+  base::{
+    iana::{Class, DigestAlg, Rcode, Rtype, SecAlg},
+    octets::{Compose, OctetsRef},
+    Dname, ParsedDname, Question, RecordSection, Serial, ToDname,
+  },
+  rdata::{rfc4034::RtypeBitmap, AllRecordData, Dnskey, Rrsig},
+  validate::{DnskeyExt, RrsigExt},
+};
+use rand::Rng;
+use ring::digest;
+use tracing::debug;
+
+use crate::{
+  budget::Budget, cache::LruCache, transport::Transport, DnsServer, Dnssec, Message, Octets,
+  ResolveError,
+};
+
+/// Most zones whose keys are remembered at once.
+pub(crate) const MAX_ZONE_KEYS: usize = 1000;
+// Algorithms signatures are checked for. Zones only signed with others are treated as unsigned
+// (RFC 4035 section 5.2).
+const SUPPORTED_ALGORITHMS: [SecAlg; 5] = [
+  SecAlg::RsaSha256,
+  SecAlg::RsaSha512,
+  SecAlg::EcdsaP256Sha256,
+  SecAlg::EcdsaP384Sha384,
+  SecAlg::Ed25519,
+];
+// Digests DS records are checked with
+const SUPPORTED_DIGESTS: [DigestAlg; 2] = [DigestAlg::Sha256, DigestAlg::Sha384];
+// Most extra NSEC3 hash iterations worked through. Denials using more are treated as unsigned,
+// as RFC 9276 section 3.2 allows, rather than spending the time.
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+// NSEC3 hash algorithm number for SHA-1, the only one defined (RFC 5155 section 11)
+const NSEC3_SHA1: u8 = 1;
+// DNSKEY protocol field, which is always 3 (RFC 4034 section 2.1.2)
+const DNSKEY_PROTOCOL: u8 = 3;
+
+// DS records of the root zone's key signing keys, KSK-2017 and KSK-2024
+const BUILTIN: &str = "\
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBF683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+/// A DS record that's trusted without being validated. The keys of its zone are checked against
+/// it, and every other zone's keys are checked through the chain of DS records leading down to
+/// them from such a zone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrustAnchor {
+  pub zone: Dname<Octets>,
+  pub key_tag: u16,
+  pub algorithm: SecAlg,
+  pub digest_type: DigestAlg,
+  pub digest: Vec<u8>,
+}
+
+impl TrustAnchor {
+  /// Whether `key`, a DNSKEY of `self.zone`, is the one this describes.
+  fn matches(&self, key: &Dnskey<Octets>) -> bool {
+    self.key_tag == key.key_tag()
+      && self.algorithm == key.algorithm()
+      && key
+        .digest(&self.zone, self.digest_type)
+        .is_ok_and(|digest| digest.as_ref() == self.digest)
+  }
+}
+
+/// The root zone's trust anchors, used when no trust anchor file is given.
+pub fn builtin() -> Vec<TrustAnchor> {
+  parse(BUILTIN).expect("built-in trust anchors parse")
+}
+
+/// Reads trust anchors from a file of DS records, such as one made from the root zone's
+/// root-anchors.xml.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<TrustAnchor>> {
+  let path = path.as_ref();
+  let contents = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+  parse(&contents).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Extracts the DS records from the contents of a trust anchor file. Each record is on a line of
+/// its own in zone file format, as in `. 86400 IN DS 20326 8 2 E06D44B8...`, and comments start
+/// with `;`. The digest may be split into several fields.
+pub fn parse(contents: &str) -> Result<Vec<TrustAnchor>> {
+  let mut anchors = Vec::new();
+  for (number, line) in contents.lines().enumerate() {
+    let line = line.split(';').next().unwrap_or_default();
+    let fields: Vec<_> = line.split_whitespace().collect();
+    if let Some(i) = fields
+      .iter()
+      .position(|field| field.eq_ignore_ascii_case("DS"))
+    {
+      let anchor =
+        parse_ds(fields[0], &fields[i + 1..]).with_context(|| format!("line {}", number + 1))?;
+      anchors.push(anchor);
+    }
+  }
+
+  if anchors.is_empty() {
+    bail!("no DS records found");
+  }
+  Ok(anchors)
+}
+
+fn parse_ds(zone: &str, fields: &[&str]) -> Result<TrustAnchor> {
+  if fields.len() < 4 {
+    bail!("missing key tag, algorithm, digest type or digest");
+  }
+  let digest = fields[3..].concat();
+  Ok(TrustAnchor {
+    zone: match zone {
+      "." => Dname::root_vec(),
+      _ => Dname::from_str(zone).with_context(|| format!("invalid zone {zone}"))?,
+    },
+    key_tag: fields[0]
+      .parse()
+      .with_context(|| format!("invalid key tag {}", fields[0]))?,
+    algorithm: SecAlg::from_int(
+      fields[1]
+        .parse()
+        .with_context(|| format!("invalid algorithm {}", fields[1]))?,
+    ),
+    digest_type: DigestAlg::from_int(
+      fields[2]
+        .parse()
+        .with_context(|| format!("invalid digest type {}", fields[2]))?,
+    ),
+    digest: decode_hex(&digest).with_context(|| format!("invalid digest {digest}"))?,
+  })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  if !hex.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+/// What's known about the keys of a zone, remembered until `expires`.
+pub(crate) struct ZoneKeys {
+  /// The zone's validated keys, or `None` if the zone isn't signed in a way that can be checked.
+  keys: Option<Vec<Dnskey<Octets>>>,
+  expires: Instant,
+}
+
+/// Whether records could be validated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Security {
+  /// They're signed by keys chained to a trust anchor.
+  Secure,
+  /// They're in a zone that isn't signed, or not with anything that can be checked.
+  Insecure,
+}
+
+/// What the DS records for a name say about it.
+enum Delegation {
+  /// The name is a signed zone, whose keys match these.
+  Signed(Vec<TrustAnchor>),
+  /// The name is a zone that isn't signed, which is known for the TTL given.
+  Unsigned(u32),
+  /// The name is within the zone above it rather than a zone of its own.
+  NotACut,
+}
+
+/// The outcome of checking the signatures over a record set.
+struct Checked {
+  security: Security,
+  /// For records expanded from a wildcard, the name the wildcard is directly below.
+  wildcard: Option<Dname<Octets>>,
+}
+
+impl Checked {
+  const INSECURE: Checked = Checked {
+    security: Security::Insecure,
+    wildcard: None,
+  };
+}
+
+/// Records sharing an owner, type and class, along with the signatures over them.
+struct RecordSet {
+  owner: Dname<Octets>,
+  rtype: Rtype,
+  class: Class,
+  ttl: u32,
+  /// The data of each record, in the canonical form signatures cover (RFC 4034 section 6.2).
+  rdata: Vec<Vec<u8>>,
+  sigs: Vec<Rrsig<Octets, Dname<Octets>>>,
+}
+
+impl RecordSet {
+  /// The data to verify `sig` against (RFC 4034 section 3.1.8.1).
+  fn signed_data(&self, sig: &Rrsig<Octets, Dname<Octets>>) -> Result<Vec<u8>, ResolveError> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&sig.type_covered().to_int().to_be_bytes());
+    data.push(sig.algorithm().to_int());
+    data.push(sig.labels());
+    data.extend_from_slice(&sig.original_ttl().to_be_bytes());
+    data.extend_from_slice(&sig.expiration().into_int().to_be_bytes());
+    data.extend_from_slice(&sig.inception().into_int().to_be_bytes());
+    data.extend_from_slice(&sig.key_tag().to_be_bytes());
+    data.extend_from_slice(&canonical_name(sig.signer_name()));
+
+    // records expanded from a wildcard are signed under the wildcard's name (RFC 4035 section
+    // 5.3.2)
+    let owner = match self.expanded_from(sig)? {
+      Some(encloser) => wildcard(&encloser)?,
+      None => self.owner.clone(),
+    };
+    let owner = canonical_name(&owner);
+    let mut rdata = self.rdata.clone();
+    rdata.sort();
+    rdata.dedup();
+    for rdata in rdata {
+      data.extend_from_slice(&owner);
+      data.extend_from_slice(&self.rtype.to_int().to_be_bytes());
+      data.extend_from_slice(&self.class.to_int().to_be_bytes());
+      data.extend_from_slice(&sig.original_ttl().to_be_bytes());
+      let len = u16::try_from(rdata.len()).map_err(|_| malformed(self))?;
+      data.extend_from_slice(&len.to_be_bytes());
+      data.extend_from_slice(&rdata);
+    }
+    Ok(data)
+  }
+
+  /// If `sig` shows the records were expanded from a wildcard, returns the name the wildcard is
+  /// directly below.
+  fn expanded_from(
+    &self,
+    sig: &Rrsig<Octets, Dname<Octets>>,
+  ) -> Result<Option<Dname<Octets>>, ResolveError> {
+    let labels = usize::from(sig.labels());
+    if labels < self.owner.label_count() - 1 {
+      Ok(Some(suffix(&self.owner, labels)?))
+    } else {
+      Ok(None)
+    }
+  }
+}
+
+/// NSEC and NSEC3 records from a response, with their signatures already checked, for proving
+/// that names or types don't exist.
+#[derive(Default)]
+struct Denial {
+  nsecs: Vec<NsecRecord>,
+  nsec3s: Vec<Nsec3Record>,
+}
+
+/// An NSEC record, which says there's nothing in `zone` between its owner and `next`, and that
+/// its owner has `types` (RFC 4034 section 4).
+struct NsecRecord {
+  zone: Dname<Octets>,
+  owner: Dname<Octets>,
+  next: Dname<Octets>,
+  types: RtypeBitmap<Octets>,
+}
+
+/// An NSEC3 record, which does the same as an NSEC record for hashed names (RFC 5155 section 3).
+struct Nsec3Record {
+  zone: Dname<Octets>,
+  hash: Vec<u8>,
+  next: Vec<u8>,
+  opt_out: bool,
+  iterations: u16,
+  salt: Vec<u8>,
+  types: RtypeBitmap<Octets>,
+}
+
+impl NsecRecord {
+  fn covers(&self, name: &Dname<Octets>) -> bool {
+    name.ends_with(&self.zone)
+      && between(&self.owner, name, &self.next, |a, b| a.name_cmp(b))
+      // an NSEC leading to a name below this one shows this one is an empty non-terminal
+      && !self.next.ends_with(name)
+  }
+}
+
+impl Nsec3Record {
+  fn hash_of(&self, name: &Dname<Octets>) -> Vec<u8> {
+    nsec3_hash(name, &self.salt, self.iterations)
+  }
+
+  fn matches(&self, name: &Dname<Octets>) -> bool {
+    name.ends_with(&self.zone) && self.hash_of(name) == self.hash
+  }
+
+  fn covers(&self, name: &Dname<Octets>) -> bool {
+    name.ends_with(&self.zone)
+      && between(&self.hash, &self.hash_of(name), &self.next, |a, b| a.cmp(b))
+  }
+}
+
+impl Denial {
+  /// Adds the records in `set`. Returns false if they're NSEC3 records that can't be used,
+  /// because of an unknown hash algorithm or too many iterations.
+  fn add(&mut self, set: &RecordSet) -> Result<bool, ResolveError> {
+    // NSEC3 records are all directly below their zone's apex, while NSEC records belong to the
+    // zone that signs them, which for the apex is the owner itself
+    let zone = parent(&set.owner)?;
+    let nsec_zone = set
+      .sigs
+      .iter()
+      .map(|sig| sig.signer_name())
+      .find(|signer| set.owner.ends_with(*signer))
+      .unwrap_or(&zone);
+    for rdata in &set.rdata {
+      match set.rtype {
+        Rtype::Nsec => {
+          let len = name_len(rdata).ok_or_else(|| malformed(set))?;
+          self.nsecs.push(NsecRecord {
+            zone: nsec_zone.clone(),
+            owner: set.owner.clone(),
+            next: Dname::from_octets(rdata[..len].to_vec()).map_err(|_| malformed(set))?,
+            types: RtypeBitmap::from_octets(rdata[len..].to_vec()).map_err(|_| malformed(set))?,
+          });
+        }
+        Rtype::Nsec3 => {
+          let nsec3 = parse_nsec3(&zone, &set.owner, rdata).ok_or_else(|| malformed(set))?;
+          if rdata[0] != NSEC3_SHA1 || nsec3.iterations > MAX_NSEC3_ITERATIONS {
+            debug!("Can't use NSEC3 record {} to prove a denial", set.owner);
+            return Ok(false);
+          }
+          self.nsec3s.push(nsec3);
+        }
+        _ => {}
+      }
+    }
+    Ok(true)
+  }
+
+  /// The types at `name`, if a record shows that it exists.
+  fn types_at(&self, name: &Dname<Octets>) -> Option<&RtypeBitmap<Octets>> {
+    let nsec = self.nsecs.iter().find(|nsec| nsec.owner == *name);
+    match nsec {
+      Some(nsec) => Some(&nsec.types),
+      None => self
+        .nsec3s
+        .iter()
+        .find(|nsec3| nsec3.matches(name))
+        .map(|nsec3| &nsec3.types),
+    }
+  }
+
+  /// Whether a record shows that `name` doesn't exist.
+  fn covers(&self, name: &Dname<Octets>) -> bool {
+    self.nsecs.iter().any(|nsec| nsec.covers(name))
+      || self.nsec3s.iter().any(|nsec3| nsec3.covers(name))
+  }
+
+  /// Whether `name` exists only because names below it do.
+  fn is_empty_non_terminal(&self, name: &Dname<Octets>) -> bool {
+    self.nsecs.iter().any(|nsec| {
+      nsec.owner.name_cmp(name) == Ordering::Less
+        && name.name_cmp(&nsec.next) == Ordering::Less
+        && nsec.next.ends_with(name)
+    })
+  }
+
+  /// The closest name above `name` that the records show exists (RFC 5155 section 7.2.1).
+  fn closest_encloser(&self, name: &Dname<Octets>) -> Result<Option<Dname<Octets>>> {
+    for nsec in self.nsecs.iter().filter(|nsec| nsec.covers(name)) {
+      // the longest name both ends of the covering record share with `name` must exist
+      let encloser = [&nsec.owner, &nsec.next]
+        .into_iter()
+        .map(|other| common_suffix(name, other))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .max_by_key(|encloser| encloser.label_count());
+      if encloser.is_some() {
+        return Ok(encloser);
+      }
+    }
+    for encloser in name.iter_suffixes().skip(1) {
+      let encloser = encloser.to_dname()?;
+      if self.nsec3s.iter().any(|nsec3| nsec3.matches(&encloser)) {
+        return Ok(Some(encloser));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Whether the records show `name` doesn't exist, not even through a wildcard.
+  fn proves_nxdomain(&self, name: &Dname<Octets>) -> Result<bool> {
+    Ok(match self.closest_encloser(name)? {
+      Some(encloser) => {
+        self.covers(&next_closer(name, &encloser)?) && self.covers(&wildcard(&encloser)?)
+      }
+      None => false,
+    })
+  }
+
+  /// Whether the records show `name` has no `rtype` records.
+  fn proves_nodata(&self, name: &Dname<Octets>, rtype: Rtype) -> Result<bool> {
+    let lacks =
+      |types: &RtypeBitmap<Octets>| !types.contains(rtype) && !types.contains(Rtype::Cname);
+    if let Some(types) = self.types_at(name) {
+      return Ok(lacks(types));
+    }
+    if self.is_empty_non_terminal(name) || (rtype == Rtype::Ds && self.opt_out_covers(name)?) {
+      return Ok(true);
+    }
+    // the name may match a wildcard that lacks the type
+    Ok(match self.closest_encloser(name)? {
+      Some(encloser) => {
+        self.covers(&next_closer(name, &encloser)?)
+          && self.types_at(&wildcard(&encloser)?).is_some_and(lacks)
+      }
+      None => false,
+    })
+  }
+
+  /// Whether an opt-out NSEC3 record shows there may be an unsigned delegation at `name`.
+  fn opt_out_covers(&self, name: &Dname<Octets>) -> Result<bool> {
+    let next_closer = match self.closest_encloser(name)? {
+      Some(encloser) => next_closer(name, &encloser)?,
+      None => return Ok(false),
+    };
+    Ok(
+      self
+        .nsec3s
+        .iter()
+        .any(|nsec3| nsec3.opt_out && nsec3.covers(&next_closer)),
+    )
+  }
+}
+
+impl<R: Rng + ?Sized, T: Transport> DnsServer<R, T> {
+  /// Validates `response`, the answer to `question`, setting AD in it if every record set it holds
+  /// is signed with keys chained to a trust anchor, and clearing AD otherwise. Answers from zones
+  /// that aren't signed are returned as they are. Answers that should be signed but aren't, or
+  /// whose signatures don't verify, are bogus, and fail with `ResolveError::Bogus`.
+  pub(crate) fn validate<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    mut response: Message,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    let security = self.check_response(question, &response, depth, budget)?;
+    debug!("Validated {question}: {security:?}");
+    response.header_mut().set_ad(security == Security::Secure);
+    Ok(response)
+  }
+
+  fn check_response<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    response: &Message,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Security, ResolveError> {
+    let rcode = response.header().rcode();
+    if rcode != Rcode::NoError && rcode != Rcode::NXDomain {
+      return Ok(Security::Insecure);
+    }
+    let (_, answers, authorities, _) = response.sections()?;
+    let answers = record_sets(answers)?;
+
+    let mut security = Security::Secure;
+    let mut wildcards = Vec::new();
+    for set in &answers {
+      let checked = self.check_set(set, None, depth, budget)?;
+      if checked.security == Security::Insecure {
+        security = Security::Insecure;
+      }
+      if let Some(encloser) = checked.wildcard {
+        wildcards.push((set.owner.clone(), encloser));
+      }
+    }
+
+    // An answer that ends in a CNAME is finished by resolving the target, whose response is
+    // validated on its own.
+    let qname = question.qname().to_dname::<Octets>()?;
+    let aliased = answers
+      .iter()
+      .any(|set| set.rtype == Rtype::Cname && set.owner == qname);
+    let answered = answers
+      .iter()
+      .any(|set| set.owner == qname && set.rtype == question.qtype());
+    let negative = !aliased && !answered;
+    if security == Security::Insecure || (!negative && wildcards.is_empty()) {
+      return Ok(security);
+    }
+
+    let denial = match self.check_denial(authorities, None, &qname, depth, budget)? {
+      Some(denial) => denial,
+      None => return Ok(Security::Insecure),
+    };
+    for (owner, encloser) in wildcards {
+      if !denial.covers(&next_closer(&owner, &encloser)?) {
+        return Err(bogus(format!(
+          "no proof that {owner} doesn't exist outside a wildcard"
+        )));
+      }
+    }
+    let proven = match (negative, rcode) {
+      (false, _) => true,
+      (true, Rcode::NXDomain) => denial.proves_nxdomain(&qname)?,
+      (true, _) => denial.proves_nodata(&qname, question.qtype())?,
+    };
+    if !proven {
+      return Err(bogus(format!("no proof of the denial for {question}")));
+    }
+    Ok(Security::Secure)
+  }
+
+  /// Checks the signatures over the SOA, NSEC and NSEC3 records in `authorities`, returning the
+  /// denial they make up. With `above`, the records must come from the zone above that name. If
+  /// any are in an unsigned zone, or none are there and `name` is in an unsigned zone, returns
+  /// `None`.
+  fn check_denial(
+    &mut self,
+    authorities: RecordSection<&Octets>,
+    above: Option<&Dname<Octets>>,
+    name: &Dname<Octets>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Option<Denial>, ResolveError> {
+    let sets: Vec<_> = record_sets(authorities)?
+      .into_iter()
+      .filter(|set| matches!(set.rtype, Rtype::Soa | Rtype::Nsec | Rtype::Nsec3))
+      .collect();
+    if sets.is_empty() {
+      let name = match above {
+        Some(above) => parent(above)?,
+        None => name.clone(),
+      };
+      return match self.name_security(&name, depth, budget)? {
+        Security::Secure => Ok(Some(Denial::default())),
+        Security::Insecure => Ok(None),
+      };
+    }
+
+    let mut denial = Denial::default();
+    for set in &sets {
+      if self.check_set(set, above, depth, budget)?.security == Security::Insecure
+        || !denial.add(set)?
+      {
+        return Ok(None);
+      }
+    }
+    Ok(Some(denial))
+  }
+
+  /// Checks the signatures over `set`. With `above`, they must be made by a zone above that name,
+  /// as they must for DS records, which belong to the zone above their owner.
+  fn check_set(
+    &mut self,
+    set: &RecordSet,
+    above: Option<&Dname<Octets>>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Checked, ResolveError> {
+    let above = match set.rtype {
+      Rtype::Ds => Some(&set.owner),
+      _ => above,
+    };
+    let now = Serial::now();
+    let mut signer_secure = false;
+    for sig in &set.sigs {
+      let signer = sig.signer_name();
+      let usable = set.owner.ends_with(signer)
+        && usize::from(sig.labels()) < set.owner.label_count()
+        && above.is_none_or(|above| above != signer && above.ends_with(signer))
+        && SUPPORTED_ALGORITHMS.contains(&sig.algorithm());
+      if !usable {
+        continue;
+      }
+      let keys = match self.zone_keys(signer, depth, budget)? {
+        Some(keys) => keys,
+        None => continue,
+      };
+      signer_secure = true;
+      if now < sig.inception() || sig.expiration() < now {
+        continue;
+      }
+      let signed_data = set.signed_data(sig)?;
+      let verified = keys
+        .iter()
+        .filter(|key| key.key_tag() == sig.key_tag() && key.algorithm() == sig.algorithm())
+        .any(|key| sig.verify_signed_data(key, &signed_data).is_ok());
+      if verified {
+        return Ok(Checked {
+          security: Security::Secure,
+          wildcard: set.expanded_from(sig)?,
+        });
+      }
+    }
+
+    // Without a valid signature, the records are only fine if they needn't be signed, which they
+    // must be if a signed zone claims to have signed them.
+    let name = match above {
+      Some(above) => parent(above)?,
+      None => set.owner.clone(),
+    };
+    if signer_secure || self.name_security(&name, depth, budget)? == Security::Secure {
+      return Err(bogus(format!(
+        "no valid signature over {} {}",
+        set.owner, set.rtype
+      )));
+    }
+    Ok(Checked::INSECURE)
+  }
+
+  /// Works out whether records at `name` have to be signed, by following the DS records down
+  /// to it from the closest zone above it with known keys or a trust anchor.
+  fn name_security(
+    &mut self,
+    name: &Dname<Octets>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Security, ResolveError> {
+    let zones = name
+      .iter_suffixes()
+      .map(|zone| zone.to_dname())
+      .collect::<Result<Vec<Dname<Octets>>, _>>()?;
+    let mut start = None;
+    for (i, zone) in zones.iter().enumerate() {
+      if let Some(keys) = self.cached_zone_keys(zone) {
+        if keys.is_none() {
+          return Ok(Security::Insecure);
+        }
+        start = Some(i);
+        break;
+      }
+      if self.anchors_for(zone).is_some() {
+        start = Some(i);
+        break;
+      }
+    }
+    let start = match start {
+      Some(start) => start,
+      None => return Ok(Security::Insecure),
+    };
+    if self.zone_keys(&zones[start], depth, budget)?.is_none() {
+      return Ok(Security::Insecure);
+    }
+
+    for zone in zones[..start].iter().rev() {
+      match self.delegation(zone, depth, budget)? {
+        Delegation::Signed(ds) => {
+          if self.keys_from_ds(zone, ds, depth, budget)?.is_none() {
+            return Ok(Security::Insecure);
+          }
+        }
+        Delegation::Unsigned(ttl) => {
+          self.store_zone_keys(zone, None, ttl);
+          return Ok(Security::Insecure);
+        }
+        Delegation::NotACut => {}
+      }
+    }
+    Ok(Security::Secure)
+  }
+
+  /// Returns the validated keys of `zone`, or `None` if it isn't a signed zone.
+  fn zone_keys(
+    &mut self,
+    zone: &Dname<Octets>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Option<Vec<Dnskey<Octets>>>, ResolveError> {
+    if let Some(keys) = self.cached_zone_keys(zone) {
+      return Ok(keys);
+    }
+    let ds = match self.anchors_for(zone) {
+      Some(anchors) => anchors,
+      None if zone.is_root() => return Ok(None),
+      None => match self.delegation(zone, depth, budget)? {
+        Delegation::Signed(ds) => ds,
+        Delegation::Unsigned(ttl) => {
+          self.store_zone_keys(zone, None, ttl);
+          return Ok(None);
+        }
+        // whatever claims to be signed by it isn't, but the zone it's in may still be signed
+        Delegation::NotACut => return Ok(None),
+      },
+    };
+    self.keys_from_ds(zone, ds, depth, budget)
+  }
+
+  /// Fetches the DNSKEY records of `zone` and checks that one matching `ds` signs them all.
+  fn keys_from_ds(
+    &mut self,
+    zone: &Dname<Octets>,
+    ds: Vec<TrustAnchor>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Option<Vec<Dnskey<Octets>>>, ResolveError> {
+    let ds: Vec<_> = ds
+      .into_iter()
+      .filter(|ds| {
+        SUPPORTED_ALGORITHMS.contains(&ds.algorithm) && SUPPORTED_DIGESTS.contains(&ds.digest_type)
+      })
+      .collect();
+    if ds.is_empty() {
+      debug!("No DS record of {zone} can be checked, treating it as unsigned");
+      return Ok(None);
+    }
+
+    let question = Question::new(zone.clone(), Rtype::Dnskey, Class::In);
+    let response = self.recurse(&question, true, Dnssec::Records, None, depth + 1, budget)?;
+    let set = record_sets(response.answer()?)?
+      .into_iter()
+      .find(|set| set.rtype == Rtype::Dnskey && set.owner == *zone)
+      .ok_or_else(|| bogus(format!("{zone} has no DNSKEY records")))?;
+    let keys: Vec<_> = set
+      .rdata
+      .iter()
+      .filter_map(|rdata| parse_dnskey(rdata))
+      .filter(|key| key.protocol() == DNSKEY_PROTOCOL && key.is_zsk() && !key.is_revoked())
+      .collect();
+
+    let now = Serial::now();
+    for sig in &set.sigs {
+      if sig.signer_name() != zone || now < sig.inception() || sig.expiration() < now {
+        continue;
+      }
+      let signed_data = set.signed_data(sig)?;
+      let verified = keys
+        .iter()
+        .filter(|key| key.key_tag() == sig.key_tag() && key.algorithm() == sig.algorithm())
+        .filter(|key| ds.iter().any(|ds| ds.matches(key)))
+        .any(|key| sig.verify_signed_data(key, &signed_data).is_ok());
+      if verified {
+        self.store_zone_keys(zone, Some(keys.clone()), set.ttl);
+        return Ok(Some(keys));
+      }
+    }
+    Err(bogus(format!(
+      "no key of {zone} matching its DS records signs its DNSKEY records"
+    )))
+  }
+
+  /// Fetches and validates the DS records for `zone`, or the proof that there are none.
+  fn delegation(
+    &mut self,
+    zone: &Dname<Octets>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Delegation, ResolveError> {
+    let question = Question::new(zone.clone(), Rtype::Ds, Class::In);
+    let response = self.recurse(&question, true, Dnssec::Records, None, depth + 1, budget)?;
+    let (_, answers, authorities, _) = response.sections()?;
+
+    let ds = record_sets(answers)?
+      .into_iter()
+      .find(|set| set.rtype == Rtype::Ds && set.owner == *zone);
+    if let Some(set) = ds {
+      return Ok(match self.check_set(&set, None, depth, budget)?.security {
+        Security::Secure => Delegation::Signed(
+          set
+            .rdata
+            .iter()
+            .filter_map(|rdata| parse_ds_rdata(zone, rdata))
+            .collect(),
+        ),
+        Security::Insecure => Delegation::Unsigned(set.ttl),
+      });
+    }
+
+    let ttl = authorities
+      .filter_map(|record| record.ok())
+      .map(|record| record.ttl())
+      .min()
+      .unwrap_or_default();
+    let denial = match self.check_denial(authorities, Some(zone), zone, depth, budget)? {
+      Some(denial) => denial,
+      None => return Ok(Delegation::Unsigned(ttl)),
+    };
+    if let Some(types) = denial.types_at(zone) {
+      return if types.contains(Rtype::Ds) {
+        Err(bogus(format!("{zone} has DS records but none were sent")))
+      } else if types.contains(Rtype::Ns) && !types.contains(Rtype::Soa) {
+        Ok(Delegation::Unsigned(ttl))
+      } else {
+        Ok(Delegation::NotACut)
+      };
+    }
+    if denial.opt_out_covers(zone)? {
+      return Ok(Delegation::Unsigned(ttl));
+    }
+    if denial.is_empty_non_terminal(zone) || denial.proves_nxdomain(zone)? {
+      return Ok(Delegation::NotACut);
+    }
+    Err(bogus(format!("no proof that {zone} has no DS records")))
+  }
+
+  /// The trust anchors for `zone`, if there are any.
+  fn anchors_for(&self, zone: &Dname<Octets>) -> Option<Vec<TrustAnchor>> {
+    let anchors: Vec<_> = self
+      .trust_anchors
+      .iter()
+      .flat_map(|anchors| anchors.iter())
+      .filter(|anchor| anchor.zone == *zone)
+      .cloned()
+      .collect();
+    Some(anchors).filter(|anchors| !anchors.is_empty())
+  }
+
+  /// Locks the zone keys, ignoring poisoning like `cache` does.
+  fn zone_key_cache(&self) -> MutexGuard<'_, LruCache<Dname<Octets>, ZoneKeys>> {
+    self
+      .zone_keys
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// What's remembered about the keys of `zone`: `Some(None)` if it's unsigned.
+  fn cached_zone_keys(&self, zone: &Dname<Octets>) -> Option<Option<Vec<Dnskey<Octets>>>> {
+    match self.zone_key_cache().get(zone) {
+      Some(entry) if entry.expires > Instant::now() => Some(entry.keys.clone()),
+      _ => None,
+    }
+  }
+
+  fn store_zone_keys(&self, zone: &Dname<Octets>, keys: Option<Vec<Dnskey<Octets>>>, ttl: u32) {
+    let expires = Instant::now() + Duration::from_secs(ttl.into());
+    self
+      .zone_key_cache()
+      .insert(zone.clone(), ZoneKeys { keys, expires });
+  }
+}
+
+/// Groups the records in `section` into record sets, attaching the signatures over each.
+fn record_sets(section: RecordSection<&Octets>) -> Result<Vec<RecordSet>, ResolveError> {
+  let mut sets: Vec<RecordSet> = Vec::new();
+  let mut sigs = Vec::new();
+  for record in
+    section.limit_to::<AllRecordData<<&Octets as OctetsRef>::Range, ParsedDname<&Octets>>>()
+  {
+    let record = record?;
+    let owner: Dname<Octets> = record.owner().to_dname()?;
+    let data = match record.data() {
+      AllRecordData::Rrsig(sig) => {
+        let sig = Rrsig::new(
+          sig.type_covered(),
+          sig.algorithm(),
+          sig.labels(),
+          sig.original_ttl(),
+          sig.expiration(),
+          sig.inception(),
+          sig.key_tag(),
+          sig.signer_name().to_dname()?,
+          sig.signature().to_vec(),
+        );
+        sigs.push((owner, record.class(), sig));
+        continue;
+      }
+      AllRecordData::Opt(_) => continue,
+      data => data,
+    };
+
+    let mut rdata = Vec::new();
+    data.compose_canonical(&mut rdata)?;
+    let (rtype, class) = (record.rtype(), record.class());
+    match sets
+      .iter_mut()
+      .find(|set| set.owner == owner && set.rtype == rtype && set.class == class)
+    {
+      Some(set) => {
+        set.ttl = set.ttl.min(record.ttl());
+        set.rdata.push(rdata);
+      }
+      None => sets.push(RecordSet {
+        owner,
+        rtype,
+        class,
+        ttl: record.ttl(),
+        rdata: vec![rdata],
+        sigs: Vec::new(),
+      }),
+    }
+  }
+
+  for (owner, class, sig) in sigs {
+    let set = sets
+      .iter_mut()
+      .find(|set| set.owner == owner && set.rtype == sig.type_covered() && set.class == class);
+    if let Some(set) = set {
+      set.sigs.push(sig);
+    }
+  }
+  Ok(sets)
+}
+
+fn parse_dnskey(rdata: &[u8]) -> Option<Dnskey<Octets>> {
+  let header = rdata.get(..4)?;
+  Some(Dnskey::new(
+    u16::from_be_bytes([header[0], header[1]]),
+    header[2],
+    SecAlg::from_int(header[3]),
+    rdata[4..].to_vec(),
+  ))
+}
+
+fn parse_ds_rdata(zone: &Dname<Octets>, rdata: &[u8]) -> Option<TrustAnchor> {
+  let header = rdata.get(..4)?;
+  Some(TrustAnchor {
+    zone: zone.clone(),
+    key_tag: u16::from_be_bytes([header[0], header[1]]),
+    algorithm: SecAlg::from_int(header[2]),
+    digest_type: DigestAlg::from_int(header[3]),
+    digest: rdata[4..].to_vec(),
+  })
+}
+
+fn parse_nsec3(zone: &Dname<Octets>, owner: &Dname<Octets>, rdata: &[u8]) -> Option<Nsec3Record> {
+  let salt_len = usize::from(*rdata.get(4)?);
+  let salt = rdata.get(5..5 + salt_len)?;
+  let hash_len = usize::from(*rdata.get(5 + salt_len)?);
+  let next_start = 6 + salt_len;
+  let next = rdata.get(next_start..next_start + hash_len)?;
+  let label = owner.first().as_slice();
+  Some(Nsec3Record {
+    zone: zone.clone(),
+    hash: decode_base32hex(label)?,
+    next: next.to_vec(),
+    opt_out: rdata[1] & 1 != 0,
+    iterations: u16::from_be_bytes([rdata[2], rdata[3]]),
+    salt: salt.to_vec(),
+    types: RtypeBitmap::from_octets(rdata[next_start + hash_len..].to_vec()).ok()?,
+  })
+}
+
+/// Decodes the Base 32 encoding with the extended hex alphabet that hashed owner names use
+/// (RFC 4648 section 7), ignoring case.
+fn decode_base32hex(text: &[u8]) -> Option<Vec<u8>> {
+  let mut bytes = Vec::new();
+  let (mut buffer, mut bits) = (0u32, 0);
+  for &c in text {
+    let value = match c.to_ascii_uppercase() {
+      c @ b'0'..=b'9' => c - b'0',
+      c @ b'A'..=b'V' => c - b'A' + 10,
+      _ => return None,
+    };
+    buffer = buffer << 5 | u32::from(value);
+    bits += 5;
+    if bits >= 8 {
+      bits -= 8;
+      bytes.push((buffer >> bits) as u8);
+    }
+  }
+  Some(bytes)
+}
+
+/// Hashes `name` the way NSEC3 records for its zone do (RFC 5155 section 5).
+fn nsec3_hash(name: &Dname<Octets>, salt: &[u8], iterations: u16) -> Vec<u8> {
+  let mut hash = canonical_name(name);
+  for _ in 0..=iterations {
+    let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+    context.update(&hash);
+    context.update(salt);
+    hash = context.finish().as_ref().to_vec();
+  }
+  hash
+}
+
+/// The wire form of `name` in lowercase, as signatures and hashes cover it. Length octets are
+/// never above 63, so lowercasing every octet leaves them alone.
+fn canonical_name(name: &Dname<Octets>) -> Vec<u8> {
+  name.as_slice().to_ascii_lowercase()
+}
+
+/// Whether `name` falls strictly between `start` and `end` in the order `cmp` gives, where
+/// an `end` before `start` wraps around past the last name of the zone.
+fn between<V: ?Sized>(start: &V, name: &V, end: &V, cmp: impl Fn(&V, &V) -> Ordering) -> bool {
+  let after_start = cmp(start, name) == Ordering::Less;
+  let before_end = cmp(name, end) == Ordering::Less;
+  match cmp(start, end) {
+    Ordering::Less => after_start && before_end,
+    _ => after_start || before_end,
+  }
+}
+
+/// The length of the uncompressed domain name at the start of `bytes`.
+fn name_len(bytes: &[u8]) -> Option<usize> {
+  let mut len = 0;
+  loop {
+    let label = usize::from(*bytes.get(len)?);
+    len += label + 1;
+    if label == 0 {
+      return Some(len);
+    }
+  }
+}
+
+/// The last `labels` labels of `name`, not counting the root.
+fn suffix(name: &Dname<Octets>, labels: usize) -> Result<Dname<Octets>, ResolveError> {
+  let skip = (name.label_count() - 1).saturating_sub(labels);
+  match name.iter_suffixes().nth(skip) {
+    Some(suffix) => Ok(suffix.to_dname()?),
+    None => Ok(Dname::root_vec()),
+  }
+}
+
+fn parent(name: &Dname<Octets>) -> Result<Dname<Octets>, ResolveError> {
+  suffix(name, (name.label_count() - 1).saturating_sub(1))
+}
+
+/// The name one label longer than `encloser` that `name` is at or below.
+fn next_closer(name: &Dname<Octets>, encloser: &Dname<Octets>) -> Result<Dname<Octets>> {
+  Ok(suffix(name, encloser.label_count())?)
+}
+
+/// The wildcard directly below `name`.
+fn wildcard(name: &Dname<Octets>) -> Result<Dname<Octets>, ResolveError> {
+  let mut octets = b"\x01*".to_vec();
+  octets.extend_from_slice(name.as_slice());
+  Dname::from_octets(octets).map_err(|e| ResolveError::MalformedResponse(e.to_string()))
+}
+
+/// The longest name that both `name` and `other` are at or below.
+fn common_suffix(name: &Dname<Octets>, other: &Dname<Octets>) -> Result<Dname<Octets>> {
+  for suffix in name.iter_suffixes() {
+    if other.ends_with(&suffix) {
+      return Ok(suffix.to_dname()?);
+    }
+  }
+  Ok(Dname::root_vec())
+}
+
+fn bogus(reason: String) -> ResolveError {
+  ResolveError::Bogus(reason)
+}
+
+fn malformed(set: &RecordSet) -> ResolveError {
+  ResolveError::MalformedResponse(format!("invalid {} record for {}", set.rtype, set.owner))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{slice, sync::Arc};
+
+  use domain::base::MessageBuilder;
+  use rand::rngs::StdRng;
+  use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+  };
+
+  use super::*;
+  use crate::{
+    tests::{
+      a, answer_addrs, ask, delegate, dnssec_query, ip, name, query, question, record, reply,
+      resolve_err, resolving_www_mock, server, TestRecord, EXAMPLE,
+    },
+    transport::mock::MockTransport,
+  };
+
+  /// An ECDSAP256SHA256 key of example.com., along with the DNSKEY record publishing it.
+  struct ZoneKey {
+    pair: EcdsaKeyPair,
+    dnskey: Dnskey<Octets>,
+  }
+
+  impl ZoneKey {
+    fn generate() -> ZoneKey {
+      let rng = SystemRandom::new();
+      let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+      let pair =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+      // DNSKEY records leave out the 0x04 that starts an uncompressed point
+      let public_key = pair.public_key().as_ref()[1..].to_vec();
+      let dnskey = Dnskey::new(257, DNSKEY_PROTOCOL, SecAlg::EcdsaP256Sha256, public_key);
+      ZoneKey { pair, dnskey }
+    }
+
+    /// A trust anchor for example.com. matching this key.
+    fn anchor(&self) -> TrustAnchor {
+      let zone = name("example.com");
+      TrustAnchor {
+        digest: self
+          .dnskey
+          .digest(&zone, DigestAlg::Sha256)
+          .unwrap()
+          .as_ref()
+          .to_vec(),
+        zone,
+        key_tag: self.dnskey.key_tag(),
+        algorithm: SecAlg::EcdsaP256Sha256,
+        digest_type: DigestAlg::Sha256,
+      }
+    }
+
+    fn record(&self) -> TestRecord {
+      record("example.com", AllRecordData::Dnskey(self.dnskey.clone()))
+    }
+
+    /// An RRSIG record over `records`, which all share an owner and type, valid for an hour
+    /// either side of now.
+    fn sign(&self, records: &[TestRecord]) -> TestRecord {
+      let first = &records[0];
+      let mut builder = MessageBuilder::new_vec().answer();
+      for record in records {
+        builder.push(record).unwrap();
+      }
+      let message = builder.into_message();
+      let set = record_sets(message.answer().unwrap()).unwrap().remove(0);
+      let now = Serial::now();
+      let unsigned = |signature| {
+        Rrsig::new(
+          first.rtype(),
+          SecAlg::EcdsaP256Sha256,
+          first.owner().label_count() as u8 - 1,
+          first.ttl(),
+          now.add(3600),
+          Serial(now.into_int() - 3600),
+          self.dnskey.key_tag(),
+          name("example.com"),
+          signature,
+        )
+      };
+      let signed_data = set.signed_data(&unsigned(Vec::new())).unwrap();
+      let signature = self.pair.sign(&SystemRandom::new(), &signed_data).unwrap();
+      let sig = unsigned(signature.as_ref().to_vec());
+      record(&first.owner().to_string(), AllRecordData::Rrsig(sig))
+    }
+  }
+
+  /// A mock where example.com.'s server answers for its keys, signed with `key`, and for
+  /// www.example.com.'s address with `answer`, trusting `key` for example.com.
+  fn signed_zone(key: &ZoneKey, answer: &[TestRecord]) -> DnsServer<StdRng, MockTransport> {
+    let mock = MockTransport::default();
+    delegate(&mock, "example.com", Rtype::Dnskey);
+    let keys = reply("example.com", Rtype::Dnskey)
+      .authoritative()
+      .answer(key.record())
+      .answer(key.sign(&[key.record()]));
+    mock.insert(
+      question("example.com", Rtype::Dnskey),
+      EXAMPLE,
+      keys.build(),
+    );
+    delegate(&mock, "www.example.com", Rtype::A);
+    let mut response = reply("www.example.com", Rtype::A).authoritative();
+    for record in answer {
+      response = response.answer(record.clone());
+    }
+    mock.insert(
+      question("www.example.com", Rtype::A),
+      EXAMPLE,
+      response.build(),
+    );
+    let mut server = server(mock);
+    server.trust_anchors = Some(Arc::new(vec![key.anchor()]));
+    server
+  }
+
+  #[test]
+  fn signed_answers_are_authenticated() {
+    let key = ZoneKey::generate();
+    let address = a("www.example.com", "192.0.2.10");
+    let mut server = signed_zone(&key, &[address.clone(), key.sign(&[address])]);
+
+    let response = server.resolve("www.example.com", Rtype::A).unwrap();
+    assert!(response.header().ad());
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    let response = ask(&mut server, &dnssec_query("www.example.com", Rtype::A));
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    assert!(response.header().ad());
+    // clients that don't show they understand AD don't get it
+    let response = ask(&mut server, &query("www.example.com", Rtype::A));
+    assert!(!response.header().ad());
+  }
+
+  #[test]
+  fn tampered_signatures_are_bogus() {
+    let key = ZoneKey::generate();
+    let address = a("www.example.com", "192.0.2.10");
+    let sig = match key.sign(slice::from_ref(&address)).into_data() {
+      AllRecordData::Rrsig(sig) => sig,
+      _ => unreachable!(),
+    };
+    let mut signature = sig.signature().clone();
+    signature[0] ^= 1;
+    let tampered = Rrsig::new(
+      sig.type_covered(),
+      sig.algorithm(),
+      sig.labels(),
+      sig.original_ttl(),
+      sig.expiration(),
+      sig.inception(),
+      sig.key_tag(),
+      sig.signer_name().clone(),
+      signature,
+    );
+    let tampered = record("www.example.com", AllRecordData::Rrsig(tampered));
+    let mut server = signed_zone(&key, &[address, tampered]);
+
+    let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+    assert!(matches!(error, ResolveError::Bogus(_)), "{error:?}");
+    let response = ask(&mut server, &query("www.example.com", Rtype::A));
+    assert_eq!(response.header().rcode(), Rcode::ServFail);
+  }
+
+  #[test]
+  fn unsigned_answers_from_unsigned_zones_are_insecure() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.trust_anchors = Some(Arc::new(Vec::new()));
+
+    let response = server.resolve("www.example.com", Rtype::A).unwrap();
+    assert!(!response.header().ad());
+  }
+
+  #[test]
+  fn trust_anchor_files_are_parsed() {
+    let anchors =
+      parse("; a key of example.com.\nexample.com. IN DS 1234 13 2 00FF 0102\n").unwrap();
+    assert_eq!(anchors.len(), 1);
+    assert_eq!(anchors[0].zone, name("example.com"));
+    assert_eq!(anchors[0].key_tag, 1234);
+    assert_eq!(anchors[0].algorithm, SecAlg::EcdsaP256Sha256);
+    assert_eq!(anchors[0].digest, [0x00, 0xff, 0x01, 0x02]);
+    assert_eq!(builtin().len(), 2);
+  }
+}
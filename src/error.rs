@@ -0,0 +1,179 @@
+use std::{
+  error::Error,
+  fmt::{self, Display},
+  io,
+  net::SocketAddr,
+  sync::Arc,
+};
+
+use domain::base::{
+  iana::{ExtendedErrorCode, Rcode},
+  name::{FromStrError, PushError},
+  octets::{ParseError, ShortBuf},
+};
+
+/// Why resolving a question failed. Errors are cheap to clone, so one resolution's failure can be
+/// handed to every query that was waiting on it.
+#[derive(Clone, Debug)]
+pub enum ResolveError {
+  /// The name asked about isn't a valid domain name.
+  InvalidName(String, FromStrError),
+  /// The name asked about is a single label without a trailing dot, and there are no search
+  /// domains to complete it with.
+  SingleLabel(String),
+  /// A name server didn't respond in time.
+  Timeout,
+  /// A name server sent something that isn't a usable response to the query, such as a message
+  /// that doesn't parse or one with the wrong ID.
+  MalformedResponse(String),
+  /// Resolving needed more nested name server lookups than allowed, which usually means the
+  /// delegations involved refer to each other. Holds the limit.
+  MaxDepthExceeded(usize),
+  /// Following referrals for the question, described by the string, never led to an answer,
+  /// either because a server was sent the question a second time or because there were too many.
+  ReferralLoop(String),
+  /// Resolving a client query needed more time or more queries to other servers than it's
+  /// allowed, for the reason described by the string.
+  BudgetExceeded(String),
+  /// The response failed DNSSEC validation, for the reason described by the string, such as a
+  /// signature that doesn't verify or a missing proof that a name doesn't exist.
+  Bogus(String),
+  /// A name server answered with SERVFAIL or REFUSED instead of answering the question, and so did
+  /// every other server asked. Holds the last server to fail and its rcode.
+  ServerFailed(SocketAddr, Rcode),
+  /// Too many queries to other servers were already outstanding to send another, even after
+  /// waiting a while for one to finish. Holds the limit.
+  Overloaded(usize),
+  /// There were no name servers to ask about the question, described by the string.
+  NoReachableServer(String),
+  /// Every name server asked about a zone turned out not to serve it, though the zone was
+  /// delegated to them. Holds the zone.
+  LameDelegation(String),
+  /// Every name server a zone was delegated to is named inside the zone itself, and the referral
+  /// came without their addresses, so there's no way to reach them. Holds the zone.
+  MissingGlue(String),
+  /// Sending or receiving a query failed.
+  Io(Arc<io::Error>),
+  /// Anything else, such as a response too large to build.
+  Other(Arc<anyhow::Error>),
+}
+
+impl ResolveError {
+  /// The Extended DNS Error (RFC 8914) that tells a client why its query failed this way. Running
+  /// out of time or queries counts as no authority being reachable, since it means none gave an
+  /// answer while there was still time to.
+  pub fn extended_error(&self) -> ExtendedErrorCode {
+    match self {
+      ResolveError::Timeout
+      | ResolveError::BudgetExceeded(_)
+      | ResolveError::ServerFailed(..)
+      | ResolveError::NoReachableServer(_)
+      | ResolveError::LameDelegation(_)
+      | ResolveError::MissingGlue(_) => ExtendedErrorCode::NoReachableAuthority,
+      ResolveError::Io(_) => ExtendedErrorCode::NetworkError,
+      ResolveError::Bogus(_) => ExtendedErrorCode::DnssecBogus,
+      ResolveError::InvalidName(..)
+      | ResolveError::SingleLabel(_)
+      | ResolveError::MalformedResponse(_)
+      | ResolveError::MaxDepthExceeded(_)
+      | ResolveError::ReferralLoop(_)
+      | ResolveError::Overloaded(_)
+      | ResolveError::Other(_) => ExtendedErrorCode::Other,
+    }
+  }
+}
+
+impl Display for ResolveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ResolveError::InvalidName(name, e) => write!(f, "invalid domain name {name:?}: {e}"),
+      ResolveError::SingleLabel(name) => write!(
+        f,
+        "{name:?} is a single label, end it with a dot to resolve it as a top-level domain"
+      ),
+      ResolveError::Timeout => write!(f, "timed out waiting for a name server"),
+      ResolveError::MalformedResponse(reason) => write!(f, "malformed response: {reason}"),
+      ResolveError::MaxDepthExceeded(depth) => {
+        write!(f, "exceeded the maximum recursion depth of {depth}")
+      }
+      ResolveError::ReferralLoop(question) => {
+        write!(f, "referrals for {question} never led to an answer")
+      }
+      ResolveError::BudgetExceeded(reason) => write!(f, "gave up resolving: {reason}"),
+      ResolveError::Bogus(reason) => write!(f, "DNSSEC validation failed: {reason}"),
+      ResolveError::ServerFailed(server, rcode) => write!(f, "{server} answered {rcode}"),
+      ResolveError::Overloaded(max) => write!(
+        f,
+        "the limit of {max} outstanding queries to other servers was reached"
+      ),
+      ResolveError::NoReachableServer(question) => {
+        write!(f, "no name servers to ask about {question}")
+      }
+      ResolveError::LameDelegation(zone) => {
+        write!(f, "every name server asked about {zone} is lame")
+      }
+      ResolveError::MissingGlue(zone) => {
+        write!(
+          f,
+          "the name servers for {zone} are all inside it and came without glue"
+        )
+      }
+      ResolveError::Io(e) => write!(f, "{e}"),
+      ResolveError::Other(e) => write!(f, "{e:#}"),
+    }
+  }
+}
+
+impl Error for ResolveError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ResolveError::InvalidName(_, e) => Some(e),
+      ResolveError::Io(e) => Some(e.as_ref()),
+      ResolveError::Other(e) => Some(e.as_ref().as_ref()),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for ResolveError {
+  fn from(e: io::Error) -> ResolveError {
+    match e.kind() {
+      // a socket read timing out is reported as WouldBlock on Unix and TimedOut on Windows
+      io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ResolveError::Timeout,
+      _ => ResolveError::Io(Arc::new(e)),
+    }
+  }
+}
+
+impl From<ParseError> for ResolveError {
+  fn from(e: ParseError) -> ResolveError {
+    ResolveError::MalformedResponse(e.to_string())
+  }
+}
+
+impl From<ShortBuf> for ResolveError {
+  fn from(_: ShortBuf) -> ResolveError {
+    ResolveError::MalformedResponse(String::from("message ends too early"))
+  }
+}
+
+impl From<PushError> for ResolveError {
+  fn from(e: PushError) -> ResolveError {
+    ResolveError::Other(Arc::new(e.into()))
+  }
+}
+
+/// Recovers the kind of failure from errors passed through code that uses `anyhow`, such as a
+/// `Transport`.
+impl From<anyhow::Error> for ResolveError {
+  fn from(e: anyhow::Error) -> ResolveError {
+    let e = match e.downcast::<ResolveError>() {
+      Ok(e) => return e,
+      Err(e) => e,
+    };
+    match e.downcast::<io::Error>() {
+      Ok(e) => e.into(),
+      Err(e) => ResolveError::Other(Arc::new(e)),
+    }
+  }
+}
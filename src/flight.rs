@@ -0,0 +1,144 @@
+use std::{
+  collections::{HashMap, HashSet},
+  hash::Hash,
+  sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+};
+
+/// Deduplicates concurrent work by key: while a call for a key is running, other callers with
+/// the same key wait for it and get a clone of its result instead of doing the work again.
+pub struct SingleFlight<K, V> {
+  calls: Mutex<HashMap<K, Arc<Call<V>>>>,
+}
+
+/// A call in progress. `state` becomes `Some` once the call has finished, holding its value, or
+/// `None` if the caller running it panicked.
+struct Call<V> {
+  state: Mutex<Option<Option<V>>>,
+  finished: Condvar,
+}
+
+/// Removes a call from the map and wakes its waiters when the caller running it is done, even if
+/// it unwinds.
+struct Finish<'a, K: Hash + Eq, V> {
+  flight: &'a SingleFlight<K, V>,
+  key: &'a K,
+  call: &'a Call<V>,
+  value: Option<V>,
+}
+
+impl<K: Hash + Eq, V> Drop for Finish<'_, K, V> {
+  fn drop(&mut self) {
+    lock(&self.flight.calls).remove(self.key);
+    *lock(&self.call.state) = Some(self.value.take());
+    self.call.finished.notify_all();
+  }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> SingleFlight<K, V> {
+  pub fn new() -> SingleFlight<K, V> {
+    SingleFlight {
+      calls: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Runs `f` for `key` unless a call for `key` is already running, in which case this waits for
+  /// that call and returns its value. If that call panicked, `f` is run here instead.
+  pub fn run(&self, key: K, f: impl FnOnce() -> V) -> V {
+    let (call, leader) = {
+      let mut calls = lock(&self.calls);
+      match calls.get(&key) {
+        Some(call) => (Arc::clone(call), false),
+        None => {
+          let call = Arc::new(Call {
+            state: Mutex::new(None),
+            finished: Condvar::new(),
+          });
+          calls.insert(key.clone(), Arc::clone(&call));
+          (call, true)
+        }
+      }
+    };
+
+    if !leader {
+      let mut state = lock(&call.state);
+      while state.is_none() {
+        state = call
+          .finished
+          .wait(state)
+          .unwrap_or_else(PoisonError::into_inner);
+      }
+      if let Some(Some(value)) = &*state {
+        return value.clone();
+      }
+      drop(state);
+      return f();
+    }
+
+    let mut finish = Finish {
+      flight: self,
+      key: &key,
+      call: &call,
+      value: None,
+    };
+    let value = f();
+    finish.value = Some(value.clone());
+    value
+  }
+}
+
+/// Keys of work in progress, for callers that would rather drop repeated work than wait for it.
+pub struct InProgress<K> {
+  keys: Mutex<HashSet<K>>,
+}
+
+/// A key claimed in an `InProgress`, released when dropped.
+pub struct Claim<'a, K: Hash + Eq> {
+  in_progress: &'a InProgress<K>,
+  key: K,
+}
+
+impl<K: Hash + Eq> Drop for Claim<'_, K> {
+  fn drop(&mut self) {
+    lock(&self.in_progress.keys).remove(&self.key);
+  }
+}
+
+impl<K: Hash + Eq + Clone> InProgress<K> {
+  pub fn new() -> InProgress<K> {
+    InProgress {
+      keys: Mutex::new(HashSet::new()),
+    }
+  }
+
+  /// Claims `key` until the returned claim is dropped, or returns `None` if it's already claimed.
+  pub fn claim(&self, key: K) -> Option<Claim<'_, K>> {
+    if !lock(&self.keys).insert(key.clone()) {
+      return None;
+    }
+    Some(Claim {
+      in_progress: self,
+      key,
+    })
+  }
+}
+
+/// Locks a mutex, ignoring poisoning: the maps and slots guarded here are never left half
+/// updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keys_are_claimed_once_until_released() {
+    let in_progress = InProgress::new();
+    let claim = in_progress.claim(1).unwrap();
+    assert!(in_progress.claim(1).is_none());
+    assert!(in_progress.claim(2).is_some());
+    drop(claim);
+    assert!(in_progress.claim(1).is_some());
+  }
+}
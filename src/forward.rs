@@ -0,0 +1,122 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use anyhow::{ensure, Context, Result};
+use domain::base::{Dname, ToDname};
+
+use crate::{config::ForwardZoneConfig, Octets};
+
+/// A zone whose names are resolved by asking particular upstream resolvers, such as internal ones
+/// for a corporate domain, instead of walking down from the root or asking the forwarder.
+#[derive(Debug)]
+pub struct ForwardZone {
+  /// The name at the apex of the zone.
+  pub name: Dname<Octets>,
+  /// The resolvers to ask, in order of preference.
+  pub servers: Vec<SocketAddr>,
+}
+
+impl ForwardZone {
+  /// Builds a forward zone from its configuration.
+  pub fn from_config(config: &ForwardZoneConfig) -> Result<ForwardZone> {
+    let name = Dname::from_str(&config.name)
+      .with_context(|| format!("invalid forward zone name {:?}", config.name))?;
+    ensure!(
+      !config.servers.is_empty(),
+      "forward zone {} has no servers",
+      config.name
+    );
+    Ok(ForwardZone {
+      name,
+      servers: config.servers.clone(),
+    })
+  }
+
+  /// Whether `name` is at or below the zone's apex.
+  pub fn contains<N: ToDname>(&self, name: &N) -> bool {
+    name.ends_with(&self.name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use domain::base::iana::Rtype;
+
+  use super::*;
+  use crate::{
+    tests::{answer, answer_addrs, delegate, ip, name, question, server, EXAMPLE, ROOT},
+    transport::mock::MockTransport,
+  };
+
+  fn forward_zone(name: &str, server: &str) -> ForwardZone {
+    let config = ForwardZoneConfig {
+      name: name.to_string(),
+      servers: vec![server.parse().unwrap()],
+    };
+    ForwardZone::from_config(&config).unwrap()
+  }
+
+  #[test]
+  fn zones_need_a_valid_name_and_servers() {
+    let zone = forward_zone("corp.internal", "10.0.0.53:53");
+    assert!(zone.contains(&name("corp.internal")));
+    assert!(zone.contains(&name("wiki.CORP.internal")));
+    assert!(!zone.contains(&name("notcorp.internal")));
+
+    let config = ForwardZoneConfig {
+      name: "corp.internal".to_string(),
+      servers: Vec::new(),
+    };
+    assert!(ForwardZone::from_config(&config).is_err());
+    let config = ForwardZoneConfig {
+      name: "corp..internal".to_string(),
+      servers: vec!["10.0.0.53:53".parse().unwrap()],
+    };
+    assert!(ForwardZone::from_config(&config).is_err());
+  }
+
+  #[test]
+  fn names_go_to_the_innermost_forward_zone_containing_them() {
+    let corp: SocketAddr = "10.0.0.53:53".parse().unwrap();
+    let eng: SocketAddr = "10.0.1.53:53".parse().unwrap();
+    let mock = MockTransport::default();
+    for (qname, upstream, addr) in [
+      ("wiki.corp.internal", corp, "10.1.0.1"),
+      ("corp.internal", corp, "10.1.0.2"),
+      ("build.eng.corp.internal", eng, "10.1.0.3"),
+    ] {
+      mock.insert(question(qname, Rtype::A), upstream, answer(qname, addr));
+    }
+    delegate(&mock, "www.example.com", Rtype::A);
+    mock.insert(
+      question("www.example.com", Rtype::A),
+      EXAMPLE,
+      answer("www.example.com", "192.0.2.10"),
+    );
+    let mut server = server(mock);
+    server.forward_zones = Arc::new(vec![
+      forward_zone("corp.internal", "10.0.0.53:53"),
+      forward_zone("eng.corp.internal", "10.0.1.53:53"),
+    ]);
+
+    for (qname, addr) in [
+      ("wiki.corp.internal", "10.1.0.1"),
+      ("corp.internal", "10.1.0.2"),
+      ("build.eng.corp.internal", "10.1.0.3"),
+    ] {
+      let response = server.resolve(qname, Rtype::A).unwrap();
+      assert_eq!(answer_addrs(&response), [ip(addr)], "{qname}");
+    }
+    assert_eq!(server.transport.sent_to(corp), 2);
+    assert_eq!(server.transport.sent_to(eng), 1);
+    assert_eq!(server.transport.sent_to(ROOT), 0);
+
+    let response = server.resolve("www.example.com", Rtype::A).unwrap();
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    assert_eq!(server.transport.sent_to(ROOT), 1);
+    // forwarded answers are cached like any other
+    server.resolve("wiki.corp.internal", Rtype::A).unwrap();
+    assert_eq!(server.transport.sent_to(corp), 2);
+  }
+}
@@ -0,0 +1,126 @@
+use std::{
+  fs,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Addresses of the root name servers, used when no hints file is given.
+pub const BUILTIN: [IpAddr; 26] = [
+  // a.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+  // b.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+  IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+  // c.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+  // d.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(199, 7, 91, 13)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd)),
+  // e.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe)),
+  // f.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(192, 5, 5, 241)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf)),
+  // g.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(192, 112, 36, 4)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d)),
+  // h.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(198, 97, 190, 53)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53)),
+  // i.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+  // j.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(192, 58, 128, 30)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30)),
+  // k.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(193, 0, 14, 129)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1)),
+  // l.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(199, 7, 83, 42)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42)),
+  // m.root-servers.net
+  IpAddr::V4(Ipv4Addr::new(202, 12, 27, 33)),
+  IpAddr::V6(Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35)),
+];
+
+/// Reads the root server addresses from a hints file such as named.root.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<IpAddr>> {
+  let path = path.as_ref();
+  let contents = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+  parse(&contents).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Extracts the A and AAAA addresses from the contents of a hints file. Each record is on a line
+/// of its own, as in `A.ROOT-SERVERS.NET. 3600000 A 198.41.0.4`, and comments start with `;`.
+pub fn parse(contents: &str) -> Result<Vec<IpAddr>> {
+  let mut addrs = Vec::new();
+  for (number, line) in contents.lines().enumerate() {
+    let line = line.split(';').next().unwrap_or_default();
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let rtype = fields
+      .iter()
+      .position(|field| field.eq_ignore_ascii_case("A") || field.eq_ignore_ascii_case("AAAA"));
+
+    if let Some(i) = rtype {
+      let addr = match fields.get(i + 1) {
+        Some(addr) => addr,
+        None => bail!("line {}: missing address", number + 1),
+      };
+      let addr: IpAddr = addr
+        .parse()
+        .with_context(|| format!("line {}: invalid address {addr}", number + 1))?;
+      addrs.push(addr);
+    }
+  }
+
+  if addrs.is_empty() {
+    bail!("no root server addresses found");
+  }
+  Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn loads_every_address_in_a_hints_file() {
+    let contents = "\
+; root hints
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000      A     170.247.170.2 ; renumbered in 2023
+B.ROOT-SERVERS.NET.      3600000      AAAA  2801:1b8:10::b
+";
+    let expected: Vec<IpAddr> = [
+      "198.41.0.4",
+      "2001:503:ba3e::2:30",
+      "170.247.170.2",
+      "2801:1b8:10::b",
+    ]
+    .iter()
+    .map(|addr| addr.parse().unwrap())
+    .collect();
+    assert_eq!(parse(contents).unwrap(), expected);
+
+    let path = std::env::temp_dir().join(format!("dns-hints-{}", std::process::id()));
+    fs::write(&path, contents).unwrap();
+    let loaded = load(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap(), expected);
+  }
+
+  #[test]
+  fn files_without_addresses_are_rejected() {
+    assert!(parse("; nothing here\n. 3600000 NS A.ROOT-SERVERS.NET.\n").is_err());
+    assert!(parse("A.ROOT-SERVERS.NET. 3600000 A 198.41.0\n").is_err());
+  }
+}
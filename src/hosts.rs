@@ -0,0 +1,217 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::Path,
+};
+
+use anyhow::{Context, Result};
+
+/// Static name to address mappings, answered without recursing. A name starting with a `*`
+/// label, such as `*.local.example`, is a wildcard for the names below the rest of it.
+#[derive(Debug, Default)]
+pub struct Hosts {
+  addrs: HashMap<String, Vec<IpAddr>>,
+  /// The first name listed for each address, which reverse lookups of the address return.
+  /// Wildcards are never returned.
+  names: HashMap<IpAddr, String>,
+  /// Every name listed along with every name above one, which are the names that exist as far
+  /// as wildcards are concerned.
+  existing: HashSet<String>,
+}
+
+impl Hosts {
+  /// Reads a hosts file such as /etc/hosts.
+  pub fn load(path: impl AsRef<Path>) -> Result<Hosts> {
+    let path = path.as_ref();
+    let contents =
+      fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    Hosts::parse(&contents).with_context(|| format!("parsing {}", path.display()))
+  }
+
+  /// Reads mappings from the contents of a hosts file. Each line holds an address followed by
+  /// the names it belongs to, as in `192.168.1.10 nas nas.home`, and comments start with `#`.
+  pub fn parse(contents: &str) -> Result<Hosts> {
+    let mut hosts = Hosts::default();
+    for (number, line) in contents.lines().enumerate() {
+      let line = line.split('#').next().unwrap_or_default();
+      let mut fields = line.split_whitespace();
+      let addr = match fields.next() {
+        Some(addr) => addr,
+        None => continue,
+      };
+      let addr: IpAddr = addr
+        .parse()
+        .with_context(|| format!("line {}: invalid address {addr}", number + 1))?;
+
+      for name in fields {
+        let normalized = normalize(name);
+        if !is_wildcard(&normalized) {
+          hosts
+            .names
+            .entry(addr)
+            .or_insert_with(|| name.trim_end_matches('.').to_string());
+        }
+        let mut above = normalized.as_str();
+        while hosts.existing.insert(above.to_string()) && !above.is_empty() {
+          above = parent(above);
+        }
+        let addrs = hosts.addrs.entry(normalized).or_default();
+        if !addrs.contains(&addr) {
+          addrs.push(addr);
+        }
+      }
+    }
+    Ok(hosts)
+  }
+
+  /// Returns the addresses mapped to `name`, ignoring case and any trailing dot. A name that
+  /// doesn't exist gets the addresses of the wildcard at its closest encloser, the closest name
+  /// above it that does, if there is one (RFC 4592 section 3.3.1). So `*.local.example` covers
+  /// `a.local.example` and `a.b.local.example`, but not `a.b.local.example` if `b.local.example`
+  /// is listed too.
+  pub fn get(&self, name: &str) -> Option<&[IpAddr]> {
+    let name = normalize(name);
+    if self.existing.contains(&name) {
+      return self.addrs.get(&name).map(Vec::as_slice);
+    }
+    let mut encloser = parent(&name);
+    while !encloser.is_empty() && !self.existing.contains(encloser) {
+      encloser = parent(encloser);
+    }
+    let wildcard = match encloser {
+      "" => String::from("*"),
+      _ => format!("*.{encloser}"),
+    };
+    self.addrs.get(&wildcard).map(Vec::as_slice)
+  }
+
+  /// Returns the name `addr` is mapped to, for answering reverse lookups.
+  pub fn name_of(&self, addr: IpAddr) -> Option<&str> {
+    self.names.get(&addr).map(String::as_str)
+  }
+}
+
+/// Parses a reverse lookup name, such as `4.3.2.1.in-addr.arpa` or the nibble form under
+/// `ip6.arpa` (RFC 3596 section 2.5), back into the address it stands for.
+pub fn reverse_addr(name: &str) -> Option<IpAddr> {
+  let name = normalize(name);
+  if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+    let labels: Vec<_> = labels.split('.').collect();
+    if labels.len() != 4 {
+      return None;
+    }
+    let mut octets = [0u8; 4];
+    for (octet, label) in octets.iter_mut().zip(labels.iter().rev()) {
+      if !label.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+      }
+      *octet = label.parse().ok()?;
+    }
+    return Some(Ipv4Addr::from(octets).into());
+  }
+
+  if let Some(labels) = name.strip_suffix(".ip6.arpa") {
+    let labels: Vec<_> = labels.split('.').collect();
+    if labels.len() != 32 {
+      return None;
+    }
+    let mut addr = 0u128;
+    for label in labels.iter().rev() {
+      let mut chars = label.chars();
+      let nibble = match (chars.next().and_then(|c| c.to_digit(16)), chars.next()) {
+        (Some(nibble), None) => nibble,
+        _ => return None,
+      };
+      addr = addr << 4 | u128::from(nibble);
+    }
+    return Some(Ipv6Addr::from(addr).into());
+  }
+  None
+}
+
+fn normalize(name: &str) -> String {
+  name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The name one label above `name`, where the root is the empty string.
+fn parent(name: &str) -> &str {
+  name.split_once('.').map_or("", |(_, parent)| parent)
+}
+
+fn is_wildcard(name: &str) -> bool {
+  name == "*" || name.starts_with("*.")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use domain::base::iana::Rtype;
+
+  use super::*;
+  use crate::{
+    tests::{answer_addrs, answer_ttls, ask, ip, query, resolving_www_mock, server, EXAMPLE},
+    transport::mock::MockTransport,
+    HOSTS_TTL,
+  };
+
+  #[test]
+  fn names_are_matched_ignoring_case() {
+    let hosts = Hosts::parse("192.168.1.10 nas NAS.home. # the file server\n").unwrap();
+    assert_eq!(hosts.get("NaS.Home"), Some(&[ip("192.168.1.10")][..]));
+    assert_eq!(hosts.get("nas.home."), Some(&[ip("192.168.1.10")][..]));
+    assert_eq!(hosts.get("printer.home"), None);
+  }
+
+  #[test]
+  fn listed_names_are_answered_without_resolving() {
+    let mut server = server(MockTransport::default());
+    server.hosts = Arc::new(Hosts::parse("192.168.1.10 nas.home\nfe80::10 nas.home\n").unwrap());
+
+    let response = ask(&mut server, &query("NAS.home", Rtype::A));
+    assert_eq!(answer_addrs(&response), [ip("192.168.1.10")]);
+    assert_eq!(answer_ttls(&response), [HOSTS_TTL]);
+    let response = ask(&mut server, &query("nas.home", Rtype::Aaaa));
+    assert_eq!(answer_addrs(&response), [ip("fe80::10")]);
+    assert!(server.transport.sent().is_empty());
+  }
+
+  #[test]
+  fn wildcards_answer_names_that_are_not_listed() {
+    let hosts = Hosts::parse(
+      "192.168.1.1 *.local.example\n192.168.1.2 nas.local.example\n192.168.1.3 b.local.example\n",
+    )
+    .unwrap();
+    assert_eq!(
+      hosts.get("nas.local.example"),
+      Some(&[ip("192.168.1.2")][..])
+    );
+    assert_eq!(
+      hosts.get("printer.local.example"),
+      Some(&[ip("192.168.1.1")][..])
+    );
+    assert_eq!(
+      hosts.get("a.printer.local.example"),
+      Some(&[ip("192.168.1.1")][..])
+    );
+    // b.local.example is the closest encloser, and has no wildcard of its own
+    assert_eq!(hosts.get("a.b.local.example"), None);
+    assert_eq!(hosts.get("local.example"), None);
+    assert_eq!(hosts.name_of(ip("192.168.1.1")), None);
+  }
+
+  #[test]
+  fn names_outside_every_wildcard_are_resolved() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.hosts = Arc::new(Hosts::parse("192.168.1.1 *.local.example\n").unwrap());
+
+    let response = ask(&mut server, &query("printer.local.example", Rtype::A));
+    assert_eq!(answer_addrs(&response), [ip("192.168.1.1")]);
+    assert!(server.transport.sent().is_empty());
+    let response = ask(&mut server, &query("www.example.com", Rtype::A));
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  }
+}
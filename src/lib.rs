@@ -0,0 +1,2617 @@
+//! A recursive DNS resolver. [`DnsServer`] answers queries received over UDP and TCP by
+//! walking down from the root servers, or by forwarding to an upstream resolver, and caches what
+//! it learns. It can also be used directly to resolve names:
+//!
+//! ```no_run
+//! use dns::DnsServer;
+//! use domain::base::iana::Rtype;
+//!
+//! let response = DnsServer::new()?.resolve("example.com", Rtype::A)?;
+//! for record in response.answer()?.limit_to::<domain::rdata::A>() {
+//!   println!("{}", record?.data());
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod acl;
+mod budget;
+mod cache;
+pub mod config;
+pub mod control;
+pub mod dnssec;
+mod error;
+mod flight;
+pub mod forward;
+pub mod hints;
+pub mod hosts;
+pub mod metrics;
+pub mod outstanding;
+mod persist;
+pub mod querylog;
+pub mod ratelimit;
+mod records;
+pub mod selection;
+pub mod serve;
+mod tcp;
+#[cfg(test)]
+mod tests;
+pub mod transport;
+pub mod views;
+pub mod zone;
+
+use std::{
+  collections::HashSet,
+  fmt::Display,
+  mem,
+  net::{IpAddr, SocketAddr},
+  ops::Deref,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex, MutexGuard, PoisonError,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use domain::{
+  base::{
+    self,
+    iana::Class,
+    iana::ExtendedErrorCode,
+    iana::Opcode,
+    iana::Rcode,
+    iana::Rtype,
+    message_builder::{AdditionalBuilder, AnswerBuilder, TreeCompressor},
+    name::UncertainDname,
+    octets::OctetsRef,
+    opt::ClientSubnet,
+    opt::ExtendedError,
+    record::AsRecord,
+    CharStr, Dname, ParsedDname, Question, Record, RecordSection, RecordSectionBuilder, ToDname,
+  },
+  rdata::{Aaaa, AllRecordData, Cname, Hinfo, Ns, Ptr, Soa, Txt, A},
+};
+use rand::{prelude::*, rngs::StdRng};
+use tracing::{debug, debug_span, error, field, info, info_span, trace, warn, Span};
+
+use acl::Cidr;
+use budget::Budget;
+use cache::LruCache;
+pub use cache::{Cache, MemoryCache, NoopCache};
+use dnssec::{TrustAnchor, ZoneKeys};
+pub use error::ResolveError;
+use flight::{InProgress, SingleFlight};
+use forward::ForwardZone;
+use hosts::Hosts;
+use metrics::Metrics;
+use outstanding::QueryLimiter;
+use querylog::{QueryLog, QueryRecord};
+use ratelimit::RateLimiter;
+use records::{RecordData, RecordStore, Trust};
+use selection::{Outcome, Selection, ServerStats};
+use transport::{NetworkTransport, Transport};
+use views::View;
+use zone::Zone;
+
+pub type Octets = Vec<u8>;
+pub type Message = base::Message<Octets>;
+/// A client's query, parsed in place from the buffer it was received into.
+type Request<'a> = base::Message<&'a [u8]>;
+type MessageBuilder = base::MessageBuilder<Octets>;
+// Responses to clients compress the names they repeat (RFC 1035 section 4.1.4), so more records
+// fit within UDP's size limits before they're truncated.
+type ResponseTarget = TreeCompressor<Octets>;
+type CnameRecord = Record<Dname<Octets>, Cname<Dname<Octets>>>;
+// Cached responses and in-flight resolutions are keyed by the question they answer. Questions
+// compare and hash by name (ignoring case), type and class, so e.g. A and AAAA answers for a
+// name, or IN and CH answers, never share an entry.
+type QuestionKey = Question<Dname<Octets>>;
+
+const DNS_PORT: u16 = 53;
+/// Most responses the cache holds at once, unless configured otherwise.
+pub const DEFAULT_MAX_CACHE_ENTRIES: usize = 10000;
+// UDP payload size advertised to upstream servers through EDNS0
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 4096;
+// Longest CNAME chain we follow before giving up on a query
+const MAX_CNAME_CHAIN: usize = 16;
+// Wait before the first retry of a query a name server didn't respond to, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Default number of times a query a name server doesn't respond to is sent again.
+pub const DEFAULT_UDP_RETRIES: usize = 2;
+// Name servers for a zone asked at the same time
+const LOOKUP_FAN_OUT: usize = 3;
+// Most referrals followed for a single question before giving up on ever reaching an answer
+const MAX_REFERRALS: usize = 30;
+// Most queries for minimized names sent while resolving a single question, after which the whole
+// name is sent, so a name with many labels doesn't take a query for each (RFC 9156 section 2.3)
+const MAX_MINIMIZED_QUERIES: usize = 10;
+// Largest UDP response a client can receive if it doesn't advertise a size through EDNS0
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+// Size of the OPT record added to responses to EDNS0 queries, which has no options
+const OPT_RECORD_LEN: usize = 11;
+// Longest text explaining an Extended DNS Error, so that with the longest question the error
+// response still fits in 512 bytes
+const MAX_EXTRA_TEXT_LEN: usize = 200;
+/// Default limit on nested lookups done to find the address of a name server.
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+/// Default time to wait for a name server to respond before trying the next one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Default time a TCP connection to a name server is kept open between queries, for reuse.
+pub const DEFAULT_UPSTREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time a client query may take to resolve, including every lookup it leads to.
+pub const DEFAULT_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default limit on the queries to other servers a single client query may lead to.
+pub const DEFAULT_MAX_UPSTREAM_QUERIES: usize = 50;
+/// Default limit on the queries to other servers outstanding at once, across every client query.
+pub const DEFAULT_MAX_OUTSTANDING_QUERIES: usize = 100;
+// Longest a lookup waits for another to finish once the outstanding queries are at their limit
+const OUTSTANDING_QUERY_WAIT: Duration = Duration::from_millis(500);
+/// Default longest TTL that answers are cached for and served with, a week as RFC 8767 section 4
+/// suggests.
+pub const DEFAULT_MAX_TTL: u32 = 7 * 24 * 60 * 60;
+// Names of CHAOS class TXT records that identify the server (RFC 4892)
+const CHAOS_NAMES: [&str; 2] = ["version.bind", "id.server"];
+/// Dots a name given to `resolve` needs to be tried as it is before the search domains, unless
+/// configured otherwise, the same as in resolv.conf.
+pub const DEFAULT_NDOTS: usize = 1;
+/// What the server says it is when asked through CHAOS class queries, unless configured otherwise.
+pub const DEFAULT_IDENTITY: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+// TTL of answers synthesized from the hosts file
+const HOSTS_TTL: u32 = 300;
+// TTL of the HINFO record answering ANY queries (RFC 8482 section 4.2)
+const MINIMAL_ANY_TTL: u32 = 3600;
+// TTL of stale answers served after resolution fails, so clients retry soon
+const STALE_TTL: u32 = 30;
+/// How much of a client's address is passed on when sending client subnets, unless configured
+/// otherwise: the prefix lengths RFC 7871 section 11.1 recommends.
+pub const DEFAULT_CLIENT_SUBNET: SubnetPrefixes = SubnetPrefixes { ipv4: 24, ipv6: 56 };
+// Most answers to a single question cached for different client subnets at once
+const MAX_SCOPED_ANSWERS: usize = 32;
+
+/// The kind of answer held by a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AnswerKind {
+  /// The answer section holds records for the question.
+  Positive,
+  /// The name does not exist.
+  NxDomain,
+  /// The name exists but has no records of the requested type.
+  NoData,
+}
+
+impl AnswerKind {
+  /// Classifies a final response. Returns `None` for responses that are neither an answer nor a
+  /// negative answer (e.g. a referral we couldn't follow), which shouldn't be cached.
+  fn of(message: &Message) -> Result<Option<AnswerKind>> {
+    let (_, mut answers, authorities, _) = message.sections()?;
+    let has_soa = authorities
+      .limit_to::<Soa<ParsedDname<&Octets>>>()
+      .next()
+      .is_some();
+
+    Ok(match message.header().rcode() {
+      Rcode::NXDomain => Some(AnswerKind::NxDomain),
+      Rcode::NoError if answers.next().is_some() => Some(AnswerKind::Positive),
+      Rcode::NoError if has_soa => Some(AnswerKind::NoData),
+      _ => None,
+    })
+  }
+}
+
+/// A cached response along with the time it was stored and the time it stops being valid.
+pub struct CacheEntry {
+  message: Message,
+  kind: AnswerKind,
+  /// Whether the response was asked for with DO set, so it holds any DNSSEC records there are.
+  dnssec: bool,
+  /// Whether the response had AA set, coming from a server authoritative for the answer rather
+  /// than one answering from its own cache. Non-authoritative responses never replace an
+  /// authoritative one before it expires.
+  authoritative: bool,
+  inserted: Instant,
+  expires: Instant,
+  /// Set once a refresh of the entry has been started, so only one is.
+  prefetching: AtomicBool,
+}
+
+impl CacheEntry {
+  /// Creates an entry for a final response. Positive answers live for the smallest TTL found in
+  /// the answer and authority sections; negative answers live for the SOA's TTL or MINIMUM field,
+  /// whichever is smaller (RFC 2308 section 5). Either way the lifetime is passed through
+  /// `clamp`. Returns `None` if the response shouldn't be cached, which includes one that would
+  /// live for no time at all.
+  fn new(message: Message, dnssec: bool, clamp: impl Fn(u32) -> u32) -> Result<Option<CacheEntry>> {
+    let kind = match AnswerKind::of(&message)? {
+      Some(kind) => kind,
+      None => return Ok(None),
+    };
+
+    let (_, answers, authorities, _) = message.sections()?;
+    let ttl = match kind {
+      AnswerKind::Positive => answers
+        .chain(authorities)
+        .filter_map(|record| record.ok())
+        .map(|record| record.ttl())
+        .min(),
+      AnswerKind::NxDomain | AnswerKind::NoData => authorities
+        .limit_to::<Soa<ParsedDname<&Octets>>>()
+        .filter_map(|record| record.ok())
+        .map(|record| record.ttl().min(record.data().minimum()))
+        .min(),
+    };
+
+    let lifetime = ttl
+      .map(clamp)
+      .filter(|&ttl| ttl > 0)
+      .map(|ttl| Duration::from_secs(ttl.into()));
+    Ok(lifetime.map(|lifetime| CacheEntry::with_lifetime(message, kind, dnssec, lifetime)))
+  }
+
+  /// Creates an entry that expires `lifetime` from now.
+  fn with_lifetime(
+    message: Message,
+    kind: AnswerKind,
+    dnssec: bool,
+    lifetime: Duration,
+  ) -> CacheEntry {
+    let inserted = Instant::now();
+    CacheEntry {
+      authoritative: message.header().aa(),
+      message,
+      kind,
+      dnssec,
+      inserted,
+      expires: inserted + lifetime,
+      prefetching: AtomicBool::new(false),
+    }
+  }
+
+  pub fn is_expired(&self) -> bool {
+    Instant::now() >= self.expires
+  }
+
+  /// Whether the entry should be refreshed before it expires, because less than `threshold` of
+  /// its lifetime remains. Only returns true once per entry.
+  fn claim_prefetch(&self, threshold: f64) -> bool {
+    let lifetime = self.expires - self.inserted;
+    let remaining = self.expires.saturating_duration_since(Instant::now());
+    remaining.as_secs_f64() < lifetime.as_secs_f64() * threshold
+      && !self.prefetching.swap(true, Ordering::Relaxed)
+  }
+
+  /// Whether the entry expired less than `window` ago, or hasn't expired at all.
+  fn is_fresher_than(&self, window: Duration) -> bool {
+    Instant::now() < self.expires + window
+  }
+
+  /// Returns the cached message with every TTL reduced by the time spent in the cache.
+  fn message(&self) -> Result<Message> {
+    let elapsed = self
+      .inserted
+      .elapsed()
+      .as_secs()
+      .try_into()
+      .unwrap_or(u32::MAX);
+    map_ttls(&self.message, |ttl| ttl.saturating_sub(elapsed))
+  }
+
+  /// Returns the cached message with every TTL set to `STALE_TTL`, for serving after it expired
+  /// (RFC 8767 section 4).
+  fn stale_message(&self) -> Result<Message> {
+    map_ttls(&self.message, |_| STALE_TTL)
+  }
+}
+
+/// Rebuilds `message` with the TTL of every record passed through `f`. OPT records are copied
+/// as-is, since their TTL field holds EDNS flags rather than a lifetime.
+fn map_ttls(message: &Message, f: impl Fn(u32) -> u32) -> Result<Message> {
+  let (questions, answers, authorities, additionals) = message.sections()?;
+  let adjusted = |it: RecordSection<_>| {
+    it.limit_to::<AllRecordData<<&Octets as OctetsRef>::Range, ParsedDname<&Octets>>>()
+      .filter_map(|r| r.ok())
+      .map(|mut r| {
+        if r.rtype() != Rtype::Opt {
+          r.set_ttl(f(r.ttl()));
+        }
+        r
+      })
+  };
+
+  let mut builder = MessageBuilder::new_vec();
+  *builder.header_mut() = message.header();
+
+  let mut builder = builder.question();
+  for question in questions {
+    builder.push(question?)?;
+  }
+
+  let mut builder = builder.answer();
+  for record in adjusted(answers) {
+    builder.push(record)?;
+  }
+
+  let mut builder = builder.authority();
+  for record in adjusted(authorities) {
+    builder.push(record)?;
+  }
+
+  let mut builder = builder.additional();
+  for record in adjusted(additionals) {
+    builder.push(record)?;
+  }
+
+  Ok(builder.into_message())
+}
+
+/// What to do after receiving a response from a name server.
+enum NextStep {
+  /// The response is final, either because it answers the question or because there is nowhere
+  /// else to go.
+  Done,
+  /// The response says the name asked about is an alias, with a CNAME record for it. The response
+  /// is final, but the target of the CNAME still has to be resolved to answer the question.
+  Alias,
+  /// The response delegated to `zone`, which each of `servers` is authoritative for.
+  Referral {
+    servers: Vec<SocketAddr>,
+    zone: Dname<Octets>,
+  },
+  /// The server doesn't serve the zone it was delegated: it neither answered with authority nor
+  /// referred onwards to a zone below. Another of the zone's servers has to be asked instead.
+  Lame,
+}
+
+/// Follows the CNAME chain starting at `qname` through the answer section of `message`. Returns
+/// the links of the chain along with whether `message` also answers `qtype` for its last name.
+fn cname_chain(
+  message: &Message,
+  qname: &Dname<Octets>,
+  qtype: Rtype,
+) -> Result<(Vec<CnameRecord>, bool)> {
+  let mut chain = Vec::new();
+  let mut name = qname.clone();
+
+  // The chain inside a single message can loop too, so never walk more links than we'd accept.
+  while chain.len() <= MAX_CNAME_CHAIN {
+    let answers = message.answer()?;
+    let answered = answers
+      .filter_map(|record| record.ok())
+      .any(|record| record.rtype() == qtype && *record.owner() == name);
+    if answered {
+      return Ok((chain, true));
+    }
+
+    let link = answers
+      .limit_to::<Cname<ParsedDname<&Octets>>>()
+      .filter_map(|record| record.ok())
+      .find(|record| *record.owner() == name);
+    match link {
+      Some(link) => {
+        let target: Dname<Octets> = link.data().cname().to_dname()?;
+        chain.push(Record::new(
+          name,
+          link.class(),
+          link.ttl(),
+          Cname::new(target.clone()),
+        ));
+        name = target;
+      }
+      None => break,
+    }
+  }
+  Ok((chain, false))
+}
+
+/// Builds the response to `question` that was reached through `chain`, placing the CNAME records
+/// ahead of the answer for the end of the chain found in `response`.
+fn prepend_cname_chain<N: ToDname>(
+  question: &Question<N>,
+  chain: &[CnameRecord],
+  response: &Message,
+) -> Result<Message> {
+  let (_, answers, authorities, additionals) = response.sections()?;
+  let records = |it: RecordSection<_>| {
+    it.limit_to::<AllRecordData<<&Octets as OctetsRef>::Range, ParsedDname<&Octets>>>()
+      .filter_map(|r| r.ok())
+  };
+
+  let mut builder = MessageBuilder::new_vec();
+  *builder.header_mut() = response.header();
+
+  let mut builder = builder.question();
+  builder.push(question)?;
+
+  let mut builder = builder.answer();
+  for record in chain {
+    builder.push(record)?;
+  }
+  // the final response may repeat the last links of the chain
+  for record in records(answers).filter(|r| r.rtype() != Rtype::Cname) {
+    builder.push(record)?;
+  }
+
+  let mut builder = builder.authority();
+  for record in records(authorities) {
+    builder.push(record)?;
+  }
+
+  let mut builder = builder.additional();
+  for record in records(additionals) {
+    builder.push(record)?;
+  }
+
+  Ok(builder.into_message())
+}
+
+/// The names `name`, as given by a user, may stand for, in the order to try them, the way a stub
+/// resolver reads resolv.conf. A name ending with a dot stands only for itself, and `.` for the
+/// root. Any other name stands for itself and for itself followed by each of `search`, with
+/// itself tried first if it has at least `ndots` dots and last otherwise.
+fn complete_names(
+  name: &str,
+  search: &[Dname<Octets>],
+  ndots: usize,
+) -> Result<Vec<Dname<Octets>>, ResolveError> {
+  if name == "." {
+    return Ok(vec![Dname::root_vec()]);
+  }
+  let relative = match UncertainDname::<Octets>::from_str(name) {
+    Ok(UncertainDname::Absolute(name)) => return Ok(vec![name]),
+    Ok(UncertainDname::Relative(relative)) => relative,
+    Err(e) => return Err(ResolveError::InvalidName(name.into(), e)),
+  };
+  if relative.label_count() == 1 && search.is_empty() {
+    return Err(ResolveError::SingleLabel(name.into()));
+  }
+  let mut names = search
+    .iter()
+    .map(|domain| {
+      let completed = relative
+        .clone()
+        .chain(domain)
+        .map_err(anyhow::Error::from)?;
+      Ok(completed.to_dname()?)
+    })
+    .collect::<Result<Vec<_>, ResolveError>>()?;
+  let dots = relative.label_count() - 1;
+  let absolute = relative.into_absolute()?;
+  if dots >= ndots {
+    names.insert(0, absolute);
+  } else {
+    names.push(absolute);
+  }
+  Ok(names)
+}
+
+/// Returns what `hosts` says about `name`: its addresses, or for the reverse name of an address,
+/// the host name it belongs to.
+fn hosts_records(hosts: &Hosts, name: &Dname<Octets>) -> Result<Option<Vec<RecordData>>> {
+  let name = name.to_string();
+  if let Some(addrs) = hosts.get(&name) {
+    return Ok(Some(addrs.iter().map(|&addr| addr.into()).collect()));
+  }
+  match hosts::reverse_addr(&name).and_then(|addr| hosts.name_of(addr)) {
+    Some(host) => Ok(Some(vec![RecordData::Ptr(Dname::from_str(host)?)])),
+    None => Ok(None),
+  }
+}
+
+/// Collects the addresses in the A and AAAA records of `section` whose owner passes `filter`,
+/// IPv4 ones first, in a single pass over the section.
+fn addresses<'a>(
+  section: RecordSection<&'a Octets>,
+  filter: impl Fn(&ParsedDname<&'a Octets>) -> bool,
+) -> Vec<IpAddr> {
+  let mut v4 = Vec::new();
+  let mut v6 = Vec::new();
+  for record in section.filter_map(|record| record.ok()) {
+    if !matches!(record.rtype(), Rtype::A | Rtype::Aaaa) || !filter(record.owner()) {
+      continue;
+    }
+    if let Ok(Some(record)) = record.to_record::<A>() {
+      v4.push(IpAddr::V4(record.data().addr()));
+    } else if let Ok(Some(record)) = record.to_record::<Aaaa>() {
+      v6.push(IpAddr::V6(record.data().addr()));
+    }
+  }
+  v4.append(&mut v6);
+  v4
+}
+
+/// Synthesizes an answer to `question` from `records`, all given the same TTL. Records that
+/// aren't of the type asked for are left out, so the answer may be empty.
+fn synthesized_answer<N: ToDname>(
+  question: &Question<N>,
+  records: &[RecordData],
+  ttl: u32,
+  authoritative: bool,
+) -> Result<Message> {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_qr(true);
+  builder.header_mut().set_aa(authoritative);
+
+  let mut builder = builder.question();
+  builder.push(question)?;
+
+  let mut builder = builder.answer();
+  let qname = question.qname();
+  let qclass = question.qclass();
+  for record in records {
+    if question.qtype() != record.rtype() && question.qtype() != Rtype::Any {
+      continue;
+    }
+    match record {
+      RecordData::A(addr) => builder.push((qname, qclass, ttl, A::new(*addr)))?,
+      RecordData::Aaaa(addr) => builder.push((qname, qclass, ttl, Aaaa::new(*addr)))?,
+      RecordData::Ns(host) => builder.push((qname, qclass, ttl, Ns::new(host)))?,
+      RecordData::Ptr(host) => builder.push((qname, qclass, ttl, Ptr::new(host)))?,
+    }
+  }
+  Ok(builder.into_message())
+}
+
+/// Pushes `records` onto `section` in order until one grows the message past `limit` bytes.
+/// Returns how many fit. The record that didn't is left in the message, since a builder can't
+/// take a record back out.
+fn push_until<S, R>(section: &mut S, records: &[R], limit: usize) -> Result<usize>
+where
+  S: RecordSectionBuilder + Deref<Target = base::MessageBuilder<ResponseTarget>>,
+  for<'a> &'a R: AsRecord,
+{
+  for (i, record) in records.iter().enumerate() {
+    section.push(record)?;
+    if section.as_slice().len() > limit {
+      return Ok(i);
+    }
+  }
+  Ok(records.len())
+}
+
+/// Fills the answer, authority and additional sections of `response` with as many of `sections`
+/// as fit in `limit` bytes, stopping at the first record that doesn't. Returns the builder along
+/// with whether answer or authority records were left out, which the client should be told of
+/// with TC. Additional records that don't fit are just dropped (RFC 2181 section 9).
+fn fill_sections<R>(
+  response: AnswerBuilder<ResponseTarget>,
+  sections: [&[R]; 3],
+  limit: usize,
+) -> Result<(AdditionalBuilder<ResponseTarget>, bool)>
+where
+  for<'a> &'a R: AsRecord,
+{
+  let [answers, authorities, additionals] = sections;
+  let empty = response.clone();
+  let mut counts = [0; 3];
+
+  let mut response = response;
+  counts[0] = push_until(&mut response, answers, limit)?;
+  let mut response = response.authority();
+  if counts[0] == answers.len() {
+    counts[1] = push_until(&mut response, authorities, limit)?;
+  }
+  let mut response = response.additional();
+  if counts[1] == authorities.len() {
+    counts[2] = push_until(&mut response, additionals, limit)?;
+  }
+  if counts == [answers.len(), authorities.len(), additionals.len()] {
+    return Ok((response, false));
+  }
+
+  // the record that didn't fit is still at the end of the message, so it's built again from the
+  // question, once, with only the records that did
+  let truncated = counts[0] < answers.len() || counts[1] < authorities.len();
+  let mut response = empty;
+  push_until(&mut response, &answers[..counts[0]], usize::MAX)?;
+  let mut response = response.authority();
+  push_until(&mut response, &authorities[..counts[1]], usize::MAX)?;
+  let mut response = response.additional();
+  push_until(&mut response, &additionals[..counts[2]], usize::MAX)?;
+  Ok((response, truncated))
+}
+
+/// Drops the records of `records` that repeat an earlier one, comparing owner, class, type and
+/// data but keeping the lower TTL, since some servers send records twice. The records of each
+/// RRset are moved next to each other too, in the order the sets first appear, which keeps CNAME
+/// chains in order.
+fn deduplicated<N, D>(records: impl Iterator<Item = Record<N, D>>) -> Vec<Record<N, D>>
+where
+  N: PartialEq,
+  D: base::rdata::RecordData + PartialEq,
+{
+  let mut sets: Vec<Vec<Record<N, D>>> = Vec::new();
+  for record in records {
+    let set = sets.iter_mut().find(|set| {
+      set[0].owner() == record.owner()
+        && set[0].rtype() == record.rtype()
+        && set[0].class() == record.class()
+    });
+    match set {
+      Some(set) => match set.iter_mut().find(|seen| **seen == record) {
+        Some(seen) => seen.set_ttl(seen.ttl().min(record.ttl())),
+        None => set.push(record),
+      },
+      None => sets.push(vec![record]),
+    }
+  }
+  sets.into_iter().flatten().collect()
+}
+
+/// Whether records of `rtype` only exist to support DNSSEC validation, and shouldn't be given to
+/// clients that don't set DO unless they ask for them.
+fn is_dnssec_rtype(rtype: Rtype) -> bool {
+  matches!(rtype, Rtype::Rrsig | Rtype::Nsec | Rtype::Nsec3)
+}
+
+/// Works out which clients `response` applies to, given the `subnet` its query was sent with.
+/// Returns `None` if it applies to all of them, because no subnet was sent or the server answered
+/// with a scope of 0. A scope longer than the subnet is cut down to it (RFC 7871 section 7.3.1),
+/// and a response whose option doesn't repeat the subnet sent is dropped altogether.
+fn answer_scope(response: &Message, subnet: Option<Cidr>) -> Result<Option<Cidr>> {
+  let (subnet, opt) = match (subnet, response.opt()) {
+    (Some(subnet), Some(opt)) => (subnet, opt),
+    _ => return Ok(None),
+  };
+  let option = match opt.as_opt().iter::<ClientSubnet>().next() {
+    Some(option) => option?,
+    None => return Ok(None),
+  };
+  let echoed = Cidr::truncated(option.addr(), option.source_prefix_len());
+  if echoed != subnet {
+    return Err(anyhow!(
+      "response is for client subnet {echoed} rather than {subnet}"
+    ));
+  }
+  Ok(match option.scope_prefix_len() {
+    0 => None,
+    scope => Some(Cidr::truncated(subnet.addr(), scope.min(subnet.prefix()))),
+  })
+}
+
+/// One try at sending a question to a name server: a query built by `DnsServer::build_query`,
+/// along with how long to wait before sending it.
+struct Attempt {
+  id: u16,
+  request: Octets,
+  backoff: Duration,
+}
+
+/// Sends `attempts` to `name_server` over UDP one after another until one gets a response, since
+/// datagrams get lost. Only a timeout is worth another try, and none is made whose backoff would
+/// end past `deadline`. Returns the response along with the attempt that got it.
+fn send_udp<'a>(
+  transport: &impl Transport,
+  attempts: &'a [Attempt],
+  name_server: SocketAddr,
+  deadline: Instant,
+) -> Result<(&'a Attempt, Octets), ResolveError> {
+  for attempt in attempts {
+    if !attempt.backoff.is_zero() {
+      if Instant::now() + attempt.backoff >= deadline {
+        break;
+      }
+      debug!(
+        "No response from {name_server}, trying again in {:?}",
+        attempt.backoff
+      );
+      thread::sleep(attempt.backoff);
+    }
+    match transport.query(&attempt.request, name_server) {
+      Ok(response) => return Ok((attempt, response)),
+      Err(e) => match ResolveError::from(e) {
+        ResolveError::Timeout => continue,
+        e => return Err(e),
+      },
+    }
+  }
+  Err(ResolveError::Timeout)
+}
+
+/// Sends `attempts` to `name_server` through `send_udp` and waits for the response, retrying over
+/// TCP if it was truncated. The response has to be for the question asked, and with `match_case`,
+/// repeat the query's name with exactly the same case.
+fn exchange(
+  transport: &impl Transport,
+  attempts: &[Attempt],
+  name_server: SocketAddr,
+  match_case: bool,
+  deadline: Instant,
+) -> Result<Message, ResolveError> {
+  let (attempt, response) = send_udp(transport, attempts, name_server, deadline)?;
+  let (id, request) = (attempt.id, attempt.request.as_slice());
+  let mut response = Message::from_octets(response)?;
+
+  if response.header().tc() {
+    debug!("Truncated response from {name_server}, retrying over TCP");
+    response = Message::from_octets(transport.query_tcp(request, name_server)?)?;
+  }
+
+  // A response with the wrong ID is either stale or spoofed, so don't trust anything in it.
+  if response.header().id() != id {
+    return Err(ResolveError::MalformedResponse(format!(
+      "response from {name_server} has ID {} but query had ID {id}",
+      response.header().id()
+    )));
+  }
+
+  // Nor is a response to some other question, which could otherwise be cached as the answer to
+  // this one.
+  let request = base::Message::from_octets(request)?;
+  let sent = request.sole_question()?;
+  let received = response.sole_question()?;
+  if received != sent {
+    return Err(ResolveError::MalformedResponse(format!(
+      "response from {name_server} is for {received} but query asked about {sent}"
+    )));
+  }
+  // Dnames compare ignoring case, so compare the names as sent on the wire as well.
+  if match_case {
+    let sent = sent.qname().to_dname::<Octets>()?;
+    let received = received.qname().to_dname::<Octets>()?;
+    if sent.as_slice() != received.as_slice() {
+      return Err(ResolveError::MalformedResponse(format!(
+        "response from {name_server} is for {received} but query asked about {sent}"
+      )));
+    }
+  }
+  Ok(response)
+}
+
+/// The question to ask the servers for `zone` about `question` with QNAME minimization: the NS
+/// records of the name `labels` labels below `zone` on the way to `question`'s name. Returns
+/// `None` once that name would be the whole of it, and the question itself should be asked.
+fn minimized_question(
+  question: &Question<Dname<Octets>>,
+  zone: &Dname<Octets>,
+  labels: usize,
+) -> Result<Option<Question<Dname<Octets>>>> {
+  let qname = question.qname();
+  let below = qname.label_count().saturating_sub(zone.label_count());
+  if labels >= below {
+    return Ok(None);
+  }
+  let name = match qname.iter_suffixes().nth(below - labels) {
+    Some(name) => name.to_dname()?,
+    None => return Ok(None),
+  };
+  Ok(Some(Question::new(name, Rtype::Ns, question.qclass())))
+}
+
+/// Randomly flips the case of each letter in `name`, for DNS 0x20 (draft-vixie-dnsext-dns0x20).
+/// Servers copy the question into their response as it was sent, so someone spoofing a response
+/// has to guess the case on top of the ID and port.
+fn randomize_case(name: &impl ToDname, rng: &mut (impl Rng + ?Sized)) -> Result<Dname<Octets>> {
+  let mut octets = name.to_dname::<Octets>()?.into_octets();
+  // label lengths are at most 63, so every letter in the wire format is part of a label
+  for octet in octets
+    .iter_mut()
+    .filter(|octet| octet.is_ascii_alphabetic())
+  {
+    if rng.gen() {
+      *octet ^= 0x20;
+    }
+  }
+  Ok(Dname::from_octets(octets)?)
+}
+
+/// How resolution deals with DNSSEC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Dnssec {
+  /// Responses leave out DNSSEC records.
+  Off,
+  /// Responses include whatever DNSSEC records the servers asked have, unchecked.
+  Records,
+  /// Responses are validated, and have AD set if they're secure.
+  Validate,
+}
+
+/// A response received from another server while resolving with `DnsServer::trace` set.
+#[derive(Clone)]
+pub struct TraceStep {
+  /// How many lookups for name server addresses the step is nested in, 0 for the question
+  /// resolved itself.
+  pub depth: usize,
+  /// The zone the server was asked as an authority for, or `None` if it's the forwarder.
+  pub zone: Option<Dname<Octets>>,
+  pub server: SocketAddr,
+  /// The question sent, which QNAME minimization can make differ from the one resolved.
+  pub question: Question<Dname<Octets>>,
+  pub response: Message,
+}
+
+/// The transport a client query arrived on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+  Udp,
+  Tcp,
+}
+
+/// How many leading bits of a client's address are passed on to other servers in an EDNS Client
+/// Subnet option (RFC 7871), so they can give answers suited to where the client is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubnetPrefixes {
+  pub ipv4: u8,
+  pub ipv6: u8,
+}
+
+impl SubnetPrefixes {
+  /// The subnet passed on for `client`.
+  pub fn subnet_of(&self, client: IpAddr) -> Cidr {
+    let client = client.to_canonical();
+    let prefix = match client {
+      IpAddr::V4(_) => self.ipv4,
+      IpAddr::V6(_) => self.ipv6,
+    };
+    Cidr::truncated(client, prefix)
+  }
+}
+
+/// Which clients a cached answer applies to, along with the answer.
+type ScopedEntry = (Cidr, CacheEntry);
+/// What concurrent resolutions are shared by: the question, whether DNSSEC records were asked for
+/// and the client subnet sent.
+type FlightKey = (QuestionKey, bool, Option<Cidr>);
+/// What identifies a client's query across retransmits: where it came from, its ID and its
+/// question.
+type ClientQueryKey = (SocketAddr, u16, QuestionKey);
+
+pub struct DnsServer<R: Rng + ?Sized, T: Transport = NetworkTransport> {
+  /// Responses cached by question, shared with every fork of this server.
+  cache: Arc<Mutex<Box<dyn Cache>>>,
+  /// Responses that name servers said only apply to some client subnets, cached by question
+  /// along with the subnet each applies to, and shared with every fork of this server.
+  scoped_cache: Arc<Mutex<LruCache<QuestionKey, Vec<ScopedEntry>>>>,
+  /// Individual records learned while resolving, shared with every fork of this server. Name
+  /// server records and addresses found here let resolution skip the zones above them.
+  records: Arc<Mutex<RecordStore>>,
+  /// Resolutions currently running for questions that missed the cache, so concurrent queries
+  /// for the same question share one resolution. They're keyed by whether DNSSEC records were
+  /// asked for and by the client subnet sent as well, since those change the response.
+  in_flight: Arc<SingleFlight<FlightKey, Result<Message, ResolveError>>>,
+  /// Client queries over UDP currently being answered, shared with every fork of this server.
+  /// A retransmit of one of them is dropped, since the client accepts the response to the
+  /// original as the response to any of its copies.
+  udp_queries: Arc<InProgress<ClientQueryKey>>,
+  /// Validated keys of the zones validation has passed through, shared with every fork of this
+  /// server.
+  zone_keys: Arc<Mutex<LruCache<Dname<Octets>, ZoneKeys>>>,
+  /// How fast the name servers asked have been responding, shared with every fork of this
+  /// server.
+  server_stats: Arc<ServerStats>,
+  /// How many nested name server lookups a single client query may trigger.
+  pub max_depth: usize,
+  /// How long resolving a client query may take, including every lookup it leads to, before it
+  /// fails with SERVFAIL.
+  pub resolution_timeout: Duration,
+  /// How many queries to other servers resolving a client query may send, counting every lookup
+  /// it leads to, before it fails with SERVFAIL.
+  pub max_upstream_queries: usize,
+  /// How many times to send a query again, after a short randomized backoff, when a name server
+  /// doesn't respond to it, before moving on to the next server.
+  pub udp_retries: usize,
+  /// Shortest TTL answers are cached for and served with. Lower TTLs are raised to it, except that
+  /// while it's 0, answers with a TTL of 0 aren't cached at all.
+  pub min_ttl: u32,
+  /// Longest TTL answers are cached for and served with. Higher TTLs are lowered to it.
+  pub max_ttl: u32,
+  /// Shortest TTL the delegations and glue learned from referrals are kept for, so resolving
+  /// names under a zone seen before can start at its servers rather than the root's. Lower TTLs
+  /// are raised to it. 0 by default.
+  pub min_referral_ttl: u32,
+  /// Longest TTL the delegations and glue learned from referrals are kept for. Higher TTLs are
+  /// lowered to it.
+  pub max_referral_ttl: u32,
+  /// Whether to resolve queries that don't set RD. When false, they're only answered from the
+  /// hosts file and the cache, as RFC 1034 section 4.3.1 describes for non-recursive queries.
+  pub always_recurse: bool,
+  /// Whether to randomize the case of names sent to other servers, and reject responses that
+  /// don't repeat it exactly. Some servers don't preserve the case, so this is off by default.
+  pub randomize_case: bool,
+  /// Whether to send the servers along a delegation chain only the part of the name they need to
+  /// refer onwards, rather than the whole name (RFC 7816). Off by default.
+  pub qname_minimization: bool,
+  /// Whether to ignore the glue in referrals for name servers outside the zone being delegated,
+  /// and look up their addresses like any other name instead, which is slower but doesn't trust
+  /// the referring server with them. Glue for servers inside the zone is still used, since
+  /// there's no other way to reach them. Off by default.
+  pub ignore_glue: bool,
+  /// How to order the name servers for a zone, and so which are asked first. Random by default.
+  pub selection: Selection,
+  /// Whether to answer ANY queries with a single synthesized HINFO record, as RFC 8482 suggests,
+  /// rather than resolving them. Answering with every record a name has makes ANY queries useful
+  /// for amplifying attacks, and what an upstream returns for them varies anyway. On by default.
+  pub minimal_any: bool,
+  /// Whether positive answers are sent with only their answer section, leaving out the
+  /// authority and additional records that came along with them, so responses stay small and
+  /// are truncated less. Negative answers and referrals are sent whole, since their authority
+  /// section is what they say. Off by default.
+  pub minimal_responses: bool,
+  /// Keys that DNSSEC validation starts from. When set, answers are validated unless the client
+  /// sets CD, secure answers get AD and bogus ones SERVFAIL. When unset, nothing is validated.
+  pub trust_anchors: Option<Arc<Vec<TrustAnchor>>>,
+  /// Upstream resolver to send every query to instead of recursing from the root.
+  pub forwarder: Option<SocketAddr>,
+  /// How much of each client's address to pass on to the forwarder and the servers below the
+  /// root when resolving its queries. Answers said to apply only to some subnets are cached for
+  /// those alone. When unset, which is the default, nothing about clients is passed on.
+  pub client_subnet: Option<SubnetPrefixes>,
+  /// Addresses of the root name servers that resolution starts from.
+  pub root_hints: Vec<IpAddr>,
+  /// Text answered to CHAOS class TXT queries for `version.bind` and `id.server`, which
+  /// monitoring uses to tell what a server is.
+  pub identity: String,
+  /// Domains that names given to `resolve` without a trailing dot are completed with, tried in
+  /// order. Names in client queries are always complete, so they're never searched.
+  pub search: Vec<Dname<Octets>>,
+  /// Dots a name given to `resolve` needs to be tried as it is before any search domain.
+  pub ndots: usize,
+  /// Static mappings answered before the cache or any name server is consulted.
+  pub hosts: Arc<Hosts>,
+  /// Counters shared with every fork of this server, exported for monitoring.
+  pub metrics: Arc<Metrics>,
+  /// Fraction of a cached answer's lifetime that, once it's all that remains, makes the next hit
+  /// refresh the answer in the background. When unset, answers are only refreshed on a miss.
+  pub prefetch: Option<f64>,
+  /// How long after expiring a cached answer may still be served if resolving the question
+  /// again fails. When unset, expired answers are never served.
+  pub serve_stale: Option<Duration>,
+  /// Clients allowed to send queries. When unset, anyone may.
+  pub allowed_clients: Option<Arc<Vec<Cidr>>>,
+  /// Static records for groups of clients, consulted before anything else for names they list.
+  /// Only the first view matching a client applies to it.
+  pub views: Arc<Vec<View>>,
+  /// Zones answered authoritatively, after views but before the hosts file, the cache or any
+  /// name server.
+  pub zones: Arc<Vec<Zone>>,
+  /// Zones whose names are resolved by asking particular upstream resolvers, ahead of the
+  /// forwarder or walking down from the root.
+  pub forward_zones: Arc<Vec<ForwardZone>>,
+  /// Limits on how fast each client may send queries, shared with every fork of this server.
+  pub rate_limiter: Option<Arc<RateLimiter>>,
+  /// Limit on the queries to other servers outstanding at once, shared with every fork of this
+  /// server. A lookup that can't send one in time fails, and the client gets SERVFAIL.
+  pub upstream_limiter: Arc<QueryLimiter>,
+  /// Where a line describing each client query answered is written, if anywhere.
+  pub query_log: Option<Arc<QueryLog>>,
+  /// How queries reach other name servers, shared with every fork of this server.
+  pub transport: Arc<T>,
+  /// When set, every response used while resolving is appended to it, so how a question was
+  /// resolved can be followed step by step. Forks of the server don't trace.
+  pub trace: Option<Vec<TraceStep>>,
+  pub rng: R,
+}
+
+impl DnsServer<StdRng> {
+  /// Creates a server that resolves from the root over the network, with the default cache size
+  /// and limits.
+  pub fn new() -> Result<DnsServer<StdRng>> {
+    DnsServer::with_cache_capacity(DEFAULT_MAX_CACHE_ENTRIES, NetworkTransport::default())
+  }
+}
+
+impl<T: Transport> DnsServer<StdRng, T> {
+  pub fn with_cache_capacity(
+    max_cache_entries: usize,
+    transport: T,
+  ) -> Result<DnsServer<StdRng, T>> {
+    Ok(DnsServer {
+      cache: Arc::new(Mutex::new(Box::new(MemoryCache::new(max_cache_entries)))),
+      scoped_cache: Arc::new(Mutex::new(LruCache::new(max_cache_entries))),
+      records: Arc::new(Mutex::new(RecordStore::new(max_cache_entries))),
+      in_flight: Arc::new(SingleFlight::new()),
+      udp_queries: Arc::new(InProgress::new()),
+      zone_keys: Arc::new(Mutex::new(LruCache::new(dnssec::MAX_ZONE_KEYS))),
+      server_stats: Arc::new(ServerStats::default()),
+      max_depth: DEFAULT_MAX_DEPTH,
+      resolution_timeout: DEFAULT_RESOLUTION_TIMEOUT,
+      max_upstream_queries: DEFAULT_MAX_UPSTREAM_QUERIES,
+      udp_retries: DEFAULT_UDP_RETRIES,
+      min_ttl: 0,
+      max_ttl: DEFAULT_MAX_TTL,
+      min_referral_ttl: 0,
+      max_referral_ttl: DEFAULT_MAX_TTL,
+      always_recurse: false,
+      randomize_case: false,
+      qname_minimization: false,
+      ignore_glue: false,
+      selection: Selection::Random,
+      minimal_any: true,
+      minimal_responses: false,
+      trust_anchors: None,
+      forwarder: None,
+      client_subnet: None,
+      root_hints: hints::BUILTIN.to_vec(),
+      identity: DEFAULT_IDENTITY.to_string(),
+      search: Vec::new(),
+      ndots: DEFAULT_NDOTS,
+      hosts: Arc::new(Hosts::default()),
+      metrics: Arc::new(Metrics::default()),
+      prefetch: None,
+      serve_stale: None,
+      allowed_clients: None,
+      views: Arc::new(Vec::new()),
+      zones: Arc::new(Vec::new()),
+      forward_zones: Arc::new(Vec::new()),
+      rate_limiter: None,
+      upstream_limiter: Arc::new(QueryLimiter::new(DEFAULT_MAX_OUTSTANDING_QUERIES)),
+      query_log: None,
+      transport: Arc::new(transport),
+      trace: None,
+      rng: StdRng::from_entropy(),
+    })
+  }
+}
+
+impl<R: Rng + ?Sized, T: Transport> DnsServer<R, T> {
+  pub fn cache_len(&self) -> usize {
+    self.cache().len()
+  }
+
+  pub fn cache_capacity(&self) -> usize {
+    self.cache().capacity()
+  }
+
+  /// Replaces the cache responses are kept in, for this server and every fork of it, dropping
+  /// what the old cache held.
+  pub fn set_cache(&self, cache: impl Cache + 'static) {
+    *self.cache() = Box::new(cache);
+  }
+
+  /// Creates another server sharing this one's cache and settings but with its own random
+  /// number generator, so it can resolve queries on another thread.
+  pub fn fork(&self) -> DnsServer<StdRng, T> {
+    DnsServer {
+      cache: Arc::clone(&self.cache),
+      scoped_cache: Arc::clone(&self.scoped_cache),
+      records: Arc::clone(&self.records),
+      in_flight: Arc::clone(&self.in_flight),
+      udp_queries: Arc::clone(&self.udp_queries),
+      zone_keys: Arc::clone(&self.zone_keys),
+      server_stats: Arc::clone(&self.server_stats),
+      max_depth: self.max_depth,
+      resolution_timeout: self.resolution_timeout,
+      max_upstream_queries: self.max_upstream_queries,
+      udp_retries: self.udp_retries,
+      min_ttl: self.min_ttl,
+      max_ttl: self.max_ttl,
+      min_referral_ttl: self.min_referral_ttl,
+      max_referral_ttl: self.max_referral_ttl,
+      always_recurse: self.always_recurse,
+      randomize_case: self.randomize_case,
+      qname_minimization: self.qname_minimization,
+      ignore_glue: self.ignore_glue,
+      selection: self.selection,
+      minimal_any: self.minimal_any,
+      minimal_responses: self.minimal_responses,
+      trust_anchors: self.trust_anchors.clone(),
+      forwarder: self.forwarder,
+      client_subnet: self.client_subnet,
+      root_hints: self.root_hints.clone(),
+      identity: self.identity.clone(),
+      search: self.search.clone(),
+      ndots: self.ndots,
+      hosts: Arc::clone(&self.hosts),
+      metrics: Arc::clone(&self.metrics),
+      prefetch: self.prefetch,
+      serve_stale: self.serve_stale,
+      allowed_clients: self.allowed_clients.clone(),
+      views: Arc::clone(&self.views),
+      zones: Arc::clone(&self.zones),
+      forward_zones: Arc::clone(&self.forward_zones),
+      rate_limiter: self.rate_limiter.clone(),
+      upstream_limiter: Arc::clone(&self.upstream_limiter),
+      query_log: self.query_log.clone(),
+      transport: Arc::clone(&self.transport),
+      trace: None,
+      rng: StdRng::from_entropy(),
+    }
+  }
+
+  /// Resolves the `rtype` records of `name` in the Internet class, following CNAMEs and using the
+  /// cache like a client query would. Negative answers such as NXDOMAIN are returned as responses
+  /// rather than errors; check the rcode of the response to tell them apart. With trust anchors
+  /// set, the response is validated.
+  ///
+  /// Like a stub resolver, a `name` without a trailing dot is also tried with each of the
+  /// `search` domains appended, until a name doesn't get NXDOMAIN. The name is tried as it is
+  /// first if it has at least `ndots` dots, and after the search domains otherwise. A single
+  /// label is most likely a local host name rather than a top-level domain, so without search
+  /// domains it fails with `ResolveError::SingleLabel`.
+  pub fn resolve(&mut self, name: &str, rtype: Rtype) -> Result<Message, ResolveError> {
+    let qnames = complete_names(name, &self.search, self.ndots)?;
+    let dnssec = match self.trust_anchors {
+      Some(_) => Dnssec::Validate,
+      None => Dnssec::Off,
+    };
+    let (last, searched) = qnames.split_last().expect("there's always a name to try");
+    for qname in searched {
+      let question = Question::new(qname.clone(), rtype, Class::In);
+      let response = self.recurse(&question, true, dnssec, None, 0, &self.budget())?;
+      if response.header().rcode() != Rcode::NXDomain {
+        return Ok(response);
+      }
+      debug!("{qname} doesn't exist, trying the next name");
+    }
+    let question = Question::new(last.clone(), rtype, Class::In);
+    self.recurse(&question, true, dnssec, None, 0, &self.budget())
+  }
+
+  /// Resolves the `rtype` records of `name` in the Internet class starting at `server` instead of
+  /// the root, so a particular name server, such as one authoritative for the zone, can be tested
+  /// directly. Referrals it gives are followed as usual, but the response is returned as the last
+  /// server sent it: it doesn't come from or go into the cache, CNAMEs in it aren't followed and
+  /// it isn't validated. `name` is always taken to be absolute.
+  pub fn resolve_via(
+    &mut self,
+    name: &str,
+    rtype: Rtype,
+    server: SocketAddr,
+  ) -> Result<Message, ResolveError> {
+    let qname = Dname::<Octets>::from_str(name)
+      .map_err(|e| ResolveError::InvalidName(name.to_string(), e))?;
+    let question = Question::new(qname, rtype, Class::In);
+    // nothing is known about which zone the server serves, so it may answer for any, and is
+    // sent the whole name since there's no telling how much of it the server needs
+    let start = (vec![server], Dname::root_vec());
+    let minimization = mem::replace(&mut self.qname_minimization, false);
+    let response = self.follow_referrals(&question, start, false, None, 0, &self.budget());
+    self.qname_minimization = minimization;
+    response
+  }
+
+  /// Asks the root hints for the root zone's NS records and their addresses (RFC 8109), so
+  /// resolution starts from the current set of root servers, all of which it then spreads its
+  /// queries over, rather than only the hints. The records are kept in the record store like any
+  /// others, so once they expire or are flushed, resolution goes back to starting from the hints.
+  /// Returns how many root server addresses were learned.
+  pub fn prime(&mut self) -> Result<usize, ResolveError> {
+    let question = Question::new(Dname::<Octets>::root_vec(), Rtype::Ns, Class::In);
+    let mut hints: Vec<_> = self
+      .root_hints
+      .iter()
+      .map(|addr| SocketAddr::new(*addr, DNS_PORT))
+      .collect();
+    self.order_servers(&mut hints);
+    let (server, response) = self.lookup_any(&question, &hints, false, None, &self.budget())?;
+    let (_, answers, _, additionals) = response.sections()?;
+    let hosts: Vec<_> = answers
+      .limit_to::<Ns<ParsedDname<&Octets>>>()
+      .filter_map(|record| record.ok())
+      .filter(|record| record.owner().is_root())
+      .map(|record| *record.data().nsdname())
+      .collect();
+    if response.header().rcode() != Rcode::NoError || hosts.is_empty() {
+      return Err(ResolveError::MalformedResponse(format!(
+        "priming response from {server} has no root name servers"
+      )));
+    }
+    let trust = if response.header().aa() {
+      Trust::Answer
+    } else {
+      Trust::NonAuthoritative
+    };
+    {
+      let mut records = self.records();
+      records.insert_section(
+        answers,
+        trust,
+        |ttl| ttl,
+        |owner, rtype| rtype == Rtype::Ns && owner.is_root(),
+      )?;
+      records.insert_section(
+        additionals,
+        Trust::Referral,
+        |ttl| self.clamp_referral_ttl(ttl),
+        |owner, _| hosts.iter().any(|host| owner == host),
+      )?;
+    }
+    Ok(self.known_servers(&Dname::root_vec()).len())
+  }
+
+  /// A fresh budget for resolving one client query.
+  fn budget(&self) -> Budget {
+    Budget::new(self.resolution_timeout, self.max_upstream_queries)
+  }
+
+  /// Locks the cache. A thread panicking while holding the lock can't leave the cache in a state
+  /// that's unsafe to keep using, so poisoning is ignored.
+  fn cache(&self) -> MutexGuard<'_, Box<dyn Cache>> {
+    self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Locks the cache of answers for client subnets, ignoring poisoning like `cache` does.
+  fn scoped_cache(&self) -> MutexGuard<'_, LruCache<QuestionKey, Vec<ScopedEntry>>> {
+    self
+      .scoped_cache
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Locks the record store, ignoring poisoning like `cache` does.
+  fn records(&self) -> MutexGuard<'_, RecordStore> {
+    self.records.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Returns the addresses of `host` learned from earlier responses.
+  fn known_addresses(&self, host: &Dname<Octets>) -> Vec<IpAddr> {
+    let mut records = self.records();
+    [Rtype::A, Rtype::Aaaa]
+      .into_iter()
+      .filter_map(|rtype| records.get(host, rtype, Class::In, Trust::Referral))
+      .flat_map(|(data, _)| data)
+      .filter_map(|data| data.addr())
+      .collect()
+  }
+
+  /// Returns the addresses of the name servers for `zone` learned from earlier responses.
+  fn known_servers(&self, zone: &Dname<Octets>) -> Vec<SocketAddr> {
+    let hosts = match self
+      .records()
+      .get(zone, Rtype::Ns, Class::In, Trust::Referral)
+    {
+      Some((hosts, _)) => hosts,
+      None => return Vec::new(),
+    };
+    hosts
+      .iter()
+      .filter_map(|data| match data {
+        RecordData::Ns(host) => Some(host),
+        _ => None,
+      })
+      .flat_map(|host| self.known_addresses(host))
+      .map(|addr| SocketAddr::new(addr, DNS_PORT))
+      .collect()
+  }
+
+  /// Finds the closest zone enclosing `qname`, other than the root, whose name servers we know
+  /// the addresses of. Returns those addresses along with the zone.
+  fn closest_delegation(
+    &self,
+    qname: &Dname<Octets>,
+  ) -> Result<Option<(Vec<SocketAddr>, Dname<Octets>)>> {
+    for zone in qname.iter_suffixes() {
+      let zone: Dname<Octets> = zone.to_dname()?;
+      if zone.is_root() {
+        break;
+      }
+      let servers = self.known_servers(&zone);
+      if !servers.is_empty() {
+        return Ok(Some((servers, zone)));
+      }
+    }
+    Ok(None)
+  }
+
+  /// The root servers to start resolving at: the ones learned by `prime` while its records last,
+  /// and the root hints otherwise.
+  fn root_servers(&self) -> Vec<SocketAddr> {
+    let primed = self.known_servers(&Dname::root_vec());
+    if !primed.is_empty() {
+      return primed;
+    }
+    self
+      .root_hints
+      .iter()
+      .map(|addr| SocketAddr::new(*addr, DNS_PORT))
+      .collect()
+  }
+
+  /// Builds a query for `question` with a random ID, returning the ID along with the query. The
+  /// case of the name is randomized if `randomize_case` is set, and DO is set with `dnssec_ok` so
+  /// the server includes DNSSEC records (RFC 3225). A `subnet` is sent in a client subnet
+  /// option.
+  fn build_query<N: ToDname>(
+    &mut self,
+    question: &Question<N>,
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+  ) -> Result<(u16, Octets)> {
+    let mut request = MessageBuilder::new_vec().question();
+    if self.randomize_case {
+      let qname = randomize_case(question.qname(), &mut self.rng)?;
+      request.push((qname, question.qtype(), question.qclass()))?;
+    } else {
+      request.push(question)?;
+    }
+    let id = self.rng.gen();
+    request.header_mut().set_id(id);
+    request.header_mut().set_rd(true);
+    let mut request = request.additional();
+    request.opt(|opt| {
+      opt.set_udp_payload_size(EDNS_UDP_PAYLOAD_SIZE);
+      opt.set_dnssec_ok(dnssec_ok);
+      if let Some(subnet) = subnet {
+        opt.push(&ClientSubnet::new(subnet.prefix(), 0, subnet.addr()))?;
+      }
+      Ok(())
+    })?;
+    Ok((id, request.finish()))
+  }
+
+  /// Adds `response`, which `server` gave to `question`, to `trace` if tracing.
+  fn trace_step<N: ToDname>(
+    &mut self,
+    depth: usize,
+    zone: Option<&Dname<Octets>>,
+    server: SocketAddr,
+    question: &Question<N>,
+    response: &Message,
+  ) -> Result<()> {
+    if let Some(trace) = &mut self.trace {
+      trace.push(TraceStep {
+        depth,
+        zone: zone.cloned(),
+        server,
+        question: Question::new(
+          question.qname().to_dname()?,
+          question.qtype(),
+          question.qclass(),
+        ),
+        response: response.clone(),
+      });
+    }
+    Ok(())
+  }
+
+  /// Orders `servers` for asking them, following `selection`.
+  fn order_servers(&mut self, servers: &mut [SocketAddr]) {
+    self
+      .server_stats
+      .order(servers, self.selection, &mut self.rng);
+  }
+
+  /// How long to wait before the `retry`th retry of a query, doubling from `RETRY_BACKOFF` with
+  /// each one and jittered by up to half either way, so retries from many queries don't line up.
+  /// The first try, retry 0, goes out straight away.
+  fn retry_backoff(&mut self, retry: usize) -> Duration {
+    match retry {
+      0 => Duration::ZERO,
+      _ => RETRY_BACKOFF * (1 << (retry - 1).min(16)) * self.rng.gen_range(50..150) / 100,
+    }
+  }
+
+  /// Sends `question` to `servers`, returning the first valid response along with the server it
+  /// came from. Up to `LOOKUP_FAN_OUT` servers are asked at once, so one slow server doesn't hold
+  /// up the rest, and each failure starts a query to the next server in line. A server that
+  /// doesn't respond is asked again up to `udp_retries` times first, and servers answering
+  /// SERVFAIL or REFUSED count as failing, since another server for the zone may well answer.
+  /// Queries still running once a response arrives are left to finish on their own threads, and
+  /// whatever they get back is dropped, though how long it took is still recorded for ordering
+  /// servers by speed. Each server asked is taken out of `budget`, retries
+  /// included, and no response is waited for past its deadline. Queries carry `subnet` as the
+  /// client's, if given.
+  fn lookup_any<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    servers: &[SocketAddr],
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+    budget: &Budget,
+  ) -> Result<(SocketAddr, Message), ResolveError> {
+    let (sender, receiver) = mpsc::channel();
+    let mut waiting = servers.iter();
+    let mut running = 0;
+    let mut last_error = None;
+    loop {
+      while running < LOOKUP_FAN_OUT {
+        let server = match waiting.next() {
+          Some(&server) => server,
+          None => break,
+        };
+        budget.spend_query()?;
+        let wait = budget.remaining()?.min(OUTSTANDING_QUERY_WAIT);
+        let permit = match self.upstream_limiter.acquire(wait) {
+          Some(permit) => permit,
+          None => {
+            warn!("Not looking up {question} with {server}: too many queries are outstanding");
+            self.metrics.upstream_rejected.inc();
+            last_error = Some(ResolveError::Overloaded(self.upstream_limiter.max()));
+            // the servers left would have to wait just the same, but responses to the queries
+            // already sent are still worth waiting for
+            waiting = [].iter();
+            break;
+          }
+        };
+        debug!("Attempting lookup of {question} with {server}");
+        // every retry has its own ID, so a late response to an earlier try can't be taken for it
+        let mut attempts = Vec::new();
+        for retry in 0..=self.udp_retries {
+          let (id, request) = self.build_query(question, dnssec_ok, subnet)?;
+          let backoff = self.retry_backoff(retry);
+          attempts.push(Attempt {
+            id,
+            request,
+            backoff,
+          });
+        }
+        let deadline = budget.deadline();
+        let transport = Arc::clone(&self.transport);
+        let stats = Arc::clone(&self.server_stats);
+        let match_case = self.randomize_case;
+        let sender = sender.clone();
+        let span = debug_span!("lookup", %server, %question, elapsed_ms = field::Empty);
+        self.metrics.upstream_queries.inc();
+        thread::spawn(move || {
+          let _entered = span.enter();
+          let started = Instant::now();
+          let result = exchange(&*transport, &attempts, server, match_case, deadline);
+          let elapsed = started.elapsed();
+          span.record("elapsed_ms", elapsed.as_millis() as u64);
+          let outcome = match &result {
+            Ok(response) => match response.header().rcode() {
+              Rcode::ServFail | Rcode::Refused => Outcome::ServFail,
+              _ => Outcome::Answered,
+            },
+            Err(ResolveError::Timeout) => Outcome::Timeout,
+            Err(_) => Outcome::Error,
+          };
+          stats.record(server.ip(), outcome, elapsed);
+          drop(permit);
+          let _ = sender.send((server, result));
+        });
+        running += 1;
+      }
+      if running == 0 {
+        break;
+      }
+
+      // every thread sends exactly once, and a sender is still held here, so this can only fail
+      // by running out of time
+      let (server, result) = match receiver.recv_timeout(budget.remaining()?) {
+        Ok(received) => received,
+        Err(_) => return Err(budget.out_of_time()),
+      };
+      running -= 1;
+      match result.map(|response| (response.header().rcode(), response)) {
+        // the server can't or won't answer, but another one for the zone might
+        Ok((rcode @ (Rcode::ServFail | Rcode::Refused), _)) => {
+          warn!("Lookup of {question} with {server} failed: it answered {rcode}");
+          last_error = Some(ResolveError::ServerFailed(server, rcode));
+        }
+        Ok((_, response)) => return Ok((server, response)),
+        Err(e) => {
+          warn!("Lookup of {question} with {server} failed: {e}");
+          last_error = Some(e);
+        }
+      }
+    }
+    Err(last_error.unwrap_or_else(|| ResolveError::NoReachableServer(question.to_string())))
+  }
+
+  /// Works out where to go after `response`, which came from a server authoritative for `zone`.
+  ///
+  /// Records outside of `zone` are out of bailiwick: the server has no authority over them, so
+  /// delegations to other zones and glue for names elsewhere are ignored.
+  fn get_next_server<N: ToDname>(
+    &mut self,
+    response: &Message,
+    question: &Question<N>,
+    zone: &Dname<Octets>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<NextStep, ResolveError> {
+    let (_, answers, authorities, additionals) = response.sections()?;
+    match response.header().rcode() {
+      // Only records for the name asked about answer it. Some servers send others along with a
+      // referral, and those are no reason to stop following it.
+      Rcode::NoError => {
+        let qtype = question.qtype();
+        let mut aliased = false;
+        for record in answers.filter_map(|record| record.ok()) {
+          if *record.owner() != *question.qname() {
+            continue;
+          }
+          if record.rtype() == qtype || qtype == Rtype::Any {
+            return Ok(NextStep::Done);
+          }
+          aliased |= record.rtype() == Rtype::Cname;
+        }
+        if aliased {
+          return Ok(NextStep::Alias);
+        }
+      }
+      // the name doesn't exist, which the server is authoritative about
+      Rcode::NXDomain => return Ok(NextStep::Done),
+      // `lookup_any` has already moved on from servers that failed, so any other rcode, such as
+      // NOTIMP, is as far as the question can go
+      _ => return Ok(NextStep::Done),
+    }
+
+    let relevant_hosts: Vec<_> = authorities
+      .limit_to::<Ns<ParsedDname<&Octets>>>()
+      .filter_map(|record| match record {
+        Ok(record) => {
+          let owner = record.owner();
+          if question.qname().ends_with(owner) && owner.ends_with(zone) {
+            Some((*owner, *record.data().nsdname()))
+          } else {
+            None
+          }
+        }
+        Err(_) => None,
+      })
+      .collect();
+
+    // An authoritative server answers for its zone, or refers to one below it. Anything else,
+    // such as a referral back up the tree or an empty response without AA, comes from a server
+    // that only thinks it was delegated the zone.
+    if !response.header().aa() && relevant_hosts.iter().all(|(owner, _)| *owner == *zone) {
+      return Ok(NextStep::Lame);
+    }
+
+    // all of the servers we hand back have to be authoritative for the same zone
+    let delegated = match relevant_hosts.first() {
+      Some((delegated, _)) => *delegated,
+      None => return Ok(NextStep::Done),
+    };
+    let relevant_hosts: Vec<_> = relevant_hosts
+      .into_iter()
+      .filter(|(owner, _)| *owner == delegated)
+      .map(|(_, host)| host)
+      .collect();
+
+    // `==` and `ends_with` on domain's names compare label by label, ignoring ASCII case and
+    // following compression pointers, so glue matches its NS name however either is written
+    let in_bailiwick = |owner: &ParsedDname<&Octets>| {
+      owner.ends_with(zone) && relevant_hosts.iter().any(|host| owner == host)
+    };
+    let ignore_glue = self.ignore_glue;
+    let usable_glue = |owner: &ParsedDname<&Octets>| {
+      in_bailiwick(owner) && (!ignore_glue || owner.ends_with(&delegated))
+    };
+    let mut resolved_ns: Vec<_> = addresses(additionals, usable_glue)
+      .into_iter()
+      .map(|addr| SocketAddr::new(addr, DNS_PORT))
+      .collect();
+
+    // remember the delegation, so later questions under it can start there
+    {
+      let mut records = self.records();
+      let ttl = |ttl| self.clamp_referral_ttl(ttl);
+      records.insert_section(authorities, Trust::Referral, ttl, |owner, rtype| {
+        rtype == Rtype::Ns && *owner == delegated
+      })?;
+      records.insert_section(additionals, Trust::Referral, ttl, |owner, _| {
+        usable_glue(owner)
+      })?;
+    }
+
+    if !resolved_ns.is_empty() {
+      self.order_servers(&mut resolved_ns);
+      return Ok(NextStep::Referral {
+        servers: resolved_ns,
+        zone: delegated.to_dname()?,
+      });
+    }
+
+    // we now need to resolve the unresolved name servers (i.e. find their IPs since we don't
+    // know them), stopping at the first one that resolves. IPv6 is only tried for names without
+    // any IPv4 address. Names inside the delegated zone can only be looked up by asking its own
+    // servers, which is what needs their addresses in the first place, so only addresses already
+    // known are used for them, and the names outside it are tried first.
+    let mut unresolved_ns = relevant_hosts;
+    unresolved_ns.shuffle(&mut self.rng);
+    unresolved_ns.sort_by_key(|name| name.ends_with(&delegated));
+    let all_in_zone = unresolved_ns.iter().all(|name| name.ends_with(&delegated));
+    for name in unresolved_ns {
+      let mut servers: Vec<_> = self
+        .known_addresses(&name.to_dname()?)
+        .into_iter()
+        .map(|addr| SocketAddr::new(addr, DNS_PORT))
+        .collect();
+      for rtype in [Rtype::A, Rtype::Aaaa] {
+        if !servers.is_empty() || name.ends_with(&delegated) {
+          break;
+        }
+        let unresolved_question = Question::new_in(name, rtype);
+        let msg = match self.recurse(
+          &unresolved_question,
+          false,
+          Dnssec::Off,
+          None,
+          depth + 1,
+          budget,
+        ) {
+          Ok(msg) => msg,
+          Err(e @ (ResolveError::MaxDepthExceeded(_) | ResolveError::BudgetExceeded(_))) => {
+            return Err(e)
+          }
+          Err(_) => continue,
+        };
+
+        servers.extend(
+          addresses(msg.answer()?, |_| true)
+            .into_iter()
+            .map(|addr| SocketAddr::new(addr, DNS_PORT)),
+        );
+      }
+
+      if !servers.is_empty() {
+        self.order_servers(&mut servers);
+        return Ok(NextStep::Referral {
+          servers,
+          zone: delegated.to_dname()?,
+        });
+      }
+    }
+    if all_in_zone {
+      return Err(ResolveError::MissingGlue(delegated.to_string()));
+    }
+    Ok(NextStep::Done)
+  }
+
+  /// Resolves `question`, following any CNAMEs found along the way to the name they point at.
+  /// `dnssec` says whether the response includes DNSSEC records, and whether it's validated, in
+  /// which case AD is set if every step along the chain is secure. `depth` counts the name server
+  /// lookups this one is nested in, and is 0 for client queries. `budget` limits the work done
+  /// for the client query this is part of, across all of its nested lookups. `subnet` is the
+  /// client's, to pass on, and is only given for client queries.
+  ///
+  /// Answers come from the cache when it has them, and are cached once resolved. With `shared`,
+  /// a resolution of the same question already running on another thread is joined rather than
+  /// repeated. Lookups made while resolving a question joined this way must not be shared.
+  fn recurse<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    shared: bool,
+    dnssec: Dnssec,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    let _entered = debug_span!("resolve", %question, depth).entered();
+    if depth > self.max_depth {
+      return Err(ResolveError::MaxDepthExceeded(self.max_depth));
+    }
+
+    let mut response = self.recurse_single(question, shared, dnssec, subnet, depth, budget)?;
+    // AD from another server is never passed on, since only our own validation is trusted
+    let mut secure = dnssec == Dnssec::Validate && response.header().ad();
+    if question.qtype() == Rtype::Cname {
+      response.header_mut().set_ad(secure);
+      return Ok(response);
+    }
+
+    let qname = question.qname().to_dname()?;
+    let (mut chain, mut answered) = cname_chain(&response, &qname, question.qtype())?;
+
+    while !chain.is_empty() && !answered {
+      if chain.len() > MAX_CNAME_CHAIN {
+        return Err(
+          anyhow!("CNAME chain for {question} is longer than {MAX_CNAME_CHAIN} records").into(),
+        );
+      }
+
+      let target = chain[chain.len() - 1].data().cname().clone();
+      debug!("Following CNAME from {question} to {target}");
+      let target_question = Question::new(target.clone(), question.qtype(), question.qclass());
+      response = self.recurse_single(&target_question, shared, dnssec, subnet, depth, budget)?;
+      secure &= response.header().ad();
+
+      let (links, found) = cname_chain(&response, &target, question.qtype())?;
+      if links.is_empty() && !found {
+        // the chain ends in a negative answer, which is what the client gets
+        break;
+      }
+      chain.extend(links);
+      answered = found;
+    }
+
+    let mut response = match chain.is_empty() {
+      true => response,
+      false => prepend_cname_chain(question, &chain, &response)?,
+    };
+    response.header_mut().set_ad(secure);
+    Ok(response)
+  }
+
+  /// Resolves `question` without following CNAMEs, answering from the hosts file or the cache
+  /// when possible. Answers from the hosts file are never validated.
+  fn recurse_single<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    shared: bool,
+    dnssec: Dnssec,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    if let Some(response) = self.hosts_answer(question)? {
+      return Ok(response);
+    }
+    let dnssec_ok = dnssec != Dnssec::Off;
+    let response = if let Some(response) = self.cached_answer(question, dnssec_ok, subnet)? {
+      response
+    } else {
+      let key = QuestionKey::new(
+        question.qname().to_dname()?,
+        question.qtype(),
+        question.qclass(),
+      );
+      let result = match shared {
+        true => self.resolve_shared(key.clone(), dnssec_ok, subnet, depth, budget),
+        // waiting on another thread's resolution from within one of ours could deadlock, if that
+        // resolution is waiting on ours in turn
+        false => self.resolve_and_cache(&key, dnssec_ok, subnet, depth, budget),
+      };
+      match (result, self.serve_stale) {
+        (Err(e), Some(window)) => match self.cache().get(&key) {
+          Some(entry) if entry.is_fresher_than(window) => {
+            warn!("Serving stale answer to {question} after resolution failed: {e:#}");
+            entry.stale_message()?
+          }
+          _ => return Err(e),
+        },
+        (result, _) => result?,
+      }
+    };
+
+    match dnssec {
+      Dnssec::Validate => self.validate(question, response, depth, budget),
+      Dnssec::Off | Dnssec::Records => Ok(response),
+    }
+  }
+
+  /// Answers `question` from the hosts file, the cache or stored records, without asking any
+  /// name server or following CNAMEs. Returns `None` if none of them can answer it. Answers from
+  /// the cache are validated like resolved ones would be, which may mean asking for keys.
+  fn local_answer<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    dnssec: Dnssec,
+    subnet: Option<Cidr>,
+  ) -> Result<Option<Message>, ResolveError> {
+    if let Some(response) = self.hosts_answer(question)? {
+      return Ok(Some(response));
+    }
+    match (
+      self.cached_answer(question, dnssec != Dnssec::Off, subnet)?,
+      dnssec,
+    ) {
+      (Some(response), Dnssec::Validate) => {
+        let budget = self.budget();
+        self.validate(question, response, 0, &budget).map(Some)
+      }
+      (response, _) => Ok(response),
+    }
+  }
+
+  fn hosts_answer<N: ToDname + Display>(&self, question: &Question<N>) -> Result<Option<Message>> {
+    // hosts files only describe the Internet class
+    if question.qclass() != Class::In {
+      return Ok(None);
+    }
+    match hosts_records(&self.hosts, &question.qname().to_dname()?)? {
+      Some(records) => {
+        debug!("Answering {question} from the hosts file");
+        synthesized_answer(question, &records, HOSTS_TTL, true).map(Some)
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Answers `question` from the first view matching `client`, if it lists the name asked
+  /// about.
+  fn view_answer<N: ToDname + Display>(
+    &self,
+    client: IpAddr,
+    question: &Question<N>,
+  ) -> Result<Option<Message>> {
+    let view = match self.views.iter().find(|view| view.matches(client)) {
+      Some(view) if question.qclass() == Class::In => view,
+      _ => return Ok(None),
+    };
+    match hosts_records(&view.hosts, &question.qname().to_dname()?)? {
+      Some(records) => {
+        debug!("Answering {question} from view {}", view.name);
+        synthesized_answer(question, &records, HOSTS_TTL, true).map(Some)
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Answers `question` from the served zone it's in, the innermost one if zones are nested.
+  fn zone_answer<N: ToDname + Display>(&self, question: &Question<N>) -> Result<Option<Message>> {
+    if question.qclass() != Class::In {
+      return Ok(None);
+    }
+    let zone = self
+      .zones
+      .iter()
+      .filter(|zone| zone.contains(question.qname()))
+      .max_by_key(|zone| zone.origin.label_count());
+    match zone {
+      Some(zone) => {
+        debug!("Answering {question} from zone {}", zone.origin);
+        zone.answer(question).map(Some)
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Answers `question` from an unexpired cache entry, or failing that from stored records. With
+  /// `dnssec_ok`, only entries that were resolved asking for DNSSEC records are used, and stored
+  /// records, which never include them, aren't.
+  fn cached_answer<N: ToDname + Display>(
+    &self,
+    question: &Question<N>,
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+  ) -> Result<Option<Message>> {
+    let key = QuestionKey::new(
+      question.qname().to_dname()?,
+      question.qtype(),
+      question.qclass(),
+    );
+    if let Some(subnet) = subnet {
+      if let Some(response) = self.scoped_answer(&key, subnet, dnssec_ok)? {
+        return Ok(Some(response));
+      }
+    }
+    let mut prefetch = false;
+    let cached = match self.cache().get(&key) {
+      Some(entry) if !entry.is_expired() && (entry.dnssec || !dnssec_ok) => {
+        self.metrics.cache_hits.inc();
+        debug!(cache = "hit", "Answering {question} from the cache");
+        if entry.kind != AnswerKind::Positive {
+          debug!("Negative cache hit ({:?}) for {question}", entry.kind);
+        }
+        prefetch = self
+          .prefetch
+          .is_some_and(|threshold| entry.claim_prefetch(threshold));
+        Some((entry.message(), entry.dnssec))
+      }
+      _ => None,
+    };
+
+    if let Some((response, dnssec)) = cached {
+      if prefetch {
+        self.start_prefetch(key, dnssec);
+      }
+      return response.map(Some);
+    }
+
+    self.metrics.cache_misses.inc();
+    debug!(cache = "miss", "{question} isn't cached");
+    if dnssec_ok {
+      return Ok(None);
+    }
+    let known = self
+      .records()
+      .get(key.qname(), key.qtype(), key.qclass(), Trust::Answer);
+    match known {
+      Some((records, ttl)) => {
+        debug!("Answering {question} from stored records");
+        synthesized_answer(question, &records, ttl, false).map(Some)
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Answers the question `key` from an unexpired answer cached for a range holding `subnet`.
+  fn scoped_answer(
+    &self,
+    key: &QuestionKey,
+    subnet: Cidr,
+    dnssec_ok: bool,
+  ) -> Result<Option<Message>> {
+    let mut cache = self.scoped_cache();
+    let found = cache.get(key).and_then(|entries| {
+      entries.iter().find(|(scope, entry)| {
+        scope.contains(subnet.addr()) && !entry.is_expired() && (entry.dnssec || !dnssec_ok)
+      })
+    });
+    match found {
+      Some((scope, entry)) => {
+        self.metrics.cache_hits.inc();
+        debug!(cache = "hit", "Answering {key} from the cache for {scope}");
+        entry.message().map(Some)
+      }
+      None => Ok(None),
+    }
+  }
+
+  /// Resolves `question` without consulting the cache: by asking the servers of the forward zone
+  /// it's in, the innermost one if several match, or else the forwarder if one is configured, or
+  /// else by walking down from the root. Returns the response along with the client
+  /// subnet it's limited to, if `subnet` was sent and the server said it only applies to some.
+  fn resolve_uncached<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<(Message, Option<Cidr>), ResolveError> {
+    let forward_zone = self
+      .forward_zones
+      .iter()
+      .filter(|zone| zone.contains(question.qname()))
+      .max_by_key(|zone| zone.name.label_count());
+    let upstreams = match (forward_zone, self.forwarder) {
+      (Some(zone), _) => {
+        debug!("Forwarding {question} to the servers for {}", zone.name);
+        Some(zone.servers.clone())
+      }
+      (None, Some(forwarder)) => Some(vec![forwarder]),
+      (None, None) => None,
+    };
+    let response = match upstreams {
+      // the upstream does the recursion for us, so its answer is final
+      Some(upstreams) => {
+        let (upstream, response) =
+          self.lookup_any(question, &upstreams, dnssec_ok, subnet, budget)?;
+        self.trace_step(depth, None, upstream, question, &response)?;
+        response
+      }
+      None => self.walk_delegations(question, dnssec_ok, subnet, depth, budget)?,
+    };
+    let response = self.clamp_ttls(response)?;
+    let scope = answer_scope(&response, subnet)?;
+    // an answer for some clients mustn't be given to others through the record store
+    if scope.is_some() {
+      return Ok((response, scope));
+    }
+
+    let qname = question.qname().to_dname::<Octets>()?;
+    let trust = if response.header().aa() {
+      Trust::Answer
+    } else {
+      Trust::NonAuthoritative
+    };
+    self.records().insert_section(
+      response.answer()?,
+      trust,
+      |ttl| ttl,
+      |owner, _| *owner == qname,
+    )?;
+    Ok((response, None))
+  }
+
+  /// Resolves `key` and caches the response, joining the resolution another thread already has
+  /// running for it if there is one.
+  fn resolve_shared(
+    &mut self,
+    key: QuestionKey,
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    let in_flight = Arc::clone(&self.in_flight);
+    in_flight.run((key.clone(), dnssec_ok, subnet), || {
+      self.resolve_and_cache(&key, dnssec_ok, subnet, depth, budget)
+    })
+  }
+
+  fn resolve_and_cache(
+    &mut self,
+    key: &QuestionKey,
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    let (response, scope) = self.resolve_uncached(key, dnssec_ok, subnet, depth, budget)?;
+    if let Some(entry) = CacheEntry::new(response.clone(), dnssec_ok, |ttl| self.clamp_ttl(ttl))? {
+      if let Some(scope) = scope {
+        debug!("Cached {key} for clients in {scope}");
+        self.cache_scoped(key, scope, entry);
+        return Ok(response);
+      }
+      let mut cache = self.cache();
+      if let Some(existing) = cache.get(key) {
+        if existing.authoritative && !entry.authoritative && !existing.is_expired() {
+          debug!("Keeping the authoritative answer cached for {key}");
+          return Ok(response);
+        }
+      }
+      cache.put(key.clone(), Arc::new(entry));
+      drop(cache);
+      debug!(
+        "Cached {key} ({}/{} entries)",
+        self.cache_len(),
+        self.cache_capacity()
+      );
+    }
+    Ok(response)
+  }
+
+  /// Caches `entry` as the answer to `key` for clients in `scope`, replacing any answer cached
+  /// for the same scope. Expired answers are dropped, and the oldest once there are more than
+  /// `MAX_SCOPED_ANSWERS`.
+  fn cache_scoped(&self, key: &QuestionKey, scope: Cidr, entry: CacheEntry) {
+    let mut cache = self.scoped_cache();
+    let mut entries = cache.get_mut(key).map(std::mem::take).unwrap_or_default();
+    entries.retain(|(cached, entry)| *cached != scope && !entry.is_expired());
+    entries.push((scope, entry));
+    if entries.len() > MAX_SCOPED_ANSWERS {
+      entries.remove(0);
+    }
+    cache.insert(key.clone(), entries);
+  }
+
+  /// Limits `ttl` to between `min_ttl` and `max_ttl`.
+  fn clamp_ttl(&self, ttl: u32) -> u32 {
+    ttl.max(self.min_ttl).min(self.max_ttl)
+  }
+
+  /// Limits `ttl` to between `min_referral_ttl` and `max_referral_ttl`.
+  fn clamp_referral_ttl(&self, ttl: u32) -> u32 {
+    ttl.max(self.min_referral_ttl).min(self.max_referral_ttl)
+  }
+
+  /// Limits every TTL in `response` with `clamp_ttl`, so the TTLs served match how long the
+  /// response is cached. The response is only rebuilt if a TTL has to change.
+  fn clamp_ttls(&self, response: Message) -> Result<Message> {
+    let (_, answers, authorities, additionals) = response.sections()?;
+    let clamped = answers
+      .chain(authorities)
+      .chain(additionals)
+      .filter_map(|record| record.ok())
+      .all(|record| record.rtype() == Rtype::Opt || self.clamp_ttl(record.ttl()) == record.ttl());
+    if clamped {
+      return Ok(response);
+    }
+    map_ttls(&response, |ttl| self.clamp_ttl(ttl))
+  }
+
+  /// Refreshes the cached response to `key` on another thread, so it's replaced before expiring.
+  fn start_prefetch(&self, key: QuestionKey, dnssec_ok: bool) {
+    debug!("Prefetching {key}");
+    let mut server = self.fork();
+    thread::spawn(move || {
+      let budget = server.budget();
+      if let Err(e) = server.resolve_shared(key.clone(), dnssec_ok, None, 0, &budget) {
+        warn!("Prefetching {key} failed: {e:#}");
+      }
+    });
+  }
+
+  /// Follows referrals starting at the root until a server gives a final response to `question`.
+  ///
+  /// With `qname_minimization`, each server is asked for the NS records of the name one label
+  /// below its zone instead, until that name is the whole of `question`'s. A referral moves on to
+  /// the next zone, and a response without one means there's no zone cut at that name, so the next
+  /// query reveals another label. Anything else, such as NXDOMAIN, is left to the full question to
+  /// find out, since only its response can be returned.
+  ///
+  /// A `subnet` is sent to every server but the root's, which only ever refer onwards.
+  fn walk_delegations<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    // DS records are served by the zone above the one they're for, so that's where to start
+    let mut qname: Dname<Octets> = question.qname().to_dname()?;
+    if question.qtype() == Rtype::Ds {
+      if let Some(parent) = qname.iter_suffixes().nth(1) {
+        qname = parent.to_dname()?;
+      }
+    }
+    let (mut servers, zone) = match self.closest_delegation(&qname)? {
+      Some((servers, zone)) => {
+        debug!("Starting resolution of {question} at {zone}");
+        (servers, zone)
+      }
+      None => (self.root_servers(), Dname::root_vec()),
+    };
+    self.order_servers(&mut servers);
+    self.follow_referrals(question, (servers, zone), dnssec_ok, subnet, depth, budget)
+  }
+
+  /// Follows referrals for `question` until a server gives a final response, starting from
+  /// `start`, the servers for a zone to ask the way down. Servers for any zone but the root come
+  /// from what was learned earlier, so if they fail, resolution starts over from the root.
+  fn follow_referrals<N: ToDname + Display>(
+    &mut self,
+    question: &Question<N>,
+    start: (Vec<SocketAddr>, Dname<Octets>),
+    dnssec_ok: bool,
+    subnet: Option<Cidr>,
+    depth: usize,
+    budget: &Budget,
+  ) -> Result<Message, ResolveError> {
+    let (mut servers, mut zone) = start;
+    let mut from_store = !zone.is_root();
+    // Asking a server the same question twice gets the same referral back, so a repeat means the
+    // delegations go in a circle. Chains that only ever move to new servers are cut off too.
+    let mut asked = HashSet::new();
+    let mut referrals = 0;
+    let full = Question::new(
+      question.qname().to_dname::<Octets>()?,
+      question.qtype(),
+      question.qclass(),
+    );
+    // how many labels below `zone` the next minimized query reveals, if still minimizing
+    let mut revealed = self.qname_minimization.then_some(1);
+    let mut minimized_queries = 0;
+
+    loop {
+      let minimized = match revealed {
+        Some(labels) => minimized_question(&full, &zone, labels)?,
+        None => None,
+      };
+      let sent = minimized.as_ref().unwrap_or(&full);
+      let sent_subnet = subnet.filter(|_| !zone.is_root());
+      self.server_stats.skip_lame(&mut servers, &zone);
+      let (server, response) = match self.lookup_any(sent, &servers, dnssec_ok, sent_subnet, budget)
+      {
+        Ok(response) => response,
+        // some servers fail queries for names they don't expect to be asked about on their own
+        Err(ResolveError::ServerFailed(..)) if minimized.is_some() => {
+          debug!("Minimized query for {question} failed, sending the whole name instead");
+          revealed = None;
+          continue;
+        }
+        // the servers we remembered may have gone away since, so start over from the root
+        Err(e) if from_store && !matches!(e, ResolveError::BudgetExceeded(_)) => {
+          warn!("Known servers for {zone} failed, resolving {question} from the root: {e:#}");
+          servers = self.root_servers();
+          self.order_servers(&mut servers);
+          zone = Dname::root_vec();
+          from_store = false;
+          revealed = revealed.map(|_| 1);
+          continue;
+        }
+        Err(e) => return Err(e),
+      };
+      from_store = false;
+      self.trace_step(depth, Some(&zone), server, sent, &response)?;
+      if !asked.insert((server, sent.qname().clone())) {
+        return Err(ResolveError::ReferralLoop(question.to_string()));
+      }
+      match self.get_next_server(&response, sent, &zone, depth, budget)? {
+        NextStep::Referral {
+          servers: next_servers,
+          zone: next_zone,
+        } => {
+          referrals += 1;
+          if referrals > MAX_REFERRALS {
+            return Err(ResolveError::ReferralLoop(question.to_string()));
+          }
+          self.metrics.referrals.inc();
+          servers = next_servers;
+          zone = next_zone;
+          revealed = revealed.map(|_| 1);
+        }
+        NextStep::Lame => {
+          warn!("{server} is lame for {zone}, so it's passed over for a while");
+          self.server_stats.mark_lame(server.ip(), &zone);
+          servers.retain(|&other| other != server);
+          if servers.is_empty() {
+            return Err(ResolveError::LameDelegation(zone.to_string()));
+          }
+        }
+        NextStep::Done | NextStep::Alias if minimized.is_some() => {
+          minimized_queries += 1;
+          let rcode = response.header().rcode();
+          revealed = revealed
+            .filter(|_| rcode == Rcode::NoError && minimized_queries < MAX_MINIMIZED_QUERIES)
+            .map(|labels| labels + 1);
+        }
+        NextStep::Alias => {
+          debug!("{question} is an alias, so resolution continues at its target");
+          return Ok(response);
+        }
+        NextStep::Done => return Ok(response),
+      }
+    }
+  }
+
+  /// Builds the response to a query received from `client` over `protocol`. Clients that
+  /// aren't allowed to query this server, or send queries faster than the rate limit allows, are
+  /// refused.
+  ///
+  /// UDP responses are kept within the payload size the client advertised, or 512 bytes without
+  /// EDNS. If the answer or authority records don't fit, TC is set so the client retries over
+  /// TCP; additional records are simply left out, since they aren't required (RFC 2181 section 9).
+  ///
+  /// A query whose question can't be parsed gets FormErr under the query's ID, while one too
+  /// short to even hold a header gets no response at all, since nothing in it could tie a
+  /// response to the query.
+  ///
+  /// `request` has to be exactly the message received, and nothing more. The serving loops
+  /// receive every datagram into one buffer that's reused without being cleared, so past the end
+  /// of the current datagram it may still hold bytes of an earlier, longer one; a request whose
+  /// header counts more records than it holds would have those bytes parsed as its own. Callers
+  /// pass only the length `recv_from` reported.
+  fn handle_query(
+    &mut self,
+    request: &[u8],
+    client: SocketAddr,
+    protocol: Protocol,
+  ) -> Result<Option<Octets>> {
+    let span = info_span!("query", %client, ?protocol, question = field::Empty);
+    let _entered = span.enter();
+    self.metrics.queries.inc();
+    let Ok(request) = Request::from_octets(request) else {
+      debug!(
+        "Dropping a {}-byte message from {client} with no header",
+        request.len()
+      );
+      return Ok(None);
+    };
+    let udp_queries = Arc::clone(&self.udp_queries);
+    let _claim = match udp_query_key(&request, client, protocol) {
+      Some(key) => match udp_queries.claim(key) {
+        Some(claim) => Some(claim),
+        None => {
+          debug!("Dropping a retransmit of a query from {client} that's still being answered");
+          self.metrics.retransmits.inc();
+          return Ok(None);
+        }
+      },
+      None => None,
+    };
+    let started = Instant::now();
+    let mut cache = None;
+    let response = self.answer_request(&request, client, protocol, &span, &mut cache)?;
+    if let Some(log) = &self.query_log {
+      let question = request.sole_question().ok();
+      let header = base::Message::from_octets(response.as_slice())?;
+      let record = QueryRecord {
+        timestamp: querylog::now(),
+        client: client.ip(),
+        qname: question.map(|question| question.qname().to_string()),
+        qtype: question.map(|question| question.qtype().to_string()),
+        rcode: header.header().rcode().to_string(),
+        answers: header.header_counts().ancount(),
+        cache: cache.map(|hit| if hit { "hit" } else { "miss" }),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+      };
+      if let Err(e) = log.write(&record) {
+        warn!("Failed to write to the query log: {e}");
+      }
+    }
+    Ok(Some(response))
+  }
+
+  /// Builds the response to a query whose header has been parsed, for `handle_query`. If the
+  /// query is resolved, `cache` is set to whether that was done without asking another server.
+  fn answer_request(
+    &mut self,
+    request: &Request,
+    client: SocketAddr,
+    protocol: Protocol,
+    span: &Span,
+    cache: &mut Option<bool>,
+  ) -> Result<Octets> {
+    if let Some(allowed) = &self.allowed_clients {
+      if !allowed.iter().any(|range| range.contains(client.ip())) {
+        debug!("Refusing query from {client}, which isn't allowed");
+        return extended_error_response(
+          request,
+          false,
+          Rcode::Refused,
+          ExtendedErrorCode::Prohibited,
+          None,
+        );
+      }
+    }
+    // whether the server recurses for this query, which RA says in every response to it
+    let recursion = request.header().rd() || self.always_recurse;
+    if let Some(limiter) = &self.rate_limiter {
+      if !limiter.allow(client.ip()) {
+        debug!("Refusing query from {client} over the rate limit");
+        self.metrics.rate_limited.inc();
+        return error_response(request, recursion, Rcode::Refused);
+      }
+    }
+    let limit = match protocol {
+      Protocol::Udp => request
+        .opt()
+        .map_or(MIN_UDP_PAYLOAD_SIZE, |opt| opt.udp_payload_size())
+        .max(MIN_UDP_PAYLOAD_SIZE),
+      Protocol::Tcp => u16::MAX,
+    };
+    let limit = usize::from(limit);
+    let edns = request.opt();
+    let dnssec_ok = edns.as_ref().is_some_and(|opt| opt.dnssec_ok());
+    // clients set CD to do their own validation (RFC 4035 section 3.2.2)
+    let dnssec = if self.trust_anchors.is_some() && !request.header().cd() {
+      Dnssec::Validate
+    } else if dnssec_ok {
+      Dnssec::Records
+    } else {
+      Dnssec::Off
+    };
+    // leave room for the OPT record answering the client's
+    let limit = match edns {
+      Some(_) => limit - OPT_RECORD_LEN,
+      None => limit,
+    };
+
+    if request.header().opcode() == Opcode::Notify {
+      return self.notify_response(request, recursion, client);
+    }
+    if request.header().opcode() != Opcode::Query {
+      debug!(
+        "Not answering {} request from {client}",
+        request.header().opcode()
+      );
+      return error_response(request, recursion, Rcode::NotImp);
+    }
+
+    // RFC 1035 allows a query to hold several questions, but a response has a single rcode and
+    // answer section to share between them, so nothing sends them in practice. Only exactly one
+    // question is supported; more get NotImp, and none at all is malformed.
+    let question = match request.header_counts().qdcount() {
+      0 => return error_response(request, recursion, Rcode::FormErr),
+      1 => match request.sole_question() {
+        Ok(question) => question,
+        Err(e) => {
+          debug!("Query from {client} has a malformed question: {e}");
+          return error_response(request, recursion, Rcode::FormErr);
+        }
+      },
+      _ => {
+        debug!("Not answering query from {client} with several questions");
+        return error_response(request, recursion, Rcode::NotImp);
+      }
+    };
+
+    span.record("question", field::display(&question));
+
+    if question.qclass() == Class::Ch {
+      return self.chaos_response(request, recursion, &question);
+    }
+    if question.qtype() == Rtype::Any && self.minimal_any {
+      return minimal_any_response(request, recursion, &question);
+    }
+
+    // lookup
+    let started = Instant::now();
+    let subnet = self
+      .client_subnet
+      .map(|prefixes| prefixes.subnet_of(client.ip()));
+    // only answers from a served zone are authoritative, not ones resolved elsewhere
+    let mut authoritative = false;
+    let result = if let Some(response) = self.view_answer(client.ip(), &question)? {
+      Ok(response)
+    } else if let Some(response) = self.zone_answer(&question)? {
+      authoritative = true;
+      Ok(response)
+    } else if request.header().rd() || self.always_recurse {
+      let budget = self.budget();
+      let result = self.recurse(&question, true, dnssec, subnet, 0, &budget);
+      *cache = Some(budget.queries() == 0);
+      result
+    } else {
+      match self.local_answer(&question, dnssec, subnet) {
+        Ok(Some(response)) => {
+          *cache = Some(true);
+          Ok(response)
+        }
+        Ok(None) => {
+          debug!("Refusing non-recursive query for {question}, which has no local answer");
+          return error_response(request, recursion, Rcode::Refused);
+        }
+        Err(e) => Err(e),
+      }
+    };
+    self.metrics.observe_latency(started.elapsed());
+
+    match result {
+      Ok(result) => {
+        info!("Question: {}", question);
+        match result.header().rcode() {
+          Rcode::NXDomain => self.metrics.nxdomain.inc(),
+          Rcode::ServFail => self.metrics.servfail.inc(),
+          _ => {}
+        }
+        let (_, answers, authorities, additionals) = result.sections()?;
+        let mut response = start_response(request, recursion, result.header().rcode())?;
+        response
+          .header_mut()
+          .set_aa(authoritative && result.header().aa());
+        // only clients that show they understand AD get it (RFC 6840 section 5.8)
+        let authenticated = result.header().ad() && (dnssec_ok || request.header().ad());
+        response.header_mut().set_ad(authenticated);
+
+        // Clients that didn't set DO only get DNSSEC records they asked for by type (RFC 4035
+        // section 3.2.1), since they'd come from the cache shared with clients that did.
+        let valid_records = |it: RecordSection<_>| {
+          it.limit_to_in::<AllRecordData<<&Octets as OctetsRef>::Range, ParsedDname<&Octets>>>()
+            .filter_map(|r| r.ok())
+            .filter(|r| dnssec_ok || !is_dnssec_rtype(r.rtype()) || r.rtype() == question.qtype())
+        };
+
+        let answers = deduplicated(valid_records(answers));
+        let minimal = self.minimal_responses
+          && result.header().rcode() == Rcode::NoError
+          && !answers.is_empty();
+        let (authorities, additionals) = if minimal {
+          (Vec::new(), Vec::new())
+        } else {
+          (
+            deduplicated(valid_records(authorities)),
+            deduplicated(valid_records(additionals)),
+          )
+        };
+
+        for record in &answers {
+          debug!("Answer {}", record);
+        }
+        for record in &authorities {
+          trace!("Authority {}", record);
+        }
+        for record in &additionals {
+          trace!("Additional {}", record);
+        }
+        let (mut response, truncated) =
+          fill_sections(response, [&answers, &authorities, &additionals], limit)?;
+        if truncated {
+          info!("Truncated response to {question} at {limit} bytes");
+          response.header_mut().set_tc(true);
+        }
+        if edns.is_some() {
+          response.opt(|opt| {
+            opt.set_udp_payload_size(EDNS_UDP_PAYLOAD_SIZE);
+            opt.set_dnssec_ok(dnssec_ok);
+            Ok(())
+          })?;
+        }
+        Ok(response.finish().into_target())
+      }
+      Err(e) => {
+        error!("Failed to resolve {question}: {e:#}");
+        self.metrics.servfail.inc();
+        extended_error_response(
+          request,
+          recursion,
+          Rcode::ServFail,
+          e.extended_error(),
+          Some(&e.to_string()),
+        )
+      }
+    }
+  }
+
+  /// Acknowledges a NOTIFY (RFC 1996) without acting on it, since zones are only ever loaded
+  /// from files, so the server sending it stops retrying. The response echoes the question with
+  /// NOERROR, and AA if the zone named is one served here.
+  fn notify_response(
+    &self,
+    request: &Request,
+    recursion: bool,
+    client: SocketAddr,
+  ) -> Result<Octets> {
+    let question = match request.sole_question() {
+      Ok(question) => question,
+      Err(_) => return error_response(request, recursion, Rcode::FormErr),
+    };
+    debug!("Ignoring NOTIFY for {} from {client}", question.qname());
+    let mut response = start_response(request, recursion, Rcode::NoError)?;
+    // A NOTIFY names the apex of the zone that changed, so only our own zones' origins make
+    // the response authoritative, not names anywhere inside them.
+    let served = self
+      .zones
+      .iter()
+      .any(|zone| zone.origin == question.qname());
+    response.header_mut().set_aa(served);
+    Ok(response.finish().into_target())
+  }
+
+  /// Answers a CHAOS class query, which asks about the server itself rather than anything in the
+  /// DNS. TXT queries for `version.bind` and `id.server` are answered with `identity`; other
+  /// names are refused rather than resolved, since there's nowhere to resolve them.
+  fn chaos_response<N: ToDname + Display>(
+    &self,
+    request: &Request,
+    recursion: bool,
+    question: &Question<N>,
+  ) -> Result<Octets> {
+    let name = question.qname().to_string();
+    if !CHAOS_NAMES
+      .iter()
+      .any(|known| name.eq_ignore_ascii_case(known))
+    {
+      debug!("Refusing CHAOS query for {question}");
+      return error_response(request, recursion, Rcode::Refused);
+    }
+
+    let mut response = start_response(request, recursion, Rcode::NoError)?;
+    response.header_mut().set_aa(true);
+    if matches!(question.qtype(), Rtype::Txt | Rtype::Any) {
+      let txt = Txt::<Octets>::from_slice(self.identity.as_bytes())?;
+      response.push((question.qname(), Class::Ch, 0, txt))?;
+    }
+    Ok(response.finish().into_target())
+  }
+}
+
+/// Starts a response to `request` with its ID, opcode and those of its questions that parse. RD
+/// and CD are echoed back as RFC 1035 and RFC 4035 section 3.2.2 ask, and RA is set if the server
+/// recurses for the query, as `recursion` says. It doesn't for clients it refuses, or for queries
+/// without RD unless it always recurses. AD is left clear for the caller to set once it knows the
+/// answer validated.
+fn start_response(
+  request: &Request,
+  recursion: bool,
+  rcode: Rcode,
+) -> Result<AnswerBuilder<ResponseTarget>> {
+  let target = TreeCompressor::new(Vec::new());
+  let mut response = base::MessageBuilder::from_target(target)?.start_answer(request, rcode)?;
+  let header = response.header_mut();
+  header.set_ra(recursion);
+  header.set_cd(request.header().cd());
+  Ok(response)
+}
+
+/// Answers an ANY query with a single HINFO record whose CPU is "RFC8482" and whose OS is empty,
+/// the conventional minimal response of RFC 8482 section 4.2, without looking the name up.
+fn minimal_any_response<N: ToDname + Display>(
+  request: &Request,
+  recursion: bool,
+  question: &Question<N>,
+) -> Result<Octets> {
+  debug!("Answering {question} with a minimal response");
+  let mut response = start_response(request, recursion, Rcode::NoError)?;
+  let hinfo = Hinfo::new(
+    CharStr::from_octets(b"RFC8482".to_vec())?,
+    CharStr::from_octets(Vec::new())?,
+  );
+  response.push((question.qname(), question.qclass(), MINIMAL_ANY_TTL, hinfo))?;
+  Ok(response.finish().into_target())
+}
+
+/// What identifies `request` across retransmits, if it came over UDP and its question parses.
+/// Queries over TCP aren't retransmitted, since the connection delivers them.
+fn udp_query_key(
+  request: &Request,
+  client: SocketAddr,
+  protocol: Protocol,
+) -> Option<ClientQueryKey> {
+  if protocol != Protocol::Udp {
+    return None;
+  }
+  let question = request.sole_question().ok()?;
+  let qname = question.qname().to_dname::<Octets>().ok()?;
+  let key = Question::new(qname, question.qtype(), question.qclass());
+  Some((client, request.header().id(), key))
+}
+
+/// Builds a response to `request` carrying nothing but `rcode` and the request's questions.
+fn error_response(request: &Request, recursion: bool, rcode: Rcode) -> Result<Octets> {
+  Ok(
+    start_response(request, recursion, rcode)?
+      .finish()
+      .into_target(),
+  )
+}
+
+/// Builds an error response like `error_response`, but saying why the query failed with an
+/// Extended DNS Error (RFC 8914), along with `text` if given. Only a client that sent an OPT
+/// record can be sent one back, so other clients just get `rcode`.
+fn extended_error_response(
+  request: &Request,
+  recursion: bool,
+  rcode: Rcode,
+  code: ExtendedErrorCode,
+  text: Option<&str>,
+) -> Result<Octets> {
+  let edns = match request.opt() {
+    Some(edns) => edns,
+    None => return error_response(request, recursion, rcode),
+  };
+  let mut error = ExtendedError::from(code);
+  if let Some(text) = text {
+    let mut end = text.len().min(MAX_EXTRA_TEXT_LEN);
+    while !text.is_char_boundary(end) {
+      end -= 1;
+    }
+    error.set_text(text.as_bytes()[..end].to_vec())?;
+  }
+  let mut response = start_response(request, recursion, rcode)?.additional();
+  response.opt(|opt| {
+    opt.set_udp_payload_size(EDNS_UDP_PAYLOAD_SIZE);
+    opt.set_dnssec_ok(edns.dnssec_ok());
+    opt.push(&error)
+  })?;
+  Ok(response.finish().into_target())
+}
@@ -1,253 +1,1150 @@
 use std::{
-  collections::HashMap,
-  fmt::Display,
-  net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+  env,
+  ffi::OsString,
+  fmt::Write,
+  io::{self, IsTerminal},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+  path::PathBuf,
+  str::FromStr,
+  sync::Arc,
+  thread,
+  time::Duration,
 };
 
-use anyhow::Result;
-use domain::{
-  base::{
-    self, iana::Rcode, octets::OctetsRef, Dname, ParsedDname, Question, RecordSection, ToDname,
+use anyhow::{ensure, Context, Result};
+use clap::Parser;
+use dns::{
+  acl::Cidr,
+  config::Config,
+  control, dnssec,
+  forward::ForwardZone,
+  hints,
+  hosts::Hosts,
+  metrics,
+  outstanding::QueryLimiter,
+  querylog::QueryLog,
+  ratelimit::RateLimiter,
+  selection::Selection,
+  serve::{self, Shutdown},
+  transport::{
+    BufferPool, ConnectionPool, DohTransport, DotTransport, NetworkTransport, Transport,
   },
-  rdata::{AllRecordData, Ns, A},
+  views::View,
+  zone::Zone,
+  DnsServer, Message, NoopCache, Octets, SubnetPrefixes, TraceStep, DEFAULT_CLIENT_SUBNET,
+  DEFAULT_IDENTITY, DEFAULT_MAX_CACHE_ENTRIES, DEFAULT_MAX_DEPTH, DEFAULT_MAX_OUTSTANDING_QUERIES,
+  DEFAULT_MAX_TTL, DEFAULT_MAX_UPSTREAM_QUERIES, DEFAULT_NDOTS, DEFAULT_RESOLUTION_TIMEOUT,
+  DEFAULT_TIMEOUT, DEFAULT_UDP_RETRIES, DEFAULT_UPSTREAM_IDLE_TIMEOUT,
 };
-use rand::prelude::*;
-
-type Octets = Vec<u8>;
-type Message = base::Message<Octets>;
-type MessageBuilder = base::MessageBuilder<Octets>;
+use domain::{
+  base::{iana::Rcode, iana::Rtype, octets::OctetsRef, Dname, ParsedDname, RecordSection},
+  rdata::{AllRecordData, Ns},
+};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
-// Root name server
-const ROOT_NAMESERVER: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 4);
-const DNS_PORT: u16 = 53;
 const LOCAL_PORT: u16 = 20053;
-const OUTBOUND_PORT: u16 = 43210;
+// Default number of threads answering UDP queries on each address
+const DEFAULT_WORKERS: usize = 4;
+// Log level used unless RUST_LOG or the configuration says otherwise
+const DEFAULT_LOG_LEVEL: &str = "info";
+// Default time after expiring that a cached answer may still be served stale
+const DEFAULT_STALE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+// Default fraction of a cached answer's lifetime left when it's refreshed in the background
+const DEFAULT_PREFETCH_THRESHOLD: f64 = 0.1;
+
+#[derive(Parser)]
+#[clap(about = "A recursive DNS resolver")]
+struct Args {
+  /// Read settings from a TOML file. Flags given on the command line take precedence
+  #[clap(long, value_name = "PATH")]
+  config: Option<PathBuf>,
+  /// Address to listen for queries on [default: :: and 0.0.0.0]
+  #[clap(long, value_name = "ADDR")]
+  listen_addr: Option<IpAddr>,
+  /// Port to listen for queries on, over both UDP and TCP [default: 20053]
+  #[clap(long, value_name = "PORT")]
+  listen_port: Option<u16>,
+  /// Send outbound queries from this port instead of a random one per query. Only one query can
+  /// use the port at a time, so this is best combined with `--workers 1`
+  #[clap(long, value_name = "PORT")]
+  outbound_port: Option<u16>,
+  /// Send outbound queries over UDP from this address, for name servers of its family. May be
+  /// given once for IPv4 and once for IPv6
+  #[clap(long, value_name = "IP")]
+  outbound_addr: Vec<IpAddr>,
+  /// Forward all queries to this upstream resolver instead of recursing from the root
+  #[clap(long, value_name = "ADDR:PORT")]
+  forward: Option<SocketAddr>,
+  /// Forward over DNS over TLS, to an upstream with a certificate for this name. The port given
+  /// with `--forward` is usually 853
+  #[clap(long, value_name = "NAME")]
+  forward_tls: Option<String>,
+  /// Forward over DNS over HTTPS, posting queries to this URL, such as
+  /// https://dns.example/dns-query. They're sent to the address given with `--forward`, usually
+  /// on port 443, rather than one looked up for the URL's host
+  #[clap(long, value_name = "URL")]
+  forward_https: Option<String>,
+  /// Accept any certificate from the `--forward-tls` or `--forward-https` upstream instead of
+  /// checking it
+  #[clap(long)]
+  forward_tls_insecure: bool,
+  /// Load the root server addresses from a named.root style hints file
+  #[clap(long, value_name = "PATH")]
+  root_hints: Option<PathBuf>,
+  /// Answer names listed in this hosts file with their addresses instead of resolving them
+  #[clap(long, value_name = "PATH")]
+  hosts: Option<PathBuf>,
+  /// Append a line of JSON describing each client query answered to this file, or to stdout if
+  /// it's `-`
+  #[clap(long, value_name = "PATH")]
+  query_log: Option<PathBuf>,
+  /// Save the cache to this file on shutdown, and start with the answers saved there that are
+  /// still valid
+  #[clap(long, value_name = "PATH")]
+  cache_file: Option<PathBuf>,
+  /// Number of threads answering UDP queries on each address listened on [default: 4]
+  #[clap(long)]
+  workers: Option<usize>,
+  /// Refresh popular cache entries in the background shortly before they expire
+  #[clap(long)]
+  prefetch: bool,
+  /// Answer from expired cache entries when resolving a question again fails
+  #[clap(long)]
+  serve_stale: bool,
+  /// Resolve queries that don't ask for recursion, instead of only answering them from the cache
+  #[clap(long)]
+  always_recurse: bool,
+  /// Randomize the case of names in outbound queries and drop responses that don't repeat it,
+  /// which makes spoofing harder. Some servers don't preserve the case, and can't be used with this
+  #[clap(long)]
+  randomize_case: bool,
+  /// Only tell each server the name being resolved as far as it needs to refer onwards, asking
+  /// for the NS records of one more label at a time (RFC 7816)
+  #[clap(long)]
+  qname_minimization: bool,
+  /// Ignore the addresses referrals give for name servers outside the zone being delegated, and
+  /// look them up instead. Slower, but gets around servers sending bad glue
+  #[clap(long)]
+  ignore_glue: bool,
+  /// How to pick the name servers asked first among those for a zone: `random`, `round-robin`,
+  /// or `fastest` by the round trip times measured so far [default: random]
+  #[clap(long, value_name = "POLICY")]
+  server_selection: Option<Selection>,
+  /// Resolve ANY queries like any other type, instead of answering them with a single HINFO
+  /// record as RFC 8482 suggests
+  #[clap(long)]
+  resolve_any: bool,
+  /// Send positive answers with only their answer section, leaving out the authority and
+  /// additional records that came with them. Negative answers and referrals are sent whole
+  #[clap(long)]
+  minimal_responses: bool,
+  /// Pass on the subnet of each client's address to the servers resolving its queries, so they
+  /// can answer with addresses close to the client (RFC 7871). The first 24 bits of IPv4
+  /// addresses and 56 of IPv6 ones are passed on, unless the configuration says otherwise
+  #[clap(long)]
+  client_subnet: bool,
+  /// Validate answers with DNSSEC. Secure answers get the AD bit, and ones that fail validation
+  /// get SERVFAIL, unless the client sets CD
+  #[clap(long)]
+  dnssec: bool,
+  /// Start DNSSEC validation from the DS records in this file instead of the root zone's keys
+  #[clap(long, value_name = "PATH")]
+  trust_anchor: Option<PathBuf>,
+  /// Only answer clients in this address range, such as 192.168.0.0/16. May be repeated
+  #[clap(long, value_name = "CIDR")]
+  allow: Vec<Cidr>,
+  /// Queries per second each client may send on average. Without this, clients aren't limited
+  #[clap(long, value_name = "QPS")]
+  rate_limit: Option<f64>,
+  /// Queries each client may send at once before the rate limit applies [default: the rate]
+  #[clap(long, value_name = "QUERIES")]
+  rate_limit_burst: Option<u32>,
+  /// Serve Prometheus metrics over HTTP on this port, at the listen address
+  #[clap(long, value_name = "PORT")]
+  metrics_port: Option<u16>,
+  /// Accept commands to inspect and flush the cache on this port on localhost, one per line over
+  /// TCP: `dump`, `flush` or `flush NAME`, or `servers` to list how the name servers asked have
+  /// been responding
+  #[clap(long, value_name = "PORT")]
+  control_port: Option<u16>,
+  /// Least severe messages to log, such as `warn` or `debug`. RUST_LOG takes precedence
+  /// [default: info]
+  #[clap(long, value_name = "LEVEL")]
+  log_level: Option<String>,
+  /// Log how long each client query took as it finishes. At the debug level, so does each nested
+  /// resolution and each query to another server. Messages logged along the way are prefixed
+  /// with the query, resolution and lookup they're part of either way
+  #[clap(long)]
+  log_spans: bool,
+  /// Resolve this name once, print the response the way dig does and exit, instead of serving
+  /// queries
+  #[clap(long, value_name = "NAME")]
+  query: Option<String>,
+  /// Record type to ask for with `--query`, such as MX or TXT
+  #[clap(long = "type", value_name = "TYPE", default_value = "A")]
+  rtype: Rtype,
+  /// Also try a `--query` name without a trailing dot with this domain appended, moving on to the
+  /// next one given whenever a name doesn't exist, like the search list of resolv.conf. May be
+  /// repeated
+  #[clap(long, value_name = "DOMAIN")]
+  search: Vec<String>,
+  /// Dots a `--query` name needs to be tried as it is before the search domains rather than
+  /// after them [default: 1]
+  #[clap(long, value_name = "DOTS")]
+  ndots: Option<usize>,
+  /// Print each response `--query` gets from other servers on the way to its answer, like
+  /// `dig +trace` does: the referrals with their name servers and glue, then the answer
+  #[clap(long, requires = "query")]
+  trace: bool,
+}
+
+/// Settings the server is started with, after applying defaults.
+#[derive(Debug)]
+struct Settings {
+  /// Where to listen for queries, over both UDP and TCP.
+  listen: Vec<SocketAddr>,
+  outbound_port: Option<u16>,
+  outbound_addrs: Vec<IpAddr>,
+  forward: Option<SocketAddr>,
+  /// The name the forwarder's certificate must be issued for, if forwarding over TLS.
+  forward_tls: Option<String>,
+  /// The URL to post queries to, if forwarding over HTTPS.
+  forward_https: Option<String>,
+  forward_tls_verify: bool,
+  root_hints: Option<PathBuf>,
+  hosts: Option<PathBuf>,
+  query_log: Option<PathBuf>,
+  workers: usize,
+  metrics_port: Option<u16>,
+  control_port: Option<u16>,
+  allow: Option<Vec<Cidr>>,
+  views: Vec<View>,
+  zones: Vec<Zone>,
+  forward_zones: Vec<ForwardZone>,
+  rate_limit: Option<f64>,
+  rate_limit_burst: Option<u32>,
+  /// Fraction of a cached answer's lifetime below which hits refresh it, if prefetching at all.
+  prefetch: Option<f64>,
+  /// How long after expiring a cached answer may be served, if serving stale answers at all.
+  serve_stale: Option<Duration>,
+  always_recurse: bool,
+  randomize_case: bool,
+  qname_minimization: bool,
+  ignore_glue: bool,
+  server_selection: Selection,
+  minimal_any: bool,
+  minimal_responses: bool,
+  /// How much of client addresses to pass on, if passing on client subnets at all.
+  client_subnet: Option<SubnetPrefixes>,
+  dnssec: bool,
+  trust_anchor: Option<PathBuf>,
+  cache_size: usize,
+  cache_file: Option<PathBuf>,
+  timeout: Duration,
+  upstream_idle_timeout: Duration,
+  max_depth: usize,
+  resolution_timeout: Duration,
+  max_upstream_queries: usize,
+  max_outstanding_queries: usize,
+  udp_retries: usize,
+  min_ttl: u32,
+  max_ttl: u32,
+  min_referral_ttl: u32,
+  max_referral_ttl: u32,
+  identity: String,
+  search: Vec<Dname<Octets>>,
+  ndots: usize,
+  log_level: String,
+  log_spans: bool,
+  /// The name and type to resolve once instead of serving queries.
+  query: Option<(String, Rtype)>,
+  /// Whether to print the steps taken to resolve `query`.
+  trace: bool,
+}
+
+/// Parses command line arguments, including the program name, into the server's settings. If
+/// a configuration file is given, it's read first and then overridden by the other arguments.
+fn build_config<I, T>(args: I) -> Result<Settings>
+where
+  I: IntoIterator<Item = T>,
+  T: Into<OsString> + Clone,
+{
+  let args = Args::try_parse_from(args)?;
+  let file = match &args.config {
+    Some(path) => Config::load(path)?,
+    None => Config::default(),
+  };
+  let query = args.query.map(|name| (name, args.rtype));
+  let config = file.merge(Config {
+    listen_addr: args.listen_addr,
+    listen_port: args.listen_port,
+    outbound_port: args.outbound_port,
+    outbound_addrs: Some(args.outbound_addr).filter(|addrs| !addrs.is_empty()),
+    forward: args.forward,
+    forward_tls_name: args.forward_tls,
+    forward_https_url: args.forward_https,
+    forward_tls_verify: Some(false).filter(|_| args.forward_tls_insecure),
+    root_hints: args.root_hints,
+    hosts: args.hosts,
+    query_log: args.query_log,
+    cache_file: args.cache_file,
+    workers: args.workers,
+    metrics_port: args.metrics_port,
+    control_port: args.control_port,
+    allow: Some(args.allow).filter(|allow| !allow.is_empty()),
+    search: Some(args.search).filter(|search| !search.is_empty()),
+    ndots: args.ndots,
+    prefetch: Some(true).filter(|_| args.prefetch),
+    serve_stale: Some(true).filter(|_| args.serve_stale),
+    always_recurse: Some(true).filter(|_| args.always_recurse),
+    randomize_case: Some(true).filter(|_| args.randomize_case),
+    qname_minimization: Some(true).filter(|_| args.qname_minimization),
+    ignore_glue: Some(true).filter(|_| args.ignore_glue),
+    server_selection: args.server_selection,
+    minimal_any: Some(false).filter(|_| args.resolve_any),
+    minimal_responses: Some(true).filter(|_| args.minimal_responses),
+    client_subnet: Some(true).filter(|_| args.client_subnet),
+    dnssec: Some(true).filter(|_| args.dnssec),
+    trust_anchor: args.trust_anchor,
+    rate_limit: args.rate_limit,
+    rate_limit_burst: args.rate_limit_burst,
+    log_level: args.log_level,
+    log_spans: Some(true).filter(|_| args.log_spans),
+    ..Config::default()
+  });
 
-struct DnsServer<R: Rng + ?Sized> {
-  pub cache: HashMap<Question<Dname<Octets>>, Message>,
-  pub socket: UdpSocket,
-  pub rng: R,
+  let min_ttl = config.min_ttl.unwrap_or(0);
+  let max_ttl = config.max_ttl.unwrap_or(DEFAULT_MAX_TTL);
+  ensure!(
+    min_ttl <= max_ttl,
+    "min_ttl ({min_ttl}) is greater than max_ttl ({max_ttl})"
+  );
+  let min_referral_ttl = config.min_referral_ttl.unwrap_or(0);
+  let max_referral_ttl = config.max_referral_ttl.unwrap_or(DEFAULT_MAX_TTL);
+  ensure!(
+    min_referral_ttl <= max_referral_ttl,
+    "min_referral_ttl ({min_referral_ttl}) is greater than max_referral_ttl ({max_referral_ttl})"
+  );
+  let ipv4_prefix = config
+    .client_subnet_ipv4_prefix
+    .unwrap_or(DEFAULT_CLIENT_SUBNET.ipv4);
+  let ipv6_prefix = config
+    .client_subnet_ipv6_prefix
+    .unwrap_or(DEFAULT_CLIENT_SUBNET.ipv6);
+  ensure!(
+    ipv4_prefix <= 32,
+    "client_subnet_ipv4_prefix ({ipv4_prefix}) is longer than an IPv4 address"
+  );
+  ensure!(
+    ipv6_prefix <= 128,
+    "client_subnet_ipv6_prefix ({ipv6_prefix}) is longer than an IPv6 address"
+  );
+  let outbound_addrs = config.outbound_addrs.unwrap_or_default();
+  ensure!(
+    outbound_addrs.iter().filter(|addr| addr.is_ipv4()).count() <= 1
+      && outbound_addrs.iter().filter(|addr| addr.is_ipv6()).count() <= 1,
+    "outbound_addrs has more than one address of the same family"
+  );
+  ensure!(
+    config.forward.is_some() || config.forward_tls_name.is_none(),
+    "forward_tls_name is set without an upstream to forward to"
+  );
+  ensure!(
+    config.forward.is_some() || config.forward_https_url.is_none(),
+    "forward_https_url is set without an upstream to forward to"
+  );
+  ensure!(
+    config.forward_tls_name.is_none() || config.forward_https_url.is_none(),
+    "forward_tls_name and forward_https_url can't both be set"
+  );
+
+  Ok(Settings {
+    listen: match config.listen_addr {
+      Some(addr) => vec![addr],
+      None => vec![Ipv6Addr::UNSPECIFIED.into(), Ipv4Addr::UNSPECIFIED.into()],
+    }
+    .into_iter()
+    .map(|addr| SocketAddr::new(addr, config.listen_port.unwrap_or(LOCAL_PORT)))
+    .collect(),
+    outbound_port: config.outbound_port,
+    outbound_addrs,
+    forward: config.forward,
+    forward_tls: config.forward_tls_name,
+    forward_https: config.forward_https_url,
+    forward_tls_verify: config.forward_tls_verify.unwrap_or(true),
+    root_hints: config.root_hints,
+    hosts: config.hosts,
+    query_log: config.query_log,
+    workers: config.workers.unwrap_or(DEFAULT_WORKERS).max(1),
+    metrics_port: config.metrics_port,
+    control_port: config.control_port,
+    allow: config.allow,
+    views: config
+      .views
+      .iter()
+      .flatten()
+      .map(View::from_config)
+      .collect::<Result<_>>()?,
+    zones: config
+      .zones
+      .iter()
+      .flatten()
+      .map(Zone::from_config)
+      .collect::<Result<_>>()?,
+    forward_zones: config
+      .forward_zones
+      .iter()
+      .flatten()
+      .map(ForwardZone::from_config)
+      .collect::<Result<_>>()?,
+    prefetch: config.prefetch.unwrap_or(false).then(|| {
+      config
+        .prefetch_threshold
+        .unwrap_or(DEFAULT_PREFETCH_THRESHOLD)
+    }),
+    serve_stale: config.serve_stale.unwrap_or(false).then(|| {
+      config
+        .stale_window_secs
+        .map_or(DEFAULT_STALE_WINDOW, Duration::from_secs)
+    }),
+    always_recurse: config.always_recurse.unwrap_or(false),
+    randomize_case: config.randomize_case.unwrap_or(false),
+    qname_minimization: config.qname_minimization.unwrap_or(false),
+    ignore_glue: config.ignore_glue.unwrap_or(false),
+    server_selection: config.server_selection.unwrap_or_default(),
+    minimal_any: config.minimal_any.unwrap_or(true),
+    minimal_responses: config.minimal_responses.unwrap_or(false),
+    client_subnet: config
+      .client_subnet
+      .unwrap_or(false)
+      .then_some(SubnetPrefixes {
+        ipv4: ipv4_prefix,
+        ipv6: ipv6_prefix,
+      }),
+    dnssec: config.dnssec.unwrap_or(false),
+    trust_anchor: config.trust_anchor,
+    rate_limit: config.rate_limit,
+    rate_limit_burst: config.rate_limit_burst,
+    cache_size: config.cache_size.unwrap_or(DEFAULT_MAX_CACHE_ENTRIES),
+    cache_file: config.cache_file,
+    timeout: config
+      .timeout_ms
+      .map_or(DEFAULT_TIMEOUT, Duration::from_millis),
+    upstream_idle_timeout: config
+      .upstream_idle_timeout_ms
+      .map_or(DEFAULT_UPSTREAM_IDLE_TIMEOUT, Duration::from_millis),
+    max_depth: config.max_depth.unwrap_or(DEFAULT_MAX_DEPTH),
+    resolution_timeout: config
+      .resolution_timeout_ms
+      .map_or(DEFAULT_RESOLUTION_TIMEOUT, Duration::from_millis),
+    max_upstream_queries: config
+      .max_upstream_queries
+      .unwrap_or(DEFAULT_MAX_UPSTREAM_QUERIES),
+    max_outstanding_queries: config
+      .max_outstanding_queries
+      .unwrap_or(DEFAULT_MAX_OUTSTANDING_QUERIES),
+    udp_retries: config.udp_retries.unwrap_or(DEFAULT_UDP_RETRIES),
+    min_ttl,
+    max_ttl,
+    min_referral_ttl,
+    max_referral_ttl,
+    identity: config
+      .identity
+      .unwrap_or_else(|| DEFAULT_IDENTITY.to_string()),
+    search: config
+      .search
+      .iter()
+      .flatten()
+      .map(|domain| {
+        Dname::from_str(domain).with_context(|| format!("invalid search domain {domain:?}"))
+      })
+      .collect::<Result<_>>()?,
+    ndots: config.ndots.unwrap_or(DEFAULT_NDOTS),
+    log_level: config
+      .log_level
+      .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string()),
+    log_spans: config.log_spans.unwrap_or(false),
+    query,
+    trace: args.trace,
+  })
 }
 
-impl DnsServer<ThreadRng> {
-  pub fn new() -> Result<DnsServer<ThreadRng>> {
-    Ok(DnsServer {
-      cache: HashMap::new(),
-      socket: UdpSocket::bind(("0.0.0.0", OUTBOUND_PORT))?,
-      rng: rand::thread_rng(),
+fn main() -> Result<()> {
+  let settings = match build_config(env::args_os()) {
+    Ok(settings) => settings,
+    // let clap print usage errors and --help the way it normally does
+    Err(e) => match e.downcast::<clap::Error>() {
+      Ok(e) => e.exit(),
+      Err(e) => return Err(e),
+    },
+  };
+  let filter =
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&settings.log_level));
+  tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_span_events(match settings.log_spans {
+      true => FmtSpan::CLOSE,
+      false => FmtSpan::NONE,
     })
+    .with_writer(io::stderr)
+    .with_ansi(io::stderr().is_terminal())
+    .init();
+
+  match (&settings.forward_tls, &settings.forward_https) {
+    (Some(name), _) => {
+      let mut transport = DotTransport::new(name, settings.forward_tls_verify)?;
+      transport.timeout = settings.timeout;
+      transport.connections = ConnectionPool::new(settings.upstream_idle_timeout);
+      run(settings, transport)
+    }
+    (None, Some(url)) => {
+      let mut transport = DohTransport::new(url, settings.forward_tls_verify)?;
+      transport.timeout = settings.timeout;
+      transport.connections = ConnectionPool::new(settings.upstream_idle_timeout);
+      run(settings, transport)
+    }
+    (None, None) => {
+      let transport = NetworkTransport {
+        timeout: settings.timeout,
+        outbound_port: settings.outbound_port,
+        outbound_addrs: settings.outbound_addrs.clone(),
+        connections: ConnectionPool::new(settings.upstream_idle_timeout),
+        buffers: BufferPool::default(),
+      };
+      run(settings, transport)
+    }
   }
 }
 
-impl<R: Rng + ?Sized> DnsServer<R> {
-  fn lookup<N: ToDname>(
-    &mut self,
-    question: &Question<N>,
-    name_server: SocketAddrV4,
-  ) -> Result<Message> {
-    let mut request = MessageBuilder::new_vec().question();
-    request.push(question)?;
-    request.header_mut().set_rd(true);
-    let bytes = request.finish();
-    self.socket.send_to(&bytes, name_server)?;
-    let mut buf = vec![0u8; 512];
-    self.socket.recv_from(&mut buf)?;
-    let response = Message::from_octets(buf)?;
-    Ok(response)
-  }
-
-  fn get_next_server<N: ToDname>(
-    &mut self,
-    response: &mut Message,
-    question: &Question<N>,
-  ) -> Result<(bool, Option<SocketAddrV4>)> {
-    let (_, answers, authorities, additionals) = response.sections()?;
-    let rcode = response.header().rcode();
-    let mut answers = answers.peekable();
+/// Sets up a server reaching other name servers through `transport`, then serves queries until
+/// shut down, or answers the one given with `--query`.
+fn run<T: Transport>(settings: Settings, transport: T) -> Result<()> {
+  let mut server = DnsServer::with_cache_capacity(settings.cache_size, transport)?;
+  if settings.cache_size == 0 {
+    server.set_cache(NoopCache);
+  }
+  if let Some(path) = &settings.root_hints {
+    server.root_hints = hints::load(path)?;
+  }
+  if let Some(path) = &settings.hosts {
+    server.hosts = Arc::new(Hosts::load(path)?);
+  }
+  if let Some(path) = &settings.query_log {
+    server.query_log = Some(Arc::new(QueryLog::open(path)?));
+  }
+  server.forwarder = settings.forward;
+  server.max_depth = settings.max_depth;
+  server.resolution_timeout = settings.resolution_timeout;
+  server.max_upstream_queries = settings.max_upstream_queries;
+  server.upstream_limiter = Arc::new(QueryLimiter::new(settings.max_outstanding_queries));
+  server.udp_retries = settings.udp_retries;
+  server.identity = settings.identity;
+  server.search = settings.search;
+  server.ndots = settings.ndots;
+  server.min_ttl = settings.min_ttl;
+  server.max_ttl = settings.max_ttl;
+  server.min_referral_ttl = settings.min_referral_ttl;
+  server.max_referral_ttl = settings.max_referral_ttl;
+  server.prefetch = settings.prefetch;
+  server.serve_stale = settings.serve_stale;
+  server.always_recurse = settings.always_recurse;
+  server.randomize_case = settings.randomize_case;
+  server.qname_minimization = settings.qname_minimization;
+  server.ignore_glue = settings.ignore_glue;
+  server.selection = settings.server_selection;
+  server.minimal_any = settings.minimal_any;
+  server.minimal_responses = settings.minimal_responses;
+  server.client_subnet = settings.client_subnet;
+  if settings.dnssec {
+    let anchors = match &settings.trust_anchor {
+      Some(path) => dnssec::load(path)?,
+      None => dnssec::builtin(),
+    };
+    server.trust_anchors = Some(Arc::new(anchors));
+  }
+  server.allowed_clients = settings.allow.map(Arc::new);
+  server.views = Arc::new(settings.views);
+  server.zones = Arc::new(settings.zones);
+  server.forward_zones = Arc::new(settings.forward_zones);
+  if let Some(rate) = settings.rate_limit {
+    let burst = settings.rate_limit_burst.unwrap_or(rate.ceil() as u32);
+    server.rate_limiter = Some(Arc::new(RateLimiter::new(rate, burst)));
+  }
+  if let Some(path) = &settings.cache_file {
+    // a cache that can't be read only costs some lookups, so it isn't worth refusing to start
+    match server.load_cache(path) {
+      Ok(loaded) => info!("Loaded {loaded} cached answers from {}", path.display()),
+      Err(e) => error!("Loading the cache: {e:#}"),
+    }
+  }
 
-    if answers.peek().is_some() && rcode == Rcode::NoError {
-      return Ok((true, None));
+  if let Some((name, rtype)) = &settings.query {
+    server.trace = settings.trace.then(Vec::new);
+    let result = server.resolve(name, *rtype);
+    // the steps taken are worth seeing most of all when resolution fails
+    if let Some(steps) = server.trace.take() {
+      print!("{}", format_trace(&steps)?);
     }
+    print!("{}", format_response(&result?)?);
+    return Ok(());
+  }
 
-    if rcode == Rcode::NXDomain {
-      return Ok((true, None));
+  // a server that can't be primed still works, it just starts from the hints each time
+  if server.forwarder.is_none() {
+    match server.prime() {
+      Ok(roots) => info!("Primed {roots} root server addresses"),
+      Err(e) => warn!("Priming the root servers failed, starting from the root hints: {e:#}"),
     }
+  }
+
+  // Bind UDP and TCP sockets on each address, port 20053 on all interfaces by default
+  let listeners = bind_listeners(&settings.listen)?;
 
-    let relevant_hosts: Vec<_> = authorities
-      .limit_to::<Ns<ParsedDname<&Octets>>>()
-      .filter_map(|record| match record {
-        Ok(record) => {
-          if question.qname().ends_with(record.owner()) {
-            Some(record.data().nsdname().clone())
-          } else {
-            None
-          }
+  if let Some(port) = settings.metrics_port {
+    let metrics_listener = TcpListener::bind((listeners[0].1.local_addr()?.ip(), port))?;
+    let metrics = Arc::clone(&server.metrics);
+    thread::spawn(move || {
+      for stream in metrics_listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| metrics::serve_http(stream, &metrics)) {
+          error!("Serving metrics: {e}");
         }
-        Err(_) => None,
-      })
-      .collect();
+      }
+    });
+  }
 
-    let unresolved_ns = relevant_hosts.get(0).cloned();
-    let resolved_ns = relevant_hosts
-      .into_iter()
-      .flat_map(|host| {
-        let additionals = additionals.clone();
-        let host = host.clone();
-        additionals
-          .limit_to::<A>()
-          .filter_map(move |record| match record {
-            Ok(record) => {
-              if record.owner().clone() == host {
-                Some(record.data().addr())
-              } else {
-                None
-              }
-            }
-            Err(_) => None,
-          })
-      })
-      .next();
+  if let Some(port) = settings.control_port {
+    // anyone who can connect may flush the cache, so only accept local connections
+    let control_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let server = server.fork();
+    thread::spawn(move || {
+      for stream in control_listener.incoming() {
+        if let Err(e) = stream.and_then(|stream| control::serve(stream, &server)) {
+          error!("Serving control commands: {e}");
+        }
+      }
+    });
+  }
 
-    if let Some(addr) = resolved_ns {
-      return Ok((false, Some(SocketAddrV4::new(addr, DNS_PORT))));
-    }
+  let shutdown = Arc::new(Shutdown::default());
+  shutdown_on_signal(Arc::clone(&shutdown))?;
 
-    let unresolved_ns = match unresolved_ns {
-      Some(name) => name,
-      None => return Ok((true, None)),
-    };
+  // Workers share the cache through their forked servers.
+  let mut workers = Vec::new();
+  for (socket, _) in &listeners {
+    for _ in 0..settings.workers {
+      let socket = socket.try_clone()?;
+      let mut server = server.fork();
+      let shutdown = Arc::clone(&shutdown);
+      workers.push(thread::spawn(move || {
+        serve::run_udp(&socket, &mut server, &shutdown)
+      }));
+    }
+  }
 
-    // we now need to resolve the unresolved_ns (i.e. find the IP since we don't know it).
-    let unresolved_question = Question::new_in(unresolved_ns, base::Rtype::A);
-    let resolve_ns = self.recurse(&unresolved_question, false);
-    match resolve_ns {
-      Ok(msg) => {
-        let random_ans = msg.answer()?.limit_to::<A>().choose(&mut self.rng);
-        if let Some(Ok(random_ans)) = random_ans {
-          let addr = random_ans.data().addr();
-          Ok((false, Some(SocketAddrV4::new(addr, DNS_PORT))))
-        } else {
-          return Ok((true, None));
+  thread::scope(|scope| {
+    for (_, listener) in &listeners {
+      let server = server.fork();
+      let shutdown = &shutdown;
+      scope.spawn(move || {
+        if let Err(e) = serve::run_tcp(listener, &server, shutdown) {
+          error!("{e}");
         }
-      }
-      Err(_) => return Ok((true, None)),
+      });
     }
+  });
+  for worker in workers {
+    if let Ok(Err(e)) = worker.join() {
+      error!("{e}");
+    }
+  }
+  if let Some(path) = &settings.cache_file {
+    let saved = server.save_cache(path)?;
+    info!("Saved {saved} cached answers to {}", path.display());
   }
+  info!("Shut down");
+  Ok(())
+}
 
-  fn recurse<N: ToDname + Display>(
-    &mut self,
-    question: &Question<N>,
-    check_cache: bool,
-  ) -> Result<Message> {
-    let run_lookup = |key: Option<Question<Dname<Octets>>>, dns_server: &mut DnsServer<R>| {
-      let server = SocketAddrV4::new(ROOT_NAMESERVER, DNS_PORT);
-      println!("Attempting lookup of {question} with {:?}", Some(server));
-      let mut response = dns_server.lookup(question, server)?;
-      let (mut done, mut name_server) = dns_server.get_next_server(&mut response, question)?;
+/// Renders `response` the way dig prints one: a summary of the header, then the question and each
+/// section that has records, one record per line. The OPT record isn't shown.
+fn format_response(response: &Message) -> Result<String> {
+  let header = response.header();
+  let counts = response.header_counts();
+  let flags: Vec<_> = [
+    ("qr", header.qr()),
+    ("aa", header.aa()),
+    ("tc", header.tc()),
+    ("rd", header.rd()),
+    ("ra", header.ra()),
+    ("ad", header.ad()),
+    ("cd", header.cd()),
+  ]
+  .into_iter()
+  .filter(|&(_, set)| set)
+  .map(|(flag, _)| flag)
+  .collect();
+  let mut out = String::new();
+  writeln!(
+    out,
+    ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+    header.opcode(),
+    header.rcode(),
+    header.id()
+  )?;
+  writeln!(
+    out,
+    ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+    flags.join(" "),
+    counts.qdcount(),
+    counts.ancount(),
+    counts.nscount(),
+    counts.arcount()
+  )?;
 
-      loop {
-        if !done {
-          println!("Attempting lookup of {question} with {:?}", name_server);
-        }
-        match (done, name_server) {
-          (false, Some(server)) => {
-            response = dns_server.lookup(question, server)?;
-            (done, name_server) = dns_server.get_next_server(&mut response, question)?;
-          }
-          _ => {
-            if let Some(key) = key {
-              dns_server.cache.insert(key, response.clone());
-            }
-            return Ok(response);
-          }
-        }
+  let (questions, answers, authorities, additionals) = response.sections()?;
+  writeln!(out, "\n;; QUESTION SECTION:")?;
+  for question in questions {
+    let question = question?;
+    writeln!(
+      out,
+      ";{}.\t\t{}\t{}",
+      question.qname(),
+      question.qclass(),
+      question.qtype()
+    )?;
+  }
+  let sections = [
+    ("ANSWER", answers),
+    ("AUTHORITY", authorities),
+    ("ADDITIONAL", additionals),
+  ];
+  for (title, section) in sections {
+    let lines = record_lines(section)?;
+    if !lines.is_empty() {
+      writeln!(out, "\n;; {title} SECTION:")?;
+      for line in lines {
+        writeln!(out, "{line}")?;
       }
+    }
+  }
+  Ok(out)
+}
+
+/// Formats the records of `section` the way dig does, one per line. OPT records are left out,
+/// since they aren't really records.
+fn record_lines(section: RecordSection<&Octets>) -> Result<Vec<String>> {
+  let mut lines = Vec::new();
+  for record in
+    section.limit_to::<AllRecordData<<&Octets as OctetsRef>::Range, ParsedDname<&Octets>>>()
+  {
+    let record = record?;
+    if let AllRecordData::Opt(_) = record.data() {
+      continue;
+    }
+    lines.push(format!(
+      "{}.\t{}\t{}\t{}\t{}",
+      record.owner(),
+      record.ttl(),
+      record.class(),
+      record.rtype(),
+      record.data()
+    ));
+  }
+  Ok(lines)
+}
+
+/// Formats the steps a resolution took, much like `dig +trace` does. Each step names the server
+/// asked and the question sent, then shows what came back: the name servers and glue of a
+/// referral, the answer records, or for anything else the rcode and authority records. Steps
+/// taken to find the address of a name server are indented below the one that needed it.
+fn format_trace(steps: &[TraceStep]) -> Result<String> {
+  let mut out = String::new();
+  for step in steps {
+    let indent = "  ".repeat(step.depth);
+    let question = &step.question;
+    let asked = match &step.zone {
+      Some(zone) => format!("{}, a server for {zone}.", step.server),
+      None => format!("the forwarder {}", step.server),
     };
+    writeln!(
+      out,
+      "{indent};; {}. {} {}: asking {asked}",
+      question.qname(),
+      question.qclass(),
+      question.qtype()
+    )?;
 
-    if check_cache {
-      let key = Question::<Dname<Octets>>::new(
-        question.qname().to_dname()?,
-        question.qtype(),
-        question.qclass(),
-      );
-      let cache_val = self.cache.get(&key);
-      match cache_val {
-        Some(response) => Ok(response.clone()),
-        None => run_lookup(Some(key), self),
-      }
+    let response = &step.response;
+    let (_, answers, authorities, additionals) = response.sections()?;
+    let referral = response.header().rcode() == Rcode::NoError
+      && answers.count() == 0
+      && authorities
+        .limit_to::<Ns<ParsedDname<&Octets>>>()
+        .next()
+        .is_some();
+    let (lines, outcome) = if referral {
+      let mut lines = record_lines(authorities)?;
+      lines.extend(record_lines(additionals)?);
+      let zone = authorities
+        .limit_to::<Ns<ParsedDname<&Octets>>>()
+        .filter_map(|record| record.ok())
+        .map(|record| format!("{}.", record.owner()))
+        .next()
+        .unwrap_or_default();
+      (lines, format!("referred to {zone}"))
+    } else if answers.count() > 0 {
+      let aa = if response.header().aa() { ", aa" } else { "" };
+      (record_lines(answers)?, format!("answered{aa}"))
     } else {
-      run_lookup(None, self)
+      (
+        record_lines(authorities)?,
+        format!("{}", response.header().rcode()),
+      )
+    };
+    for line in lines {
+      writeln!(out, "{indent}{line}")?;
     }
+    writeln!(out, "{indent};; {outcome}\n")?;
   }
+  Ok(out)
+}
 
-  fn handle_query(&mut self, socket: &UdpSocket, buf: &mut [u8]) -> Result<()> {
-    let (_, src) = socket.recv_from(buf)?;
-    let request = Message::from_octets(buf.to_vec())?;
+/// Binds a UDP socket and a TCP listener on each of `addrs`, skipping those that can't be bound
+/// as long as at least one can. That's how the default addresses are made to work whether or not
+/// the host has IPv6, and whether or not its IPv6 sockets accept IPv4 too: the IPv4 address then
+/// can't be bound, being taken already, and IPv4 clients are served through the IPv6 sockets.
+fn bind_listeners(addrs: &[SocketAddr]) -> Result<Vec<(UdpSocket, TcpListener)>> {
+  let mut listeners = Vec::new();
+  let mut last_error = None;
+  for &addr in addrs {
+    let bound = UdpSocket::bind(addr).and_then(|socket| Ok((socket, TcpListener::bind(addr)?)));
+    match bound {
+      Ok(bound) => {
+        info!("Listening on {addr}");
+        listeners.push(bound);
+      }
+      Err(e) => {
+        let dual_stack = addr.ip() == Ipv4Addr::UNSPECIFIED
+          && e.kind() == io::ErrorKind::AddrInUse
+          && listeners.iter().any(|(socket, _)| {
+            socket
+              .local_addr()
+              .is_ok_and(|a| a.ip() == Ipv6Addr::UNSPECIFIED)
+          });
+        if dual_stack {
+          debug!("Not listening on {addr} separately, since IPv4 is served over IPv6");
+        } else {
+          warn!("Not listening on {addr}: {e}");
+        }
+        last_error = Some(anyhow::Error::new(e).context(format!("listening on {addr}")));
+      }
+    }
+  }
+  match last_error {
+    Some(e) if listeners.is_empty() => Err(e),
+    _ => Ok(listeners),
+  }
+}
 
-    // lookup
-    let bytes = if let Ok(question) = request.sole_question() {
-      if let Ok(result) = self.recurse(&question, true) {
-        println!("Question: {}", question);
-        let (_, answers, authorities, additionals) = result.sections()?;
-        let mut response =
-          MessageBuilder::new_vec().start_answer(&request, result.header().rcode())?;
+/// Triggers `shutdown` when the process is asked to stop with SIGINT or SIGTERM.
+fn shutdown_on_signal(shutdown: Arc<Shutdown>) -> Result<()> {
+  ctrlc::set_handler(move || {
+    info!("Shutting down once queries being answered are done");
+    shutdown.trigger();
+  })?;
+  Ok(())
+}
 
-        let valid_records = |it: RecordSection<_>| {
-          it.limit_to_in::<AllRecordData<<&Octets as OctetsRef>::Range, ParsedDname<&Octets>>>()
-            .filter_map(|r| r.ok())
-        };
+#[cfg(test)]
+mod tests {
+  use domain::{
+    base::{iana::Class, MessageBuilder, Question, Record},
+    rdata::{Mx, Soa, A},
+  };
 
-        let answers = valid_records(answers);
-        let authorities = valid_records(authorities);
-        let additionals = valid_records(additionals);
+  use super::*;
 
-        for record in answers {
-          println!("Answer {}", record);
-          response.push(record)?;
-        }
+  #[test]
+  fn listen_and_outbound_addresses_come_from_the_arguments() {
+    let settings = build_config([
+      "dns",
+      "--listen-addr",
+      "127.0.0.1",
+      "--listen-port",
+      "5300",
+      "--outbound-port",
+      "4000",
+    ])
+    .unwrap();
+    assert_eq!(settings.listen, ["127.0.0.1:5300".parse().unwrap()]);
+    assert_eq!(settings.outbound_port, Some(4000));
+  }
 
-        let mut response = response.authority();
-        for record in authorities {
-          // println!("Authority {}", record);
-          response.push(record)?;
-        }
+  #[test]
+  fn listens_on_every_address_by_default() {
+    let settings = build_config(["dns"]).unwrap();
+    let listen: Vec<SocketAddr> = ["[::]:20053", "0.0.0.0:20053"]
+      .iter()
+      .map(|addr| addr.parse().unwrap())
+      .collect();
+    assert_eq!(settings.listen, listen);
+    assert_eq!(settings.outbound_port, None);
+  }
 
-        let mut response = response.additional();
-        for record in additionals {
-          // println!("Additional {}", record);
-          response.push(record)?;
-        }
+  #[test]
+  fn addresses_that_cant_be_bound_are_skipped_unless_all_are() {
+    let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let taken = taken.local_addr().unwrap();
+    let free: SocketAddr = "127.0.0.1:0".parse().unwrap();
 
-        response.finish()
-      } else {
-        MessageBuilder::new_vec()
-          .start_answer(&request, Rcode::ServFail)?
-          .finish()
-      }
-    } else {
-      MessageBuilder::new_vec()
-        .start_answer(&request, Rcode::FormErr)?
-        .finish()
-    };
+    let listeners = bind_listeners(&[taken, free]).unwrap();
+    assert_eq!(listeners.len(), 1);
+    let (socket, listener) = &listeners[0];
+    assert_ne!(socket.local_addr().unwrap(), taken);
+    assert_eq!(listener.local_addr().unwrap().ip(), free.ip());
+    assert!(bind_listeners(&[taken]).is_err());
+  }
 
-    socket.send_to(&bytes, src)?;
-    Ok(())
+  #[test]
+  fn invalid_arguments_are_rejected() {
+    assert!(build_config(["dns", "--listen-port", "70000"]).is_err());
+    assert!(build_config(["dns", "--listen-addr", "localhost"]).is_err());
   }
-}
 
-fn main() -> Result<()> {
-  // Bind an UDP socket on port 2053
-  let mut packet_buf = [0u8; 512];
-  let socket = UdpSocket::bind(("0.0.0.0", LOCAL_PORT))?;
+  #[test]
+  fn outbound_addresses_are_one_per_family() {
+    let settings = build_config([
+      "dns",
+      "--outbound-addr",
+      "192.0.2.53",
+      "--outbound-addr",
+      "2001:db8::53",
+    ])
+    .unwrap();
+    let addrs: [IpAddr; 2] = [
+      "192.0.2.53".parse().unwrap(),
+      "2001:db8::53".parse().unwrap(),
+    ];
+    assert_eq!(settings.outbound_addrs, addrs);
+    assert!(build_config([
+      "dns",
+      "--outbound-addr",
+      "192.0.2.53",
+      "--outbound-addr",
+      "192.0.2.54",
+    ])
+    .is_err());
+  }
 
-  let mut server = DnsServer::new()?;
+  #[test]
+  fn query_types_are_parsed_from_their_mnemonics() {
+    let settings = build_config(["dns", "--query", "example.com", "--type", "MX"]).unwrap();
+    assert_eq!(settings.query, Some(("example.com".into(), Rtype::Mx)));
+    let settings = build_config(["dns", "--query", "example.com"]).unwrap();
+    assert_eq!(settings.query, Some(("example.com".into(), Rtype::A)));
+    for (mnemonic, rtype) in [
+      ("AAAA", Rtype::Aaaa),
+      ("TXT", Rtype::Txt),
+      ("NS", Rtype::Ns),
+      ("SOA", Rtype::Soa),
+    ] {
+      let settings = build_config(["dns", "--query", "example.com", "--type", mnemonic]).unwrap();
+      assert_eq!(settings.query.unwrap().1, rtype);
+    }
+    assert!(build_config(["dns", "--query", "example.com", "--type", "NOPE"]).is_err());
+  }
+
+  #[test]
+  fn ndots_comes_from_the_arguments() {
+    let settings = build_config(["dns", "--query", "www", "--ndots", "2"]).unwrap();
+    assert_eq!(settings.ndots, 2);
+    let settings = build_config(["dns", "--query", "www"]).unwrap();
+    assert_eq!(settings.ndots, DEFAULT_NDOTS);
+  }
+
+  #[test]
+  fn trace_needs_a_query() {
+    let settings = build_config(["dns", "--query", "example.com", "--trace"]).unwrap();
+    assert!(settings.trace);
+    assert!(
+      !build_config(["dns", "--query", "example.com"])
+        .unwrap()
+        .trace
+    );
+    assert!(build_config(["dns", "--trace"]).is_err());
+  }
+
+  #[test]
+  fn responses_are_formatted_like_dig() {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_id(4660);
+    builder.header_mut().set_qr(true);
+    builder.header_mut().set_rd(true);
+    builder.header_mut().set_ra(true);
+    let mut builder = builder.question();
+    let qname = Dname::<Octets>::from_str("example.com").unwrap();
+    builder
+      .push(Question::new_in(qname.clone(), Rtype::Mx))
+      .unwrap();
+    let mut builder = builder.answer();
+    let exchange = Dname::<Octets>::from_str("mail.example.com").unwrap();
+    builder
+      .push(Record::new(qname, Class::In, 300, Mx::new(10, exchange)))
+      .unwrap();
+    let mut builder = builder.additional();
+    builder.opt(|_| Ok(())).unwrap();
+    let response = builder.into_message();
+
+    assert_eq!(
+      format_response(&response).unwrap(),
+      "\
+;; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4660
+;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1
+
+;; QUESTION SECTION:
+;example.com.\t\tIN\tMX
+
+;; ANSWER SECTION:
+example.com.\t300\tIN\tMX\t10 mail.example.com.
+"
+    );
+  }
+
+  fn dname(name: &str) -> Dname<Octets> {
+    Dname::from_str(name).unwrap()
+  }
+
+  type TraceRecord = Record<Dname<Octets>, AllRecordData<Octets, Dname<Octets>>>;
 
-  // For now, queries are handled sequentially, so an infinite loop for servicing
-  // requests is initiated.
-  loop {
-    match server.handle_query(&socket, &mut packet_buf) {
-      Ok(_) => {}
-      Err(e) => eprintln!("Error: {}", e),
+  fn a(owner: &str, addr: &str) -> TraceRecord {
+    Record::new(
+      dname(owner),
+      Class::In,
+      3600,
+      A::new(addr.parse().unwrap()).into(),
+    )
+  }
+
+  fn ns(owner: &str, host: &str) -> TraceRecord {
+    Record::new(dname(owner), Class::In, 172800, Ns::new(dname(host)).into())
+  }
+
+  /// A step of a trace, at `depth`, in which `server` answered the question for `qname`'s A
+  /// records with `rcode` and the records of each section.
+  fn step(
+    depth: usize,
+    zone: Dname<Octets>,
+    server: &str,
+    qname: &str,
+    rcode: Rcode,
+    sections: [&[TraceRecord]; 3],
+  ) -> TraceStep {
+    let question = Question::new_in(dname(qname), Rtype::A);
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_qr(true);
+    builder.header_mut().set_rcode(rcode);
+    builder.header_mut().set_aa(!sections[0].is_empty());
+    let mut builder = builder.question();
+    builder.push(&question).unwrap();
+    let mut builder = builder.answer();
+    for record in sections[0] {
+      builder.push(record).unwrap();
+    }
+    let mut builder = builder.authority();
+    for record in sections[1] {
+      builder.push(record).unwrap();
+    }
+    let mut builder = builder.additional();
+    for record in sections[2] {
+      builder.push(record).unwrap();
+    }
+    TraceStep {
+      depth,
+      zone: Some(zone),
+      server: server.parse().unwrap(),
+      question,
+      response: builder.into_message(),
     }
   }
+
+  #[test]
+  fn traces_are_formatted_like_dig() {
+    let soa = Record::new(
+      dname("test"),
+      Class::In,
+      900,
+      Soa::new(
+        dname("ns.test"),
+        dname("hostmaster.test"),
+        1.into(),
+        7200,
+        900,
+        1209600,
+        300,
+      )
+      .into(),
+    );
+    let steps = [
+      step(
+        0,
+        Dname::root_vec(),
+        "192.0.2.1:53",
+        "www.example.test",
+        Rcode::NoError,
+        [&[], &[ns("test", "ns.example.net")], &[]],
+      ),
+      step(
+        1,
+        Dname::root_vec(),
+        "192.0.2.1:53",
+        "ns.example.net",
+        Rcode::NoError,
+        [
+          &[],
+          &[ns("net", "a.gtld-servers.net")],
+          &[a("a.gtld-servers.net", "192.0.2.2")],
+        ],
+      ),
+      step(
+        1,
+        dname("net"),
+        "192.0.2.2:53",
+        "ns.example.net",
+        Rcode::NoError,
+        [&[a("ns.example.net", "192.0.2.3")], &[], &[]],
+      ),
+      step(
+        0,
+        dname("test"),
+        "192.0.2.3:53",
+        "www.example.test",
+        Rcode::NXDomain,
+        [&[], &[soa], &[]],
+      ),
+    ];
+
+    assert_eq!(
+      format_trace(&steps).unwrap(),
+      "\
+;; www.example.test. IN A: asking 192.0.2.1:53, a server for .
+test.\t172800\tIN\tNS\tns.example.net.
+;; referred to test.
+
+  ;; ns.example.net. IN A: asking 192.0.2.1:53, a server for .
+  net.\t172800\tIN\tNS\ta.gtld-servers.net.
+  a.gtld-servers.net.\t3600\tIN\tA\t192.0.2.2
+  ;; referred to net.
+
+  ;; ns.example.net. IN A: asking 192.0.2.2:53, a server for net.
+  ns.example.net.\t3600\tIN\tA\t192.0.2.3
+  ;; answered, aa
+
+;; www.example.test. IN A: asking 192.0.2.3:53, a server for test.
+test.\t900\tIN\tSOA\tns.test. hostmaster.test. 1 7200 900 1209600 300
+;; NXDOMAIN
+
+"
+    );
+  }
 }
@@ -0,0 +1,221 @@
+use std::{
+  fmt::Write as _,
+  io::{self, BufRead, BufReader, Write},
+  net::TcpStream,
+  sync::atomic::{AtomicU64, Ordering},
+  time::Duration,
+};
+
+/// Upper bounds, in seconds, of the resolution latency histogram's buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+  0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long to wait for a scraper to send its request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters describing the server's work, shared by every thread. Updates are single relaxed
+/// atomic additions, so they're cheap enough to make on every query.
+#[derive(Default)]
+pub struct Metrics {
+  pub queries: Counter,
+  pub retransmits: Counter,
+  pub cache_hits: Counter,
+  pub cache_misses: Counter,
+  pub upstream_queries: Counter,
+  pub upstream_rejected: Counter,
+  pub referrals: Counter,
+  pub servfail: Counter,
+  pub nxdomain: Counter,
+  pub rate_limited: Counter,
+  latency: Histogram,
+}
+
+/// A count that only goes up.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+  pub fn inc(&self) {
+    self.0.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn get(&self) -> u64 {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+/// A histogram with the buckets in `LATENCY_BUCKETS`. Each bucket only counts the observations
+/// falling in it; they're accumulated when rendered, as Prometheus expects.
+#[derive(Default)]
+struct Histogram {
+  buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+  count: AtomicU64,
+  sum_micros: AtomicU64,
+}
+
+impl Metrics {
+  /// Records how long resolving a client query took.
+  pub fn observe_latency(&self, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64();
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+      self.latency.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    self.latency.count.fetch_add(1, Ordering::Relaxed);
+    let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+    self.latency.sum_micros.fetch_add(micros, Ordering::Relaxed);
+  }
+
+  /// Renders every metric in the Prometheus text exposition format.
+  pub fn render(&self) -> String {
+    let mut out = String::new();
+    let counters = [
+      (
+        "dns_queries_total",
+        "Client queries received",
+        &self.queries,
+      ),
+      (
+        "dns_retransmits_dropped_total",
+        "Client queries dropped as retransmits of one still being answered",
+        &self.retransmits,
+      ),
+      (
+        "dns_cache_hits_total",
+        "Questions answered from the cache",
+        &self.cache_hits,
+      ),
+      (
+        "dns_cache_misses_total",
+        "Questions not found in the cache",
+        &self.cache_misses,
+      ),
+      (
+        "dns_upstream_queries_total",
+        "Queries sent to other name servers",
+        &self.upstream_queries,
+      ),
+      (
+        "dns_upstream_rejected_total",
+        "Queries to other name servers not sent because too many were outstanding",
+        &self.upstream_rejected,
+      ),
+      (
+        "dns_referrals_total",
+        "Referrals followed to other zones",
+        &self.referrals,
+      ),
+      (
+        "dns_servfail_total",
+        "Responses sent with SERVFAIL",
+        &self.servfail,
+      ),
+      (
+        "dns_nxdomain_total",
+        "Responses sent with NXDOMAIN",
+        &self.nxdomain,
+      ),
+      (
+        "dns_rate_limited_total",
+        "Queries refused for exceeding the rate limit",
+        &self.rate_limited,
+      ),
+    ];
+    for (name, help, counter) in counters {
+      let _ = writeln!(out, "# HELP {name} {help}");
+      let _ = writeln!(out, "# TYPE {name} counter");
+      let _ = writeln!(out, "{name} {}", counter.get());
+    }
+
+    let name = "dns_resolution_duration_seconds";
+    let _ = writeln!(out, "# HELP {name} Time taken to resolve client queries");
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    let mut cumulative = 0;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.latency.buckets) {
+      cumulative += bucket.load(Ordering::Relaxed);
+      let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+    }
+    let count = self.latency.count.load(Ordering::Relaxed);
+    let sum = self.latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_sum {sum}");
+    let _ = writeln!(out, "{name}_count {count}");
+    out
+  }
+}
+
+/// Answers a single HTTP request on `stream`, serving the metrics at `/metrics` and nothing else.
+/// This only covers what Prometheus needs to scrape them, not HTTP in general.
+pub fn serve_http(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+  // requests are handled one at a time, so don't let a client that never finishes hold it up
+  stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line)?;
+  // skip the headers, which end with an empty line
+  let mut line = String::new();
+  while reader.read_line(&mut line)? > 0 && !line.trim_end().is_empty() {
+    line.clear();
+  }
+
+  let mut parts = request_line.split_whitespace();
+  let (status, body) = match (parts.next(), parts.next()) {
+    (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+    _ => ("404 Not Found", String::from("not found\n")),
+  };
+
+  write!(
+    stream,
+    "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+    body.len()
+  )?;
+  stream.write_all(body.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::Read, net::TcpListener, sync::Arc, thread};
+
+  use domain::base::iana::Rtype;
+
+  use super::*;
+  use crate::tests::{ask, query, resolving_www_mock, server};
+
+  #[test]
+  fn scraped_metrics_count_the_queries_answered() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    for _ in 0..3 {
+      ask(&mut server, &query("www.example.com", Rtype::A));
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = Arc::clone(&server.metrics);
+    let scraper = thread::spawn(move || {
+      let mut stream = TcpStream::connect(addr).unwrap();
+      write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+      let mut response = String::new();
+      stream.read_to_string(&mut response).unwrap();
+      response
+    });
+    let (stream, _) = listener.accept().unwrap();
+    serve_http(stream, &metrics).unwrap();
+    let response = scraper.join().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    let lines: Vec<_> = response.lines().collect();
+    for expected in [
+      "dns_queries_total 3",
+      "dns_cache_hits_total 2",
+      "dns_cache_misses_total 1",
+      "dns_upstream_queries_total 3",
+      "dns_resolution_duration_seconds_count 3",
+    ] {
+      assert!(
+        lines.contains(&expected),
+        "{expected} missing from {response}"
+      );
+    }
+  }
+}
@@ -0,0 +1,135 @@
+use std::{
+  sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+  time::{Duration, Instant},
+};
+
+/// Limits how many queries to other name servers may be outstanding at once, across every fork
+/// of a server, so a flood of client queries can't open sockets without bound or hammer the
+/// servers they lead to. It's a counting semaphore: each query holds a permit until it's done.
+pub struct QueryLimiter {
+  max: usize,
+  outstanding: Mutex<usize>,
+  released: Condvar,
+}
+
+/// Leave to have one query outstanding, given back to its limiter when dropped.
+pub struct Permit {
+  limiter: Arc<QueryLimiter>,
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    *lock(&self.limiter.outstanding) -= 1;
+    self.limiter.released.notify_one();
+  }
+}
+
+impl QueryLimiter {
+  /// Creates a limiter allowing up to `max` queries outstanding at once.
+  pub fn new(max: usize) -> QueryLimiter {
+    QueryLimiter {
+      max: max.max(1),
+      outstanding: Mutex::new(0),
+      released: Condvar::new(),
+    }
+  }
+
+  /// Most queries allowed to be outstanding at once.
+  pub fn max(&self) -> usize {
+    self.max
+  }
+
+  /// Takes a permit, waiting up to `wait` for one to be given back if they're all taken. Returns
+  /// `None` if none was given back in time, so callers under load fail fast rather than queue up
+  /// behind each other.
+  pub fn acquire(self: &Arc<Self>, wait: Duration) -> Option<Permit> {
+    let deadline = Instant::now() + wait;
+    let mut outstanding = lock(&self.outstanding);
+    while *outstanding >= self.max {
+      let left = deadline
+        .checked_duration_since(Instant::now())
+        .filter(|left| !left.is_zero())?;
+      outstanding = self
+        .released
+        .wait_timeout(outstanding, left)
+        .unwrap_or_else(PoisonError::into_inner)
+        .0;
+    }
+    *outstanding += 1;
+    Some(Permit {
+      limiter: Arc::clone(self),
+    })
+  }
+}
+
+/// Locks a mutex, ignoring poisoning: the count is only ever changed by a single addition or
+/// subtraction.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+
+  use domain::base::iana::{Rcode, Rtype};
+
+  use super::*;
+  use crate::{
+    tests::{answer_addrs, ask, ip, query, resolving_www_mock, server},
+    ResolveError,
+  };
+
+  #[test]
+  fn permits_over_the_limit_are_refused_after_the_wait() {
+    let limiter = Arc::new(QueryLimiter::new(2));
+    let first = limiter.acquire(Duration::ZERO).unwrap();
+    let _second = limiter.acquire(Duration::ZERO).unwrap();
+
+    let start = Instant::now();
+    assert!(limiter.acquire(Duration::from_millis(50)).is_none());
+    let waited = start.elapsed();
+    assert!(waited >= Duration::from_millis(50), "{waited:?}");
+    assert!(waited < Duration::from_secs(1), "{waited:?}");
+
+    drop(first);
+    assert!(limiter.acquire(Duration::ZERO).is_some());
+  }
+
+  #[test]
+  fn permits_given_back_during_the_wait_are_taken() {
+    let limiter = Arc::new(QueryLimiter::new(1));
+    let permit = limiter.acquire(Duration::ZERO).unwrap();
+    let release = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      drop(permit);
+    });
+    assert!(limiter.acquire(Duration::from_secs(5)).is_some());
+    release.join().unwrap();
+  }
+
+  #[test]
+  fn lookups_fail_rather_than_queue_once_the_limit_is_reached() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.upstream_limiter = Arc::new(QueryLimiter::new(1));
+    let held = server.upstream_limiter.acquire(Duration::ZERO).unwrap();
+
+    let start = Instant::now();
+    let response = ask(&mut server, &query("www.example.com", Rtype::A));
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(response.header().rcode(), Rcode::ServFail);
+    assert!(matches!(
+      server.resolve("www.example.com", Rtype::A),
+      Err(ResolveError::Overloaded(1))
+    ));
+    assert!(server.transport.sent().is_empty());
+    assert_eq!(server.metrics.upstream_rejected.get(), 2);
+
+    // every permit taken by the lookups was given back
+    drop(held);
+    assert_eq!(*lock(&server.upstream_limiter.outstanding), 0);
+    let response = ask(&mut server, &query("www.example.com", Rtype::A));
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  }
+}
@@ -0,0 +1,197 @@
+use std::{
+  fs::{self, File},
+  io::{BufReader, BufWriter, ErrorKind, Read, Write},
+  path::Path,
+  sync::Arc,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use domain::base::{Question, ToDname};
+use rand::Rng;
+
+use crate::{map_ttls, transport::Transport, AnswerKind, CacheEntry, DnsServer, Message};
+
+// A cache file starts with `MAGIC` and the time it was written, followed by one record per cached
+// response: the time the response expires, whether it was asked for with DO set, its length, and
+// the response itself with its TTLs already reduced by the time it spent in the cache. Times are
+// big endian seconds since the Unix epoch, the DO flag is a single byte and lengths are big
+// endian u16s.
+const MAGIC: &[u8; 8] = b"DNSCACH2";
+
+impl<R: Rng + ?Sized, T: Transport> DnsServer<R, T> {
+  /// Writes every unexpired cached response to `path`, replacing the file, so a later run can
+  /// start with them through `load_cache`. Returns how many were written.
+  pub fn save_cache(&self, path: impl AsRef<Path>) -> Result<usize> {
+    let path = path.as_ref();
+    let partial = path.with_extension("partial");
+    let saved = self
+      .write_cache(&partial)
+      .with_context(|| format!("writing {}", partial.display()))?;
+    // replace the old file only once the new one is complete
+    fs::rename(&partial, path).with_context(|| format!("replacing {}", path.display()))?;
+    Ok(saved)
+  }
+
+  fn write_cache(&self, path: &Path) -> Result<usize> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let now = SystemTime::now();
+    file.write_all(MAGIC)?;
+    file.write_all(&unix_time(now).to_be_bytes())?;
+
+    let mut saved = 0;
+    let cache = self.cache();
+    // oldest first, so loading the file reinserts the entries in the same order of recency
+    for (_, entry) in cache.iter().filter(|(_, entry)| !entry.is_expired()) {
+      let message = entry.message()?;
+      let len: u16 = match message.as_slice().len().try_into() {
+        Ok(len) => len,
+        Err(_) => continue,
+      };
+      let remaining = entry.expires.saturating_duration_since(Instant::now());
+      file.write_all(&unix_time(now + remaining).to_be_bytes())?;
+      file.write_all(&[entry.dnssec.into()])?;
+      file.write_all(&len.to_be_bytes())?;
+      file.write_all(message.as_slice())?;
+      saved += 1;
+    }
+    file.flush()?;
+    Ok(saved)
+  }
+
+  /// Adds the responses saved to `path` by `save_cache` to the cache, skipping those that have
+  /// expired since, and reducing the TTLs of the rest by the time passed since the file was
+  /// written. A missing file is treated as an empty one. Returns how many responses were added.
+  pub fn load_cache(&self, path: impl AsRef<Path>) -> Result<usize> {
+    let path = path.as_ref();
+    let file = match File::open(path) {
+      Ok(file) => file,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+      Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    self
+      .read_cache(BufReader::new(file))
+      .with_context(|| format!("reading {}", path.display()))
+  }
+
+  fn read_cache(&self, mut file: impl Read) -> Result<usize> {
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+      bail!("not a cache file");
+    }
+    let now = unix_time(SystemTime::now());
+    let saved_at = read_u64(&mut file)?;
+    let downtime: u32 = now.saturating_sub(saved_at).try_into().unwrap_or(u32::MAX);
+
+    let mut loaded = 0;
+    loop {
+      let expires = match read_u64(&mut file) {
+        Ok(expires) => expires,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+        Err(e) => return Err(e.into()),
+      };
+      let mut dnssec = [0u8];
+      file.read_exact(&mut dnssec)?;
+      let mut len = [0u8; 2];
+      file.read_exact(&mut len)?;
+      let mut octets = vec![0u8; u16::from_be_bytes(len).into()];
+      file.read_exact(&mut octets)?;
+      if expires <= now {
+        continue;
+      }
+
+      let message = Message::from_octets(octets).context("malformed response")?;
+      let message = map_ttls(&message, |ttl| ttl.saturating_sub(downtime))?;
+      let kind = match AnswerKind::of(&message)? {
+        Some(kind) => kind,
+        None => continue,
+      };
+      let question = message.sole_question()?;
+      let key = Question::new(
+        question.qname().to_dname()?,
+        question.qtype(),
+        question.qclass(),
+      );
+      let lifetime = Duration::from_secs(expires - now);
+      let entry = CacheEntry::with_lifetime(message, kind, dnssec[0] != 0, lifetime);
+      self.cache().put(key, Arc::new(entry));
+      loaded += 1;
+    }
+    Ok(loaded)
+  }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+  time
+    .duration_since(UNIX_EPOCH)
+    .map(|elapsed| elapsed.as_secs())
+    .unwrap_or_default()
+}
+
+fn read_u64(file: &mut impl Read) -> std::io::Result<u64> {
+  let mut bytes = [0u8; 8];
+  file.read_exact(&mut bytes)?;
+  Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use domain::base::iana::Rtype;
+
+  use super::*;
+  use crate::{
+    tests::{answer, answer_addrs, cached, ip, question, server},
+    transport::mock::MockTransport,
+  };
+
+  #[test]
+  fn unexpired_answers_survive_a_restart() {
+    let path = env::temp_dir().join(format!("dns-cache-{}", std::process::id()));
+    let before = server(MockTransport::default());
+    before.cache().put(
+      question("www.example.com", Rtype::A),
+      cached(answer("www.example.com", "192.0.2.10")),
+    );
+    let mut expired = CacheEntry::with_lifetime(
+      answer("old.example.com", "192.0.2.20"),
+      AnswerKind::Positive,
+      false,
+      Duration::from_secs(60),
+    );
+    expired.inserted -= Duration::from_secs(120);
+    expired.expires -= Duration::from_secs(120);
+    before
+      .cache()
+      .put(question("old.example.com", Rtype::A), Arc::new(expired));
+    let saved = before.save_cache(&path).unwrap();
+
+    let mut after = server(MockTransport::default());
+    let loaded = after.load_cache(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(saved, 1);
+    assert_eq!(loaded.unwrap(), 1);
+    let response = after.resolve("www.example.com", Rtype::A).unwrap();
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    assert!(after.transport.sent().is_empty());
+    assert!(after
+      .cache()
+      .get(&question("old.example.com", Rtype::A))
+      .is_none());
+  }
+
+  #[test]
+  fn missing_cache_files_load_nothing() {
+    let server = server(MockTransport::default());
+    let path = env::temp_dir().join("dns-cache-that-does-not-exist");
+    assert_eq!(server.load_cache(path).unwrap(), 0);
+  }
+
+  #[test]
+  fn other_files_are_rejected() {
+    let server = server(MockTransport::default());
+    assert!(server.read_cache(&b"not a cache file at all"[..]).is_err());
+  }
+}
@@ -0,0 +1,153 @@
+use std::{
+  fs::OpenOptions,
+  io::{self, LineWriter, Write},
+  net::IpAddr,
+  path::Path,
+  sync::{Mutex, PoisonError},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// Where the query log is written: one JSON object per line, for each client query answered.
+pub struct QueryLog {
+  out: Mutex<LineWriter<Box<dyn Write + Send>>>,
+}
+
+/// What the query log records about a single client query.
+#[derive(Debug, Serialize)]
+pub struct QueryRecord {
+  /// When the response was sent, in seconds since the Unix epoch.
+  pub timestamp: f64,
+  pub client: IpAddr,
+  /// The name asked about, or `None` if the question didn't parse.
+  pub qname: Option<String>,
+  pub qtype: Option<String>,
+  pub rcode: String,
+  /// How many records the answer section of the response holds.
+  pub answers: u16,
+  /// `hit` if resolving the query didn't need to ask another server, `miss` if it did, or `None`
+  /// if the query wasn't resolved at all, such as one refused or answered from a served zone.
+  pub cache: Option<&'static str>,
+  /// How long answering took, in milliseconds.
+  pub latency_ms: f64,
+}
+
+impl QueryLog {
+  /// Opens the log at `path` for appending, creating it if needed. A path of `-` means writing
+  /// to stdout instead.
+  pub fn open(path: impl AsRef<Path>) -> Result<QueryLog> {
+    let path = path.as_ref();
+    let out: Box<dyn Write + Send> = if path == Path::new("-") {
+      Box::new(io::stdout())
+    } else {
+      let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+      Box::new(file)
+    };
+    Ok(QueryLog {
+      out: Mutex::new(LineWriter::new(out)),
+    })
+  }
+
+  /// Appends `record` to the log as a line of its own.
+  pub fn write(&self, record: &QueryRecord) -> io::Result<()> {
+    let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+    writeln!(out, "{}", to_json(record))
+  }
+}
+
+/// Serializes `record` as a single line of JSON.
+pub fn to_json(record: &QueryRecord) -> String {
+  // only maps with non-string keys fail to serialize, and a record has none
+  serde_json::to_string(record).expect("query records always serialize")
+}
+
+/// The current time as a `timestamp`.
+pub fn now() -> f64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or(Duration::ZERO)
+    .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{env, fs, sync::Arc};
+
+  use domain::base::iana::Rtype;
+  use serde_json::Value;
+
+  use super::*;
+  use crate::tests::{ask, ip, query, resolving_www_mock, server};
+
+  #[test]
+  fn records_are_single_lines_of_json() {
+    let record = QueryRecord {
+      timestamp: 1.5,
+      client: ip("198.51.100.1"),
+      qname: Some("www.example.com".to_string()),
+      qtype: Some("A".to_string()),
+      rcode: "NOERROR".to_string(),
+      answers: 2,
+      cache: None,
+      latency_ms: 0.25,
+    };
+    assert_eq!(
+      to_json(&record),
+      concat!(
+        r#"{"timestamp":1.5,"client":"198.51.100.1","qname":"www.example.com","qtype":"A","#,
+        r#""rcode":"NOERROR","answers":2,"cache":null,"latency_ms":0.25}"#
+      )
+    );
+  }
+
+  #[test]
+  fn answered_queries_are_logged_as_hits_or_misses() {
+    let path = env::temp_dir().join(format!("dns-query-log-{}", std::process::id()));
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.query_log = Some(Arc::new(QueryLog::open(&path).unwrap()));
+
+    for _ in 0..2 {
+      ask(&mut server, &query("www.example.com", Rtype::A));
+    }
+    server.allowed_clients = Some(Arc::new(vec!["10.0.0.0/8".parse().unwrap()]));
+    ask(&mut server, &query("www.example.com", Rtype::A));
+
+    let log = fs::read_to_string(&path);
+    fs::remove_file(&path).unwrap();
+    let records: Vec<Value> = log
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    let summaries: Vec<_> = records
+      .iter()
+      .map(|record| {
+        (
+          record["rcode"].as_str().unwrap(),
+          record["answers"].as_u64().unwrap(),
+          record["cache"].as_str(),
+        )
+      })
+      .collect();
+    assert_eq!(
+      summaries,
+      [
+        ("NOERROR", 1, Some("miss")),
+        ("NOERROR", 1, Some("hit")),
+        ("REFUSED", 0, None)
+      ]
+    );
+    for record in &records {
+      assert_eq!(record["client"], "198.51.100.1");
+      assert_eq!(record["qname"], "www.example.com");
+      assert_eq!(record["qtype"], "A");
+    }
+  }
+}
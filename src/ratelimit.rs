@@ -0,0 +1,150 @@
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{Mutex, PoisonError},
+  time::{Duration, Instant},
+};
+
+// Clients tracked at once. Past this, buckets that have refilled completely are forgotten, and
+// new clients are limited until there's room for them.
+const MAX_TRACKED_CLIENTS: usize = 65536;
+// Least time between looking through every bucket for ones to forget, so that a flood of new
+// clients doesn't make each of their queries do it
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits how many queries each client may send, with a token bucket per client address.
+/// Clients are told apart by IP alone, since the source port changes from query to query.
+pub struct RateLimiter {
+  /// Tokens added to a bucket per second.
+  rate: f64,
+  /// Most tokens a bucket can hold, and so the longest burst a client can send at once.
+  burst: f64,
+  /// Most clients tracked at once.
+  max_clients: usize,
+  clients: Mutex<Clients>,
+}
+
+struct Clients {
+  buckets: HashMap<IpAddr, Bucket>,
+  /// When buckets were last looked through for ones to forget.
+  swept: Option<Instant>,
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+impl RateLimiter {
+  /// Creates a limiter allowing each client `rate` queries per second on average, and bursts of
+  /// up to `burst` queries.
+  pub fn new(rate: f64, burst: u32) -> RateLimiter {
+    RateLimiter {
+      rate,
+      burst: f64::from(burst.max(1)),
+      max_clients: MAX_TRACKED_CLIENTS,
+      clients: Mutex::new(Clients {
+        buckets: HashMap::new(),
+        swept: None,
+      }),
+    }
+  }
+
+  /// Takes a token from `client`'s bucket, returning whether one was available. A client that
+  /// isn't tracked yet while there's no room for another has none.
+  pub fn allow(&self, client: IpAddr) -> bool {
+    let now = Instant::now();
+    let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+    let Clients { buckets, swept } = &mut *clients;
+    if buckets.len() >= self.max_clients && !buckets.contains_key(&client) {
+      if swept.is_some_and(|swept| now.duration_since(swept) < SWEEP_INTERVAL) {
+        return false;
+      }
+      // a full bucket behaves the same as a new one, so it's safe to drop
+      buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+      *swept = Some(now);
+      if buckets.len() >= self.max_clients {
+        return false;
+      }
+    }
+
+    let bucket = buckets.entry(client).or_insert(Bucket {
+      tokens: self.burst,
+      updated: now,
+    });
+    let tokens = self.refill(bucket, now);
+    if tokens < 1.0 {
+      return false;
+    }
+    bucket.tokens = tokens - 1.0;
+    true
+  }
+
+  /// Adds the tokens earned since the bucket was last updated, returning the new count.
+  fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+    let earned = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+    bucket.tokens = (bucket.tokens + earned).min(self.burst);
+    bucket.updated = now;
+    bucket.tokens
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::Arc, thread};
+
+  use domain::base::iana::{Rcode, Rtype};
+
+  use super::*;
+  use crate::{
+    tests::{ask_from, ip, query, resolving_www_mock, server, CLIENT},
+    Protocol,
+  };
+
+  #[test]
+  fn bursts_over_the_limit_are_refused_for_that_client_only() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.rate_limiter = Some(Arc::new(RateLimiter::new(0.001, 3)));
+    let request = query("www.example.com", Rtype::A);
+
+    let rcodes: Vec<_> = (0..5)
+      .map(|_| {
+        let response = ask_from(&mut server, &request, CLIENT, Protocol::Udp);
+        response.header().rcode()
+      })
+      .collect();
+    assert_eq!(
+      rcodes,
+      [
+        Rcode::NoError,
+        Rcode::NoError,
+        Rcode::NoError,
+        Rcode::Refused,
+        Rcode::Refused
+      ]
+    );
+    assert_eq!(server.metrics.rate_limited.get(), 2);
+
+    let other = "198.51.100.2:5353".parse().unwrap();
+    let response = ask_from(&mut server, &request, other, Protocol::Udp);
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+  }
+
+  #[test]
+  fn new_clients_are_limited_while_every_tracked_one_is() {
+    let mut limiter = RateLimiter::new(10.0, 1);
+    limiter.max_clients = 2;
+    assert!(limiter.allow(ip("192.0.2.1")));
+    assert!(limiter.allow(ip("192.0.2.2")));
+    // both buckets are empty, so neither can be forgotten to make room
+    assert!(!limiter.allow(ip("192.0.2.3")));
+
+    // once they've refilled, they make way for new clients, but only after a sweep is due
+    thread::sleep(Duration::from_millis(200));
+    assert!(!limiter.allow(ip("192.0.2.3")));
+    thread::sleep(SWEEP_INTERVAL);
+    assert!(limiter.allow(ip("192.0.2.3")));
+    assert!(limiter.allow(ip("192.0.2.4")));
+  }
+}
@@ -0,0 +1,329 @@
+use std::{
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use domain::{
+  base::{iana::Class, iana::Rtype, Dname, ParsedDname, RecordSection, ToDname},
+  rdata::{Aaaa, Ns, A},
+};
+
+use crate::{cache::LruCache, Octets};
+
+type Key = (Dname<Octets>, Rtype, Class);
+/// A `Key` whose owner still points into the message it was parsed from.
+type ParsedKey<'a> = (ParsedDname<&'a Octets>, Rtype, Class);
+
+// Most record sets learned from referrals kept at once. They're kept apart from the rest, so a
+// flood of answers for distinct names can't push out the delegations of the root and TLDs that
+// nearly every resolution starts from.
+const MAX_DELEGATION_SETS: usize = 10000;
+
+/// The data of a record kept in the record store or synthesized locally. Only the types needed
+/// to find and reach name servers are stored, since they're what many different questions end up
+/// sharing; PTR records only come from the hosts file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordData {
+  A(Ipv4Addr),
+  Aaaa(Ipv6Addr),
+  Ns(Dname<Octets>),
+  Ptr(Dname<Octets>),
+}
+
+impl RecordData {
+  pub fn rtype(&self) -> Rtype {
+    match self {
+      RecordData::A(_) => Rtype::A,
+      RecordData::Aaaa(_) => Rtype::Aaaa,
+      RecordData::Ns(_) => Rtype::Ns,
+      RecordData::Ptr(_) => Rtype::Ptr,
+    }
+  }
+
+  /// The address held by an A or AAAA record.
+  pub fn addr(&self) -> Option<IpAddr> {
+    match self {
+      RecordData::A(addr) => Some((*addr).into()),
+      RecordData::Aaaa(addr) => Some((*addr).into()),
+      RecordData::Ns(_) | RecordData::Ptr(_) => None,
+    }
+  }
+}
+
+impl From<IpAddr> for RecordData {
+  fn from(addr: IpAddr) -> RecordData {
+    match addr {
+      IpAddr::V4(addr) => RecordData::A(addr),
+      IpAddr::V6(addr) => RecordData::Aaaa(addr),
+    }
+  }
+}
+
+/// How much a record set can be relied on, based on where it was learned (RFC 2181 section
+/// 5.4.1). Sets are never replaced by less trustworthy data before they expire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trust {
+  /// Delegation records and glue from a referral, which are only good for finding servers.
+  Referral,
+  /// Records from the answer section of a final response without AA set, such as one from a
+  /// forwarder or from a server answering out of its own cache. They're good for finding servers
+  /// but not given to clients, who get the cached response instead.
+  NonAuthoritative,
+  /// Records from the answer section of an authoritative final response, which may be given to
+  /// clients.
+  Answer,
+}
+
+/// A set of records sharing an owner, type and class, which expires as a whole when the first of
+/// its records does.
+struct RecordSet {
+  records: Vec<RecordData>,
+  trust: Trust,
+  expires: Instant,
+}
+
+/// Individual record sets learned while resolving, keyed by owner, type and class, so records
+/// seen in one response can be reused for other questions. Sets learned from referrals are kept
+/// in a tier of their own, with room for `MAX_DELEGATION_SETS`.
+pub struct RecordStore {
+  sets: LruCache<Key, RecordSet>,
+  delegations: LruCache<Key, RecordSet>,
+}
+
+impl RecordStore {
+  pub fn new(capacity: usize) -> RecordStore {
+    RecordStore {
+      sets: LruCache::new(capacity),
+      delegations: LruCache::new(MAX_DELEGATION_SETS),
+    }
+  }
+
+  /// Returns the records of an unexpired record set learned with at least `trust`, along with
+  /// their remaining TTL. A set learned from an answer is preferred over one from a referral.
+  pub fn get(
+    &mut self,
+    owner: &Dname<Octets>,
+    rtype: Rtype,
+    class: Class,
+    trust: Trust,
+  ) -> Option<(Vec<RecordData>, u32)> {
+    let key = (owner.clone(), rtype, class);
+    let now = Instant::now();
+    let unexpired = |set: &RecordSet| {
+      let remaining = set.expires.checked_duration_since(now)?;
+      let ttl = remaining.as_secs().try_into().unwrap_or(u32::MAX);
+      Some((set.records.clone(), ttl))
+    };
+    if let Some(found) = self
+      .sets
+      .get(&key)
+      .filter(|set| set.trust >= trust)
+      .and_then(unexpired)
+    {
+      return Some(found);
+    }
+    if trust > Trust::Referral {
+      return None;
+    }
+    self.delegations.get(&key).and_then(unexpired)
+  }
+
+  /// Removes the record sets owned by `owner`, or every set if it's `None`. Returns how many
+  /// were removed.
+  pub fn remove(&mut self, owner: Option<&Dname<Octets>>) -> usize {
+    let keep = |(name, _, _): &Key, _: &RecordSet| owner.is_some_and(|owner| owner != name);
+    self.sets.retain(keep) + self.delegations.retain(keep)
+  }
+
+  /// Stores the A, AAAA and NS records of `section` for which `filter` returns true, each set
+  /// for the TTL that `ttl` makes of its own. Each record set found replaces any stored earlier,
+  /// rather than adding to it, unless the earlier one is more trustworthy and hasn't expired.
+  pub fn insert_section(
+    &mut self,
+    section: RecordSection<&Octets>,
+    trust: Trust,
+    ttl: impl Fn(u32) -> u32,
+    filter: impl Fn(&ParsedDname<&Octets>, Rtype) -> bool,
+  ) -> Result<()> {
+    // Sets are grouped by the owner as parsed, so its name is only copied out of the message once
+    // per set. Sections are small enough that searching the sets found so far is cheap.
+    let mut sets: Vec<(ParsedKey, u32, Vec<RecordData>)> = Vec::new();
+    for record in section.filter_map(|record| record.ok()) {
+      let rtype = record.rtype();
+      if !matches!(rtype, Rtype::A | Rtype::Aaaa | Rtype::Ns) || !filter(record.owner(), rtype) {
+        continue;
+      }
+      // records whose data doesn't parse are skipped, like the rest of the section's
+      let data = match rtype {
+        Rtype::A => match record.to_record::<A>() {
+          Ok(Some(record)) => RecordData::A(record.data().addr()),
+          _ => continue,
+        },
+        Rtype::Aaaa => match record.to_record::<Aaaa>() {
+          Ok(Some(record)) => RecordData::Aaaa(record.data().addr()),
+          _ => continue,
+        },
+        _ => match record.to_record::<Ns<ParsedDname<&Octets>>>() {
+          Ok(Some(record)) => RecordData::Ns(record.data().nsdname().to_dname()?),
+          _ => continue,
+        },
+      };
+      let key = (*record.owner(), rtype, record.class());
+      let ttl = record.ttl();
+      match sets.iter_mut().find(|(found, ..)| *found == key) {
+        Some((_, min_ttl, records)) => {
+          *min_ttl = ttl.min(*min_ttl);
+          if !records.contains(&data) {
+            records.push(data);
+          }
+        }
+        None => sets.push((key, ttl, vec![data])),
+      }
+    }
+
+    let now = Instant::now();
+    for ((owner, rtype, class), set_ttl, records) in sets {
+      let key = (owner.to_dname()?, rtype, class);
+      if let Some(existing) = self.sets.get(&key) {
+        if existing.trust > trust && existing.expires > now {
+          continue;
+        }
+      }
+      let expires = now + Duration::from_secs(ttl(set_ttl).into());
+      let set = RecordSet {
+        records,
+        trust,
+        expires,
+      };
+      if trust == Trust::Referral {
+        self.delegations.insert(key, set);
+      } else {
+        self.sets.insert(key, set);
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::{a, aaaa, ip, name, ns, reply, with_ttl};
+
+  #[test]
+  fn sections_are_stored_as_record_sets() {
+    let response = reply("www.example.com", Rtype::A)
+      .additional(a("ns1.example.com", "192.0.2.3"))
+      .additional(aaaa("ns1.example.com", "2001:db8::3"))
+      .additional(with_ttl(a("NS1.example.com", "192.0.2.4"), 60))
+      .additional(a("ns1.example.com", "192.0.2.3"))
+      .additional(a("ns.example.org", "203.0.113.66"))
+      .build();
+    let mut store = RecordStore::new(100);
+    store
+      .insert_section(
+        response.additional().unwrap(),
+        Trust::Referral,
+        |ttl| ttl,
+        |owner, _| owner.ends_with(&name("example.com")),
+      )
+      .unwrap();
+
+    let ns1 = name("ns1.example.com");
+    let (records, ttl) = store
+      .get(&ns1, Rtype::A, Class::In, Trust::Referral)
+      .unwrap();
+    let addrs: Vec<_> = records.iter().filter_map(RecordData::addr).collect();
+    assert_eq!(addrs, [ip("192.0.2.3"), ip("192.0.2.4")]);
+    assert!((59..=60).contains(&ttl), "{ttl}");
+    let (records, _) = store
+      .get(&ns1, Rtype::Aaaa, Class::In, Trust::Referral)
+      .unwrap();
+    assert_eq!(records, [RecordData::from(ip("2001:db8::3"))]);
+    assert!(store
+      .get(
+        &name("ns.example.org"),
+        Rtype::A,
+        Class::In,
+        Trust::Referral
+      )
+      .is_none());
+    // glue isn't good enough to answer clients with
+    assert!(store
+      .get(&ns1, Rtype::A, Class::In, Trust::Answer)
+      .is_none());
+  }
+
+  #[test]
+  fn answers_are_not_replaced_by_referrals() {
+    let answer = reply("ns.example.com", Rtype::A)
+      .answer(a("ns.example.com", "192.0.2.3"))
+      .build();
+    let referral = reply("www.example.com", Rtype::A)
+      .authority(ns("example.com", "ns.example.com"))
+      .additional(a("ns.example.com", "203.0.113.66"))
+      .build();
+    let mut store = RecordStore::new(100);
+    let all = |_: &ParsedDname<&Octets>, _| true;
+    store
+      .insert_section(answer.answer().unwrap(), Trust::Answer, |ttl| ttl, all)
+      .unwrap();
+    store
+      .insert_section(
+        referral.additional().unwrap(),
+        Trust::Referral,
+        |ttl| ttl,
+        all,
+      )
+      .unwrap();
+
+    let owner = name("ns.example.com");
+    let (records, _) = store
+      .get(&owner, Rtype::A, Class::In, Trust::Referral)
+      .unwrap();
+    assert_eq!(records, [RecordData::from(ip("192.0.2.3"))]);
+    assert_eq!(store.remove(Some(&owner)), 1);
+    assert!(store
+      .get(&owner, Rtype::A, Class::In, Trust::Referral)
+      .is_none());
+  }
+
+  #[test]
+  fn referrals_are_kept_apart_from_answers() {
+    let referral = reply("www.example.com", Rtype::A)
+      .authority(ns("com", "a.gtld-servers.net"))
+      .build();
+    let mut store = RecordStore::new(2);
+    let all = |_: &ParsedDname<&Octets>, _| true;
+    store
+      .insert_section(
+        referral.authority().unwrap(),
+        Trust::Referral,
+        |ttl| ttl.min(60),
+        all,
+      )
+      .unwrap();
+    // more answers than the store has room for
+    for i in 0..4 {
+      let owner = format!("host{i}.example.com");
+      let answer = reply(&owner, Rtype::A)
+        .answer(a(&owner, "192.0.2.10"))
+        .build();
+      store
+        .insert_section(answer.answer().unwrap(), Trust::Answer, |ttl| ttl, all)
+        .unwrap();
+    }
+
+    let (records, ttl) = store
+      .get(&name("com"), Rtype::Ns, Class::In, Trust::Referral)
+      .unwrap();
+    assert_eq!(records.len(), 1);
+    assert!((59..=60).contains(&ttl), "{ttl}");
+    // referral data still isn't good enough to answer clients with
+    assert!(store
+      .get(&name("com"), Rtype::Ns, Class::In, Trust::Answer)
+      .is_none());
+    assert_eq!(store.remove(None), 3);
+  }
+}
@@ -0,0 +1,317 @@
+use std::{
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex, PoisonError,
+  },
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Error, Result};
+use rand::{seq::SliceRandom, Rng};
+use serde::Deserialize;
+
+use domain::base::Dname;
+
+use crate::{cache::LruCache, Octets};
+
+// Name servers whose round trip times are remembered at once
+const MAX_TRACKED_SERVERS: usize = 4096;
+// Weight of each new measurement in a server's smoothed round trip time
+const RTT_WEIGHT: f64 = 0.3;
+// Round trip time counted for a query that got no usable response, so failing servers sort
+// behind slow ones
+const FAILED_RTT: Duration = Duration::from_secs(5);
+// Lame servers remembered at once, each for the zone it's lame for
+const MAX_TRACKED_LAME_SERVERS: usize = 4096;
+// How long a server found lame for a zone is passed over when asking about the zone, so it's
+// given another chance once its delegation may have been fixed
+const LAME_SERVER_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How the name servers for a zone are ordered before they're asked, which decides the ones
+/// asked first. Written as `random`, `round-robin` or `fastest`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Selection {
+  /// A new random order every time, spreading queries evenly.
+  #[default]
+  Random,
+  /// The servers in address order, starting one further along each time a list is ordered.
+  RoundRobin,
+  /// Lowest smoothed round trip time first. Servers not measured yet come before all others, so
+  /// each gets measured once, and ties are broken at random.
+  Fastest,
+}
+
+impl FromStr for Selection {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Selection> {
+    match s {
+      "random" => Ok(Selection::Random),
+      "round-robin" => Ok(Selection::RoundRobin),
+      "fastest" => Ok(Selection::Fastest),
+      _ => bail!("unknown server selection {s:?}, expected random, round-robin or fastest"),
+    }
+  }
+}
+
+impl TryFrom<String> for Selection {
+  type Error = Error;
+
+  fn try_from(s: String) -> Result<Selection> {
+    s.parse()
+  }
+}
+
+/// How a lookup with a name server turned out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+  /// The server gave a usable response.
+  Answered,
+  /// The server didn't respond to the query or any of its retries.
+  Timeout,
+  /// The server answered SERVFAIL or REFUSED.
+  ServFail,
+  /// Anything else went wrong, such as the response not being for the question asked.
+  Error,
+}
+
+/// What's been seen of a single name server since it was first asked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerRecord {
+  /// Lookups sent to the server, each counting once however many retries it took.
+  pub queries: u64,
+  /// Lookups that got a usable response.
+  pub answered: u64,
+  /// Lookups the server never responded to.
+  pub timeouts: u64,
+  /// Lookups the server answered with SERVFAIL or REFUSED.
+  pub servfails: u64,
+  /// Lookups that failed any other way.
+  pub errors: u64,
+  /// Smoothed round trip time, in which lookups without a usable response count as taking
+  /// `FAILED_RTT`.
+  pub rtt: Duration,
+}
+
+/// Counts of how lookups with each name server turned out, along with round trip times smoothed
+/// the way TCP smooths them (RFC 6298), and what round-robin ordering needs. Servers found lame
+/// for a zone are remembered too, until when they're passed over.
+pub struct ServerStats {
+  servers: Mutex<LruCache<IpAddr, ServerRecord>>,
+  turn: AtomicUsize,
+  lame: Mutex<LruCache<(IpAddr, Dname<Octets>), Instant>>,
+}
+
+impl Default for ServerStats {
+  fn default() -> ServerStats {
+    ServerStats {
+      servers: Mutex::new(LruCache::new(MAX_TRACKED_SERVERS)),
+      turn: AtomicUsize::new(0),
+      lame: Mutex::new(LruCache::new(MAX_TRACKED_LAME_SERVERS)),
+    }
+  }
+}
+
+impl ServerStats {
+  /// Counts a lookup with `server` that took `elapsed` and turned out as `outcome`.
+  pub fn record(&self, server: IpAddr, outcome: Outcome, elapsed: Duration) {
+    let rtt = match outcome {
+      Outcome::Answered => elapsed,
+      Outcome::Timeout | Outcome::ServFail | Outcome::Error => FAILED_RTT,
+    };
+    let mut servers = self.servers.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut record = match servers.get(&server) {
+      Some(&record) => ServerRecord {
+        rtt: record.rtt.mul_f64(1.0 - RTT_WEIGHT) + rtt.mul_f64(RTT_WEIGHT),
+        ..record
+      },
+      None => ServerRecord {
+        rtt,
+        ..ServerRecord::default()
+      },
+    };
+    record.queries += 1;
+    match outcome {
+      Outcome::Answered => record.answered += 1,
+      Outcome::Timeout => record.timeouts += 1,
+      Outcome::ServFail => record.servfails += 1,
+      Outcome::Error => record.errors += 1,
+    }
+    servers.insert(server, record);
+  }
+
+  /// Returns what's been seen of each server still tracked, least recently asked first.
+  pub fn servers(&self) -> Vec<(IpAddr, ServerRecord)> {
+    let servers = self.servers.lock().unwrap_or_else(PoisonError::into_inner);
+    servers
+      .iter()
+      .map(|(&server, &record)| (server, record))
+      .collect()
+  }
+
+  /// Remembers that `server` was delegated `zone` but doesn't serve it.
+  pub fn mark_lame(&self, server: IpAddr, zone: &Dname<Octets>) {
+    let mut lame = self.lame.lock().unwrap_or_else(PoisonError::into_inner);
+    lame.insert((server, zone.clone()), Instant::now() + LAME_SERVER_TTL);
+  }
+
+  /// Takes the servers recently found lame for `zone` out of `servers`, unless that would leave
+  /// none, since a lame server is still better than nothing to ask.
+  pub fn skip_lame(&self, servers: &mut Vec<SocketAddr>, zone: &Dname<Octets>) {
+    let mut lame = self.lame.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+    let mut is_lame = |server: &SocketAddr| {
+      lame
+        .get(&(server.ip(), zone.clone()))
+        .is_some_and(|&until| until > now)
+    };
+    if servers.iter().all(&mut is_lame) {
+      return;
+    }
+    servers.retain(|server| !is_lame(server));
+  }
+
+  /// Puts `servers` in the order `selection` says to ask them in.
+  pub fn order<R: Rng + ?Sized>(
+    &self,
+    servers: &mut [SocketAddr],
+    selection: Selection,
+    rng: &mut R,
+  ) {
+    match selection {
+      Selection::Random => servers.shuffle(rng),
+      Selection::RoundRobin => {
+        servers.sort_unstable();
+        if !servers.is_empty() {
+          let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+          servers.rotate_left(turn % servers.len());
+        }
+      }
+      Selection::Fastest => {
+        servers.shuffle(rng);
+        let mut records = self.servers.lock().unwrap_or_else(PoisonError::into_inner);
+        servers.sort_by_cached_key(|server| {
+          records
+            .get(&server.ip())
+            .map_or(Duration::ZERO, |record| record.rtt)
+        });
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rand::{rngs::StdRng, SeedableRng};
+
+  use super::*;
+
+  fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+    addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+  }
+
+  #[test]
+  fn selections_are_parsed_by_name() {
+    assert_eq!("random".parse::<Selection>().unwrap(), Selection::Random);
+    assert_eq!(
+      "round-robin".parse::<Selection>().unwrap(),
+      Selection::RoundRobin
+    );
+    assert_eq!("fastest".parse::<Selection>().unwrap(), Selection::Fastest);
+    assert!("nearest".parse::<Selection>().is_err());
+  }
+
+  #[test]
+  fn round_robin_starts_one_further_along_each_time() {
+    let stats = ServerStats::default();
+    let mut rng = StdRng::seed_from_u64(0);
+    let given = addrs(&["192.0.2.3:53", "192.0.2.1:53", "192.0.2.2:53"]);
+
+    let orders: Vec<_> = (0..4)
+      .map(|_| {
+        let mut servers = given.clone();
+        stats.order(&mut servers, Selection::RoundRobin, &mut rng);
+        servers
+      })
+      .collect();
+    assert_eq!(
+      orders,
+      [
+        addrs(&["192.0.2.1:53", "192.0.2.2:53", "192.0.2.3:53"]),
+        addrs(&["192.0.2.2:53", "192.0.2.3:53", "192.0.2.1:53"]),
+        addrs(&["192.0.2.3:53", "192.0.2.1:53", "192.0.2.2:53"]),
+        addrs(&["192.0.2.1:53", "192.0.2.2:53", "192.0.2.3:53"]),
+      ]
+    );
+  }
+
+  #[test]
+  fn fastest_puts_the_lowest_rtt_first_after_warmup() {
+    let stats = ServerStats::default();
+    let mut rng = StdRng::seed_from_u64(0);
+    let (slow, fast, failing) = (
+      "192.0.2.1".parse().unwrap(),
+      "192.0.2.2".parse().unwrap(),
+      "192.0.2.3".parse().unwrap(),
+    );
+    let mut servers = addrs(&["192.0.2.1:53", "192.0.2.2:53", "192.0.2.3:53"]);
+
+    // until it's been measured, each server comes first
+    stats.record(slow, Outcome::Answered, Duration::from_millis(80));
+    stats.order(&mut servers, Selection::Fastest, &mut rng);
+    assert_ne!(servers[0].ip(), slow);
+
+    for _ in 0..3 {
+      stats.record(slow, Outcome::Answered, Duration::from_millis(80));
+      stats.record(fast, Outcome::Answered, Duration::from_millis(10));
+      stats.record(failing, Outcome::Timeout, Duration::from_millis(1));
+    }
+    for _ in 0..4 {
+      stats.order(&mut servers, Selection::Fastest, &mut rng);
+      assert_eq!(
+        servers,
+        addrs(&["192.0.2.2:53", "192.0.2.1:53", "192.0.2.3:53"])
+      );
+    }
+  }
+
+  #[test]
+  fn outcomes_are_counted_with_a_smoothed_rtt() {
+    let stats = ServerStats::default();
+    let server = "192.0.2.1".parse().unwrap();
+    stats.record(server, Outcome::Answered, Duration::from_millis(100));
+    stats.record(server, Outcome::ServFail, Duration::from_millis(1));
+
+    let [(tracked, record)] = stats.servers()[..] else {
+      panic!("one server should be tracked");
+    };
+    assert_eq!(tracked, server);
+    assert_eq!(record.queries, 2);
+    assert_eq!(record.answered, 1);
+    assert_eq!(record.servfails, 1);
+    let expected = Duration::from_millis(100).mul_f64(0.7) + FAILED_RTT.mul_f64(0.3);
+    assert_eq!(record.rtt, expected);
+  }
+
+  #[test]
+  fn lame_servers_are_skipped_for_their_zone_unless_all_are() {
+    let stats = ServerStats::default();
+    let example = "example.com".parse().unwrap();
+    let org = "example.org".parse().unwrap();
+    stats.mark_lame("192.0.2.1".parse().unwrap(), &example);
+
+    let mut servers = addrs(&["192.0.2.1:53", "192.0.2.2:53"]);
+    stats.skip_lame(&mut servers, &example);
+    assert_eq!(servers, addrs(&["192.0.2.2:53"]));
+    let mut servers = addrs(&["192.0.2.1:53", "192.0.2.2:53"]);
+    stats.skip_lame(&mut servers, &org);
+    assert_eq!(servers, addrs(&["192.0.2.1:53", "192.0.2.2:53"]));
+    // a lame server is still asked when there's nothing else to ask
+    let mut servers = addrs(&["192.0.2.1:53"]);
+    stats.skip_lame(&mut servers, &example);
+    assert_eq!(servers, addrs(&["192.0.2.1:53"]));
+  }
+}
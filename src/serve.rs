@@ -0,0 +1,303 @@
+use std::{
+  io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex, PoisonError,
+  },
+  thread,
+  time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::Rng;
+use tracing::{debug, error};
+
+use crate::{tcp, transport::Transport, DnsServer, Protocol, EDNS_UDP_PAYLOAD_SIZE};
+
+// How often loops blocked waiting for a query check whether to shut down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// How long a TCP connection may sit idle between queries before it's closed (RFC 7766 6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to spend waking a TCP accept loop on shutdown
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Tells the serving loops to stop. It's triggered by whoever owns the loops, such as a signal
+/// handler, after which the loops finish the queries they're answering and return.
+#[derive(Default)]
+pub struct Shutdown {
+  triggered: AtomicBool,
+  /// Addresses of the TCP listeners serving, which are connected to once so their blocked
+  /// accept loops notice the shutdown.
+  listeners: Mutex<Vec<SocketAddr>>,
+}
+
+impl Shutdown {
+  /// Asks every loop watching this to stop.
+  pub fn trigger(&self) {
+    self.triggered.store(true, Ordering::SeqCst);
+    let listeners = self
+      .listeners
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    for addr in listeners.iter() {
+      let _ = TcpStream::connect_timeout(addr, WAKE_TIMEOUT);
+    }
+  }
+
+  pub fn is_triggered(&self) -> bool {
+    self.triggered.load(Ordering::SeqCst)
+  }
+
+  fn watch_listener(&self, listener: &TcpListener) -> io::Result<()> {
+    let mut addr = listener.local_addr()?;
+    // a listener on every interface can be reached through loopback
+    if addr.ip().is_unspecified() {
+      let loopback: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+      };
+      addr.set_ip(loopback);
+    }
+    self
+      .listeners
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .push(addr);
+    Ok(())
+  }
+}
+
+/// Answers queries arriving on `socket` until `shutdown` is triggered. Several workers can run
+/// this on handles to the same socket, and the OS hands each datagram to whichever one is free.
+pub fn run_udp<R: Rng, T: Transport>(
+  socket: &UdpSocket,
+  server: &mut DnsServer<R, T>,
+  shutdown: &Shutdown,
+) -> io::Result<()> {
+  // wake up now and then to check for shutdown, since nothing else interrupts recv_from
+  socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+  // queries using EDNS can be as large as the payload size we advertise
+  let mut packet_buf = [0u8; EDNS_UDP_PAYLOAD_SIZE as usize];
+  while !shutdown.is_triggered() {
+    match serve_udp(socket, &mut packet_buf, server) {
+      Ok(_) => {}
+      Err(e)
+        if e
+          .downcast_ref::<io::Error>()
+          .is_some_and(|e| is_timeout(e.kind())) => {}
+      Err(e) => error!("{e:#}"),
+    }
+  }
+  Ok(())
+}
+
+/// Accepts TCP connections on `listener` until `shutdown` is triggered, answering each on its own
+/// thread with a fork of `server`. Returns once every connection is closed.
+pub fn run_tcp<R: Rng, T: Transport>(
+  listener: &TcpListener,
+  server: &DnsServer<R, T>,
+  shutdown: &Shutdown,
+) -> io::Result<()> {
+  shutdown.watch_listener(listener)?;
+  thread::scope(|scope| {
+    for stream in listener.incoming() {
+      if shutdown.is_triggered() {
+        break;
+      }
+      match stream {
+        Ok(stream) => {
+          let mut server = server.fork();
+          scope.spawn(move || {
+            if let Err(e) = serve_tcp(stream, &mut server, shutdown) {
+              error!("{e:#}");
+            }
+          });
+        }
+        Err(e) => error!("{e:#}"),
+      }
+    }
+  });
+  Ok(())
+}
+
+/// Receives a single query on `socket` into `buf` and sends back the response, if it gets one.
+/// `buf` is reused across calls, so only the bytes received this time are handed on, as
+/// `DnsServer::handle_query` requires.
+pub fn serve_udp<R: Rng, T: Transport>(
+  socket: &UdpSocket,
+  buf: &mut [u8],
+  server: &mut DnsServer<R, T>,
+) -> Result<()> {
+  let (len, src) = socket.recv_from(buf)?;
+  if let Some(response) = server.handle_query(&buf[..len], client_addr(src), Protocol::Udp)? {
+    socket.send_to(&response, src)?;
+  }
+  Ok(())
+}
+
+/// Answers queries sent over a single TCP connection until the client closes it, it goes idle,
+/// or `shutdown` is triggered.
+pub fn serve_tcp<R: Rng, T: Transport>(
+  mut stream: TcpStream,
+  server: &mut DnsServer<R, T>,
+  shutdown: &Shutdown,
+) -> Result<()> {
+  let client = client_addr(stream.peer_addr()?);
+  while wait_for_message(&stream, shutdown)? {
+    // a client that has started a message gets the full timeout to finish it
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let request = match tcp::read_message(&mut stream) {
+      Ok(Some(request)) => request,
+      Ok(None) => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+    if let Some(response) = server.handle_query(&request, client, Protocol::Tcp)? {
+      tcp::write_message(&mut stream, &response)?;
+    }
+  }
+  debug!("Closing idle TCP connection from {client}");
+  Ok(())
+}
+
+/// Waits for the client to start sending another message, or to close the connection. Returns
+/// false if the connection stays idle for too long or `shutdown` is triggered first.
+fn wait_for_message(stream: &TcpStream, shutdown: &Shutdown) -> io::Result<bool> {
+  stream.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+  let started = Instant::now();
+  let mut byte = [0u8];
+  while !shutdown.is_triggered() && started.elapsed() < TCP_IDLE_TIMEOUT {
+    match stream.peek(&mut byte) {
+      Ok(_) => return Ok(true),
+      Err(e) if is_timeout(e.kind()) => {}
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(false)
+}
+
+/// The address to know a client by. IPv4 clients reaching a socket on `::` that also accepts IPv4
+/// show up with IPv4-mapped IPv6 addresses, which are turned back into the IPv4 ones so access
+/// lists, rate limits and logs see the same address however the client connected.
+fn client_addr(src: SocketAddr) -> SocketAddr {
+  SocketAddr::new(src.ip().to_canonical(), src.port())
+}
+
+/// Whether an error comes from a socket read timing out, which is reported as WouldBlock on Unix
+/// and TimedOut on Windows.
+fn is_timeout(kind: io::ErrorKind) -> bool {
+  matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+
+  use domain::base::iana::{Rcode, Rtype};
+
+  use super::*;
+  use crate::{
+    tests::{answer_addrs, dnssec_query, ip, query, resolving_www_mock, server},
+    transport::mock::MockTransport,
+    Message,
+  };
+
+  // An OPT record with no options: the root name, then type, class, TTL and RDATA length
+  const OPT_RECORD_LEN: usize = 11;
+
+  #[test]
+  fn short_requests_are_read_without_bytes_of_longer_ones() {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(socket.local_addr().unwrap()).unwrap();
+    client
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+
+    // a query ending in an OPT record setting DO, then the same query cut short before the OPT
+    // record, though its header still counts it
+    let long = dnssec_query("www.example.com", Rtype::A);
+    let short = &long[..long.len() - OPT_RECORD_LEN];
+    let mut buf = [0u8; 4096];
+    let mut received = [0u8; 4096];
+    for request in [&long[..], short] {
+      client.send(request).unwrap();
+      serve_udp(&socket, &mut buf, &mut server).unwrap();
+    }
+    let len = client.recv(&mut received).unwrap();
+    let response = Message::from_octets(received[..len].to_vec()).unwrap();
+    assert!(response.opt().unwrap().dnssec_ok());
+
+    // had the OPT record left in the buffer been read, the second response would carry one too
+    let len = client.recv(&mut received).unwrap();
+    let response = Message::from_octets(received[..len].to_vec()).unwrap();
+    assert_eq!(response.header().id(), 0x1234);
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    assert!(response.opt().is_none());
+  }
+
+  #[test]
+  fn answers_length_prefixed_queries_over_tcp() {
+    let mock = resolving_www_mock();
+    let server = server(mock);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = Shutdown::default();
+
+    thread::scope(|scope| {
+      scope.spawn(|| run_tcp(&listener, &server, &shutdown).unwrap());
+      let mut stream = TcpStream::connect(addr).unwrap();
+      stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+      let request = query("www.example.com", Rtype::A);
+      let len = u16::try_from(request.len()).unwrap();
+      stream.write_all(&len.to_be_bytes()).unwrap();
+      stream.write_all(&request).unwrap();
+
+      let mut len = [0u8; 2];
+      stream.read_exact(&mut len).unwrap();
+      let mut response = vec![0u8; u16::from_be_bytes(len).into()];
+      stream.read_exact(&mut response).unwrap();
+      drop(stream);
+      shutdown.trigger();
+
+      let response = Message::from_octets(response).unwrap();
+      assert_eq!(response.header().id(), 0x1234);
+      assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    });
+  }
+
+  #[test]
+  fn serving_loops_return_once_shut_down() {
+    let server = server(MockTransport::default());
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let shutdown = Shutdown::default();
+    let mut udp_server = server.fork();
+
+    thread::scope(|scope| {
+      let udp = scope.spawn(|| run_udp(&socket, &mut udp_server, &shutdown));
+      let tcp = scope.spawn(|| run_tcp(&listener, &server, &shutdown));
+      thread::sleep(Duration::from_millis(50));
+      assert!(!udp.is_finished() && !tcp.is_finished());
+
+      let triggered = Instant::now();
+      shutdown.trigger();
+      udp.join().unwrap().unwrap();
+      tcp.join().unwrap().unwrap();
+      assert!(triggered.elapsed() < SHUTDOWN_POLL_INTERVAL * 3);
+    });
+  }
+
+  #[test]
+  fn ipv4_mapped_clients_are_known_by_their_ipv4_address() {
+    let mapped: SocketAddr = "[::ffff:198.51.100.1]:5353".parse().unwrap();
+    assert_eq!(client_addr(mapped), "198.51.100.1:5353".parse().unwrap());
+    let v6: SocketAddr = "[2001:db8::1]:5353".parse().unwrap();
+    assert_eq!(client_addr(v6), v6);
+  }
+}
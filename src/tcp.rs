@@ -0,0 +1,25 @@
+use std::io::{self, Read, Write};
+
+/// Reads one DNS message sent over a stream, which is prefixed by its length as a two byte
+/// integer (RFC 1035 section 4.2.2). Returns `None` if the stream was closed before a new
+/// message started.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+  let mut len = [0u8; 2];
+  match stream.read_exact(&mut len) {
+    Ok(()) => {}
+    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e),
+  }
+
+  let mut buf = vec![0u8; u16::from_be_bytes(len).into()];
+  stream.read_exact(&mut buf)?;
+  Ok(Some(buf))
+}
+
+/// Writes a DNS message to a stream, prefixed by its length.
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+  let len = u16::try_from(message.len())
+    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long for TCP"))?;
+  stream.write_all(&len.to_be_bytes())?;
+  stream.write_all(message)
+}
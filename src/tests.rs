@@ -0,0 +1,3263 @@
+//! Tests of resolution and of answering client queries, run against a `MockTransport` playing a
+//! root server, a com. server and an example.com. server. The helpers here are shared with the
+//! tests of other modules.
+
+use std::{
+  io,
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  str::FromStr,
+  sync::{Arc, Mutex, PoisonError},
+  thread,
+  time::{Duration, Instant},
+};
+
+use domain::{
+  base::{
+    iana::{Class, ExtendedErrorCode, Opcode, Rcode, Rtype, SecAlg},
+    message_builder::{AdditionalBuilder, TreeCompressor},
+    octets::OctetsBuilder,
+    opt::{ClientSubnet, ExtendedError},
+    Dname, MessageBuilder, Question, Record, Serial,
+  },
+  rdata::{Aaaa, AllRecordData, Cname, Hinfo, Ns, Ptr, Rrsig, Soa, Txt, A},
+};
+use rand::{rngs::StdRng, SeedableRng};
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+use crate::{
+  acl::Cidr, addresses, complete_names, records::Trust, transport::mock::MockTransport,
+  transport::Transport, AnswerKind, CacheEntry, DnsServer, Message, Octets, Protocol, QuestionKey,
+  ResolveError, SubnetPrefixes, Zone, DEFAULT_CLIENT_SUBNET, DEFAULT_IDENTITY,
+  EDNS_UDP_PAYLOAD_SIZE, STALE_TTL,
+};
+
+pub(crate) const ROOT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+pub(crate) const COM: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)), 53);
+pub(crate) const EXAMPLE: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3)), 53);
+pub(crate) const CLIENT: SocketAddr =
+  SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 5353);
+
+pub(crate) type TestRecord = Record<Dname<Octets>, AllRecordData<Octets, Dname<Octets>>>;
+
+pub(crate) fn name(name: &str) -> Dname<Octets> {
+  Dname::from_str(name).unwrap()
+}
+
+pub(crate) fn ip(addr: &str) -> IpAddr {
+  addr.parse().unwrap()
+}
+
+pub(crate) fn question(qname: &str, qtype: Rtype) -> QuestionKey {
+  Question::new_in(name(qname), qtype)
+}
+
+pub(crate) fn record(owner: &str, data: AllRecordData<Octets, Dname<Octets>>) -> TestRecord {
+  Record::new(name(owner), Class::In, 3600, data)
+}
+
+pub(crate) fn a(owner: &str, addr: &str) -> TestRecord {
+  record(owner, A::new(addr.parse().unwrap()).into())
+}
+
+pub(crate) fn aaaa(owner: &str, addr: &str) -> TestRecord {
+  record(owner, Aaaa::new(addr.parse().unwrap()).into())
+}
+
+pub(crate) fn ns(owner: &str, host: &str) -> TestRecord {
+  record(owner, Ns::new(name(host)).into())
+}
+
+pub(crate) fn ptr(owner: &str, host: &str) -> TestRecord {
+  record(owner, Ptr::new(name(host)).into())
+}
+
+pub(crate) fn cname(owner: &str, target: &str) -> TestRecord {
+  record(owner, Cname::new(name(target)).into())
+}
+
+/// The SOA record of `zone`, whose MINIMUM field is `minimum`.
+pub(crate) fn soa(zone: &str, minimum: u32) -> TestRecord {
+  let soa = Soa::new(
+    name(&format!("ns.{zone}")),
+    name(&format!("hostmaster.{zone}")),
+    1.into(),
+    3600,
+    600,
+    86400,
+    minimum,
+  );
+  record(zone, soa.into())
+}
+
+pub(crate) fn with_ttl(mut record: TestRecord, ttl: u32) -> TestRecord {
+  record.set_ttl(ttl);
+  record
+}
+
+/// A response to a question, built section by section.
+#[derive(Clone)]
+pub(crate) struct Reply {
+  question: QuestionKey,
+  rcode: Rcode,
+  aa: bool,
+  tc: bool,
+  answers: Vec<TestRecord>,
+  authorities: Vec<TestRecord>,
+  additionals: Vec<TestRecord>,
+  client_subnet: Option<(Cidr, u8)>,
+  compressed: bool,
+}
+
+/// Starts a NOERROR response to the question for `qname`'s `qtype` records.
+pub(crate) fn reply(qname: &str, qtype: Rtype) -> Reply {
+  Reply {
+    question: question(qname, qtype),
+    rcode: Rcode::NoError,
+    aa: false,
+    tc: false,
+    answers: Vec::new(),
+    authorities: Vec::new(),
+    additionals: Vec::new(),
+    client_subnet: None,
+    compressed: false,
+  }
+}
+
+impl Reply {
+  pub(crate) fn rcode(mut self, rcode: Rcode) -> Reply {
+    self.rcode = rcode;
+    self
+  }
+
+  pub(crate) fn authoritative(mut self) -> Reply {
+    self.aa = true;
+    self
+  }
+
+  pub(crate) fn truncated(mut self) -> Reply {
+    self.tc = true;
+    self
+  }
+
+  pub(crate) fn answer(mut self, record: TestRecord) -> Reply {
+    self.answers.push(record);
+    self
+  }
+
+  pub(crate) fn authority(mut self, record: TestRecord) -> Reply {
+    self.authorities.push(record);
+    self
+  }
+
+  pub(crate) fn additional(mut self, record: TestRecord) -> Reply {
+    self.additionals.push(record);
+    self
+  }
+
+  /// Repeats `subnet` in a client subnet option, saying the answer applies to the addresses
+  /// sharing its first `scope` bits.
+  pub(crate) fn client_subnet(mut self, subnet: Cidr, scope: u8) -> Reply {
+    self.client_subnet = Some((subnet, scope));
+    self
+  }
+
+  /// Has names in the response compressed, as most servers do.
+  pub(crate) fn compressed(mut self) -> Reply {
+    self.compressed = true;
+    self
+  }
+
+  pub(crate) fn build(&self) -> Message {
+    if self.compressed {
+      let builder = MessageBuilder::from_target(TreeCompressor::new(Vec::new())).unwrap();
+      let octets = self.fill(builder).finish().into_target();
+      Message::from_octets(octets).unwrap()
+    } else {
+      self.fill(MessageBuilder::new_vec()).into_message()
+    }
+  }
+
+  fn fill<Target: OctetsBuilder>(
+    &self,
+    mut builder: MessageBuilder<Target>,
+  ) -> AdditionalBuilder<Target> {
+    let header = builder.header_mut();
+    header.set_qr(true);
+    header.set_rcode(self.rcode);
+    header.set_aa(self.aa);
+    header.set_tc(self.tc);
+    let mut builder = builder.question();
+    builder.push(&self.question).unwrap();
+    let mut builder = builder.answer();
+    for record in &self.answers {
+      builder.push(record).unwrap();
+    }
+    let mut builder = builder.authority();
+    for record in &self.authorities {
+      builder.push(record).unwrap();
+    }
+    let mut builder = builder.additional();
+    for record in &self.additionals {
+      builder.push(record).unwrap();
+    }
+    if let Some((subnet, scope)) = self.client_subnet {
+      builder
+        .opt(|opt| opt.push(&ClientSubnet::new(subnet.prefix(), scope, subnet.addr())))
+        .unwrap();
+    }
+    builder
+  }
+}
+
+/// An authoritative answer from example.com.'s server giving `qname` the address `addr`.
+pub(crate) fn answer(qname: &str, addr: &str) -> Message {
+  reply(qname, Rtype::A)
+    .authoritative()
+    .answer(a(qname, addr))
+    .build()
+}
+
+/// Makes the root and com. servers of `mock` refer the question for `qname`'s `qtype` records
+/// down to example.com.'s server, ns.example.com. at `EXAMPLE`.
+pub(crate) fn delegate(mock: &MockTransport, qname: &str, qtype: Rtype) {
+  let to_com = reply(qname, qtype)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, qtype), ROOT, to_com.build());
+  let to_example = reply(qname, qtype)
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"));
+  mock.insert(question(qname, qtype), COM, to_example.build());
+}
+
+/// A mock that resolves www.example.com. to 192.0.2.10 from the root down, through
+/// [`delegate`].
+pub(crate) fn resolving_www_mock() -> MockTransport {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  mock
+}
+
+/// A server resolving through `mock` from `ROOT`, asking every server only once. Its random
+/// numbers are seeded, so the IDs of its queries are the same on every run.
+pub(crate) fn server(mock: MockTransport) -> DnsServer<StdRng, MockTransport> {
+  let mut server = DnsServer::with_cache_capacity(100, mock).unwrap();
+  server.root_hints = vec![ROOT.ip()];
+  server.udp_retries = 0;
+  server.rng = StdRng::seed_from_u64(0);
+  server
+}
+
+/// A cache entry holding a positive answer, `response`, for an hour.
+pub(crate) fn cached(response: Message) -> Arc<CacheEntry> {
+  Arc::new(CacheEntry::with_lifetime(
+    response,
+    AnswerKind::Positive,
+    false,
+    Duration::from_secs(3600),
+  ))
+}
+
+/// Resolves `qname`'s `qtype` records with `server`, which is expected to fail.
+pub(crate) fn resolve_err<T: Transport>(
+  server: &mut DnsServer<StdRng, T>,
+  qname: &str,
+  qtype: Rtype,
+) -> ResolveError {
+  match server.resolve(qname, qtype) {
+    Ok(_) => panic!("resolving {qname} should fail"),
+    Err(e) => e,
+  }
+}
+
+/// A client query for `qname`'s `qtype` records with RD set.
+pub(crate) fn query(qname: &str, qtype: Rtype) -> Octets {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_id(0x1234);
+  builder.header_mut().set_rd(true);
+  let mut builder = builder.question();
+  builder.push(question(qname, qtype)).unwrap();
+  builder.finish()
+}
+
+/// Has `server` answer `request` as if it came from `CLIENT` over UDP.
+pub(crate) fn ask<T: Transport>(server: &mut DnsServer<StdRng, T>, request: &[u8]) -> Message {
+  ask_from(server, request, CLIENT, Protocol::Udp)
+}
+
+/// Has `server` answer `request` as if it came from `client` over `protocol`.
+pub(crate) fn ask_from<T: Transport>(
+  server: &mut DnsServer<StdRng, T>,
+  request: &[u8],
+  client: SocketAddr,
+  protocol: Protocol,
+) -> Message {
+  let response = server.handle_query(request, client, protocol).unwrap();
+  Message::from_octets(response.expect("query should be answered")).unwrap()
+}
+
+/// The TTLs of the records in the answer section of `response`.
+pub(crate) fn answer_ttls(response: &Message) -> Vec<u32> {
+  let answers = response.answer().unwrap();
+  answers.map(|record| record.unwrap().ttl()).collect()
+}
+
+/// The addresses in the answer section of `response`.
+pub(crate) fn answer_addrs(response: &Message) -> Vec<IpAddr> {
+  addresses(response.answer().unwrap(), |_| true)
+}
+
+#[test]
+fn resolves_through_the_mock_transport() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let servers: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .map(|sent| sent.server)
+    .collect();
+  assert_eq!(servers, [ROOT, COM, EXAMPLE]);
+}
+
+#[test]
+fn answers_client_queries_through_the_mock_transport() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().id(), 0x1234);
+  assert!(response.header().qr());
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+}
+
+#[test]
+fn expired_answers_are_resolved_again() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let short_lived = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(with_ttl(a("www.example.com", "192.0.2.10"), 1));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    short_lived.build(),
+  );
+  let mut server = server(mock);
+
+  server.resolve("www.example.com", Rtype::A).unwrap();
+  server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+
+  thread::sleep(Duration::from_millis(1100));
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 2);
+}
+
+#[test]
+fn cached_answers_are_served_with_the_time_left() {
+  let mut server = server(MockTransport::default());
+  let mut entry = CacheEntry::with_lifetime(
+    answer("www.example.com", "192.0.2.10"),
+    AnswerKind::Positive,
+    false,
+    Duration::from_secs(3600),
+  );
+  entry.inserted -= Duration::from_secs(600);
+  entry.expires -= Duration::from_secs(600);
+  server
+    .cache()
+    .put(question("www.example.com", Rtype::A), Arc::new(entry));
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_ttls(&response), [3000]);
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn nxdomain_is_cached_for_the_soa_minimum() {
+  let mock = MockTransport::default();
+  delegate(&mock, "nope.example.com", Rtype::A);
+  let nxdomain = reply("nope.example.com", Rtype::A)
+    .rcode(Rcode::NXDomain)
+    .authoritative()
+    .authority(soa("example.com", 60));
+  mock.insert(
+    question("nope.example.com", Rtype::A),
+    EXAMPLE,
+    nxdomain.build(),
+  );
+  let mut server = server(mock);
+
+  for _ in 0..2 {
+    let response = server.resolve("nope.example.com", Rtype::A).unwrap();
+    assert_eq!(response.header().rcode(), Rcode::NXDomain);
+  }
+  assert_eq!(server.transport.sent().len(), 3);
+  let entry = server
+    .cache()
+    .get(&question("nope.example.com", Rtype::A))
+    .unwrap();
+  assert_eq!(entry.kind, AnswerKind::NxDomain);
+  assert_eq!(entry.expires - entry.inserted, Duration::from_secs(60));
+}
+
+#[test]
+fn nodata_is_cached_apart_from_nxdomain() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::Aaaa);
+  let nodata = reply("www.example.com", Rtype::Aaaa)
+    .authoritative()
+    .authority(soa("example.com", 60));
+  mock.insert(
+    question("www.example.com", Rtype::Aaaa),
+    EXAMPLE,
+    nodata.build(),
+  );
+  let mut server = server(mock);
+
+  for _ in 0..2 {
+    let response = server.resolve("www.example.com", Rtype::Aaaa).unwrap();
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    assert_eq!(response.header_counts().ancount(), 0);
+  }
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  let entry = server
+    .cache()
+    .get(&question("www.example.com", Rtype::Aaaa))
+    .unwrap();
+  assert_eq!(entry.kind, AnswerKind::NoData);
+}
+
+#[test]
+fn cache_holds_at_most_its_capacity() {
+  let mock = MockTransport::default();
+  for host in ["a", "b", "c"] {
+    let qname = format!("{host}.example.com");
+    delegate(&mock, &qname, Rtype::A);
+    mock.insert(
+      question(&qname, Rtype::A),
+      EXAMPLE,
+      answer(&qname, "192.0.2.10"),
+    );
+  }
+  let mut server = DnsServer::with_cache_capacity(2, mock).unwrap();
+  server.root_hints = vec![ROOT.ip()];
+  assert_eq!(server.cache_capacity(), 2);
+
+  for qname in [
+    "a.example.com",
+    "b.example.com",
+    "a.example.com",
+    "c.example.com",
+  ] {
+    server.resolve(qname, Rtype::A).unwrap();
+  }
+  assert_eq!(server.cache_len(), 2);
+  let mut cache = server.cache();
+  assert!(cache.get(&question("b.example.com", Rtype::A)).is_none());
+  assert!(cache.get(&question("a.example.com", Rtype::A)).is_some());
+  assert!(cache.get(&question("c.example.com", Rtype::A)).is_some());
+}
+
+#[test]
+fn truncated_responses_are_retried_over_tcp() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let truncated = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .truncated()
+    .answer(a("www.example.com", "192.0.2.10"));
+  let full = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"))
+    .answer(a("www.example.com", "192.0.2.11"));
+  let www = question("www.example.com", Rtype::A);
+  mock.insert_udp(www.clone(), EXAMPLE, truncated.build());
+  mock.insert_tcp(www, EXAMPLE, full.build());
+  let mut server = server(mock);
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert!(!response.header().tc());
+  assert_eq!(
+    answer_addrs(&response),
+    [ip("192.0.2.10"), ip("192.0.2.11")]
+  );
+}
+
+#[test]
+fn empty_truncated_responses_are_retried_over_tcp_once() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let truncated = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .truncated();
+  let full = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"))
+    .answer(a("www.example.com", "192.0.2.11"))
+    .answer(a("www.example.com", "192.0.2.12"));
+  let www = question("www.example.com", Rtype::A);
+  mock.insert_udp(www.clone(), EXAMPLE, truncated.build());
+  mock.insert_tcp(www, EXAMPLE, full.build());
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert!(!response.header().tc());
+  assert_eq!(
+    answer_addrs(&response),
+    [ip("192.0.2.10"), ip("192.0.2.11"), ip("192.0.2.12")]
+  );
+  let sent = server.transport.sent();
+  let exchanges: Vec<_> = sent
+    .iter()
+    .filter(|sent| sent.server == EXAMPLE)
+    .map(|sent| sent.tcp)
+    .collect();
+  assert_eq!(exchanges, [false, true]);
+}
+
+#[test]
+fn queries_advertise_a_larger_udp_payload_size() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  server.resolve("www.example.com", Rtype::A).unwrap();
+
+  for sent in server.transport.sent() {
+    let additional = sent.request.additional().unwrap();
+    let opt = sent.request.opt().expect("query should have an OPT record");
+    assert_eq!(opt.udp_payload_size(), EDNS_UDP_PAYLOAD_SIZE);
+    assert_eq!(additional.count(), 1);
+  }
+}
+
+#[test]
+fn responses_with_the_wrong_id_are_discarded() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let mut spoofed = answer("www.example.com", "203.0.113.66").into_octets();
+  spoofed[..2].copy_from_slice(&[0, 0]);
+  mock.insert_raw(question("www.example.com", Rtype::A), EXAMPLE, spoofed);
+  let mut server = server(mock);
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(&error, ResolveError::MalformedResponse(reason) if reason.contains("has ID 0")),
+    "{error}"
+  );
+  assert_eq!(server.cache_len(), 0);
+}
+
+#[test]
+fn glue_for_names_outside_the_zone_is_ignored() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let poisoned = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"))
+    .additional(a("www.example.org", "203.0.113.66"));
+  mock.insert(question(qname, Rtype::A), COM, poisoned.build());
+  mock.insert(
+    question(qname, Rtype::A),
+    EXAMPLE,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(
+    server.known_addresses(&name("ns.example.com")),
+    [ip("192.0.2.3")]
+  );
+  assert!(server.known_addresses(&name("www.example.org")).is_empty());
+}
+
+#[test]
+fn delegations_outside_the_zone_are_not_followed() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let hijack = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns.evil.org"))
+    .authority(ns("org", "ns.evil.org"))
+    .additional(a("ns.evil.org", "203.0.113.66"));
+  mock.insert(question(qname, Rtype::A), COM, hijack.build());
+  let evil: SocketAddr = "203.0.113.66:53".parse().unwrap();
+  mock.insert(
+    question(qname, Rtype::A),
+    evil,
+    answer(qname, "203.0.113.66"),
+  );
+  let mut server = server(mock);
+
+  // the glue for ns.evil.org is ignored, and looking up its address from the root gets nowhere
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert!(answer_addrs(&response).is_empty());
+  assert_eq!(server.transport.sent_to(evil), 0);
+  assert!(server.known_servers(&name("org")).is_empty());
+}
+
+#[test]
+fn cname_chains_are_followed_to_the_answer() {
+  let mock = MockTransport::default();
+  delegate(&mock, "a.example.com", Rtype::A);
+  let alias = reply("a.example.com", Rtype::A)
+    .authoritative()
+    .answer(cname("a.example.com", "b.example.com"));
+  mock.insert(question("a.example.com", Rtype::A), EXAMPLE, alias.build());
+  mock.insert(
+    question("b.example.com", Rtype::A),
+    EXAMPLE,
+    answer("b.example.com", "1.2.3.4"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve("a.example.com", Rtype::A).unwrap();
+  let answers: Vec<_> = response
+    .answer()
+    .unwrap()
+    .map(|record| record.unwrap())
+    .map(|record| (record.owner().to_string(), record.rtype()))
+    .collect();
+  assert_eq!(
+    answers,
+    [
+      ("a.example.com".to_string(), Rtype::Cname),
+      ("b.example.com".to_string(), Rtype::A)
+    ]
+  );
+  assert_eq!(answer_addrs(&response), [ip("1.2.3.4")]);
+}
+
+#[test]
+fn mutually_referential_delegations_fail() {
+  let mock = MockTransport::default();
+  // example.com. is served by ns.example.net., and example.net. by ns.example.com., neither with
+  // glue, so finding either server's address needs the other's first
+  for (qname, tld, zone, host) in [
+    ("www.example.com", "com", "example.com", "ns.example.net"),
+    ("ns.example.com", "com", "example.com", "ns.example.net"),
+    ("ns.example.net", "net", "example.net", "ns.example.com"),
+  ] {
+    let to_tld = reply(qname, Rtype::A)
+      .authority(ns(tld, "a.gtld-servers.net"))
+      .additional(a("a.gtld-servers.net", "192.0.2.2"));
+    mock.insert(question(qname, Rtype::A), ROOT, to_tld.build());
+    let to_zone = reply(qname, Rtype::A).authority(ns(zone, host));
+    mock.insert(question(qname, Rtype::A), COM, to_zone.build());
+  }
+  let mut server = server(mock);
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(error, ResolveError::MaxDepthExceeded(_)),
+    "{error}"
+  );
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+}
+
+#[test]
+fn servers_that_time_out_are_passed_over() {
+  let mock = MockTransport::default();
+  let good: SocketAddr = "192.0.2.34:53".parse().unwrap();
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    good,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  // more servers fail to respond than are asked at once, so the good one is only asked once some
+  // of them time out
+  let dead: Vec<SocketAddr> = ["192.0.2.31:53", "192.0.2.32:53", "192.0.2.33:53"]
+    .iter()
+    .map(|addr| addr.parse().unwrap())
+    .collect();
+  let servers = [&dead[..], &[good]].concat();
+  let budget = server.budget();
+  let www = question("www.example.com", Rtype::A);
+  let (answered, response) = server
+    .lookup_any(&www, &servers, false, None, &budget)
+    .unwrap();
+  assert_eq!(answered, good);
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(budget.queries(), 4);
+}
+
+#[test]
+fn resolution_survives_an_unresponsive_name_server() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns1.example.com"))
+    .authority(ns("example.com", "ns2.example.com"))
+    .additional(a("ns1.example.com", "192.0.2.31"))
+    .additional(a("ns2.example.com", "192.0.2.3"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  mock.insert(
+    question(qname, Rtype::A),
+    EXAMPLE,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+}
+
+#[test]
+fn name_servers_are_tried_until_one_resolves() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  // neither server comes with glue, and only the second one's name resolves
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns1.example.org"))
+    .authority(ns("example.com", "ns2.example.net"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  for rtype in [Rtype::A, Rtype::Aaaa] {
+    let missing = reply("ns1.example.org", rtype)
+      .rcode(Rcode::NXDomain)
+      .authoritative()
+      .authority(soa("org", 60));
+    mock.insert(question("ns1.example.org", rtype), ROOT, missing.build());
+  }
+  let to_net = reply("ns2.example.net", Rtype::A)
+    .authority(ns("net", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question("ns2.example.net", Rtype::A), ROOT, to_net.build());
+  mock.insert(
+    question("ns2.example.net", Rtype::A),
+    COM,
+    answer("ns2.example.net", "192.0.2.3"),
+  );
+  mock.insert(
+    question(qname, Rtype::A),
+    EXAMPLE,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let asked: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .map(|sent| sent.request.sole_question().unwrap().qname().to_string())
+    .collect();
+  assert!(asked.iter().any(|qname| qname == "ns1.example.org"));
+}
+
+#[test]
+fn forwarded_queries_skip_the_root_and_are_cached() {
+  let mock = MockTransport::default();
+  let upstream: SocketAddr = "192.0.2.53:53".parse().unwrap();
+  let forwarded = reply("www.example.com", Rtype::A).answer(a("www.example.com", "192.0.2.10"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    upstream,
+    forwarded.build(),
+  );
+  let mut server = server(mock);
+  server.forwarder = Some(upstream);
+
+  for _ in 0..2 {
+    let response = server.resolve("www.example.com", Rtype::A).unwrap();
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  }
+  let sent = server.transport.sent();
+  assert_eq!(sent.len(), 1);
+  assert_eq!(sent[0].server, upstream);
+  assert!(sent[0].request.header().rd());
+  assert_eq!(server.transport.sent_to(ROOT), 0);
+}
+
+#[test]
+fn delegations_with_only_aaaa_glue_are_followed_over_ipv6() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(aaaa("ns.example.com", "2001:db8::3"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  let example_v6: SocketAddr = "[2001:db8::3]:53".parse().unwrap();
+  mock.insert(
+    question(qname, Rtype::A),
+    example_v6,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(example_v6), 1);
+}
+
+#[test]
+fn name_servers_without_glue_are_found_by_their_aaaa_records() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let to_example = reply(qname, Rtype::A).authority(ns("example.com", "ns.example.net"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  for rtype in [Rtype::A, Rtype::Aaaa] {
+    let to_net = reply("ns.example.net", rtype)
+      .authority(ns("net", "a.gtld-servers.net"))
+      .additional(a("a.gtld-servers.net", "192.0.2.2"));
+    mock.insert(question("ns.example.net", rtype), ROOT, to_net.build());
+  }
+  let no_a = reply("ns.example.net", Rtype::A)
+    .authoritative()
+    .authority(soa("example.net", 60));
+  mock.insert(question("ns.example.net", Rtype::A), COM, no_a.build());
+  let v6 = reply("ns.example.net", Rtype::Aaaa)
+    .authoritative()
+    .answer(aaaa("ns.example.net", "2001:db8::3"));
+  mock.insert(question("ns.example.net", Rtype::Aaaa), COM, v6.build());
+  let example_v6: SocketAddr = "[2001:db8::3]:53".parse().unwrap();
+  mock.insert(
+    question(qname, Rtype::A),
+    example_v6,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(example_v6), 1);
+}
+
+#[test]
+fn answers_too_large_for_udp_are_truncated() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let mut large = reply("www.example.com", Rtype::A).authoritative();
+  for host in 1..=40 {
+    large = large.answer(a("www.example.com", &format!("192.0.2.{host}")));
+  }
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    large.build(),
+  );
+  let mut server = server(mock);
+  let request = query("www.example.com", Rtype::A);
+
+  let udp = server
+    .handle_query(&request, CLIENT, Protocol::Udp)
+    .unwrap()
+    .unwrap();
+  assert!(udp.len() <= 512, "{} bytes", udp.len());
+  let udp = Message::from_octets(udp).unwrap();
+  assert!(udp.header().tc());
+  let fitted = answer_addrs(&udp);
+  assert!(!fitted.is_empty() && fitted.len() < 40);
+  assert_eq!(udp.header_counts().ancount() as usize, fitted.len());
+
+  let tcp = ask_from(&mut server, &request, CLIENT, Protocol::Tcp);
+  assert!(!tcp.header().tc());
+  assert_eq!(answer_addrs(&tcp).len(), 40);
+  assert_eq!(answer_addrs(&tcp)[..fitted.len()], fitted[..]);
+}
+
+#[test]
+fn concurrent_queries_are_all_answered_from_one_cache() {
+  const CLIENTS: usize = 8;
+  let mock = MockTransport::default();
+  for host in 0..CLIENTS {
+    let qname = format!("host{host}.example.com");
+    delegate(&mock, &qname, Rtype::A);
+    mock.insert(
+      question(&qname, Rtype::A),
+      EXAMPLE,
+      answer(&qname, &format!("192.0.2.{}", 100 + host)),
+    );
+  }
+  mock.set_delay(Duration::from_millis(10));
+  let server = server(mock);
+
+  thread::scope(|scope| {
+    for host in 0..CLIENTS {
+      let mut server = server.fork();
+      scope.spawn(move || {
+        let qname = format!("host{host}.example.com");
+        let response = ask(&mut server, &query(&qname, Rtype::A));
+        assert_eq!(
+          answer_addrs(&response),
+          [ip(&format!("192.0.2.{}", 100 + host))]
+        );
+      });
+    }
+  });
+  assert_eq!(server.cache_len(), CLIENTS);
+  for host in 0..CLIENTS {
+    let qname = format!("host{host}.example.com");
+    let entry = server.cache().get(&question(&qname, Rtype::A)).unwrap();
+    let cached = entry.message().unwrap();
+    assert_eq!(
+      answer_addrs(&cached),
+      [ip(&format!("192.0.2.{}", 100 + host))]
+    );
+  }
+}
+
+#[test]
+fn simultaneous_identical_queries_share_one_resolution() {
+  let mock = resolving_www_mock();
+  // slow enough that every client asks while the first resolution is still running
+  mock.set_delay(Duration::from_millis(100));
+  let server = server(mock);
+
+  thread::scope(|scope| {
+    for port in 5300..5308 {
+      let mut server = server.fork();
+      scope.spawn(move || {
+        // from a port of its own, so it isn't taken for a retransmission of another's query
+        let client = SocketAddr::new(CLIENT.ip(), port);
+        let request = query("www.example.com", Rtype::A);
+        let response = ask_from(&mut server, &request, client, Protocol::Udp);
+        assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+      });
+    }
+  });
+  assert_eq!(server.transport.sent_to(ROOT), 1);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+#[test]
+fn udp_retransmits_of_queries_being_answered_are_dropped() {
+  let mock = resolving_www_mock();
+  mock.set_delay(Duration::from_millis(100));
+  let mut server = server(mock);
+  let request = query("www.example.com", Rtype::A);
+
+  thread::scope(|scope| {
+    let mut original = server.fork();
+    let request = &request;
+    scope.spawn(move || {
+      let response = ask(&mut original, request);
+      assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    });
+    thread::sleep(Duration::from_millis(50));
+    let copy = server.handle_query(request, CLIENT, Protocol::Udp);
+    assert!(copy.unwrap().is_none());
+    // the same query over TCP isn't a retransmit, and shares the original's resolution
+    let response = ask_from(&mut server.fork(), request, CLIENT, Protocol::Tcp);
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  });
+  assert_eq!(server.metrics.retransmits.get(), 1);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+
+  // once answered, the same query is a new one
+  let response = ask(&mut server, &request);
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+}
+
+/// Collects everything logged through it, for tests to look at.
+#[derive(Clone, Default)]
+struct CapturedLog(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLog {
+  fn contents(&self) -> String {
+    let log = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+    String::from_utf8_lossy(&log).into_owned()
+  }
+}
+
+impl io::Write for CapturedLog {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let mut log = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+    log.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn answered_queries_are_logged() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  let log = CapturedLog::default();
+  let writer = log.clone();
+  let subscriber = tracing_subscriber::fmt()
+    .with_max_level(Level::DEBUG)
+    .with_ansi(false)
+    .with_writer(move || writer.clone())
+    .finish();
+
+  tracing::subscriber::with_default(subscriber, || {
+    ask(&mut server, &query("www.example.com", Rtype::A));
+  });
+  let log = log.contents();
+  assert!(
+    log.contains("INFO") && log.contains("Question: www.example.com"),
+    "{log}"
+  );
+  assert!(
+    log.contains("DEBUG") && log.contains("Attempting lookup of www.example.com"),
+    "{log}"
+  );
+}
+
+/// Caches `response` to the question for `qname`'s `qtype` records as if it had been cached
+/// `age` ago with a lifetime of `lifetime`.
+fn cache_aged<T: Transport>(
+  server: &DnsServer<StdRng, T>,
+  qname: &str,
+  qtype: Rtype,
+  response: Message,
+  lifetime: Duration,
+  age: Duration,
+) {
+  let mut entry = CacheEntry::with_lifetime(response, AnswerKind::Positive, false, lifetime);
+  entry.inserted -= age;
+  entry.expires -= age;
+  server.cache().put(question(qname, qtype), Arc::new(entry));
+}
+
+#[test]
+fn stale_answers_are_served_when_resolution_fails() {
+  // nothing answers, so resolving anything times out
+  let mut server = server(MockTransport::default());
+  server.serve_stale = Some(Duration::from_secs(24 * 60 * 60));
+  cache_aged(
+    &server,
+    "www.example.com",
+    Rtype::A,
+    answer("www.example.com", "192.0.2.10"),
+    Duration::from_secs(60),
+    Duration::from_secs(3600),
+  );
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(answer_ttls(&response), [STALE_TTL]);
+  assert!(server.transport.sent_to(ROOT) > 0);
+}
+
+#[test]
+fn stale_answers_are_only_served_within_the_window_when_enabled() {
+  let mut server = server(MockTransport::default());
+  cache_aged(
+    &server,
+    "www.example.com",
+    Rtype::A,
+    answer("www.example.com", "192.0.2.10"),
+    Duration::from_secs(60),
+    Duration::from_secs(3600),
+  );
+  resolve_err(&mut server, "www.example.com", Rtype::A);
+
+  server.serve_stale = Some(Duration::from_secs(600));
+  resolve_err(&mut server, "www.example.com", Rtype::A);
+}
+
+#[test]
+fn hits_near_expiry_refresh_the_answer_once() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.11"),
+  );
+  mock.set_delay(Duration::from_millis(20));
+  let mut server = server(mock);
+  server.prefetch = Some(0.1);
+  cache_aged(
+    &server,
+    "www.example.com",
+    Rtype::A,
+    answer("www.example.com", "192.0.2.10"),
+    Duration::from_secs(100),
+    Duration::from_secs(95),
+  );
+
+  // every hit is answered straight from the cache, while the first starts the refresh
+  for _ in 0..3 {
+    let response = server.resolve("www.example.com", Rtype::A).unwrap();
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  }
+  for _ in 0..100 {
+    if server.transport.sent_to(EXAMPLE) > 0 {
+      break;
+    }
+    thread::sleep(Duration::from_millis(10));
+  }
+  thread::sleep(Duration::from_millis(100));
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.11")]);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+#[test]
+fn answers_are_cached_apart_by_type() {
+  let mock = MockTransport::default();
+  delegate(&mock, "example.com", Rtype::Aaaa);
+  let nodata = reply("example.com", Rtype::Aaaa)
+    .authoritative()
+    .authority(soa("example.com", 60));
+  mock.insert(
+    question("example.com", Rtype::Aaaa),
+    EXAMPLE,
+    nodata.build(),
+  );
+  let mut server = server(mock);
+  server.cache().put(
+    question("example.com", Rtype::A),
+    cached(answer("example.com", "192.0.2.10")),
+  );
+
+  let response = server.resolve("example.com", Rtype::Aaaa).unwrap();
+  assert_eq!(response.header_counts().ancount(), 0);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  let response = server.resolve("example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+}
+
+#[test]
+fn answers_are_cached_apart_by_class() {
+  let server = server(MockTransport::default());
+  let mut cache = server.cache();
+  cache.put(
+    question("example.com", Rtype::A),
+    cached(answer("example.com", "192.0.2.10")),
+  );
+
+  let chaos = Question::new(name("example.com"), Rtype::A, Class::Ch);
+  assert!(cache.get(&chaos).is_none());
+  assert!(cache.get(&question("example.com", Rtype::A)).is_some());
+}
+
+#[test]
+fn delegations_learned_for_one_name_are_reused_for_another() {
+  let mock = resolving_www_mock();
+  mock.insert(
+    question("mail.example.com", Rtype::A),
+    EXAMPLE,
+    answer("mail.example.com", "192.0.2.25"),
+  );
+  let mut server = server(mock);
+  server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(
+    server.known_addresses(&name("ns.example.com")),
+    [ip("192.0.2.3")]
+  );
+
+  // the NS records and glue for example.com are stored, so the root and com. aren't asked again
+  let response = server.resolve("mail.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.25")]);
+  let servers: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .map(|sent| sent.server)
+    .collect();
+  assert_eq!(servers, [ROOT, COM, EXAMPLE, EXAMPLE]);
+}
+
+#[test]
+fn reverse_names_are_resolved_through_their_delegations() {
+  // the reverse names of 192.0.2.10 and 2001:db8::a
+  let names = [
+    ("10.2.0.192.in-addr.arpa", "www.example.com"),
+    (
+      "a.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+      "www6.example.com",
+    ),
+  ];
+  let mock = MockTransport::default();
+  for (qname, host) in names {
+    let (tld, zone) = match qname.ends_with(".ip6.arpa") {
+      true => ("ip6.arpa", "8.b.d.0.1.0.0.2.ip6.arpa"),
+      false => ("in-addr.arpa", "2.0.192.in-addr.arpa"),
+    };
+    let to_arpa = reply(qname, Rtype::Ptr)
+      .authority(ns(tld, "a.in-addr-servers.arpa"))
+      .additional(a("a.in-addr-servers.arpa", "192.0.2.2"));
+    mock.insert(question(qname, Rtype::Ptr), ROOT, to_arpa.build());
+    let to_zone = reply(qname, Rtype::Ptr)
+      .authority(ns(zone, &format!("ns.{zone}")))
+      .additional(a(&format!("ns.{zone}"), "192.0.2.3"));
+    mock.insert(question(qname, Rtype::Ptr), COM, to_zone.build());
+    let named = reply(qname, Rtype::Ptr)
+      .authoritative()
+      .answer(ptr(qname, host));
+    mock.insert(question(qname, Rtype::Ptr), EXAMPLE, named.build());
+  }
+  let mut server = server(mock);
+
+  for (qname, host) in names {
+    let response = ask(&mut server, &query(qname, Rtype::Ptr));
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    let answers: Vec<_> = response
+      .answer()
+      .unwrap()
+      .limit_to::<Ptr<_>>()
+      .map(|record| record.unwrap().data().ptrdname().to_string())
+      .collect();
+    assert_eq!(answers, [host]);
+  }
+}
+
+/// A client query with RD set asking every question in `questions`.
+fn query_all(questions: &[QuestionKey]) -> Octets {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_id(0x1234);
+  builder.header_mut().set_rd(true);
+  let mut builder = builder.question();
+  for question in questions {
+    builder.push(question).unwrap();
+  }
+  builder.finish()
+}
+
+#[test]
+fn queries_are_answered_by_how_many_questions_they_ask() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  let www = question("www.example.com", Rtype::A);
+  let mail = question("mail.example.com", Rtype::A);
+
+  let none = ask(&mut server, &query_all(&[]));
+  assert_eq!(none.header().rcode(), Rcode::FormErr);
+  assert_eq!(none.header().id(), 0x1234);
+
+  let one = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(one.header().rcode(), Rcode::NoError);
+  assert_eq!(answer_addrs(&one), [ip("192.0.2.10")]);
+
+  let sent = server.transport.sent().len();
+  let two = ask(&mut server, &query_all(&[www, mail]));
+  assert_eq!(two.header().rcode(), Rcode::NotImp);
+  assert_eq!(two.header().id(), 0x1234);
+  assert_eq!(two.header_counts().ancount(), 0);
+  assert_eq!(server.transport.sent().len(), sent);
+}
+
+#[test]
+fn recursive_queries_get_their_flags_back() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  let mut request = MessageBuilder::new_vec();
+  request.header_mut().set_rd(true);
+  request.header_mut().set_cd(true);
+  let mut request = request.question();
+  request.push(question("www.example.com", Rtype::A)).unwrap();
+
+  let response = ask(&mut server, &request.finish());
+  let header = response.header();
+  assert!(header.qr());
+  assert_eq!(header.opcode(), Opcode::Query);
+  assert!(header.rd());
+  assert!(header.ra());
+  assert!(header.cd());
+  assert!(!header.aa());
+  assert_eq!(header.rcode(), Rcode::NoError);
+}
+
+#[test]
+fn updates_are_not_implemented() {
+  let mut server = server(MockTransport::default());
+  let mut request = MessageBuilder::new_vec();
+  request.header_mut().set_id(0x1234);
+  request.header_mut().set_opcode(Opcode::Update);
+  let mut request = request.question();
+  request
+    .push(Question::new(name("example.com"), Rtype::Soa, Class::In))
+    .unwrap();
+
+  let response = ask(&mut server, &request.finish());
+  assert_eq!(response.header().rcode(), Rcode::NotImp);
+  assert_eq!(response.header().opcode(), Opcode::Update);
+  assert_eq!(response.header().id(), 0x1234);
+  assert!(server.transport.sent().is_empty());
+}
+
+/// A NOTIFY for `zone`'s SOA record, as a primary sends it when the zone changes.
+fn notify(zone: &str, opcode: Opcode) -> Octets {
+  let mut request = MessageBuilder::new_vec();
+  request.header_mut().set_id(0x1234);
+  request.header_mut().set_opcode(opcode);
+  let mut request = request.question();
+  request
+    .push(Question::new_in(name(zone), Rtype::Soa))
+    .unwrap();
+  request.finish()
+}
+
+#[test]
+fn notifies_are_acknowledged_without_acting_on_them() {
+  let mut server = server(MockTransport::default());
+  let zone =
+    "$TTL 3600\n@ IN SOA ns1 hostmaster 1 7200 900 1209600 300\n  IN NS ns1\nns1 A 192.0.2.53\n";
+  server.zones = Arc::new(vec![Zone::parse(name("example.test"), zone).unwrap()]);
+
+  for (zone, served) in [
+    ("example.test", true),
+    ("www.example.test", false),
+    ("example.com", false),
+  ] {
+    let response = ask(&mut server, &notify(zone, Opcode::Notify));
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    assert_eq!(response.header().opcode(), Opcode::Notify);
+    assert_eq!(response.header().id(), 0x1234);
+    assert_eq!(response.header().aa(), served, "{zone}");
+    let question = response.sole_question().unwrap();
+    assert_eq!(question.qname().to_string(), zone);
+    assert_eq!(response.header_counts().ancount(), 0);
+  }
+  let response = ask(&mut server, &notify("example.test", Opcode::Status));
+  assert_eq!(response.header().rcode(), Rcode::NotImp);
+  assert!(server.transport.sent().is_empty());
+}
+
+/// A client query for `qname`'s `qtype` records with RD clear, asking for only what the server
+/// already knows.
+fn nonrecursive_query(qname: &str, qtype: Rtype) -> Octets {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_id(0x1234);
+  let mut builder = builder.question();
+  builder.push(question(qname, qtype)).unwrap();
+  builder.finish()
+}
+
+#[test]
+fn queries_without_rd_are_only_answered_locally() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  server.cache().put(
+    question("mail.example.com", Rtype::A),
+    cached(answer("mail.example.com", "192.0.2.25")),
+  );
+
+  let refused = ask(
+    &mut server,
+    &nonrecursive_query("www.example.com", Rtype::A),
+  );
+  assert_eq!(refused.header().rcode(), Rcode::Refused);
+  assert!(!refused.header().rd() && !refused.header().ra());
+  assert!(server.transport.sent().is_empty());
+
+  let cached = ask(
+    &mut server,
+    &nonrecursive_query("mail.example.com", Rtype::A),
+  );
+  assert_eq!(answer_addrs(&cached), [ip("192.0.2.25")]);
+  assert!(!cached.header().ra());
+
+  let recursed = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_addrs(&recursed), [ip("192.0.2.10")]);
+  assert!(recursed.header().rd() && recursed.header().ra());
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+#[test]
+fn queries_without_rd_are_resolved_when_always_recursing() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  server.always_recurse = true;
+
+  let response = ask(
+    &mut server,
+    &nonrecursive_query("www.example.com", Rtype::A),
+  );
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert!(response.header().ra());
+}
+
+#[test]
+fn refused_clients_are_not_offered_recursion() {
+  let mut server = server(MockTransport::default());
+  server.allowed_clients = Some(Arc::new(vec!["10.0.0.0/8".parse().unwrap()]));
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::Refused);
+  assert!(response.header().rd());
+  assert!(!response.header().ra());
+}
+
+#[test]
+fn the_first_server_to_answer_is_used() {
+  let mock = MockTransport::default();
+  let servers: Vec<SocketAddr> = ["192.0.2.31:53", "192.0.2.32:53", "192.0.2.33:53"]
+    .iter()
+    .map(|addr| addr.parse().unwrap())
+    .collect();
+  for (i, &server) in servers.iter().enumerate() {
+    mock.insert(
+      question("www.example.com", Rtype::A),
+      server,
+      answer("www.example.com", &format!("192.0.2.{}", 10 + i)),
+    );
+  }
+  // only the last server asked answers quickly
+  mock.set_server_delay(servers[0], Duration::from_secs(2));
+  mock.set_server_delay(servers[1], Duration::from_secs(2));
+  let mut server = server(mock);
+
+  let started = Instant::now();
+  let budget = server.budget();
+  let www = question("www.example.com", Rtype::A);
+  let (answered, response) = server
+    .lookup_any(&www, &servers, false, None, &budget)
+    .unwrap();
+  assert_eq!(answered, servers[2]);
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.12")]);
+  assert!(
+    started.elapsed() < Duration::from_secs(1),
+    "{:?}",
+    started.elapsed()
+  );
+  assert_eq!(server.transport.sent().len(), 3);
+}
+
+#[test]
+fn resolve_rejects_invalid_names() {
+  let mut server = server(MockTransport::default());
+
+  let error = resolve_err(&mut server, "bad..name", Rtype::A);
+  assert!(
+    matches!(&error, ResolveError::InvalidName(name, _) if name == "bad..name"),
+    "{error}"
+  );
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn unparseable_responses_are_malformed() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  mock.insert_raw(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    vec![0x12, 0x34, 0x81],
+  );
+  let mut server = server(mock);
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(error, ResolveError::MalformedResponse(_)),
+    "{error:?}"
+  );
+}
+
+#[test]
+fn servers_that_never_respond_time_out() {
+  let mut server = server(MockTransport::default());
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(matches!(error, ResolveError::Timeout), "{error:?}");
+}
+
+#[test]
+fn servers_referring_to_themselves_fail_quickly() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  // example.com.'s server refers the question back to itself
+  let to_itself = reply("www.example.com", Rtype::A)
+    .authority(ns("www.example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    to_itself.build(),
+  );
+  let mut server = server(mock);
+
+  let started = Instant::now();
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(matches!(error, ResolveError::ReferralLoop(_)), "{error:?}");
+  assert!(started.elapsed() < Duration::from_secs(1));
+  // the loop is noticed as soon as the server answers the same question a second time
+  assert_eq!(server.transport.sent_to(EXAMPLE), 2);
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+}
+
+#[test]
+fn randomized_case_is_sent_and_checked() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  server.randomize_case = true;
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let sent: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .map(|sent| sent.request.sole_question().unwrap().qname().to_string())
+    .collect();
+  assert!(
+    sent.iter().any(|qname| qname != "www.example.com"),
+    "{sent:?}"
+  );
+}
+
+#[test]
+fn responses_in_another_case_are_rejected() {
+  let mock = resolving_www_mock();
+  mock.keep_question_case();
+  let mut server = server(mock);
+  server.randomize_case = true;
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(&error, ResolveError::MalformedResponse(reason) if reason.contains("is for")),
+    "{error}"
+  );
+}
+
+#[test]
+fn responses_to_other_questions_are_discarded() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.org", "203.0.113.66"),
+  );
+  let mut server = server(mock);
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(&error, ResolveError::MalformedResponse(reason) if reason.contains("www.example.org")),
+    "{error}"
+  );
+  assert_eq!(server.cache_len(), 0);
+  assert!(server
+    .cache()
+    .get(&question("www.example.org", Rtype::A))
+    .is_none());
+}
+
+/// A client query for `qname`'s `qtype` records with RD set and an OPT record setting DO.
+pub(crate) fn dnssec_query(qname: &str, qtype: Rtype) -> Octets {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_id(0x1234);
+  builder.header_mut().set_rd(true);
+  let mut builder = builder.question();
+  builder.push(question(qname, qtype)).unwrap();
+  let mut builder = builder.additional();
+  builder
+    .opt(|opt| {
+      opt.set_dnssec_ok(true);
+      Ok(())
+    })
+    .unwrap();
+  builder.finish()
+}
+
+#[test]
+fn do_is_passed_upstream_and_signatures_back() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let signature = Rrsig::new(
+    Rtype::A,
+    SecAlg::EcdsaP256Sha256,
+    3,
+    3600,
+    Serial(2_000_000_000),
+    Serial(1_000_000_000),
+    12345,
+    name("example.com"),
+    vec![0; 64],
+  );
+  // none of the mock's responses carry an OPT record
+  let signed = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"))
+    .answer(record("www.example.com", AllRecordData::Rrsig(signature)));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    signed.build(),
+  );
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &dnssec_query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert!(response.opt().unwrap().dnssec_ok());
+  let rtypes: Vec<_> = response
+    .answer()
+    .unwrap()
+    .map(|record| record.unwrap().rtype())
+    .collect();
+  assert_eq!(rtypes, [Rtype::A, Rtype::Rrsig]);
+  let sent = server.transport.sent();
+  assert!(!sent.is_empty());
+  assert!(sent
+    .iter()
+    .all(|sent| sent.request.opt().is_some_and(|opt| opt.dnssec_ok())));
+
+  // a client without DO gets the same answer without the signature
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  let rtypes: Vec<_> = response
+    .answer()
+    .unwrap()
+    .map(|record| record.unwrap().rtype())
+    .collect();
+  assert_eq!(rtypes, [Rtype::A]);
+}
+
+/// The questions `server` sent to `to`, written as `name type`.
+fn questions_sent_to(server: &DnsServer<StdRng, MockTransport>, to: SocketAddr) -> Vec<String> {
+  server
+    .transport
+    .sent()
+    .iter()
+    .filter(|sent| sent.server == to)
+    .map(|sent| {
+      let question = sent.request.sole_question().unwrap();
+      format!("{} {}", question.qname(), question.qtype())
+    })
+    .collect()
+}
+
+/// Makes the root and com. servers of `mock` refer minimized queries down to example.com.'s
+/// server, ns.example.com. at `EXAMPLE`.
+fn delegate_minimized(mock: &MockTransport) {
+  let to_com = reply("com", Rtype::Ns)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question("com", Rtype::Ns), ROOT, to_com.build());
+  let to_example = reply("example.com", Rtype::Ns)
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"));
+  mock.insert(question("example.com", Rtype::Ns), COM, to_example.build());
+}
+
+#[test]
+fn minimized_queries_reveal_one_label_per_zone() {
+  let mock = MockTransport::default();
+  delegate_minimized(&mock);
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+  server.qname_minimization = true;
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(questions_sent_to(&server, ROOT), ["com NS"]);
+  assert_eq!(questions_sent_to(&server, COM), ["example.com NS"]);
+  assert_eq!(questions_sent_to(&server, EXAMPLE), ["www.example.com A"]);
+}
+
+#[test]
+fn names_without_a_zone_cut_reveal_another_label() {
+  let mock = MockTransport::default();
+  delegate_minimized(&mock);
+  // b.example.com. is in example.com.'s zone, which has no NS records for it
+  let nodata = reply("b.example.com", Rtype::Ns)
+    .authoritative()
+    .authority(soa("example.com", 300));
+  mock.insert(
+    question("b.example.com", Rtype::Ns),
+    EXAMPLE,
+    nodata.build(),
+  );
+  mock.insert(
+    question("a.b.example.com", Rtype::A),
+    EXAMPLE,
+    answer("a.b.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+  server.qname_minimization = true;
+
+  let response = server.resolve("a.b.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(
+    questions_sent_to(&server, EXAMPLE),
+    ["b.example.com NS", "a.b.example.com A"]
+  );
+}
+
+#[test]
+fn failed_minimized_queries_send_the_whole_name() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  mock.insert(
+    question("com", Rtype::Ns),
+    ROOT,
+    reply("com", Rtype::Ns).rcode(Rcode::ServFail).build(),
+  );
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+  server.qname_minimization = true;
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(
+    questions_sent_to(&server, ROOT),
+    ["com NS", "www.example.com A"]
+  );
+}
+
+#[test]
+fn cname_only_answers_continue_in_the_target_zone() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let alias = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(cname("www.example.com", "www.example.net"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    alias.build(),
+  );
+  // the root refers example.net. straight to its server, which is at `COM` as well
+  let to_example_net = reply("www.example.net", Rtype::A)
+    .authority(ns("example.net", "ns.example.net"))
+    .additional(a("ns.example.net", "192.0.2.2"));
+  mock.insert(
+    question("www.example.net", Rtype::A),
+    ROOT,
+    to_example_net.build(),
+  );
+  mock.insert(
+    question("www.example.net", Rtype::A),
+    COM,
+    answer("www.example.net", "192.0.2.20"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.20")]);
+  assert_eq!(
+    questions_sent_to(&server, COM),
+    ["www.example.com A", "www.example.net A"]
+  );
+}
+
+#[test]
+fn referrals_with_answers_for_other_names_are_followed() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let stray = reply("www.example.com", Rtype::A)
+    .answer(a("other.example.com", "203.0.113.66"))
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"));
+  mock.insert(question("www.example.com", Rtype::A), COM, stray.build());
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+}
+
+/// A server whose answer for www.example.com. takes seven queries: its name server,
+/// ns.example.net., is only found through that of example.net., ns.example.org., which has glue
+/// at 192.0.2.4.
+fn glueless_chain() -> DnsServer<StdRng, MockTransport> {
+  let org: SocketAddr = "192.0.2.4:53".parse().unwrap();
+  let mock = MockTransport::default();
+  let to_com = reply("www.example.com", Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question("www.example.com", Rtype::A), ROOT, to_com.build());
+  let to_example =
+    reply("www.example.com", Rtype::A).authority(ns("example.com", "ns.example.net"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    COM,
+    to_example.build(),
+  );
+  let to_net = reply("ns.example.net", Rtype::A).authority(ns("example.net", "ns.example.org"));
+  mock.insert(question("ns.example.net", Rtype::A), ROOT, to_net.build());
+  let to_org = reply("ns.example.org", Rtype::A)
+    .authority(ns("example.org", "ns.example.org"))
+    .additional(a("ns.example.org", "192.0.2.4"));
+  mock.insert(question("ns.example.org", Rtype::A), ROOT, to_org.build());
+  mock.insert(
+    question("ns.example.org", Rtype::A),
+    org,
+    answer("ns.example.org", "192.0.2.4"),
+  );
+  mock.insert(
+    question("ns.example.net", Rtype::A),
+    org,
+    answer("ns.example.net", "192.0.2.3"),
+  );
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  server(mock)
+}
+
+#[test]
+fn glueless_chains_resolve_within_the_default_budget() {
+  let mut server = glueless_chain();
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent().len(), 7);
+}
+
+#[test]
+fn queries_needing_more_than_the_budget_fail() {
+  let mut server = glueless_chain();
+  server.max_upstream_queries = 5;
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(error, ResolveError::BudgetExceeded(_)),
+    "{error:?}"
+  );
+  // the nested lookups of name server addresses count against the same budget
+  assert_eq!(server.transport.sent().len(), 5);
+
+  // a fresh server, since the first attempt cached what it found along the way
+  let mut server = glueless_chain();
+  server.max_upstream_queries = 5;
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+}
+
+#[test]
+fn lookups_are_traced_in_spans_naming_the_server() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  let log = CapturedLog::default();
+  let writer = log.clone();
+  let subscriber = tracing_subscriber::fmt()
+    .with_max_level(Level::DEBUG)
+    .with_ansi(false)
+    .with_span_events(FmtSpan::NEW)
+    .with_writer(move || writer.clone())
+    .finish();
+
+  tracing::subscriber::with_default(subscriber, || {
+    ask(&mut server, &query("www.example.com", Rtype::A));
+    ask(&mut server, &query("www.example.com", Rtype::A));
+  });
+  let log = log.contents();
+  // lookup spans are logged as they're created, nested in the query and resolution spans
+  let created: Vec<_> = log.lines().filter(|line| line.ends_with("new")).collect();
+  for server in [ROOT, COM, EXAMPLE] {
+    let lookup = format!(":lookup{{server={server} question=www.example.com.");
+    assert!(
+      created.iter().any(|line| line.contains("query{")
+        && line.contains("}:resolve{")
+        && line.contains(&lookup)),
+      "no span for {server} in {log}"
+    );
+  }
+  assert!(log.contains("cache=\"miss\""), "{log}");
+  assert!(log.contains("cache=\"hit\""), "{log}");
+}
+
+#[test]
+fn shared_name_servers_are_looked_up_once() {
+  let org: SocketAddr = "192.0.2.4:53".parse().unwrap();
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  // both example.com. and example.net. are served by ns.example.org., which has no glue
+  let to_example =
+    reply("www.example.com", Rtype::A).authority(ns("example.com", "ns.example.org"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    COM,
+    to_example.build(),
+  );
+  let to_net = reply("www.example.net", Rtype::A).authority(ns("example.net", "ns.example.org"));
+  mock.insert(question("www.example.net", Rtype::A), ROOT, to_net.build());
+  let to_org = reply("ns.example.org", Rtype::A)
+    .authority(ns("example.org", "a.iana-servers.org"))
+    .additional(a("a.iana-servers.org", "192.0.2.4"));
+  mock.insert(question("ns.example.org", Rtype::A), ROOT, to_org.build());
+  mock.insert(
+    question("ns.example.org", Rtype::A),
+    org,
+    answer("ns.example.org", "192.0.2.3"),
+  );
+  for qname in ["www.example.com", "www.example.net"] {
+    mock.insert(
+      question(qname, Rtype::A),
+      EXAMPLE,
+      answer(qname, "192.0.2.10"),
+    );
+  }
+  let mut server = server(mock);
+
+  for qname in ["www.example.com", "www.example.net"] {
+    let response = ask(&mut server, &query(qname, Rtype::A));
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")], "{qname}");
+  }
+  let lookups = server
+    .transport
+    .sent()
+    .iter()
+    .filter(|sent| sent.request.sole_question().unwrap().qname().to_string() == "ns.example.org")
+    .count();
+  assert_eq!(
+    lookups, 2,
+    "one query to the root and one to example.org.'s server"
+  );
+  assert_eq!(questions_sent_to(&server, org), ["ns.example.org A"]);
+}
+
+#[test]
+fn non_authoritative_answers_are_not_cached_as_authoritative() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  // example.com.'s server answers from its own cache, without AA
+  let from_cache = reply("www.example.com", Rtype::A).answer(a("www.example.com", "192.0.2.10"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    from_cache.build(),
+  );
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert!(!response.header().aa());
+  let entry = server
+    .cache()
+    .get(&question("www.example.com", Rtype::A))
+    .unwrap();
+  assert!(!entry.authoritative);
+}
+
+#[test]
+fn non_authoritative_refreshes_keep_authoritative_answers() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let from_cache = reply("www.example.com", Rtype::A).answer(a("www.example.com", "203.0.113.66"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    from_cache.build(),
+  );
+  let mut server = server(mock);
+  let key = question("www.example.com", Rtype::A);
+  server
+    .cache()
+    .put(key.clone(), cached(answer("www.example.com", "192.0.2.10")));
+
+  // as a prefetch would, resolve again while the cached answer is still fresh
+  let budget = server.budget();
+  let refreshed = server
+    .resolve_and_cache(&key, false, None, 0, &budget)
+    .unwrap();
+  assert_eq!(answer_addrs(&refreshed), [ip("203.0.113.66")]);
+  let entry = server.cache().get(&key).unwrap();
+  assert!(entry.authoritative);
+  assert_eq!(answer_addrs(&entry.message().unwrap()), [ip("192.0.2.10")]);
+}
+
+#[test]
+fn duplicate_records_are_sent_once_with_the_lowest_ttl() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let repeated = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"))
+    .answer(a("www.example.com", "192.0.2.11"))
+    .answer(with_ttl(a("www.example.com", "192.0.2.10"), 60))
+    .authority(ns("example.com", "ns.example.com"))
+    .authority(ns("example.com", "ns.example.com"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    repeated.build(),
+  );
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(
+    answer_addrs(&response),
+    [ip("192.0.2.10"), ip("192.0.2.11")]
+  );
+  assert_eq!(answer_ttls(&response), [60, 3600]);
+  assert_eq!(response.header_counts().nscount(), 1);
+}
+
+/// A server whose example.com. server answers for www.example.com. with a TTL of `ttl`.
+fn answering_with_ttl(ttl: u32) -> DnsServer<StdRng, MockTransport> {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let response = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(with_ttl(a("www.example.com", "192.0.2.10"), ttl));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    response.build(),
+  );
+  server(mock)
+}
+
+#[test]
+fn small_ttls_are_raised_to_the_minimum() {
+  let mut server = answering_with_ttl(5);
+  server.min_ttl = 60;
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_ttls(&response), [60]);
+  let entry = server
+    .cache()
+    .get(&question("www.example.com", Rtype::A))
+    .unwrap();
+  assert!(entry.expires > Instant::now() + Duration::from_secs(55));
+}
+
+#[test]
+fn large_ttls_are_capped_at_the_maximum() {
+  let mut server = answering_with_ttl(i32::MAX as u32);
+  server.max_ttl = 86400;
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_ttls(&response), [86400]);
+  let entry = server
+    .cache()
+    .get(&question("www.example.com", Rtype::A))
+    .unwrap();
+  assert!(entry.expires <= Instant::now() + Duration::from_secs(86400));
+}
+
+#[test]
+fn zero_ttls_are_only_cached_with_a_minimum() {
+  let mut server = answering_with_ttl(0);
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_ttls(&response), [0]);
+  assert_eq!(server.cache_len(), 0);
+
+  let mut server = answering_with_ttl(0);
+  server.min_ttl = 30;
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_ttls(&response), [30]);
+  assert_eq!(server.cache_len(), 1);
+}
+
+/// A second server for example.com., ns2.example.com.
+const EXAMPLE2: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 5)), 53);
+
+/// Makes `mock` delegate www.example.com. to both ns1.example.com. at `EXAMPLE` and
+/// ns2.example.com. at `EXAMPLE2`, with `first` answering from `EXAMPLE` at once and `second`
+/// from `EXAMPLE2` a moment later.
+fn two_servers(first: Message, second: Message) -> MockTransport {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns1.example.com"))
+    .authority(ns("example.com", "ns2.example.com"))
+    .additional(a("ns1.example.com", "192.0.2.3"))
+    .additional(a("ns2.example.com", "192.0.2.5"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  mock.insert(question(qname, Rtype::A), EXAMPLE, first);
+  mock.insert(question(qname, Rtype::A), EXAMPLE2, second);
+  mock.set_server_delay(EXAMPLE2, Duration::from_millis(50));
+  mock
+}
+
+#[test]
+fn servfail_from_one_server_moves_on_to_the_next() {
+  let servfail = reply("www.example.com", Rtype::A).rcode(Rcode::ServFail);
+  let mock = two_servers(servfail.build(), answer("www.example.com", "192.0.2.10"));
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  assert_eq!(server.transport.sent_to(EXAMPLE2), 1);
+}
+
+#[test]
+fn servfail_from_every_server_fails() {
+  let servfail = reply("www.example.com", Rtype::A).rcode(Rcode::ServFail);
+  let mock = two_servers(servfail.build(), servfail.build());
+  let mut server = server(mock);
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(error, ResolveError::ServerFailed(_, Rcode::ServFail)),
+    "{error:?}"
+  );
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+}
+
+#[test]
+fn nxdomain_after_a_refusal_is_final() {
+  let refused = reply("www.example.com", Rtype::A).rcode(Rcode::Refused);
+  let nxdomain = reply("www.example.com", Rtype::A)
+    .rcode(Rcode::NXDomain)
+    .authoritative()
+    .authority(soa("example.com", 300));
+  let mock = two_servers(refused.build(), nxdomain.build());
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::NXDomain);
+}
+
+#[test]
+fn lost_datagrams_are_retried_with_a_new_id() {
+  let mock = resolving_www_mock();
+  // the first query, to the root, is lost
+  mock.fail_next(1);
+  let mut server = server(mock);
+  server.udp_retries = 2;
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let ids: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .filter(|sent| sent.server == ROOT)
+    .map(|sent| sent.request.header().id())
+    .collect();
+  assert_eq!(ids.len(), 2);
+  assert_ne!(ids[0], ids[1]);
+}
+
+#[test]
+fn unresponsive_servers_are_asked_udp_retries_more_times() {
+  let mut server = server(MockTransport::default());
+  server.udp_retries = 2;
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(matches!(error, ResolveError::Timeout), "{error:?}");
+  let mut ids: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .map(|sent| sent.request.header().id())
+    .collect();
+  assert_eq!(ids.len(), 3);
+  ids.sort();
+  ids.dedup();
+  assert_eq!(ids.len(), 3);
+}
+
+#[test]
+fn retry_backoffs_double_with_jitter() {
+  let mut server = server(MockTransport::default());
+  assert_eq!(server.retry_backoff(0), Duration::ZERO);
+  for retry in 1..=3 {
+    let base = Duration::from_millis(100) * (1 << (retry - 1));
+    let backoff = server.retry_backoff(retry);
+    assert!(
+      backoff >= base / 2 && backoff < base * 3 / 2,
+      "{backoff:?} for retry {retry}"
+    );
+  }
+}
+
+/// A query for `qname`'s `qtype` records in the CHAOS class.
+fn chaos_query(qname: &str, qtype: Rtype) -> Octets {
+  let mut builder = MessageBuilder::new_vec();
+  builder.header_mut().set_id(0x1234);
+  let mut builder = builder.question();
+  builder
+    .push(Question::new(name(qname), qtype, Class::Ch))
+    .unwrap();
+  builder.finish()
+}
+
+/// The text of the TXT records in the answer section of `response`.
+fn answer_texts(response: &Message) -> Vec<String> {
+  let answers = response.answer().unwrap().limit_to::<Txt<_>>();
+  answers
+    .map(|record| {
+      let text: Vec<u8> = record.unwrap().data().text().unwrap();
+      String::from_utf8(text).unwrap()
+    })
+    .collect()
+}
+
+#[test]
+fn version_queries_get_the_identity() {
+  let mut server = server(MockTransport::default());
+
+  let response = ask(&mut server, &chaos_query("version.bind", Rtype::Txt));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert!(response.header().aa());
+  assert_eq!(answer_texts(&response), [DEFAULT_IDENTITY]);
+
+  server.identity = "resolver 7".to_string();
+  for qname in ["VERSION.BIND", "id.server"] {
+    let response = ask(&mut server, &chaos_query(qname, Rtype::Txt));
+    assert_eq!(answer_texts(&response), ["resolver 7"], "{qname}");
+  }
+  let response = ask(&mut server, &chaos_query("version.bind", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert_eq!(response.header_counts().ancount(), 0);
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn other_chaos_queries_are_refused() {
+  let mut server = server(MockTransport::default());
+
+  for qname in ["hostname.bind", "www.example.com"] {
+    let response = ask(&mut server, &chaos_query(qname, Rtype::Txt));
+    assert_eq!(response.header().rcode(), Rcode::Refused, "{qname}");
+  }
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn glueless_in_zone_delegations_fail_without_looping() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  // com. names example.com.'s only server inside example.com. but gives no address for it
+  let to_example =
+    reply("www.example.com", Rtype::A).authority(ns("example.com", "ns.example.com"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    COM,
+    to_example.build(),
+  );
+  let mut server = server(mock);
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(matches!(error, ResolveError::MissingGlue(_)), "{error:?}");
+  assert_eq!(server.transport.sent().len(), 2);
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+}
+
+#[test]
+fn glueless_in_zone_delegations_resolve_through_a_sibling() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  // the server inside example.com. has no glue, but the one in example.net. can be looked up
+  let to_example = reply("www.example.com", Rtype::A)
+    .authority(ns("example.com", "ns.example.com"))
+    .authority(ns("example.com", "ns.example.net"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    COM,
+    to_example.build(),
+  );
+  let to_net = reply("ns.example.net", Rtype::A)
+    .authority(ns("example.net", "ns.example.org"))
+    .additional(a("ns.example.org", "192.0.2.2"));
+  mock.insert(question("ns.example.net", Rtype::A), ROOT, to_net.build());
+  mock.insert(
+    question("ns.example.net", Rtype::A),
+    COM,
+    answer("ns.example.net", "192.0.2.3"),
+  );
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  // looking up the server inside the zone would mean asking the zone itself
+  assert!(server.transport.sent().iter().all(|sent| {
+    let question = sent.request.sole_question().unwrap();
+    question.qname().to_string() != "ns.example.com"
+  }));
+}
+
+#[test]
+fn any_queries_get_a_single_hinfo_record() {
+  let mut server = server(MockTransport::default());
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::Any));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  let answers: Vec<_> = response
+    .answer()
+    .unwrap()
+    .limit_to::<Hinfo<_>>()
+    .map(|record| record.unwrap())
+    .collect();
+  assert_eq!(answers.len(), 1);
+  assert_eq!(response.header_counts().ancount(), 1);
+  assert_eq!(answers[0].owner().to_string(), "www.example.com");
+  assert_eq!(answers[0].ttl(), 3600);
+  assert_eq!(answers[0].data().cpu().as_slice(), b"RFC8482");
+  assert!(answers[0].data().os().as_slice().is_empty());
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn any_queries_are_resolved_without_minimal_any() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::Any);
+  let records = reply("www.example.com", Rtype::Any)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"))
+    .answer(aaaa("www.example.com", "2001:db8::10"));
+  mock.insert(
+    question("www.example.com", Rtype::Any),
+    EXAMPLE,
+    records.build(),
+  );
+  let mut server = server(mock);
+  server.minimal_any = false;
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::Any));
+  assert_eq!(
+    answer_addrs(&response),
+    [ip("192.0.2.10"), ip("2001:db8::10")]
+  );
+}
+
+#[test]
+fn glue_addresses_come_ipv4_first_for_matching_owners() {
+  let referral = reply("www.example.com", Rtype::A)
+    .additional(aaaa("ns1.example.com", "2001:db8::3"))
+    .additional(a("NS1.Example.com", "192.0.2.3"))
+    .additional(a("ns2.example.com", "192.0.2.4"))
+    .additional(aaaa("ns1.example.com", "2001:db8::4"))
+    .build();
+  let ns1 = name("ns1.example.com");
+
+  let addrs = addresses(referral.additional().unwrap(), |owner| *owner == ns1);
+  assert_eq!(
+    addrs,
+    [ip("192.0.2.3"), ip("2001:db8::3"), ip("2001:db8::4")]
+  );
+}
+
+#[test]
+fn aaaa_answers_are_copied_to_clients() {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::Aaaa);
+  let records = reply("www.example.com", Rtype::Aaaa)
+    .authoritative()
+    .answer(aaaa("www.example.com", "2001:db8::1"));
+  mock.insert(
+    question("www.example.com", Rtype::Aaaa),
+    EXAMPLE,
+    records.build(),
+  );
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::Aaaa));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert_eq!(answer_addrs(&response), [ip("2001:db8::1")]);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+#[test]
+fn aaaa_answers_follow_cnames() {
+  let mock = MockTransport::default();
+  delegate(&mock, "a.example.com", Rtype::Aaaa);
+  let alias = reply("a.example.com", Rtype::Aaaa)
+    .authoritative()
+    .answer(cname("a.example.com", "b.example.com"));
+  mock.insert(
+    question("a.example.com", Rtype::Aaaa),
+    EXAMPLE,
+    alias.build(),
+  );
+  let records = reply("b.example.com", Rtype::Aaaa)
+    .authoritative()
+    .answer(aaaa("b.example.com", "2001:db8::2"));
+  mock.insert(
+    question("b.example.com", Rtype::Aaaa),
+    EXAMPLE,
+    records.build(),
+  );
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("a.example.com", Rtype::Aaaa));
+  let answers: Vec<_> = response
+    .answer()
+    .unwrap()
+    .map(|record| record.unwrap())
+    .map(|record| (record.owner().to_string(), record.rtype()))
+    .collect();
+  assert_eq!(
+    answers,
+    [
+      ("a.example.com".to_string(), Rtype::Cname),
+      ("b.example.com".to_string(), Rtype::Aaaa)
+    ]
+  );
+  assert_eq!(answer_addrs(&response), [ip("2001:db8::2")]);
+}
+
+#[test]
+fn a_queries_for_aaaa_only_names_get_no_data() {
+  let mock = MockTransport::default();
+  delegate(&mock, "v6.example.com", Rtype::A);
+  let nodata = reply("v6.example.com", Rtype::A)
+    .authoritative()
+    .authority(soa("example.com", 60));
+  mock.insert(
+    question("v6.example.com", Rtype::A),
+    EXAMPLE,
+    nodata.build(),
+  );
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query("v6.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::NoError);
+  assert_eq!(response.header_counts().ancount(), 0);
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+/// The client subnets sent in each query `server` sent to `to`.
+fn subnets_sent_to(server: &DnsServer<StdRng, MockTransport>, to: SocketAddr) -> Vec<Option<Cidr>> {
+  let sent = server.transport.sent();
+  sent
+    .iter()
+    .filter(|sent| sent.server == to)
+    .map(|sent| {
+      let opt = sent.request.opt()?;
+      let option = opt.as_opt().iter::<ClientSubnet>().next()?.unwrap();
+      Some(Cidr::truncated(option.addr(), option.source_prefix_len()))
+    })
+    .collect()
+}
+
+/// A server resolving www.example.com. to 192.0.2.10, with an answer scoped by `scope` bits of
+/// the client subnet 10.1.2.0/24 if `scope` is given.
+fn resolving_with_scope(scope: Option<u8>) -> DnsServer<StdRng, MockTransport> {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let mut records = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"));
+  if let Some(scope) = scope {
+    records = records.client_subnet("10.1.2.0/24".parse().unwrap(), scope);
+  }
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    records.build(),
+  );
+  server(mock)
+}
+
+#[test]
+fn client_subnets_are_sent_below_the_root() {
+  let mut server = resolving_with_scope(None);
+  server.client_subnet = Some(DEFAULT_CLIENT_SUBNET);
+  let client = "10.1.2.3:5353".parse().unwrap();
+
+  let response = ask_from(
+    &mut server,
+    &query("www.example.com", Rtype::A),
+    client,
+    Protocol::Udp,
+  );
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let subnet = Some("10.1.2.0/24".parse().unwrap());
+  assert_eq!(subnets_sent_to(&server, ROOT), [None]);
+  assert_eq!(subnets_sent_to(&server, COM), [subnet]);
+  assert_eq!(subnets_sent_to(&server, EXAMPLE), [subnet]);
+}
+
+#[test]
+fn client_subnet_prefixes_are_configurable() {
+  let mut server = resolving_with_scope(None);
+  server.client_subnet = Some(SubnetPrefixes { ipv4: 16, ipv6: 48 });
+  let client = "[2001:db8:aa:bbcc::1]:5353".parse().unwrap();
+
+  ask_from(
+    &mut server,
+    &query("www.example.com", Rtype::A),
+    client,
+    Protocol::Udp,
+  );
+  assert_eq!(
+    subnets_sent_to(&server, EXAMPLE),
+    [Some("2001:db8:aa::/48".parse().unwrap())]
+  );
+}
+
+#[test]
+fn client_subnets_are_not_sent_by_default() {
+  let mut server = resolving_with_scope(None);
+
+  ask(&mut server, &query("www.example.com", Rtype::A));
+  for to in [ROOT, COM, EXAMPLE] {
+    assert_eq!(subnets_sent_to(&server, to), [None]);
+  }
+}
+
+#[test]
+fn scoped_answers_are_only_served_to_their_subnet() {
+  let mut server = resolving_with_scope(Some(24));
+  server.client_subnet = Some(DEFAULT_CLIENT_SUBNET);
+  let request = query("www.example.com", Rtype::A);
+
+  for client in ["10.1.2.3:5353", "10.1.2.200:5353"] {
+    let response = ask_from(
+      &mut server,
+      &request,
+      client.parse().unwrap(),
+      Protocol::Udp,
+    );
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  }
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  // a client in another subnet isn't given the cached answer
+  ask_from(
+    &mut server,
+    &request,
+    "10.9.9.9:5353".parse().unwrap(),
+    Protocol::Udp,
+  );
+  assert_eq!(server.transport.sent_to(EXAMPLE), 2);
+  assert_eq!(
+    subnets_sent_to(&server, EXAMPLE)[1],
+    Some("10.9.9.0/24".parse().unwrap())
+  );
+}
+
+#[test]
+fn traces_record_each_response_used() {
+  let mut server = glueless_chain();
+  server.trace = Some(Vec::new());
+
+  server.resolve("www.example.com", Rtype::A).unwrap();
+  let steps: Vec<_> = server
+    .trace
+    .take()
+    .unwrap()
+    .iter()
+    .map(|step| {
+      let zone = step.zone.as_ref().unwrap();
+      format!(
+        "{} {zone}. {} {}",
+        step.depth,
+        step.server,
+        step.question.qname()
+      )
+    })
+    .collect();
+  // the address of each name server is looked up a level further down
+  assert_eq!(
+    steps,
+    [
+      "0 . 192.0.2.1:53 www.example.com",
+      "0 com. 192.0.2.2:53 www.example.com",
+      "1 . 192.0.2.1:53 ns.example.net",
+      "2 . 192.0.2.1:53 ns.example.org",
+      "2 example.org. 192.0.2.4:53 ns.example.org",
+      "1 example.net. 192.0.2.4:53 ns.example.net",
+      "0 example.com. 192.0.2.3:53 www.example.com",
+    ]
+  );
+}
+
+#[test]
+fn traces_name_no_zone_for_the_forwarder() {
+  let mock = MockTransport::default();
+  let forwarder: SocketAddr = "192.0.2.53:53".parse().unwrap();
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    forwarder,
+    answer("www.example.com", "192.0.2.10"),
+  );
+  let mut server = server(mock);
+  server.forwarder = Some(forwarder);
+  server.trace = Some(Vec::new());
+
+  server.resolve("www.example.com", Rtype::A).unwrap();
+  let steps = server.trace.take().unwrap();
+  assert_eq!(steps.len(), 1);
+  assert_eq!(steps[0].zone, None);
+  assert_eq!(steps[0].server, forwarder);
+  assert_eq!(answer_addrs(&steps[0].response), [ip("192.0.2.10")]);
+}
+
+/// A server with www.example.com. at 192.0.2.10, and an NXDOMAIN from the root for
+/// www.example.org.
+fn resolving_www() -> DnsServer<StdRng, MockTransport> {
+  let mock = resolving_www_mock();
+  let nxdomain = reply("www.example.org", Rtype::A)
+    .rcode(Rcode::NXDomain)
+    .authoritative()
+    .authority(soa("", 86400));
+  mock.insert(
+    question("www.example.org", Rtype::A),
+    ROOT,
+    nxdomain.build(),
+  );
+  server(mock)
+}
+
+#[test]
+fn names_are_absolute_with_or_without_a_trailing_dot() {
+  let names = |name| complete_names(name, &[], 1).unwrap();
+  assert_eq!(names("www.example.com"), [name("www.example.com")]);
+  assert_eq!(names("www.example.com."), [name("www.example.com")]);
+  assert_eq!(names("."), [Dname::root_vec()]);
+  assert_eq!(names("com."), [name("com")]);
+
+  let mut server = resolving_www();
+  for qname in ["www.example.com", "www.example.com."] {
+    let response = server.resolve(qname, Rtype::A).unwrap();
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  }
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+#[test]
+fn single_labels_need_a_search_domain() {
+  let mut server = resolving_www();
+  assert!(matches!(
+    resolve_err(&mut server, "www", Rtype::A),
+    ResolveError::SingleLabel(_)
+  ));
+  assert!(matches!(
+    resolve_err(&mut server, "bad..name", Rtype::A),
+    ResolveError::InvalidName(..)
+  ));
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn single_labels_are_completed_with_the_search_domains() {
+  let mut server = resolving_www();
+  server.search = vec![name("example.org"), name("example.com")];
+
+  let response = server.resolve("www", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  // www.example.org. doesn't exist, so the next search domain is tried
+  assert_eq!(questions_sent_to(&server, ROOT)[0], "www.example.org A");
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+}
+
+#[test]
+fn names_are_tried_around_the_search_domains_by_their_dots() {
+  let search = [name("corp.example"), name("example.com")];
+  assert_eq!(
+    complete_names("www", &search, 1).unwrap(),
+    [
+      name("www.corp.example"),
+      name("www.example.com"),
+      name("www")
+    ]
+  );
+  assert_eq!(
+    complete_names("www.lab", &search, 1).unwrap(),
+    [
+      name("www.lab"),
+      name("www.lab.corp.example"),
+      name("www.lab.example.com")
+    ]
+  );
+  assert_eq!(
+    complete_names("www.lab", &search, 2).unwrap(),
+    [
+      name("www.lab.corp.example"),
+      name("www.lab.example.com"),
+      name("www.lab")
+    ]
+  );
+  assert_eq!(
+    complete_names("www.lab.", &search, 2).unwrap(),
+    [name("www.lab")]
+  );
+}
+
+#[test]
+fn single_labels_resolve_through_the_first_search_domain() {
+  let mut server = resolving_www();
+  server.search = vec![name("example.com"), name("example.org")];
+
+  let response = server.resolve("www", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(questions_sent_to(&server, ROOT), ["www.example.com A"]);
+}
+
+#[test]
+fn dotted_names_resolve_as_they_are_before_searching() {
+  let mut server = resolving_www();
+  server.search = vec![name("example.org")];
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(questions_sent_to(&server, ROOT), ["www.example.com A"]);
+}
+
+#[test]
+fn compressed_glue_matches_its_name_server_ignoring_case() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld-servers.net"))
+    .additional(a("a.gtld-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  // the glue's owner is spelled differently, and all but its first label is a pointer
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns1.example.com"))
+    .additional(a("NS1.EXAMPLE.com", "192.0.2.3"));
+  let compressed = to_example.clone().compressed().build();
+  assert!(compressed.as_slice().len() < to_example.build().as_slice().len());
+  mock.insert(question(qname, Rtype::A), COM, compressed);
+  mock.insert(
+    question(qname, Rtype::A),
+    EXAMPLE,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let servers: Vec<_> = server
+    .transport
+    .sent()
+    .iter()
+    .map(|sent| sent.server)
+    .collect();
+  assert_eq!(servers, [ROOT, COM, EXAMPLE]);
+}
+
+#[test]
+fn names_in_different_cases_share_one_lookup_and_cache_entry() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+
+  for qname in ["www.example.com", "WwW.ExAmPlE.CoM"] {
+    let response = ask(&mut server, &query(qname, Rtype::A));
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    // each client gets its question back in the case it asked in
+    let echoed = response.sole_question().unwrap().qname().to_string();
+    assert_eq!(echoed, qname);
+  }
+  assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  assert_eq!(server.cache().len(), 1);
+}
+
+#[test]
+fn delegations_match_the_zone_ignoring_case() {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("COM", "a.gtld-servers.net"))
+    .additional(a("A.GTLD-servers.net", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("Example.COM", "ns.example.com"))
+    .additional(a("NS.Example.Com", "192.0.2.3"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  mock.insert(
+    question(qname, Rtype::A),
+    EXAMPLE,
+    answer(qname, "192.0.2.10"),
+  );
+  let mut server = server(mock);
+
+  let response = server.resolve(qname, Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent().len(), 3);
+}
+
+/// A server whose root hints list only `ROOT`, which answers the priming query with itself and
+/// a second root server at 192.0.2.6, both of which answer NXDOMAIN for the names in `names`.
+fn two_roots(names: &[&str]) -> (DnsServer<StdRng, MockTransport>, SocketAddr) {
+  let root2: SocketAddr = "192.0.2.6:53".parse().unwrap();
+  let mock = MockTransport::default();
+  let roots = reply("", Rtype::Ns)
+    .authoritative()
+    .answer(ns("", "a.root-servers.net"))
+    .answer(ns("", "b.root-servers.net"))
+    .additional(a("a.root-servers.net", "192.0.2.1"))
+    .additional(a("b.root-servers.net", "192.0.2.6"));
+  mock.insert(question("", Rtype::Ns), ROOT, roots.build());
+  for qname in names {
+    let nxdomain = reply(qname, Rtype::A)
+      .rcode(Rcode::NXDomain)
+      .authoritative()
+      .authority(soa("", 86400));
+    for root in [ROOT, root2] {
+      mock.insert(question(qname, Rtype::A), root, nxdomain.build());
+    }
+  }
+  (server(mock), root2)
+}
+
+#[test]
+fn priming_spreads_queries_over_every_root_server() {
+  let names = ["www.one", "www.two", "www.three"];
+  let (mut server, root2) = two_roots(&names);
+
+  assert_eq!(server.prime().unwrap(), 2);
+  assert_eq!(questions_sent_to(&server, ROOT), [" NS"]);
+  // the root from the hints is slow to answer, so every answer comes from the one primed
+  server
+    .transport
+    .set_server_delay(ROOT, Duration::from_millis(50));
+  for qname in names {
+    let response = server.resolve(qname, Rtype::A).unwrap();
+    assert_eq!(response.header().rcode(), Rcode::NXDomain);
+  }
+  assert_eq!(server.transport.sent_to(root2), names.len());
+}
+
+#[test]
+fn resolution_starts_from_the_hints_once_the_roots_are_flushed() {
+  let names = ["www.one", "www.two"];
+  let (mut server, root2) = two_roots(&names);
+
+  server.prime().unwrap();
+  server.control("flush");
+  for qname in names {
+    server.resolve(qname, Rtype::A).unwrap();
+  }
+  assert_eq!(server.transport.sent_to(ROOT), 3);
+  assert_eq!(server.transport.sent_to(root2), 0);
+}
+
+#[test]
+fn failed_priming_leaves_the_hints() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+
+  assert!(server.prime().is_err());
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+}
+
+/// A server whose answer for www.example.com. comes with example.com.'s NS record and its glue,
+/// and whose answer for nope.example.com. is NXDOMAIN.
+fn answering_with_extras(minimal_responses: bool) -> DnsServer<StdRng, MockTransport> {
+  let mock = MockTransport::default();
+  delegate(&mock, "www.example.com", Rtype::A);
+  let records = reply("www.example.com", Rtype::A)
+    .authoritative()
+    .answer(a("www.example.com", "192.0.2.10"))
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"));
+  mock.insert(
+    question("www.example.com", Rtype::A),
+    EXAMPLE,
+    records.build(),
+  );
+  delegate(&mock, "nope.example.com", Rtype::A);
+  let nxdomain = reply("nope.example.com", Rtype::A)
+    .rcode(Rcode::NXDomain)
+    .authoritative()
+    .authority(soa("example.com", 60));
+  mock.insert(
+    question("nope.example.com", Rtype::A),
+    EXAMPLE,
+    nxdomain.build(),
+  );
+  let mut server = server(mock);
+  server.minimal_responses = minimal_responses;
+  server
+}
+
+#[test]
+fn positive_answers_keep_their_extra_sections_by_default() {
+  let mut server = answering_with_extras(false);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  let counts = response.header_counts();
+  assert_eq!(
+    (counts.ancount(), counts.nscount(), counts.arcount()),
+    (1, 1, 1)
+  );
+}
+
+#[test]
+fn minimal_responses_send_positive_answers_alone() {
+  let mut server = answering_with_extras(true);
+
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  let counts = response.header_counts();
+  assert_eq!((counts.nscount(), counts.arcount()), (0, 0));
+  // clients using EDNS still get their OPT record
+  let response = ask(&mut server, &dnssec_query("www.example.com", Rtype::A));
+  assert_eq!(response.header_counts().arcount(), 1);
+  assert!(response.opt().is_some());
+  // a negative answer still says where it comes from
+  let response = ask(&mut server, &query("nope.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::NXDomain);
+  let soa = response.authority().unwrap().next().unwrap().unwrap();
+  assert_eq!(soa.rtype(), Rtype::Soa);
+}
+
+#[test]
+fn queries_with_a_malformed_question_get_form_err_under_their_id() {
+  let mut server = server(MockTransport::default());
+  let request = query("www.example.com", Rtype::A);
+  // the header still claims one question, which ends partway through its first label
+  let truncated = &request[..15];
+  // a question whose name points back at itself
+  let mut looped = request[..12].to_vec();
+  looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+
+  for malformed in [truncated, &looped[..]] {
+    let response = ask(&mut server, malformed);
+    assert_eq!(response.header().rcode(), Rcode::FormErr);
+    assert_eq!(response.header().id(), 0x1234);
+    assert_eq!(response.header_counts().qdcount(), 0);
+  }
+  assert!(server.transport.sent().is_empty());
+}
+
+#[test]
+fn messages_too_short_for_a_header_are_dropped() {
+  let mut server = server(MockTransport::default());
+  for garbage in [&b""[..], &b"\x12\x34\x01"[..], &[0xff; 11][..]] {
+    for protocol in [Protocol::Udp, Protocol::Tcp] {
+      let response = server.handle_query(garbage, CLIENT, protocol).unwrap();
+      assert!(response.is_none(), "{garbage:?} over {protocol:?}");
+    }
+  }
+}
+
+/// The Extended DNS Error in `response`'s OPT record, with its EXTRA-TEXT.
+fn extended_error(response: &Message) -> Option<(ExtendedErrorCode, Option<String>)> {
+  let opt = response.opt()?;
+  let error = opt.as_opt().iter::<ExtendedError<_>>().next()?.unwrap();
+  let text = error
+    .text()
+    .map(|text| String::from_utf8(text.to_vec()).unwrap());
+  Some((error.code(), text))
+}
+
+#[test]
+fn timeouts_are_explained_to_edns_clients() {
+  let mut server = server(MockTransport::default());
+
+  let response = ask(&mut server, &dnssec_query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+  let (code, text) = extended_error(&response).expect("response should carry an EDE");
+  assert_eq!(code, ExtendedErrorCode::NoReachableAuthority);
+  assert_eq!(text.unwrap(), ResolveError::Timeout.to_string());
+  assert!(response.opt().unwrap().dnssec_ok());
+
+  // a client without EDNS can't be sent an OPT record
+  let response = ask(&mut server, &query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+  assert!(response.opt().is_none());
+}
+
+#[test]
+fn long_error_texts_still_fit_a_udp_response() {
+  let mut server = server(MockTransport::default());
+  let label = "a".repeat(63);
+  let qname = format!("{label}.{label}.{label}.{}.example", "a".repeat(53));
+
+  let response = ask(&mut server, &dnssec_query(&qname, Rtype::A));
+  assert!(response.as_slice().len() <= 512);
+  let (code, text) = extended_error(&response).unwrap();
+  assert_eq!(code, ExtendedErrorCode::NoReachableAuthority);
+  assert!(!text.unwrap().is_empty());
+}
+
+#[test]
+fn refused_clients_are_told_they_are_prohibited() {
+  let mut server = server(MockTransport::default());
+  server.allowed_clients = Some(Arc::new(vec!["10.0.0.0/8".parse().unwrap()]));
+
+  let response = ask(&mut server, &dnssec_query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::Refused);
+  assert_eq!(
+    extended_error(&response),
+    Some((ExtendedErrorCode::Prohibited, None))
+  );
+}
+
+const NS1: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 41)), 53);
+const NS2: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 42)), 53);
+
+/// A mock whose com. server delegates example.com. to ns1.example.com. at `NS1` and
+/// ns2.example.com. at `NS2` for each of `qnames`. The first refers back up to com. without AA,
+/// while the second answers, unless `both_lame`, in which case it answers without AA or records.
+fn lame_delegation(qnames: &[&str], both_lame: bool) -> MockTransport {
+  let mock = MockTransport::default();
+  for qname in qnames {
+    let to_com = reply(qname, Rtype::A)
+      .authority(ns("com", "a.gtld-servers.net"))
+      .additional(a("a.gtld-servers.net", "192.0.2.2"));
+    mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+    let to_example = reply(qname, Rtype::A)
+      .authority(ns("example.com", "ns1.example.com"))
+      .authority(ns("example.com", "ns2.example.com"))
+      .additional(a("ns1.example.com", "192.0.2.41"))
+      .additional(a("ns2.example.com", "192.0.2.42"));
+    mock.insert(question(qname, Rtype::A), COM, to_example.build());
+    let upwards = reply(qname, Rtype::A)
+      .authority(ns("com", "a.gtld-servers.net"))
+      .additional(a("a.gtld-servers.net", "192.0.2.2"));
+    mock.insert(question(qname, Rtype::A), NS1, upwards.build());
+    let second = match both_lame {
+      true => reply(qname, Rtype::A).build(),
+      false => answer(qname, "192.0.2.10"),
+    };
+    mock.insert(question(qname, Rtype::A), NS2, second);
+  }
+  mock
+}
+
+#[test]
+fn lame_servers_are_passed_over_for_the_zone() {
+  let mock = lame_delegation(&["www.example.com", "mail.example.com"], false);
+  // the lame server answers first
+  mock.set_server_delay(NS2, Duration::from_millis(50));
+  let mut server = server(mock);
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(NS1), 1);
+
+  let response = server.resolve("mail.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  // the lame server isn't asked again about the zone
+  assert_eq!(server.transport.sent_to(NS1), 1);
+}
+
+#[test]
+fn delegations_to_only_lame_servers_fail() {
+  let mut server = server(lame_delegation(&["www.example.com"], true));
+
+  let error = resolve_err(&mut server, "www.example.com", Rtype::A);
+  assert!(
+    matches!(&error, ResolveError::LameDelegation(zone) if zone == "example.com"),
+    "{error:?}"
+  );
+  assert!(server.transport.sent_to(NS1) > 0);
+  assert!(server.transport.sent_to(NS2) > 0);
+  let response = ask(&mut server, &dnssec_query("www.example.com", Rtype::A));
+  assert_eq!(response.header().rcode(), Rcode::ServFail);
+  assert_eq!(
+    extended_error(&response).unwrap().0,
+    ExtendedErrorCode::NoReachableAuthority
+  );
+}
+
+/// A mock whose com. server delegates example.com. to ns.example-dns.com. with glue pointing at a
+/// server that never answers, while asking it about ns.example-dns.com. gives the real address,
+/// `EXAMPLE`.
+fn bad_glue() -> MockTransport {
+  let mock = MockTransport::default();
+  let qname = "www.example.com";
+  let to_com = reply(qname, Rtype::A)
+    .authority(ns("com", "a.gtld.com"))
+    .additional(a("a.gtld.com", "192.0.2.2"));
+  mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+  let to_example = reply(qname, Rtype::A)
+    .authority(ns("example.com", "ns.example-dns.com"))
+    .additional(a("ns.example-dns.com", "192.0.2.99"));
+  mock.insert(question(qname, Rtype::A), COM, to_example.build());
+  let to_com = reply("ns.example-dns.com", Rtype::A)
+    .authority(ns("com", "a.gtld.com"))
+    .additional(a("a.gtld.com", "192.0.2.2"));
+  mock.insert(
+    question("ns.example-dns.com", Rtype::A),
+    ROOT,
+    to_com.build(),
+  );
+  mock.insert(
+    question("ns.example-dns.com", Rtype::A),
+    COM,
+    answer("ns.example-dns.com", "192.0.2.3"),
+  );
+  mock.insert(
+    question(qname, Rtype::A),
+    EXAMPLE,
+    answer(qname, "192.0.2.10"),
+  );
+  mock
+}
+
+#[test]
+fn glue_outside_the_delegated_zone_is_ignored_when_asked_to() {
+  let bad: SocketAddr = "192.0.2.99:53".parse().unwrap();
+  let mut trusting = server(bad_glue());
+  let mut server = server(bad_glue());
+  server.ignore_glue = true;
+
+  let response = server.resolve("www.example.com", Rtype::A).unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(bad), 0);
+  // glue inside the delegated zone is still followed
+  assert!(questions_sent_to(&server, COM).contains(&"www.example.com A".to_string()));
+
+  // by default the glue is trusted
+  let error = resolve_err(&mut trusting, "www.example.com", Rtype::A);
+  assert!(matches!(error, ResolveError::Timeout), "{error:?}");
+  assert_eq!(trusting.transport.sent_to(bad), 1);
+}
+
+#[test]
+fn resolution_can_start_at_a_chosen_server() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+
+  let response = server
+    .resolve_via("www.example.com", Rtype::A, EXAMPLE)
+    .unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(server.transport.sent_to(ROOT), 0);
+  assert!(server.cache().is_empty());
+}
+
+#[test]
+fn resolution_from_a_chosen_server_follows_its_referrals_with_the_whole_name() {
+  let mock = resolving_www_mock();
+  let mut server = server(mock);
+  server.qname_minimization = true;
+
+  let response = server
+    .resolve_via("www.example.com", Rtype::A, COM)
+    .unwrap();
+  assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  assert_eq!(questions_sent_to(&server, COM), ["www.example.com A"]);
+  assert_eq!(questions_sent_to(&server, EXAMPLE), ["www.example.com A"]);
+  assert_eq!(server.transport.sent_to(ROOT), 0);
+  assert!(server.qname_minimization);
+}
+
+/// A mock resolving `qnames` from `ROOT` through `COM` to `EXAMPLE`, whose referrals have a TTL
+/// of `ttl`.
+fn referrals_with_ttl(qnames: &[&str], ttl: u32) -> MockTransport {
+  let mock = MockTransport::default();
+  for qname in qnames {
+    let to_com = reply(qname, Rtype::A)
+      .authority(with_ttl(ns("com", "a.gtld-servers.net"), ttl))
+      .additional(with_ttl(a("a.gtld-servers.net", "192.0.2.2"), ttl));
+    mock.insert(question(qname, Rtype::A), ROOT, to_com.build());
+    let to_example = reply(qname, Rtype::A)
+      .authority(with_ttl(ns("example.com", "ns.example.com"), ttl))
+      .additional(with_ttl(a("ns.example.com", "192.0.2.3"), ttl));
+    mock.insert(question(qname, Rtype::A), COM, to_example.build());
+    mock.insert(
+      question(qname, Rtype::A),
+      EXAMPLE,
+      answer(qname, "192.0.2.10"),
+    );
+  }
+  mock
+}
+
+#[test]
+fn referrals_are_kept_at_least_the_min_referral_ttl() {
+  let qnames = ["www.example.com", "mail.example.com"];
+  let mut expiring = server(referrals_with_ttl(&qnames, 0));
+  let mut kept = server(referrals_with_ttl(&qnames, 0));
+  kept.min_referral_ttl = 60;
+
+  for qname in qnames {
+    expiring.resolve(qname, Rtype::A).unwrap();
+    kept.resolve(qname, Rtype::A).unwrap();
+  }
+  assert_eq!(expiring.transport.sent_to(ROOT), 2);
+  assert_eq!(kept.transport.sent_to(ROOT), 1);
+  assert_eq!(kept.transport.sent_to(COM), 1);
+}
+
+#[test]
+fn referrals_are_kept_at_most_the_max_referral_ttl() {
+  let mut server = server(referrals_with_ttl(&["www.example.com"], 86400));
+  server.max_referral_ttl = 60;
+  server.resolve("www.example.com", Rtype::A).unwrap();
+
+  let mut records = server.records();
+  for (owner, rtype) in [("com", Rtype::Ns), ("a.gtld-servers.net", Rtype::A)] {
+    let (_, ttl) = records
+      .get(&name(owner), rtype, Class::In, Trust::Referral)
+      .unwrap();
+    assert!((59..=60).contains(&ttl), "{owner}: {ttl}");
+  }
+}
+
+#[test]
+fn responses_to_clients_compress_repeated_names() {
+  let qname = "a-rather-long-host-name.in-a-rather-long-zone.example.com";
+  let mut upstream = reply(qname, Rtype::A)
+    .authoritative()
+    .authority(ns("example.com", "ns.example.com"))
+    .additional(a("ns.example.com", "192.0.2.3"));
+  let addrs: Vec<_> = (10..18).map(|i| format!("192.0.2.{i}")).collect();
+  for addr in &addrs {
+    upstream = upstream.answer(a(qname, addr));
+  }
+  let upstream = upstream.build();
+  let mock = MockTransport::default();
+  delegate(&mock, qname, Rtype::A);
+  mock.insert(question(qname, Rtype::A), EXAMPLE, upstream.clone());
+  let mut server = server(mock);
+
+  let response = ask(&mut server, &query(qname, Rtype::A));
+  let expected: Vec<_> = addrs.iter().map(|addr| ip(addr)).collect();
+  assert_eq!(answer_addrs(&response), expected);
+  let counts = response.header_counts();
+  assert_eq!((counts.nscount(), counts.arcount()), (1, 1));
+  // the same records, every name written out in full
+  let (compressed, uncompressed) = (response.as_slice().len(), upstream.as_slice().len());
+  assert!(
+    compressed < uncompressed / 2,
+    "{compressed} of {uncompressed} bytes"
+  );
+  let record = response.authority().unwrap().next().unwrap().unwrap();
+  let AllRecordData::Ns(ns) = record
+    .to_record::<AllRecordData<_, _>>()
+    .unwrap()
+    .unwrap()
+    .into_data()
+  else {
+    panic!("the authority section should hold an NS record");
+  };
+  assert_eq!(ns.nsdname().to_string(), "ns.example.com");
+}
@@ -0,0 +1,1251 @@
+use std::{
+  collections::HashMap,
+  io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+  sync::{Arc, Mutex, PoisonError},
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use rand::Rng;
+use rustls::{
+  client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+  crypto::{self, CryptoProvider},
+  pki_types::{CertificateDer, ServerName, UnixTime},
+  ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+  StreamOwned,
+};
+use tracing::debug;
+
+use crate::{tcp, DEFAULT_TIMEOUT, DEFAULT_UPSTREAM_IDLE_TIMEOUT, EDNS_UDP_PAYLOAD_SIZE};
+
+// Attempts at binding a random outbound port before letting the OS pick one
+const OUTBOUND_BIND_ATTEMPTS: usize = 8;
+// Most idle connections kept open to a single name server
+const MAX_IDLE_PER_SERVER: usize = 4;
+// Most receive buffers kept for reuse while no query is using them
+const MAX_POOLED_BUFFERS: usize = 64;
+// Media type of DNS messages sent over HTTPS (RFC 8484 section 6)
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How queries reach other name servers. Resolution only touches the network through this, so it
+/// can be run against canned responses instead.
+pub trait Transport: Send + Sync + 'static {
+  /// Sends `request` to `server` in a single datagram and returns the response.
+  fn query(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>>;
+
+  /// Sends `request` to `server` over a stream, for responses that didn't fit in a datagram.
+  fn query_tcp(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>>;
+}
+
+/// Sends queries over real UDP and TCP sockets.
+pub struct NetworkTransport {
+  /// How long to wait for a single name server to respond.
+  pub timeout: Duration,
+  /// Port to send UDP queries from. When unset, each query gets a random port.
+  pub outbound_port: Option<u16>,
+  /// Addresses to send UDP queries from, at most one of each family. Queries to servers of a
+  /// family without one leave from whichever address the OS picks, as TCP connections always
+  /// do, since the standard library can't bind a stream before connecting it.
+  pub outbound_addrs: Vec<IpAddr>,
+  /// TCP connections to name servers kept open between queries.
+  pub connections: ConnectionPool,
+  /// Buffers UDP responses are received into.
+  pub buffers: BufferPool,
+}
+
+impl Default for NetworkTransport {
+  fn default() -> NetworkTransport {
+    NetworkTransport {
+      timeout: DEFAULT_TIMEOUT,
+      outbound_port: None,
+      outbound_addrs: Vec::new(),
+      connections: ConnectionPool::new(DEFAULT_UPSTREAM_IDLE_TIMEOUT),
+      buffers: BufferPool::default(),
+    }
+  }
+}
+
+/// Buffers big enough for any response to a UDP query, kept between queries so each one doesn't
+/// allocate and zero a fresh one. A query borrows a buffer while it waits for the response, and
+/// only the bytes actually received are copied out of it.
+///
+/// Buffers hold one byte more than the payload size advertised through EDNS0, since the OS
+/// silently cuts off datagrams longer than the buffer they're received into. A datagram filling
+/// the whole buffer is then known to be longer than was allowed, and possibly cut off.
+#[derive(Default)]
+pub struct BufferPool {
+  free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+  fn take(&self) -> Vec<u8> {
+    let free = self
+      .free
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .pop();
+    free.unwrap_or_else(|| vec![0u8; usize::from(EDNS_UDP_PAYLOAD_SIZE) + 1])
+  }
+
+  fn put(&self, buf: Vec<u8>) {
+    let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+    if free.len() < MAX_POOLED_BUFFERS {
+      free.push(buf);
+    }
+  }
+}
+
+/// A stream to a name server that can be kept open in a `ConnectionPool`.
+pub trait Connection: Read + Write + Send {
+  /// The TCP socket the stream runs over.
+  fn socket(&self) -> &TcpStream;
+}
+
+impl Connection for TcpStream {
+  fn socket(&self) -> &TcpStream {
+    self
+  }
+}
+
+/// A TLS session to a name server, for DNS over TLS.
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+impl Connection for TlsStream {
+  fn socket(&self) -> &TcpStream {
+    &self.sock
+  }
+}
+
+/// Idle connections to name servers, keyed by address, so a query can reuse one a previous query
+/// opened rather than paying for a new handshake. Each connection is used by one query at a time:
+/// it's taken out of the pool while in use and put back once the response is read.
+pub struct ConnectionPool<S = TcpStream> {
+  idle_timeout: Duration,
+  idle: Mutex<HashMap<SocketAddr, Vec<(S, Instant)>>>,
+}
+
+impl<S> ConnectionPool<S> {
+  /// Creates a pool that closes connections left idle for longer than `idle_timeout`. A zero
+  /// timeout closes every connection once its query is answered.
+  pub fn new(idle_timeout: Duration) -> ConnectionPool<S> {
+    ConnectionPool {
+      idle_timeout,
+      idle: Mutex::new(HashMap::new()),
+    }
+  }
+}
+
+impl<S: Connection> ConnectionPool<S> {
+  /// Takes the most recently used idle connection to `server` that's still open, closing any that
+  /// have been idle too long or that the server has closed.
+  fn take(&self, server: &SocketAddr) -> Option<S> {
+    let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+    let connections = idle.get_mut(server)?;
+    while let Some((stream, used)) = connections.pop() {
+      if used.elapsed() < self.idle_timeout && is_open(stream.socket()) {
+        return Some(stream);
+      }
+    }
+    None
+  }
+
+  /// Returns a connection to `server` to the pool once its query is answered, and closes any
+  /// connections, to any server, that have been idle too long.
+  fn put(&self, server: SocketAddr, stream: S) {
+    if self.idle_timeout.is_zero() {
+      return;
+    }
+    let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+    idle.retain(|_, connections| {
+      connections.retain(|(_, used)| used.elapsed() < self.idle_timeout);
+      !connections.is_empty()
+    });
+    let connections = idle.entry(server).or_default();
+    if connections.len() < MAX_IDLE_PER_SERVER {
+      connections.push((stream, Instant::now()));
+    }
+  }
+}
+
+/// Whether an idle connection can still be used: nothing is waiting to be read on it, and in
+/// particular the server hasn't closed it.
+fn is_open(stream: &TcpStream) -> bool {
+  if stream.set_nonblocking(true).is_err() {
+    return false;
+  }
+  let open = matches!(stream.peek(&mut [0]), Err(e) if e.kind() == ErrorKind::WouldBlock);
+  stream.set_nonblocking(false).is_ok() && open
+}
+
+/// Whether `e` means the other end closed the connection, rather than that it's slow to answer.
+fn is_closed(e: &io::Error) -> bool {
+  matches!(
+    e.kind(),
+    ErrorKind::ConnectionReset
+      | ErrorKind::ConnectionAborted
+      | ErrorKind::BrokenPipe
+      | ErrorKind::UnexpectedEof
+  )
+}
+
+/// Sends `request` on `stream` and reads the response. Returns `None` if the stream was closed
+/// before a response started.
+fn exchange_tcp(stream: &mut impl Connection, request: &[u8]) -> io::Result<Option<Vec<u8>>> {
+  tcp::write_message(stream, request)?;
+  stream.flush()?;
+  tcp::read_message(stream)
+}
+
+/// Sends `request` to `server` with `exchange` over an idle connection from `pool` if there is
+/// one, or else over a new one opened with `connect`, and puts the connection back in the pool
+/// once the response is read.
+fn query_pooled<S: Connection>(
+  pool: &ConnectionPool<S>,
+  request: &[u8],
+  server: SocketAddr,
+  exchange: impl Fn(&mut S, &[u8]) -> io::Result<Option<Vec<u8>>>,
+  connect: impl FnOnce() -> Result<S>,
+) -> Result<Vec<u8>> {
+  if let Some(mut stream) = pool.take(&server) {
+    match exchange(&mut stream, request) {
+      Ok(Some(buf)) => {
+        pool.put(server, stream);
+        return Ok(buf);
+      }
+      // the server may have closed the connection just as it was reused, so open a new one
+      Ok(None) => debug!("{server} closed a reused connection, opening a new one"),
+      Err(e) if is_closed(&e) => {
+        debug!("{server} closed a reused connection ({e}), opening a new one")
+      }
+      Err(e) => return Err(e.into()),
+    }
+  }
+
+  let mut stream = connect()?;
+  match exchange(&mut stream, request)? {
+    Some(buf) => {
+      pool.put(server, stream);
+      Ok(buf)
+    }
+    None => bail!("{server} closed the connection without responding"),
+  }
+}
+
+/// Opens a TCP connection to `server` whose reads and writes give up after `timeout`.
+fn connect_tcp(server: SocketAddr, timeout: Duration) -> Result<TcpStream> {
+  let stream = TcpStream::connect_timeout(&server, timeout)?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+  Ok(stream)
+}
+
+/// Builds the TLS settings for connecting to upstreams. Their certificates must be issued by one
+/// of the web's usual root authorities, unless `verify` is false and any certificate is accepted.
+fn tls_config(verify: bool) -> Result<ClientConfig> {
+  let provider = Arc::new(crypto::ring::default_provider());
+  let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+    .with_safe_default_protocol_versions()?;
+  let config = if verify {
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    builder.with_root_certificates(roots).with_no_client_auth()
+  } else {
+    builder
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+      .with_no_client_auth()
+  };
+  Ok(config)
+}
+
+/// Opens a TLS session to `server`, which must present a certificate for `name`. The handshake
+/// itself happens on the first write.
+fn connect_tls(
+  server: SocketAddr,
+  timeout: Duration,
+  config: &Arc<ClientConfig>,
+  name: &ServerName<'static>,
+) -> Result<TlsStream> {
+  let stream = connect_tcp(server, timeout)?;
+  let session = ClientConnection::new(Arc::clone(config), name.clone())?;
+  Ok(StreamOwned::new(session, stream))
+}
+
+impl NetworkTransport {
+  /// Binds a socket for a single outbound query, on the outbound address of the name server's
+  /// family if there is one. Unless `self.outbound_port` is set, the port is picked at random
+  /// rather than left to the OS, since many systems hand out ephemeral ports sequentially.
+  fn bind_outbound_socket(&self, name_server: &SocketAddr) -> Result<UdpSocket> {
+    let unspecified: IpAddr = match name_server {
+      SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+      SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let source = self
+      .outbound_addrs
+      .iter()
+      .copied()
+      .find(|addr| addr.is_ipv4() == name_server.is_ipv4())
+      .unwrap_or(unspecified);
+    if let Some(port) = self.outbound_port {
+      return Ok(UdpSocket::bind((source, port))?);
+    }
+
+    let mut rng = rand::thread_rng();
+    for _ in 0..OUTBOUND_BIND_ATTEMPTS {
+      let port = rng.gen_range(1024..=u16::MAX);
+      match UdpSocket::bind((source, port)) {
+        Ok(socket) => return Ok(socket),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+        Err(e) => return Err(e.into()),
+      }
+    }
+    Ok(UdpSocket::bind((source, 0))?)
+  }
+}
+
+impl Transport for NetworkTransport {
+  fn query(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    let socket = self.bind_outbound_socket(&server)?;
+    socket.send_to(request, server)?;
+    // Servers that don't support EDNS will simply answer within the classic 512 byte limit.
+    let mut buf = self.buffers.take();
+    let received = recv_from_server(&socket, &mut buf, server, self.timeout);
+    let response = received.map(|len| (len == buf.len(), buf[..len].to_vec()));
+    self.buffers.put(buf);
+    let (oversized, response) = response?;
+    if oversized {
+      // a response cut off by the OS may still parse, so it can't be trusted to be whole
+      debug!(
+        "{server} sent a datagram longer than the {EDNS_UDP_PAYLOAD_SIZE} bytes allowed, asking \
+         again over TCP"
+      );
+      return self.query_tcp(request, server);
+    }
+    Ok(response)
+  }
+
+  /// Uses the two byte length prefix from RFC 1035 section 4.2.2 in both directions. Connections
+  /// are reused from `connections` when there's one to `server`, and put back there afterwards.
+  fn query_tcp(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    query_pooled(&self.connections, request, server, exchange_tcp, || {
+      connect_tcp(server, self.timeout)
+    })
+  }
+}
+
+/// Receives the datagram `server` sends to `socket` into `buf`, returning its length. Datagrams
+/// from any other address are dropped, since only someone guessing the port to forge a response
+/// would send them, and waiting goes on for the rest of `timeout`.
+fn recv_from_server(
+  socket: &UdpSocket,
+  buf: &mut [u8],
+  server: SocketAddr,
+  timeout: Duration,
+) -> io::Result<usize> {
+  let deadline = Instant::now() + timeout;
+  loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+      return Err(ErrorKind::TimedOut.into());
+    }
+    socket.set_read_timeout(Some(remaining))?;
+    let (len, source) = socket.recv_from(buf)?;
+    // the scope of a link-local source doesn't have to match how the server was written down
+    if source.ip() == server.ip() && source.port() == server.port() {
+      return Ok(len);
+    }
+    debug!("Dropping datagram from {source} while waiting for a response from {server}");
+  }
+}
+
+/// Sends every query over TLS (RFC 7858), for forwarding to an upstream resolver that offers DNS
+/// over TLS, usually on port 853. Messages are framed with the same length prefix as over TCP,
+/// and sessions are reused between queries like TCP connections are.
+pub struct DotTransport {
+  /// How long to wait for the upstream to complete the handshake or respond.
+  pub timeout: Duration,
+  /// TLS sessions to the upstream kept open between queries.
+  pub connections: ConnectionPool<TlsStream>,
+  name: ServerName<'static>,
+  config: Arc<ClientConfig>,
+}
+
+impl DotTransport {
+  /// Creates a transport whose upstream must present a certificate for `name`, issued by one of
+  /// the web's usual root authorities. If `verify` is false, any certificate is accepted, which
+  /// hides queries from onlookers but not from someone able to impersonate the upstream.
+  pub fn new(name: &str, verify: bool) -> Result<DotTransport> {
+    let name = ServerName::try_from(name.to_string())
+      .with_context(|| format!("invalid TLS server name {name:?}"))?;
+    Ok(DotTransport {
+      timeout: DEFAULT_TIMEOUT,
+      connections: ConnectionPool::new(DEFAULT_UPSTREAM_IDLE_TIMEOUT),
+      name,
+      config: Arc::new(tls_config(verify)?),
+    })
+  }
+}
+
+impl Transport for DotTransport {
+  /// Also goes over TLS, since there's no datagram form of DNS over TLS.
+  fn query(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    self.query_tcp(request, server)
+  }
+
+  fn query_tcp(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    query_pooled(&self.connections, request, server, exchange_tcp, || {
+      connect_tls(server, self.timeout, &self.config, &self.name)
+    })
+  }
+}
+
+/// Sends every query over HTTPS (RFC 8484), for forwarding to an upstream resolver that offers
+/// DNS over HTTPS. Each query is POSTed to the endpoint's URL as a dns-message body over
+/// HTTP/1.1, and sessions are kept alive between queries like TCP connections are.
+pub struct DohTransport {
+  /// How long to wait for the upstream to complete the handshake or respond.
+  pub timeout: Duration,
+  /// TLS sessions to the upstream kept open between queries.
+  pub connections: ConnectionPool<TlsStream>,
+  /// The host and port of the endpoint's URL, sent as the Host header.
+  authority: String,
+  path: String,
+  name: ServerName<'static>,
+  config: Arc<ClientConfig>,
+}
+
+impl DohTransport {
+  /// Creates a transport posting queries to `url`, such as `https://dns.example/dns-query`. The
+  /// upstream must present a certificate for the URL's host unless `verify` is false. The URL's
+  /// host is only used for TLS and HTTP: queries go to the address they're sent to, since looking
+  /// the host up would need a resolver.
+  pub fn new(url: &str, verify: bool) -> Result<DohTransport> {
+    let rest = url
+      .strip_prefix("https://")
+      .with_context(|| format!("{url:?} isn't an https URL"))?;
+    let (authority, path) = match rest.find('/') {
+      Some(end) => rest.split_at(end),
+      None => (rest, "/"),
+    };
+    ensure!(!authority.is_empty(), "{url:?} has no host");
+    let host = authority
+      .rsplit_once(':')
+      .filter(|(_, port)| port.parse::<u16>().is_ok())
+      .map_or(authority, |(host, _)| host)
+      .trim_start_matches('[')
+      .trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())
+      .with_context(|| format!("invalid TLS server name {host:?}"))?;
+    let mut config = tls_config(verify)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(DohTransport {
+      timeout: DEFAULT_TIMEOUT,
+      connections: ConnectionPool::new(DEFAULT_UPSTREAM_IDLE_TIMEOUT),
+      authority: authority.to_string(),
+      path: path.to_string(),
+      name,
+      config: Arc::new(config),
+    })
+  }
+
+  /// POSTs `request` on `stream` and reads the body of the response. Returns `None` if the
+  /// stream was closed before a response started.
+  fn exchange(&self, stream: &mut TlsStream, request: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let head = format!(
+      "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {DNS_MESSAGE}\r\nAccept: {DNS_MESSAGE}\r\n\
+       Content-Length: {}\r\n\r\n",
+      self.path,
+      self.authority,
+      request.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(request)?;
+    stream.flush()?;
+
+    // nothing more arrives until the next request, so the reader can't buffer past this response
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    let status = line
+      .split_whitespace()
+      .nth(1)
+      .unwrap_or_default()
+      .to_string();
+    let mut length = None;
+    let mut chunked = false;
+    let mut content_type = String::new();
+    loop {
+      line.clear();
+      if reader.read_line(&mut line)? == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+      }
+      let header = line.trim_end();
+      if header.is_empty() {
+        break;
+      }
+      let Some((name, value)) = header.split_once(':') else {
+        continue;
+      };
+      let value = value.trim();
+      match name.to_ascii_lowercase().as_str() {
+        "content-length" => {
+          let len = value
+            .parse()
+            .map_err(|_| http_error("invalid Content-Length"))?;
+          length = Some(len);
+        }
+        "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+        "content-type" => content_type = value.to_ascii_lowercase(),
+        _ => {}
+      }
+    }
+
+    let body = match (chunked, length) {
+      (true, _) => read_chunked(&mut reader)?,
+      (false, Some(len)) if len <= u16::MAX.into() => {
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)?;
+        body
+      }
+      (false, Some(_)) => return Err(http_error("response too long for a DNS message")),
+      (false, None) => return Err(http_error("response has no Content-Length")),
+    };
+    if status != "200" {
+      return Err(http_error(format!(
+        "upstream answered HTTP status {status}"
+      )));
+    }
+    if content_type.split(';').next().map(str::trim) != Some(DNS_MESSAGE) {
+      return Err(http_error(format!(
+        "response has Content-Type {content_type:?}"
+      )));
+    }
+    Ok(Some(body))
+  }
+}
+
+impl Transport for DohTransport {
+  /// Also goes over HTTPS, since every DNS over HTTPS query does.
+  fn query(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    self.query_tcp(request, server)
+  }
+
+  fn query_tcp(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+    let exchange = |stream: &mut TlsStream, request: &[u8]| self.exchange(stream, request);
+    query_pooled(&self.connections, request, server, exchange, || {
+      connect_tls(server, self.timeout, &self.config, &self.name)
+    })
+  }
+}
+
+/// Reads an HTTP body sent with chunked transfer encoding, up to and including its trailers.
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+  let mut body = Vec::new();
+  let mut line = String::new();
+  loop {
+    line.clear();
+    reader.read_line(&mut line)?;
+    let size = line.split(';').next().unwrap_or_default().trim();
+    let size = usize::from_str_radix(size, 16).map_err(|_| http_error("invalid chunk size"))?;
+    if size == 0 {
+      break;
+    }
+    let start = body.len();
+    if start + size > u16::MAX.into() {
+      return Err(http_error("response too long for a DNS message"));
+    }
+    body.resize(start + size, 0);
+    reader.read_exact(&mut body[start..])?;
+    // each chunk ends with a line break of its own
+    line.clear();
+    reader.read_line(&mut line)?;
+  }
+  loop {
+    line.clear();
+    if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+      return Ok(body);
+    }
+  }
+}
+
+fn http_error(message: impl Into<String>) -> io::Error {
+  io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Accepts whatever certificate a server presents, while still checking that the handshake is
+/// signed by its key, for upstreams whose certificates can't be verified.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+  fn verify_server_cert(
+    &self,
+    _end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    crypto::verify_tls12_signature(
+      message,
+      cert,
+      dss,
+      &self.0.signature_verification_algorithms,
+    )
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    crypto::verify_tls13_signature(
+      message,
+      cert,
+      dss,
+      &self.0.signature_verification_algorithms,
+    )
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.0.signature_verification_algorithms.supported_schemes()
+  }
+}
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock {
+  use std::{
+    collections::HashMap,
+    io, mem,
+    net::SocketAddr,
+    ops::Range,
+    sync::{
+      atomic::{AtomicBool, AtomicUsize, Ordering},
+      Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+  };
+
+  use anyhow::Result;
+  use domain::base::{header::HeaderSection, ToDname};
+
+  use super::Transport;
+  use crate::{Message, QuestionKey};
+
+  /// A query a `MockTransport` was sent.
+  #[derive(Clone)]
+  pub struct SentQuery {
+    pub server: SocketAddr,
+    pub request: Message,
+    /// Whether it was sent over TCP rather than UDP.
+    pub tcp: bool,
+  }
+
+  /// A canned response, given the ID and question case of the query it answers unless it's sent
+  /// exactly as it is.
+  struct Canned {
+    response: Vec<u8>,
+    patch_id: bool,
+  }
+
+  /// Answers queries with canned responses keyed by question and server, so resolution can be
+  /// exercised without a network. Each response is given the ID of the query it answers and
+  /// repeats its question name in the case it was sent in, as servers do, and questions without a
+  /// response fail like an unreachable server would. Every query is recorded,
+  /// so what resolution sent can be checked afterwards.
+  #[derive(Default)]
+  pub struct MockTransport {
+    udp: Mutex<HashMap<(QuestionKey, SocketAddr), Canned>>,
+    tcp: Mutex<HashMap<(QuestionKey, SocketAddr), Canned>>,
+    sent: Mutex<Vec<SentQuery>>,
+    delay: Mutex<Duration>,
+    server_delays: Mutex<HashMap<SocketAddr, Duration>>,
+    failures: AtomicUsize,
+    keep_case: AtomicBool,
+  }
+
+  impl MockTransport {
+    /// Makes `server` answer `question` with `response`, over both UDP and TCP.
+    pub fn insert(&self, question: QuestionKey, server: SocketAddr, response: Message) {
+      self.insert_udp(question.clone(), server, response.clone());
+      self.insert_tcp(question, server, response);
+    }
+
+    /// Makes `server` answer `question` with `response` over UDP only.
+    pub fn insert_udp(&self, question: QuestionKey, server: SocketAddr, response: Message) {
+      let canned = Canned {
+        response: response.into_octets(),
+        patch_id: true,
+      };
+      lock(&self.udp).insert((question, server), canned);
+    }
+
+    /// Makes `server` answer `question` with `response` over TCP only.
+    pub fn insert_tcp(&self, question: QuestionKey, server: SocketAddr, response: Message) {
+      let canned = Canned {
+        response: response.into_octets(),
+        patch_id: true,
+      };
+      lock(&self.tcp).insert((question, server), canned);
+    }
+
+    /// Makes `server` answer `question` over UDP with exactly `response`, which keeps whatever ID
+    /// it has and needn't even parse.
+    pub fn insert_raw(&self, question: QuestionKey, server: SocketAddr, response: Vec<u8>) {
+      let canned = Canned {
+        response,
+        patch_id: false,
+      };
+      lock(&self.udp).insert((question, server), canned);
+    }
+
+    /// Makes every query wait for `delay` before it's answered, like a slow server would.
+    pub fn set_delay(&self, delay: Duration) {
+      *lock(&self.delay) = delay;
+    }
+
+    /// Makes queries to `server` wait for `delay` before they're answered, on top of any delay
+    /// every query waits for.
+    pub fn set_server_delay(&self, server: SocketAddr, delay: Duration) {
+      lock(&self.server_delays).insert(server, delay);
+    }
+
+    /// Makes the next `count` queries time out whatever they ask, like lost datagrams would.
+    pub fn fail_next(&self, count: usize) {
+      self.failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Makes responses keep the question name in the case they were inserted with, rather than
+    /// the case of the query, like servers that don't preserve case.
+    pub fn keep_question_case(&self) {
+      self.keep_case.store(true, Ordering::SeqCst);
+    }
+
+    /// The queries sent so far, in the order they were sent.
+    pub fn sent(&self) -> Vec<SentQuery> {
+      lock(&self.sent).clone()
+    }
+
+    /// How many queries were sent to `server`.
+    pub fn sent_to(&self, server: SocketAddr) -> usize {
+      lock(&self.sent)
+        .iter()
+        .filter(|sent| sent.server == server)
+        .count()
+    }
+
+    fn respond(&self, request: &[u8], server: SocketAddr, tcp: bool) -> Result<Vec<u8>> {
+      let request = Message::from_octets(request.to_vec())?;
+      lock(&self.sent).push(SentQuery {
+        server,
+        request: request.clone(),
+        tcp,
+      });
+      let server_delay = lock(&self.server_delays).get(&server).copied();
+      let delay = *lock(&self.delay) + server_delay.unwrap_or_default();
+      if !delay.is_zero() {
+        thread::sleep(delay);
+      }
+      let failed = self
+        .failures
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+          count.checked_sub(1)
+        })
+        .is_ok();
+
+      let question = request.sole_question()?;
+      let key = QuestionKey::new(
+        question.qname().to_dname()?,
+        question.qtype(),
+        question.qclass(),
+      );
+      let responses = lock(if tcp { &self.tcp } else { &self.udp });
+      let canned = match responses.get(&(key, server)) {
+        Some(canned) if !failed => canned,
+        _ => {
+          let message = format!("No response for {question} from {server}");
+          return Err(io::Error::new(io::ErrorKind::TimedOut, message).into());
+        }
+      };
+      let mut response = canned.response.clone();
+      if canned.patch_id {
+        response[..2].copy_from_slice(&request.header().id().to_be_bytes());
+        if !self.keep_case.load(Ordering::SeqCst) {
+          let qname = question_name(request.as_slice());
+          let sent = &request.as_slice()[qname.clone()];
+          if response
+            .get(qname.clone())
+            .is_some_and(|name| name.eq_ignore_ascii_case(sent))
+          {
+            response[qname].copy_from_slice(sent);
+          }
+        }
+      }
+      Ok(response)
+    }
+  }
+
+  /// Where the name of the first question of `message` is, which is never compressed.
+  fn question_name(message: &[u8]) -> Range<usize> {
+    let start = mem::size_of::<HeaderSection>();
+    let mut end = start;
+    while let Some(&len) = message.get(end) {
+      end += 1 + usize::from(len);
+      if len == 0 {
+        break;
+      }
+    }
+    start..end
+  }
+
+  impl Transport for MockTransport {
+    fn query(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+      self.respond(request, server, false)
+    }
+
+    fn query_tcp(&self, request: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+      self.respond(request, server, true)
+    }
+  }
+
+  /// Locks `mutex`, ignoring poisoning, since a test that panicked has failed already.
+  fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{TcpListener, UdpSocket},
+    thread,
+  };
+
+  use domain::base::iana::Rtype;
+
+  use rustls::{pki_types::PrivatePkcs8KeyDer, ServerConfig, ServerConnection};
+
+  use super::*;
+  use crate::{
+    tests::{a, answer, answer_addrs, ip, query, reply},
+    DnsServer,
+  };
+
+  #[test]
+  fn queries_over_tcp_with_length_prefixes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap();
+    let response = reply("www.example.com", Rtype::A).build().into_octets();
+    let expected = response.clone();
+    let handle = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let request = tcp::read_message(&mut stream).unwrap().unwrap();
+      tcp::write_message(&mut stream, &response).unwrap();
+      request
+    });
+
+    let request = query("www.example.com", Rtype::A);
+    let received = NetworkTransport::default()
+      .query_tcp(&request, server)
+      .unwrap();
+    assert_eq!(received, expected);
+    assert_eq!(handle.join().unwrap(), request);
+  }
+
+  /// Binds a UDP server on localhost that answers the queries it receives with `responses`, one
+  /// each and in order, returning its address and a handle giving the addresses the queries came
+  /// from.
+  fn udp_server(responses: Vec<Vec<u8>>) -> (SocketAddr, thread::JoinHandle<Vec<SocketAddr>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+      let mut sources = Vec::new();
+      let mut buf = [0u8; 512];
+      for response in responses {
+        let (_, source) = socket.recv_from(&mut buf).unwrap();
+        socket.send_to(&response, source).unwrap();
+        sources.push(source);
+      }
+      sources
+    });
+    (server, handle)
+  }
+
+  #[test]
+  fn each_query_leaves_from_another_port() {
+    let request = query("www.example.com", Rtype::A);
+    let (server, handle) = udp_server(vec![request.clone(), request.clone()]);
+    let transport = NetworkTransport::default();
+    for _ in 0..2 {
+      assert_eq!(transport.query(&request, server).unwrap(), request);
+    }
+
+    let sources = handle.join().unwrap();
+    assert_ne!(sources[0].port(), sources[1].port());
+  }
+
+  #[test]
+  fn queries_leave_from_the_outbound_address_of_the_family() {
+    let request = query("www.example.com", Rtype::A);
+    let (server, handle) = udp_server(vec![request.clone(), request.clone()]);
+    let transport = NetworkTransport {
+      outbound_addrs: vec![ip("2001:db8::53"), ip("127.0.0.2")],
+      ..NetworkTransport::default()
+    };
+    for _ in 0..2 {
+      assert_eq!(transport.query(&request, server).unwrap(), request);
+    }
+
+    let sources = handle.join().unwrap();
+    assert!(sources.iter().all(|source| source.ip() == ip("127.0.0.2")));
+    // the port is still picked at random
+    assert_ne!(sources[0].port(), sources[1].port());
+  }
+
+  #[test]
+  fn short_responses_are_received_without_trailing_bytes() {
+    let long = vec![0xAA; 400];
+    let short = reply("www.example.com", Rtype::A).build().into_octets();
+    let (server, handle) = udp_server(vec![long.clone(), short.clone()]);
+    let transport = NetworkTransport::default();
+    let request = query("www.example.com", Rtype::A);
+
+    // the second response is received into the buffer the first one filled
+    assert_eq!(transport.query(&request, server).unwrap(), long);
+    assert_eq!(transport.query(&request, server).unwrap(), short);
+    handle.join().unwrap();
+  }
+
+  #[test]
+  fn datagrams_filling_the_buffer_are_asked_for_again_over_tcp() {
+    let (server, udp) = udp_server(vec![
+      vec![0xAA; EDNS_UDP_PAYLOAD_SIZE.into()],
+      vec![0xBB; usize::from(EDNS_UDP_PAYLOAD_SIZE) + 1],
+    ]);
+    let listener = TcpListener::bind(server).unwrap();
+    let response = answer("www.example.com", "192.0.2.10").into_octets();
+    let expected = response.clone();
+    let tcp = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let request = tcp::read_message(&mut stream).unwrap().unwrap();
+      tcp::write_message(&mut stream, &response).unwrap();
+      request
+    });
+
+    let transport = NetworkTransport::default();
+    let request = query("www.example.com", Rtype::A);
+    // a datagram of exactly the advertised size is whole
+    let received = transport.query(&request, server).unwrap();
+    assert_eq!(received.len(), usize::from(EDNS_UDP_PAYLOAD_SIZE));
+    assert_eq!(transport.query(&request, server).unwrap(), expected);
+    assert_eq!(tcp.join().unwrap(), request);
+    udp.join().unwrap();
+  }
+
+  #[test]
+  fn datagrams_from_other_sources_are_ignored() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    let forged = reply("www.example.com", Rtype::A)
+      .answer(a("www.example.com", "203.0.113.66"))
+      .build()
+      .into_octets();
+    let real = answer("www.example.com", "192.0.2.10").into_octets();
+    let response = real.clone();
+    let handle = thread::spawn(move || {
+      let mut buf = [0u8; 512];
+      let (_, client) = socket.recv_from(&mut buf).unwrap();
+      // the same answer, but from another port on the server's host and from another host
+      for forger in ["127.0.0.1:0", "127.0.0.2:0"] {
+        let forger = UdpSocket::bind(forger).unwrap();
+        forger.send_to(&forged, client).unwrap();
+      }
+      thread::sleep(Duration::from_millis(50));
+      socket.send_to(&response, client).unwrap();
+    });
+
+    let request = query("www.example.com", Rtype::A);
+    let received = NetworkTransport::default().query(&request, server).unwrap();
+    assert_eq!(received, real);
+    handle.join().unwrap();
+  }
+
+  #[test]
+  fn only_datagrams_from_other_sources_time_out() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server = socket.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+      let mut buf = [0u8; 512];
+      let (len, client) = socket.recv_from(&mut buf).unwrap();
+      let forger = UdpSocket::bind("127.0.0.1:0").unwrap();
+      forger.send_to(&buf[..len], client).unwrap();
+    });
+    let transport = NetworkTransport {
+      timeout: Duration::from_millis(200),
+      ..NetworkTransport::default()
+    };
+
+    let error = transport
+      .query(&query("www.example.com", Rtype::A), server)
+      .unwrap_err();
+    let error = error.downcast_ref::<io::Error>().unwrap();
+    assert!(matches!(
+      error.kind(),
+      ErrorKind::TimedOut | ErrorKind::WouldBlock
+    ));
+    handle.join().unwrap();
+  }
+
+  /// Binds a TCP server on localhost that echoes every query back on the connection it came
+  /// over, closing each connection after `per_connection` queries. The handle gives how many
+  /// connections were accepted once `total` queries have been answered.
+  fn tcp_echo_server(
+    per_connection: usize,
+    total: usize,
+  ) -> (SocketAddr, thread::JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+      let (mut connections, mut answered) = (0, 0);
+      while answered < total {
+        let (mut stream, _) = listener.accept().unwrap();
+        connections += 1;
+        for _ in 0..per_connection.min(total - answered) {
+          match tcp::read_message(&mut stream).unwrap() {
+            Some(request) => tcp::write_message(&mut stream, &request).unwrap(),
+            None => break,
+          }
+          answered += 1;
+        }
+      }
+      connections
+    });
+    (server, handle)
+  }
+
+  #[test]
+  fn sequential_tcp_queries_share_a_connection() {
+    let (server, handle) = tcp_echo_server(usize::MAX, 2);
+    let transport = NetworkTransport::default();
+    let request = query("www.example.com", Rtype::A);
+
+    for _ in 0..2 {
+      assert_eq!(transport.query_tcp(&request, server).unwrap(), request);
+    }
+    assert_eq!(handle.join().unwrap(), 1);
+  }
+
+  #[test]
+  fn connections_closed_by_the_server_are_replaced() {
+    let (server, handle) = tcp_echo_server(1, 2);
+    let transport = NetworkTransport::default();
+    let request = query("www.example.com", Rtype::A);
+
+    for _ in 0..2 {
+      assert_eq!(transport.query_tcp(&request, server).unwrap(), request);
+    }
+    assert_eq!(handle.join().unwrap(), 2);
+  }
+
+  #[test]
+  fn connections_are_not_kept_without_an_idle_timeout() {
+    let (server, handle) = tcp_echo_server(usize::MAX, 2);
+    let transport = NetworkTransport {
+      connections: ConnectionPool::new(Duration::ZERO),
+      ..NetworkTransport::default()
+    };
+    let request = query("www.example.com", Rtype::A);
+
+    for _ in 0..2 {
+      assert_eq!(transport.query_tcp(&request, server).unwrap(), request);
+    }
+    assert_eq!(handle.join().unwrap(), 2);
+  }
+
+  /// Binds a TLS server on localhost presenting the self-signed certificate for dns.test in
+  /// testdata, and runs `serve` on each of the first `connections` sessions it accepts. The
+  /// handle gives what `serve` returned for each.
+  fn tls_server<T: Send + 'static>(
+    connections: usize,
+    serve: impl Fn(&mut StreamOwned<ServerConnection, TcpStream>) -> T + Send + 'static,
+  ) -> (SocketAddr, thread::JoinHandle<Vec<T>>) {
+    let cert = CertificateDer::from(&include_bytes!("../testdata/dns.test.crt.der")[..]);
+    let key = PrivatePkcs8KeyDer::from(&include_bytes!("../testdata/dns.test.key.der")[..]);
+    let config = ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_no_client_auth()
+      .with_single_cert(vec![cert.into_owned()], key.clone_key().into())
+      .unwrap();
+    let config = Arc::new(config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+      (0..connections)
+        .map(|_| {
+          let (stream, _) = listener.accept().unwrap();
+          let session = ServerConnection::new(Arc::clone(&config)).unwrap();
+          serve(&mut StreamOwned::new(session, stream))
+        })
+        .collect()
+    });
+    (server, handle)
+  }
+
+  #[test]
+  fn dot_queries_round_trip_over_one_session() {
+    let (server, handle) = tls_server(1, |stream| {
+      let mut answered = 0;
+      while let Some(request) = tcp::read_message(stream).unwrap() {
+        tcp::write_message(stream, &request).unwrap();
+        stream.flush().unwrap();
+        answered += 1;
+      }
+      answered
+    });
+    let transport = DotTransport::new("dns.test", false).unwrap();
+    let request = query("www.example.com", Rtype::A);
+
+    for _ in 0..2 {
+      assert_eq!(transport.query(&request, server).unwrap(), request);
+    }
+    drop(transport);
+    assert_eq!(handle.join().unwrap(), [2]);
+  }
+
+  #[test]
+  fn dot_upstreams_with_untrusted_certificates_are_refused() {
+    let (server, handle) = tls_server(1, |stream| tcp::read_message(stream).is_err());
+    let transport = DotTransport::new("dns.test", true).unwrap();
+
+    let error = transport
+      .query(&query("www.example.com", Rtype::A), server)
+      .unwrap_err();
+    assert!(format!("{error:#}").contains("certificate"), "{error:#}");
+    assert_eq!(handle.join().unwrap(), [true]);
+  }
+
+  /// Reads an HTTP/1.1 request with a Content-Length body from `stream`, returning its request
+  /// line, its Content-Type and its body, or `None` once the client closes the connection.
+  fn read_http_request(stream: &mut impl Read) -> Option<(String, String, Vec<u8>)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+      return None;
+    }
+    let request_line = line.trim_end().to_string();
+    let (mut content_type, mut length) = (String::new(), 0);
+    loop {
+      line.clear();
+      reader.read_line(&mut line).unwrap();
+      match line.trim_end().split_once(": ") {
+        Some((name, value)) if name.eq_ignore_ascii_case("content-type") => {
+          content_type = value.to_string()
+        }
+        Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+          length = value.parse().unwrap()
+        }
+        Some(_) => {}
+        None => break,
+      }
+    }
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body).unwrap();
+    Some((request_line, content_type, body))
+  }
+
+  #[test]
+  fn doh_responses_are_parsed_by_the_resolver() {
+    let answer = answer("www.example.com", "192.0.2.10").into_octets();
+    let (server, handle) = tls_server(1, move |stream| {
+      let mut requests = Vec::new();
+      // the first response has a Content-Length and the second one is chunked
+      while let Some((request_line, content_type, body)) = read_http_request(stream) {
+        let mut response = answer.clone();
+        response[..2].copy_from_slice(&body[..2]);
+        let head = match requests.len() {
+          0 => format!("Content-Length: {}\r\n\r\n", response.len()),
+          _ => "Transfer-Encoding: chunked\r\n\r\n".to_string(),
+        };
+        let body = match requests.len() {
+          0 => response,
+          _ => [
+            format!("{:x}\r\n", response.len()).into_bytes(),
+            response,
+            b"\r\n0\r\n\r\n".to_vec(),
+          ]
+          .concat(),
+        };
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Type: {DNS_MESSAGE}\r\n{head}");
+        stream
+          .write_all(&[head.into_bytes(), body].concat())
+          .unwrap();
+        stream.flush().unwrap();
+        requests.push((request_line, content_type));
+      }
+      requests
+    });
+    let transport = DohTransport::new("https://dns.test/dns-query", false).unwrap();
+    let mut resolver = DnsServer::with_cache_capacity(0, transport).unwrap();
+    resolver.forwarder = Some(server);
+
+    for qname in ["www.example.com", "WWW.example.com"] {
+      let response = resolver.resolve(qname, Rtype::A).unwrap();
+      assert_eq!(answer_addrs(&response), [ip("192.0.2.10")], "{qname}");
+    }
+    drop(resolver);
+    let requests = handle.join().unwrap().remove(0);
+    assert_eq!(requests.len(), 2);
+    for (request_line, content_type) in requests {
+      assert_eq!(request_line, "POST /dns-query HTTP/1.1");
+      assert_eq!(content_type, DNS_MESSAGE);
+    }
+  }
+
+  #[test]
+  fn doh_errors_fail_the_lookup() {
+    let (server, handle) = tls_server(1, |stream| {
+      read_http_request(stream).unwrap();
+      let response = "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n";
+      stream.write_all(response.as_bytes()).unwrap();
+      stream.flush().unwrap();
+    });
+    let transport = DohTransport::new("https://dns.test/dns-query", false).unwrap();
+
+    let error = transport
+      .query(&query("www.example.com", Rtype::A), server)
+      .unwrap_err();
+    assert!(format!("{error:#}").contains("status 500"), "{error:#}");
+    handle.join().unwrap();
+  }
+
+  #[test]
+  fn receive_buffers_are_reused_up_to_a_limit() {
+    let pool = BufferPool::default();
+    let mut buf = pool.take();
+    assert_eq!(buf.len(), usize::from(EDNS_UDP_PAYLOAD_SIZE) + 1);
+    buf[0] = 0xAA;
+    pool.put(buf);
+    assert_eq!(pool.take()[0], 0xAA);
+
+    // as many queries as that at once allocate buffers of their own, but only so many are kept
+    let taken: Vec<_> = (0..MAX_POOLED_BUFFERS + 10).map(|_| pool.take()).collect();
+    for buf in taken {
+      pool.put(buf);
+    }
+    assert_eq!(pool.free.lock().unwrap().len(), MAX_POOLED_BUFFERS);
+  }
+}
@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+
+use anyhow::{Context, Result};
+
+use crate::{acl::Cidr, config::ViewConfig, hosts::Hosts};
+
+/// Static records served to the clients in some address ranges ahead of resolving anything, so
+/// different groups of clients can get different answers for the same names (a split horizon).
+/// Names a view doesn't list are resolved as usual for its clients too.
+#[derive(Debug)]
+pub struct View {
+  /// What the view is called in logs.
+  pub name: String,
+  /// Address ranges of the clients the view applies to.
+  pub clients: Vec<Cidr>,
+  /// The names the view answers and their addresses.
+  pub hosts: Hosts,
+}
+
+impl View {
+  /// Builds a view from its configuration, whose records are lines of a hosts file.
+  pub fn from_config(config: &ViewConfig) -> Result<View> {
+    let hosts = Hosts::parse(&config.records.join("\n"))
+      .with_context(|| format!("records of view {}", config.name))?;
+    Ok(View {
+      name: config.name.clone(),
+      clients: config.clients.clone(),
+      hosts,
+    })
+  }
+
+  /// Whether `client` is one of the view's clients.
+  pub fn matches(&self, client: IpAddr) -> bool {
+    self.clients.iter().any(|range| range.contains(client))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use domain::base::iana::Rtype;
+  use rand::rngs::StdRng;
+
+  use super::*;
+  use crate::{
+    tests::{answer_addrs, ask_from, ip, query, resolving_www_mock, server, EXAMPLE},
+    transport::mock::MockTransport,
+    DnsServer, Protocol,
+  };
+
+  fn view(name: &str, clients: &[&str], records: &[&str]) -> View {
+    View::from_config(&ViewConfig {
+      name: name.to_string(),
+      clients: clients.iter().map(|range| range.parse().unwrap()).collect(),
+      records: records.iter().map(|line| line.to_string()).collect(),
+    })
+    .unwrap()
+  }
+
+  fn views_server(views: Vec<View>) -> DnsServer<StdRng, MockTransport> {
+    let mock = resolving_www_mock();
+    let mut server = server(mock);
+    server.views = Arc::new(views);
+    server
+  }
+
+  #[test]
+  fn internal_clients_get_the_view_records() {
+    let mut server = views_server(vec![view(
+      "internal",
+      &["10.0.0.0/8"],
+      &["10.0.0.10 www.example.com"],
+    )]);
+
+    let client = "10.1.2.3:5353".parse().unwrap();
+    let response = ask_from(
+      &mut server,
+      &query("www.example.com", Rtype::A),
+      client,
+      Protocol::Udp,
+    );
+    assert_eq!(answer_addrs(&response), [ip("10.0.0.10")]);
+    assert!(server.transport.sent().is_empty());
+  }
+
+  #[test]
+  fn external_clients_are_resolved_for() {
+    let mut server = views_server(vec![view(
+      "internal",
+      &["10.0.0.0/8"],
+      &["10.0.0.10 www.example.com"],
+    )]);
+
+    let client = "203.0.113.7:5353".parse().unwrap();
+    let response = ask_from(
+      &mut server,
+      &query("www.example.com", Rtype::A),
+      client,
+      Protocol::Udp,
+    );
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    assert_eq!(server.transport.sent_to(EXAMPLE), 1);
+  }
+
+  #[test]
+  fn the_first_matching_view_wins() {
+    let mut server = views_server(vec![
+      view("lab", &["10.1.0.0/16"], &["10.1.0.10 www.example.com"]),
+      view("internal", &["10.0.0.0/8"], &["10.0.0.10 www.example.com"]),
+      view(
+        "everyone",
+        &["0.0.0.0/0"],
+        &["198.51.100.10 www.example.com"],
+      ),
+    ]);
+    let request = query("www.example.com", Rtype::A);
+
+    for (client, addr) in [
+      ("10.1.2.3:5353", "10.1.0.10"),
+      ("10.2.3.4:5353", "10.0.0.10"),
+      ("203.0.113.7:5353", "198.51.100.10"),
+    ] {
+      let response = ask_from(
+        &mut server,
+        &request,
+        client.parse().unwrap(),
+        Protocol::Udp,
+      );
+      assert_eq!(answer_addrs(&response), [ip(addr)], "{client}");
+    }
+    assert!(server.transport.sent().is_empty());
+  }
+}
@@ -0,0 +1,693 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs,
+  net::{Ipv4Addr, Ipv6Addr},
+  path::Path,
+  str::FromStr,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use domain::{
+  base::{
+    iana::{Class, Rcode, Rtype},
+    name::UncertainDname,
+    rdata::UnknownRecordData,
+    Dname, MessageBuilder, Question, Record, Serial, ToDname,
+  },
+  rdata::{Aaaa, AllRecordData, Cname, Mx, Ns, Ptr, Soa, A},
+};
+
+use crate::{config::ZoneConfig, Message, Octets};
+
+// Most CNAMEs followed within a zone while answering a single question
+const MAX_ZONE_CNAMES: usize = 8;
+
+type ZoneRecord = Record<Dname<Octets>, AllRecordData<Octets, Dname<Octets>>>;
+
+/// A zone served authoritatively from a master file (RFC 1035 section 5), whose names are
+/// answered without asking any other server. SOA, NS, A, AAAA, CNAME, MX, PTR and TXT records are
+/// understood. Names at or below an NS record other than the apex's are delegated elsewhere and
+/// get a referral, and wildcards are served as the literal name `*`.
+#[derive(Debug)]
+pub struct Zone {
+  /// The name at the apex of the zone.
+  pub origin: Dname<Octets>,
+  /// The records of each name in the zone, in the order the file lists them.
+  records: HashMap<Dname<Octets>, Vec<ZoneRecord>>,
+  /// Every name with records along with every name between it and the origin, which exist even
+  /// without records of their own.
+  existing: HashSet<Dname<Octets>>,
+  /// The SOA record negative answers carry in their authority section, whose TTL is the
+  /// negative caching TTL (RFC 2308 section 3).
+  negative_soa: ZoneRecord,
+}
+
+/// A field of a master file entry. Quoted fields keep their escapes, which only mean something
+/// once it's known what the field is.
+struct Field {
+  text: String,
+  quoted: bool,
+}
+
+/// A master file entry: a directive or a record, which parentheses may spread over several lines.
+struct Entry {
+  /// The line the entry starts on.
+  line: usize,
+  /// Whether the entry starts with whitespace, leaving out its owner to repeat the last one.
+  blank_owner: bool,
+  fields: Vec<Field>,
+}
+
+impl Zone {
+  /// Reads the zone a configuration describes from its master file.
+  pub fn from_config(config: &ZoneConfig) -> Result<Zone> {
+    let origin = parse_name(&config.origin, &Dname::root_vec())
+      .with_context(|| format!("invalid zone origin {:?}", config.origin))?;
+    Zone::load(origin, &config.file)
+  }
+
+  /// Reads the zone at `origin` from a master file.
+  pub fn load(origin: Dname<Octets>, path: impl AsRef<Path>) -> Result<Zone> {
+    let path = path.as_ref();
+    let contents =
+      fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    Zone::parse(origin, &contents).with_context(|| format!("parsing {}", path.display()))
+  }
+
+  /// Reads the zone at `origin` from the contents of a master file. Relative names are relative
+  /// to `origin` until a `$ORIGIN` directive says otherwise, and records without a TTL get the
+  /// one `$TTL` sets, or else the previous record's. `$INCLUDE` isn't supported, and every record
+  /// has to be in the Internet class and inside the zone, whose apex needs a single SOA record.
+  pub fn parse(origin: Dname<Octets>, contents: &str) -> Result<Zone> {
+    let mut current_origin = origin.clone();
+    let mut default_ttl = None;
+    let mut last_ttl = None;
+    let mut owner: Option<Dname<Octets>> = None;
+    let mut records: HashMap<Dname<Octets>, Vec<ZoneRecord>> = HashMap::new();
+    let mut soa = None;
+    for entry in entries(contents)? {
+      let context = || format!("line {}", entry.line);
+      let mut fields = entry.fields.iter();
+      let first = fields.next().expect("entries have fields");
+      match first.text.to_ascii_uppercase().as_str() {
+        "$ORIGIN" => {
+          let name = single_field(fields).with_context(context)?;
+          current_origin = parse_name(name, &current_origin).with_context(context)?;
+          continue;
+        }
+        "$TTL" => {
+          let ttl = single_field(fields).with_context(context)?;
+          default_ttl = Some(parse_number(ttl, "TTL").with_context(context)?);
+          continue;
+        }
+        "$INCLUDE" => bail!("line {}: $INCLUDE isn't supported", entry.line),
+        directive if directive.starts_with('$') => {
+          bail!("line {}: unknown directive {}", entry.line, first.text)
+        }
+        _ => {}
+      }
+
+      let mut fields = entry.fields.iter().peekable();
+      if !entry.blank_owner {
+        let name = &fields.next().expect("entries have fields").text;
+        owner = Some(parse_name(name, &current_origin).with_context(context)?);
+      }
+      let owner = match &owner {
+        Some(owner) => owner.clone(),
+        None => bail!("line {}: the first record has no owner", entry.line),
+      };
+      ensure!(
+        owner.ends_with(&origin),
+        "line {}: {owner} is outside the zone",
+        entry.line
+      );
+
+      // the TTL and class are both optional and may come in either order
+      let mut ttl = None;
+      while let Some(field) = fields.next_if(|field| is_ttl_or_class(&field.text)) {
+        if field.text.eq_ignore_ascii_case("IN") {
+          continue;
+        }
+        ttl = Some(parse_number(&field.text, "TTL").with_context(context)?);
+      }
+      let ttl = match ttl.or(default_ttl).or(last_ttl) {
+        Some(ttl) => ttl,
+        None => bail!("line {}: no TTL given and no $TTL set", entry.line),
+      };
+      last_ttl = Some(ttl);
+      let rtype = match fields.next() {
+        Some(field) => Rtype::from_str(&field.text)
+          .map_err(|_| anyhow!("line {}: unknown record type {}", entry.line, field.text))?,
+        None => bail!("line {}: missing record type", entry.line),
+      };
+      let rdata: Vec<_> = fields.collect();
+      let data = parse_rdata(rtype, &rdata, &current_origin)
+        .with_context(|| format!("line {}: {rtype} record", entry.line))?;
+
+      if rtype == Rtype::Soa {
+        ensure!(
+          owner == origin,
+          "line {}: SOA record for {owner} isn't at the apex",
+          entry.line
+        );
+        ensure!(soa.is_none(), "line {}: second SOA record", entry.line);
+        soa = Some(Record::new(owner.clone(), Class::In, ttl, data.clone()));
+      }
+      let set = records.entry(owner.clone()).or_default();
+      // a CNAME's owner can't have anything else (RFC 1034 section 3.6.2)
+      ensure!(
+        set
+          .iter()
+          .all(|record| record.rtype() != Rtype::Cname && rtype != Rtype::Cname),
+        "line {}: {owner} has a CNAME and other records",
+        entry.line
+      );
+      set.push(Record::new(owner, Class::In, ttl, data));
+    }
+
+    let soa = match soa {
+      Some(soa) => soa,
+      None => bail!("no SOA record for {origin}"),
+    };
+    let minimum = match soa.data() {
+      AllRecordData::Soa(data) => data.minimum(),
+      _ => unreachable!("the SOA record has SOA data"),
+    };
+    let negative_soa = Record::new(
+      soa.owner().clone(),
+      Class::In,
+      soa.ttl().min(minimum),
+      soa.data().clone(),
+    );
+    let mut existing = HashSet::new();
+    for name in records.keys() {
+      for above in name.iter_suffixes() {
+        let above: Dname<Octets> = above.to_dname()?;
+        if !above.ends_with(&origin) || !existing.insert(above) {
+          break;
+        }
+      }
+    }
+    Ok(Zone {
+      origin,
+      records,
+      existing,
+      negative_soa,
+    })
+  }
+
+  /// Whether `name` is at or below the zone's apex.
+  pub fn contains<N: ToDname>(&self, name: &N) -> bool {
+    name.ends_with(&self.origin)
+  }
+
+  /// Answers `question`, whose name is in the zone, the way the zone's authoritative server
+  /// would: with the records asked for, following CNAMEs as long as they stay in the zone and
+  /// aren't delegated, or with NODATA or NXDOMAIN and the zone's SOA record in the authority
+  /// section. AA is set on everything but referrals.
+  pub fn answer<N: ToDname>(&self, question: &Question<N>) -> Result<Message> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_qr(true);
+    let mut builder = builder.question();
+    builder.push(question)?;
+
+    let qname: Dname<Octets> = question.qname().to_dname()?;
+    if let Some(cut) = self.delegation(&qname)? {
+      return self.referral(builder.answer(), cut);
+    }
+    let qtype = question.qtype();
+    let mut builder = builder.answer();
+    builder.header_mut().set_aa(true);
+    let mut name = qname;
+    for _ in 0..=MAX_ZONE_CNAMES {
+      let records = match self.records.get(&name) {
+        Some(records) => records,
+        None if self.existing.contains(&name) => break,
+        None => {
+          builder.header_mut().set_rcode(Rcode::NXDomain);
+          break;
+        }
+      };
+      let mut answered = false;
+      for record in records {
+        if record.rtype() == qtype || qtype == Rtype::Any {
+          builder.push(record)?;
+          answered = true;
+        }
+      }
+      if answered {
+        return Ok(builder.into_message());
+      }
+      let target = match records.first().map(|record| record.data()) {
+        Some(AllRecordData::Cname(cname)) => cname.cname().clone(),
+        _ => break,
+      };
+      builder.push(&records[0])?;
+      // a target elsewhere is for the client to resolve, as it would be for any other server
+      if !self.contains(&target) || self.delegation(&target)?.is_some() {
+        return Ok(builder.into_message());
+      }
+      name = target;
+    }
+    let mut builder = builder.authority();
+    builder.push(&self.negative_soa)?;
+    Ok(builder.into_message())
+  }
+
+  /// The closest name to the apex at or above `name` that has NS records of its own, other than
+  /// the apex, meaning the names below it belong to another zone.
+  fn delegation(&self, name: &Dname<Octets>) -> Result<Option<Dname<Octets>>> {
+    let mut cut = None;
+    for above in name.iter_suffixes() {
+      let above: Dname<Octets> = above.to_dname()?;
+      if above == self.origin {
+        break;
+      }
+      let delegated = self
+        .records
+        .get(&above)
+        .is_some_and(|records| records.iter().any(|record| record.rtype() == Rtype::Ns));
+      if delegated {
+        cut = Some(above);
+      }
+    }
+    Ok(cut)
+  }
+
+  /// Finishes a referral to the servers `cut` is delegated to, with the addresses the zone has
+  /// for them as glue.
+  fn referral(
+    &self,
+    builder: domain::base::message_builder::AnswerBuilder<Octets>,
+    cut: Dname<Octets>,
+  ) -> Result<Message> {
+    let mut builder = builder.authority();
+    let delegation: Vec<_> = self.records[&cut]
+      .iter()
+      .filter(|record| record.rtype() == Rtype::Ns)
+      .collect();
+    for record in &delegation {
+      builder.push(*record)?;
+    }
+    let mut builder = builder.additional();
+    for record in delegation {
+      let host = match record.data() {
+        AllRecordData::Ns(ns) => ns.nsdname(),
+        _ => continue,
+      };
+      for glue in self.records.get(host).into_iter().flatten() {
+        if matches!(glue.rtype(), Rtype::A | Rtype::Aaaa) {
+          builder.push(glue)?;
+        }
+      }
+    }
+    Ok(builder.into_message())
+  }
+}
+
+/// Splits the contents of a master file into entries, leaving out comments and blank lines and
+/// joining the lines parentheses group together.
+fn entries(contents: &str) -> Result<Vec<Entry>> {
+  let mut entries = Vec::new();
+  let mut entry: Option<Entry> = None;
+  let mut field: Option<Field> = None;
+  let mut depth = 0;
+  let mut line = 1;
+  let mut line_start = true;
+  let mut chars = contents.chars().peekable();
+  while let Some(c) = chars.next() {
+    let starts_line = line_start;
+    line_start = false;
+    let quoted = field.as_ref().is_some_and(|field| field.quoted);
+    if quoted {
+      let text = &mut field.as_mut().expect("quoted fields exist").text;
+      match c {
+        '"' => {
+          let field = field.take().expect("quoted fields exist");
+          entry
+            .as_mut()
+            .expect("fields belong to entries")
+            .fields
+            .push(field);
+        }
+        '\\' => {
+          text.push(c);
+          text.extend(chars.next());
+        }
+        '\n' => bail!("line {line}: unterminated quoted string"),
+        _ => text.push(c),
+      }
+      continue;
+    }
+    if c.is_whitespace() || matches!(c, '(' | ')' | ';' | '"') {
+      if let Some(field) = field.take() {
+        entry
+          .as_mut()
+          .expect("fields belong to entries")
+          .fields
+          .push(field);
+      }
+    }
+    match c {
+      ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+      '(' => depth += 1,
+      ')' if depth == 0 => bail!("line {line}: unbalanced parenthesis"),
+      ')' => depth -= 1,
+      '\n' => {
+        line += 1;
+        line_start = true;
+        if depth == 0 {
+          entries.extend(entry.take().filter(|entry| !entry.fields.is_empty()));
+        }
+      }
+      c if c.is_whitespace() => {}
+      _ => {
+        entry.get_or_insert_with(|| Entry {
+          line,
+          blank_owner: false,
+          fields: Vec::new(),
+        });
+        if c == '"' {
+          field = Some(Field {
+            text: String::new(),
+            quoted: true,
+          });
+          continue;
+        }
+        let field = field.get_or_insert_with(|| Field {
+          text: String::new(),
+          quoted: false,
+        });
+        field.text.push(c);
+        if c == '\\' {
+          field.text.extend(chars.next());
+        }
+        continue;
+      }
+    }
+    // an entry starting with whitespace repeats the previous owner
+    if starts_line && c != '\n' && c.is_whitespace() && entry.is_none() {
+      entry = Some(Entry {
+        line,
+        blank_owner: true,
+        fields: Vec::new(),
+      });
+    }
+  }
+  ensure!(
+    field.as_ref().is_none_or(|field| !field.quoted),
+    "unterminated quoted string"
+  );
+  ensure!(depth == 0, "unbalanced parenthesis");
+  if let Some(field) = field {
+    entry
+      .as_mut()
+      .expect("fields belong to entries")
+      .fields
+      .push(field);
+  }
+  entries.extend(entry.filter(|entry| !entry.fields.is_empty()));
+  Ok(entries)
+}
+
+/// Parses a name as it appears in a master file: `@` stands for `origin`, and names that don't
+/// end with a dot are relative to it.
+fn parse_name(name: &str, origin: &Dname<Octets>) -> Result<Dname<Octets>> {
+  match name {
+    "@" => return Ok(origin.clone()),
+    "." => return Ok(Dname::root_vec()),
+    _ => {}
+  }
+  match UncertainDname::<Octets>::from_str(name).with_context(|| format!("invalid name {name}"))? {
+    UncertainDname::Absolute(name) => Ok(name),
+    UncertainDname::Relative(relative) => {
+      let name = relative.chain(origin).map_err(anyhow::Error::from)?;
+      Ok(name.to_dname()?)
+    }
+  }
+}
+
+fn parse_number<T: FromStr>(field: &str, what: &str) -> Result<T> {
+  field.parse().map_err(|_| anyhow!("invalid {what} {field}"))
+}
+
+/// Whether a field before the record type is a TTL or the class rather than the type.
+fn is_ttl_or_class(field: &str) -> bool {
+  field.eq_ignore_ascii_case("IN") || field.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The only field left in a directive.
+fn single_field<'a>(mut fields: impl Iterator<Item = &'a Field>) -> Result<&'a str> {
+  match (fields.next(), fields.next()) {
+    (Some(field), None) => Ok(&field.text),
+    _ => bail!("expected a single value"),
+  }
+}
+
+fn parse_rdata(
+  rtype: Rtype,
+  fields: &[&Field],
+  origin: &Dname<Octets>,
+) -> Result<AllRecordData<Octets, Dname<Octets>>> {
+  let text: Vec<_> = fields.iter().map(|field| field.text.as_str()).collect();
+  let data = match (rtype, text.as_slice()) {
+    (Rtype::A, [addr]) => A::new(parse_number::<Ipv4Addr>(addr, "address")?).into(),
+    (Rtype::Aaaa, [addr]) => Aaaa::new(parse_number::<Ipv6Addr>(addr, "address")?).into(),
+    (Rtype::Ns, [host]) => Ns::new(parse_name(host, origin)?).into(),
+    (Rtype::Cname, [target]) => Cname::new(parse_name(target, origin)?).into(),
+    (Rtype::Ptr, [host]) => Ptr::new(parse_name(host, origin)?).into(),
+    (Rtype::Mx, [preference, exchange]) => Mx::new(
+      parse_number(preference, "preference")?,
+      parse_name(exchange, origin)?,
+    )
+    .into(),
+    (Rtype::Soa, [mname, rname, serial, refresh, retry, expire, minimum]) => Soa::new(
+      parse_name(mname, origin)?,
+      parse_name(rname, origin)?,
+      Serial(parse_number(serial, "serial")?),
+      parse_number(refresh, "refresh")?,
+      parse_number(retry, "retry")?,
+      parse_number(expire, "expire")?,
+      parse_number(minimum, "minimum")?,
+    )
+    .into(),
+    (Rtype::Txt, [_, ..]) => {
+      // each field is a character string of its own, which domain's Txt can't be built from
+      let mut wire = Vec::new();
+      for field in fields {
+        let text = unescape(&field.text)?;
+        ensure!(text.len() <= 255, "text longer than 255 bytes");
+        wire.push(text.len() as u8);
+        wire.extend(text);
+      }
+      AllRecordData::Other(UnknownRecordData::from_octets(Rtype::Txt, wire))
+    }
+    (Rtype::A | Rtype::Aaaa | Rtype::Ns | Rtype::Cname | Rtype::Ptr | Rtype::Mx, _)
+    | (Rtype::Soa | Rtype::Txt, _) => bail!("wrong number of fields"),
+    _ => bail!("unsupported record type"),
+  };
+  Ok(data)
+}
+
+/// Turns the text of a character string into its bytes, where `\X` stands for `X` and `\DDD` for
+/// the byte with the decimal value `DDD` (RFC 1035 section 5.1).
+fn unescape(text: &str) -> Result<Vec<u8>> {
+  let mut bytes = Vec::new();
+  let mut rest = text.as_bytes();
+  while let Some((&b, after)) = rest.split_first() {
+    rest = after;
+    if b != b'\\' {
+      bytes.push(b);
+      continue;
+    }
+    match rest {
+      [d1, d2, d3, after @ ..] if [d1, d2, d3].iter().all(|d| d.is_ascii_digit()) => {
+        let value = u32::from(d1 - b'0') * 100 + u32::from(d2 - b'0') * 10 + u32::from(d3 - b'0');
+        bytes.push(u8::try_from(value).map_err(|_| anyhow!("invalid escape in {text}"))?);
+        rest = after;
+      }
+      [c, after @ ..] => {
+        bytes.push(*c);
+        rest = after;
+      }
+      [] => bail!("{text} ends with a backslash"),
+    }
+  }
+  Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use domain::rdata::Txt;
+
+  use super::*;
+  use crate::{
+    addresses,
+    tests::{answer_addrs, ask, ip, name, query, server},
+    transport::mock::MockTransport,
+  };
+
+  const ZONE: &str = r#"$ORIGIN example.test.
+$TTL 3600
+@       IN  SOA   ns1 hostmaster (
+                  2024010101 ; serial
+                  7200 900 1209600 300 )
+        IN  NS    ns1
+        IN  MX    10 mail
+ns1         A     192.0.2.53
+www     600 A     192.0.2.10
+            AAAA  2001:db8::10
+mail        A     192.0.2.25
+alias       CNAME www
+info        TXT   "hello world" "v=1\;x"
+lab         NS    ns.lab
+ns.lab      A     192.0.2.99
+a.b.deep    A     192.0.2.77
+"#;
+
+  fn zone() -> Zone {
+    Zone::parse(name("example.test"), ZONE).unwrap()
+  }
+
+  fn ask_zone(qname: &str, qtype: Rtype) -> Message {
+    zone()
+      .answer(&Question::new_in(name(qname), qtype))
+      .unwrap()
+  }
+
+  /// The owner and type of each record in the authority section of `response`.
+  fn authorities(response: &Message) -> Vec<(String, Rtype)> {
+    response
+      .authority()
+      .unwrap()
+      .map(|record| record.unwrap())
+      .map(|record| (record.owner().to_string(), record.rtype()))
+      .collect()
+  }
+
+  #[test]
+  fn positive_answers_are_authoritative() {
+    let response = ask_zone("www.example.test", Rtype::A);
+    assert!(response.header().aa());
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    let ttls: Vec<_> = response
+      .answer()
+      .unwrap()
+      .map(|record| record.unwrap().ttl())
+      .collect();
+    assert_eq!(ttls, [600]);
+    // the AAAA record repeats the owner of the line above it
+    let response = ask_zone("www.example.test", Rtype::Aaaa);
+    assert_eq!(answer_addrs(&response), [ip("2001:db8::10")]);
+  }
+
+  #[test]
+  fn missing_types_get_nodata_with_the_soa() {
+    let response = ask_zone("mail.example.test", Rtype::Mx);
+    assert!(response.header().aa());
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+    assert_eq!(response.header_counts().ancount(), 0);
+    assert_eq!(
+      authorities(&response),
+      [("example.test".to_string(), Rtype::Soa)]
+    );
+    // names only existing above others get NODATA too
+    let response = ask_zone("b.deep.example.test", Rtype::A);
+    assert_eq!(response.header().rcode(), Rcode::NoError);
+  }
+
+  #[test]
+  fn missing_names_get_nxdomain_with_the_soa() {
+    let response = ask_zone("nope.example.test", Rtype::A);
+    assert!(response.header().aa());
+    assert_eq!(response.header().rcode(), Rcode::NXDomain);
+    let soa = response.authority().unwrap().next().unwrap().unwrap();
+    assert_eq!(soa.rtype(), Rtype::Soa);
+    // the SOA record is served for no longer than its minimum
+    assert_eq!(soa.ttl(), 300);
+  }
+
+  #[test]
+  fn cnames_in_the_zone_are_followed() {
+    let response = ask_zone("alias.example.test", Rtype::A);
+    let answers: Vec<_> = response
+      .answer()
+      .unwrap()
+      .map(|record| record.unwrap().rtype())
+      .collect();
+    assert_eq!(answers, [Rtype::Cname, Rtype::A]);
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+  }
+
+  #[test]
+  fn apex_and_text_records_are_parsed() {
+    let response = ask_zone("example.test", Rtype::Mx);
+    let mx = response
+      .answer()
+      .unwrap()
+      .limit_to::<Mx<_>>()
+      .next()
+      .unwrap()
+      .unwrap();
+    assert_eq!(mx.data().preference(), 10);
+    assert_eq!(mx.data().exchange().to_string(), "mail.example.test");
+    let response = ask_zone("info.example.test", Rtype::Txt);
+    let txt = response
+      .answer()
+      .unwrap()
+      .limit_to::<Txt<_>>()
+      .next()
+      .unwrap()
+      .unwrap();
+    let strings: Vec<_> = txt.data().iter().map(|string| string.to_vec()).collect();
+    assert_eq!(strings, [b"hello world".to_vec(), b"v=1;x".to_vec()]);
+  }
+
+  #[test]
+  fn delegated_names_get_referrals_with_glue() {
+    let response = ask_zone("www.lab.example.test", Rtype::A);
+    assert!(!response.header().aa());
+    assert_eq!(response.header_counts().ancount(), 0);
+    assert_eq!(
+      authorities(&response),
+      [("lab.example.test".to_string(), Rtype::Ns)]
+    );
+    let glue = addresses(response.additional().unwrap(), |_| true);
+    assert_eq!(glue, [ip("192.0.2.99")]);
+  }
+
+  #[test]
+  fn invalid_zones_are_rejected() {
+    let origin = name("example.test");
+    let soa = "@ 3600 SOA ns1 hostmaster 1 7200 900 1209600 300\n";
+    for contents in [
+      "www 3600 A 192.0.2.10\n".to_string(),
+      format!("{soa}www.example.org. 3600 A 192.0.2.10\n"),
+      format!("{soa}www 3600 CNAME mail\nwww 3600 A 192.0.2.10\n"),
+      format!("{soa}www A 192.0.2.10 (\n"),
+      format!("{soa}$INCLUDE other.zone\n"),
+      format!("{soa}www 3600 A 192.0.2.300\n"),
+    ] {
+      assert!(
+        Zone::parse(origin.clone(), &contents).is_err(),
+        "{contents}"
+      );
+    }
+  }
+
+  #[test]
+  fn client_queries_in_served_zones_are_answered_authoritatively() {
+    let mut server = server(MockTransport::default());
+    server.zones = Arc::new(vec![zone()]);
+
+    let response = ask(&mut server, &query("www.example.test", Rtype::A));
+    assert!(response.header().aa());
+    assert_eq!(answer_addrs(&response), [ip("192.0.2.10")]);
+    let response = ask(&mut server, &query("nope.example.test", Rtype::A));
+    assert_eq!(response.header().rcode(), Rcode::NXDomain);
+    assert!(server.transport.sent().is_empty());
+  }
+}